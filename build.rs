@@ -1,6 +1,92 @@
+use std::{
+    fs,
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Certificates embedded by config.rs with the embedded-certs feature, keep them in sync
+const CERTIFICATES: [(&str, &str); 3] = [
+    ("certificates/AmazonRootCA1.pem", "AWS root CA certificate"),
+    (
+        "certificates/esp32-epaper-main.client.crt",
+        "device certificate",
+    ),
+    (
+        "certificates/esp32-epaper-main.private.key",
+        "device private key",
+    ),
+];
+
 // Necessary because of this issue: https://github.com/rust-lang/cargo/issues/9641
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    embuild::build::CfgArgs::output_propagated("ESP_IDF")?;
-    embuild::build::LinkArgs::output_propagated("ESP_IDF")?;
+    // Without the feature, the certificates are provisioned in NVS, or not needed at all over plain TCP
+    if std::env::var_os("CARGO_FEATURE_EMBEDDED_CERTS").is_some() {
+        check_certificates()?;
+    }
+    export_build_info();
+    // ESP-IDF isn't built for the unit tests on the host
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("espidf") {
+        embuild::build::CfgArgs::output_propagated("ESP_IDF")?;
+        embuild::build::LinkArgs::output_propagated("ESP_IDF")?;
+    }
+    Ok(())
+}
+
+/// Sets the BUILD_TIMESTAMP (in seconds since the epoch, SOURCE_DATE_EPOCH for reproducible builds) and GIT_HASH
+/// (the short hash of the commit, with a "-dirty" suffix when the tree has changes) variables read by the firmware.
+/// The hash is left unset outside of a git checkout.
+fn export_build_info() {
+    // Built again with the sources, so that the timestamp follows them
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let timestamp = std::env::var("SOURCE_DATE_EPOCH").ok().unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs())
+            .to_string()
+    });
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", timestamp);
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    if let Some(hash) = git(&["rev-parse", "--short", "HEAD"]).filter(|hash| !hash.is_empty()) {
+        let dirty = git(&["status", "--porcelain"]).map_or(false, |changes| !changes.is_empty());
+        println!(
+            "cargo:rustc-env=GIT_HASH={}{}",
+            hash,
+            if dirty { "-dirty" } else { "" }
+        );
+    }
+}
+
+/// Fails the build when a certificate is missing or isn't in PEM format, rather than at the TLS handshake
+fn check_certificates() -> Result<(), String> {
+    println!("cargo:rerun-if-changed=build.rs");
+    for (path, name) in CERTIFICATES {
+        println!("cargo:rerun-if-changed={}", path);
+        let bytes = fs::read(Path::new(path)).map_err(|e| {
+            format!(
+                "couldn't read the {} from {}: {}. Register the thing on AWS IoT and place its certificates there, see the README",
+                name, path, e
+            )
+        })?;
+        if !bytes.windows(11).any(|window| window == b"-----BEGIN ") {
+            return Err(format!(
+                "the {} in {} isn't in PEM format, it has no -----BEGIN marker",
+                name, path
+            ));
+        }
+        if bytes.contains(&0) {
+            return Err(format!("the {} in {} contains a NUL byte", name, path));
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,168 @@
+//! Local time, synchronized over SNTP
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::sntp::{EspSntp, SyncStatus};
+use log::*;
+use std::{
+    mem, thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Local calendar time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LocalTime {
+    pub year: i32,
+    /// From 1 to 12
+    pub month: u32,
+    pub day: u32,
+    /// From 0 (Sunday) to 6
+    pub weekday: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl LocalTime {
+    const WEEKDAYS: [&'static str; 7] = [
+        "Sunday",
+        "Monday",
+        "Tuesday",
+        "Wednesday",
+        "Thursday",
+        "Friday",
+        "Saturday",
+    ];
+    const MONTHS: [&'static str; 12] = [
+        "January",
+        "February",
+        "March",
+        "April",
+        "May",
+        "June",
+        "July",
+        "August",
+        "September",
+        "October",
+        "November",
+        "December",
+    ];
+
+    /// The date in words, e.g. "Wednesday 14 October 2026"
+    pub fn date(&self) -> String {
+        format!(
+            "{} {} {} {}",
+            Self::WEEKDAYS[self.weekday as usize % 7],
+            self.day,
+            Self::MONTHS[(self.month as usize + 11) % 12],
+            self.year
+        )
+    }
+}
+
+/// "HH:MM" of the time, or "--:--" before the clock has been synchronized
+pub fn clock_label(time: Option<LocalTime>) -> String {
+    match time {
+        Some(time) => format!("{:02}:{:02}", time.hour, time.minute),
+        None => "--:--".to_string(),
+    }
+}
+
+/// Sets the local time zone, as a POSIX TZ string
+#[cfg(target_os = "espidf")]
+pub fn set_timezone(timezone: &str) {
+    std::env::set_var("TZ", timezone);
+    unsafe { esp_idf_sys::tzset() };
+}
+
+/// Waits for the first SNTP synchronization of the system clock, up to `timeout`. Returns whether it happened.
+#[cfg(target_os = "espidf")]
+pub fn wait_for_time_sync(sntp: &EspSntp, timeout: Duration) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < timeout {
+        if sntp.get_sync_status() == SyncStatus::Completed {
+            info!("Time synchronized: {}", clock_label(now()));
+            return true;
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+    warn!(
+        "The time couldn't be synchronized within {}s, it will be shown once it is",
+        timeout.as_secs()
+    );
+    false
+}
+
+/// Time left until the clock shows the next minute
+pub fn until_next_minute() -> Duration {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let into_minute = Duration::new(since_epoch.as_secs() % 60, since_epoch.subsec_nanos());
+    Duration::from_secs(60) - into_minute
+}
+
+/// Current local time, in the time zone set by set_timezone. Returns None until the clock has been synchronized.
+#[cfg(target_os = "espidf")]
+pub fn now() -> Option<LocalTime> {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+    // Before the first SNTP sync the clock starts from 1970
+    if since_epoch.as_secs() < 1_600_000_000 {
+        return None;
+    }
+    let seconds = since_epoch.as_secs() as esp_idf_sys::time_t;
+    let mut tm: esp_idf_sys::tm = unsafe { mem::zeroed() };
+    if unsafe { esp_idf_sys::localtime_r(&seconds, &mut tm) }.is_null() {
+        return None;
+    }
+    Some(LocalTime {
+        year: tm.tm_year + 1900,
+        month: tm.tm_mon as u32 + 1,
+        day: tm.tm_mday as u32,
+        weekday: tm.tm_wday as u32,
+        hour: tm.tm_hour as u32,
+        minute: tm.tm_min as u32,
+        second: tm.tm_sec as u32,
+    })
+}
+
+/// Off-device the clock is never synchronized
+#[cfg(not(target_os = "espidf"))]
+pub fn now() -> Option<LocalTime> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIME: LocalTime = LocalTime {
+        year: 2026,
+        month: 10,
+        day: 14,
+        weekday: 3,
+        hour: 9,
+        minute: 5,
+        second: 59,
+    };
+
+    #[test]
+    fn clock_label_pads_the_time() {
+        assert_eq!(clock_label(Some(TIME)), "09:05");
+        let evening = LocalTime {
+            hour: 23,
+            minute: 59,
+            ..TIME
+        };
+        assert_eq!(clock_label(Some(evening)), "23:59");
+    }
+
+    #[test]
+    fn clock_label_before_the_sync() {
+        assert_eq!(clock_label(None), "--:--");
+    }
+
+    #[test]
+    fn date_in_words() {
+        assert_eq!(TIME.date(), "Wednesday 14 October 2026");
+    }
+}
@@ -0,0 +1,1145 @@
+//! Compile-time defaults, the runtime configuration stored in NVS and its serial provisioning
+
+use crate::{
+    display::{Dithering, Region, Screensaver, TextLayout},
+    mqtt::{InvalidUtf8, MqttSecurity, QueueFullPolicy, TopicHandler},
+    panel::{PANEL_HEIGHT, PANEL_WIDTH},
+};
+use embedded_svc::{mqtt::client::QoS, wifi::AuthMethod};
+use epd_waveshare::prelude::DisplayRotation;
+#[cfg(target_os = "espidf")]
+use esp_idf_hal::{delay::TickType, uart::UartDriver};
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+#[cfg(target_os = "espidf")]
+use esp_idf_sys::EspError;
+use log::LevelFilter;
+use log::*;
+use std::{
+    fmt,
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+// Orientation of the panel inside its frame. Drawing happens in the rotated (logical) coordinates,
+// so with Rotate90/Rotate270 the logical width and height of the display are swapped.
+// A "rotate=<0|90|180|270>" payload on "<MQTT topic>/<MQTT_CONTROL_SUBTOPIC>" changes it at runtime, and the rotation
+// chosen is kept in NVS, in place of this one, across reboots.
+pub const DISPLAY_ROTATION: DisplayRotation = DisplayRotation::Rotate0;
+// Size of the display in the rotated (logical) coordinates, derived from the panel model and DISPLAY_ROTATION.
+// LAYOUT_REGIONS are laid out for it, those exceeding the display once rotated at runtime are clipped.
+pub const DISPLAY_WIDTH: u32 = match DISPLAY_ROTATION {
+    DisplayRotation::Rotate0 | DisplayRotation::Rotate180 => PANEL_WIDTH,
+    DisplayRotation::Rotate90 | DisplayRotation::Rotate270 => PANEL_HEIGHT,
+};
+pub const DISPLAY_HEIGHT: u32 = match DISPLAY_ROTATION {
+    DisplayRotation::Rotate0 | DisplayRotation::Rotate180 => PANEL_HEIGHT,
+    DisplayRotation::Rotate90 | DisplayRotation::Rotate270 => PANEL_WIDTH,
+};
+
+// GPIOs wired to the panel and the SPI clock rate, see BoardConfig. The panel refresh is mostly spent waiting for the
+// panel itself, but the frame transfer gets noticeably faster at a few MHz.
+pub const BOARD: BoardConfig = BoardConfig::FIREBEETLE;
+// Self-test checking the wiring of a new board, right after the panel is set up: it's filled with black, then white,
+// then shows its borders and "EPD OK <resolution> baud=<SPI clock>". Skipped on wakes from deep sleep.
+pub const SELF_TEST_ON_BOOT: bool = false;
+// Shows the reason of resets caused by a crash, a watchdog or a brownout on the panel at boot, until the first message
+pub const RESET_REASON_ON_PANEL: bool = false;
+// Splash drawn once the panel is set up, until the first message replaces it: the MQTT client id of the device, the
+// firmware version and SPLASH_TEXT. Only when the content of the panel is unknown, e.g. on a fresh panel, so the reboots
+// and wakes finding the content they drew keep it, and not over the self-test or the reset reason.
+pub const SPLASH_SCREEN: bool = true;
+pub const SPLASH_TEXT: &str = "Waiting for messages...";
+
+// Number of consecutive partial refreshes after which a full refresh is forced to clear ghosting, on the panels
+// supporting them. The black/white drivers don't implement partial updates, so only the tri-color panel uses them
+pub const PARTIAL_REFRESHES_BEFORE_FULL: u32 = 10;
+// Frame diff: a copy of the frame last pushed to the panel is kept (one more framebuffer of heap, two on the tri-color
+// panel) and compared with the new one, skipping the refreshes which change no pixel and narrowing the partial ones
+// down to the bounding box of the changed pixels. Changes covering more than FRAME_DIFF_FULL_RATIO of the panel get a
+// full refresh instead.
+pub const FRAME_DIFF: bool = true;
+pub const FRAME_DIFF_FULL_RATIO: f32 = 0.5;
+
+// Anti-ghosting: the panel is cleared to white with a full refresh, before redrawing the frame,
+// after this many updates or this long since the last clear, whichever comes first
+pub const ANTI_GHOSTING_UPDATES: u32 = 50;
+pub const ANTI_GHOSTING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Shortest time between the end of a panel refresh and the display of the next message, so that bursts of messages
+// don't refresh the panel over and over: the messages arriving sooner wait, only the latest one of each topic being
+// kept. Waveshare recommends at least 180s between refreshes for panels refreshed around the clock. None displays the
+// messages as soon as they arrive.
+pub const MIN_REFRESH_INTERVAL: Option<Duration> = Some(Duration::from_secs(10));
+
+// Marquee mode: pixels the text advances by and time between steps. Each step needs a refresh of the text line,
+// so the interval shouldn't be shorter than the panel refresh time
+pub const MARQUEE_STEP: u32 = 40;
+pub const MARQUEE_INTERVAL: Duration = Duration::from_secs(5);
+// Blank space between the end of the scrolling text and its repetition
+pub const MARQUEE_GAP: u32 = 60;
+
+// How often the countdown of "timer:<seconds>" messages is redrawn, on the panels supporting partial refreshes
+// (PanelFrame::PARTIAL_REFRESH), which it shouldn't be shorter than. The other panels do a full refresh, flashing for
+// seconds, on every redraw: they count down every TIMER_STEP_FULL_REFRESH instead, and shouldn't go below a minute.
+pub const TIMER_STEP: Duration = Duration::from_secs(1);
+pub const TIMER_STEP_FULL_REFRESH: Duration = Duration::from_secs(60);
+
+// Height of the strip at the bottom of the display reserved for the status bar, and how often it's redrawn.
+// Every redraw refreshes the panel, so the interval should be kept long.
+pub const STATUS_BAR_HEIGHT: u32 = 20;
+pub const STATUS_BAR_INTERVAL: Duration = Duration::from_secs(60);
+// Whether the status bar shows the WiFi signal strength
+pub const STATUS_BAR_RSSI: bool = true;
+// Whether the status bar shows the boot counter and the uptime, to spot the devices restarting too often
+pub const STATUS_BAR_BOOTS: bool = false;
+// Whether the status bar shows the firmware version, to check that an update landed
+pub const STATUS_BAR_VERSION: bool = false;
+
+// Local time zone of the status bar and the clock, as a POSIX TZ string, e.g. "CET-1CEST,M3.5.0,M10.5.0/3" for
+// central Europe or "EST5EDT,M3.2.0,M11.1.0" for the US east coast
+pub const TIMEZONE: &str = "UTC0";
+// How long the first SNTP synchronization is waited for at boot. Until then, the time is shown as "--:--".
+pub const SNTP_SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+// Payload drawing a clock of the local time, updated every minute until the next message
+pub const CLOCK_PAYLOAD: &str = "clock:";
+
+// Whether a message byte-identical to the last one displayed, on the same topic, is skipped rather than redrawn.
+// Disable it to have every message refresh the panel.
+pub const DEDUPLICATE_MESSAGES: bool = true;
+
+// Rotation through the last messages of different topics, e.g. weather, todo and quote: while no message arrives, the
+// next one is displayed again every interval. A new message replaces the previous one of its topic, and is shown for
+// a whole interval. Only the messages replacing the whole content (text, images, weather cards and the clock) take part.
+// None only shows the latest message.
+pub const ROTATION_INTERVAL: Option<Duration> = None;
+// Topics kept in the rotation, the oldest one is dropped beyond
+pub const ROTATION_SLOTS: usize = 4;
+// Messages kept to draw the content of the panel again once the display is rotated at runtime: the last one replacing
+// the whole content, then the regions and drawings over it. The oldest ones are forgotten beyond.
+pub const REDRAW_MAX_MESSAGES: usize = 16;
+// Screen state kept in NVS, drawn again at boot and on waking from deep sleep without waiting for the topics to
+// publish again: the header, and the messages making up the content as for REDRAW_MAX_MESSAGES. The messages are kept
+// as their payloads, so screens showing images or raw frames aren't, nor the states whose JSON exceeds
+// SCREEN_STATE_MAX_LEN bytes (the default NVS partition is 24KB). It isn't restored after a crash, which the content
+// could have caused.
+pub const RESTORE_SCREEN_STATE: bool = true;
+pub const SCREEN_STATE_MAX_LEN: usize = 4000;
+
+// Screensaver against the burn-in of an image kept on the panel for long: once no message has arrived for the timeout,
+// the content is replaced with a full refresh, until the next message. The time is counted while awake, so it doesn't
+// apply with DEEP_SLEEP_INTERVAL. None keeps the last message on the panel.
+pub const SCREENSAVER_TIMEOUT: Option<Duration> = None;
+pub const SCREENSAVER: Screensaver = Screensaver::Clock;
+// How the shades of gray of BMP images are reduced to black and white. 1-bit images are drawn as they are either way.
+pub const IMAGE_DITHERING: Dithering = Dithering::FloydSteinberg;
+
+// Regions of the display that text published on "<MQTT topic>/<region name>" is drawn into, in rotated
+// (logical) coordinates. Each region is redrawn on its own, leaving the rest of the frame untouched.
+// By default, a title strip above two columns filling the panel down to the status bar.
+pub const LAYOUT_REGIONS: &[(&str, Region)] = &[
+    (
+        "title",
+        Region {
+            x: 0,
+            y: 0,
+            w: DISPLAY_WIDTH,
+            h: 40,
+        },
+    ),
+    (
+        "weather",
+        Region {
+            x: 0,
+            y: 40,
+            w: DISPLAY_WIDTH / 2,
+            h: DISPLAY_HEIGHT - 40 - STATUS_BAR_HEIGHT,
+        },
+    ),
+    (
+        "news",
+        Region {
+            x: (DISPLAY_WIDTH / 2) as i32,
+            y: 40,
+            w: DISPLAY_WIDTH / 2,
+            h: DISPLAY_HEIGHT - 40 - STATUS_BAR_HEIGHT,
+        },
+    ),
+];
+
+// Drawn in place of the characters the fonts have no glyph for. The fonts cover Latin-1 (most Western European
+// languages), the other Latin letters are drawn without their accents. Must be a Latin-1 character.
+pub const MISSING_GLYPH: char = '?';
+
+// Spacing of wrapped text, in pixels: the top and left margins of the messages drawn in the message area and in the
+// layout regions (text drawn at a position, headers and fitted text left out), and the space between consecutive lines
+// of any wrapped text. All 0 draws the lines against each other from the corner, e.g. 8, 10 and 4 give airier text.
+pub const TEXT_LAYOUT: TextLayout = TextLayout {
+    margin_top: 0,
+    margin_left: 0,
+    line_spacing: 0,
+};
+
+// Size of the bar drawn by "progress:" messages: height, and horizontal margin from the display edges
+pub const PROGRESS_BAR_HEIGHT: u32 = 60;
+pub const PROGRESS_BAR_MARGIN: u32 = 40;
+
+// Width of the blank border around QR codes, in modules. 4 is the minimum required by the QR code spec
+pub const QR_QUIET_ZONE: i32 = 4;
+
+// Largest payload accepted over MQTT, to avoid running out of memory while reassembling chunked messages.
+// A full screen 1-bit BMP is about 39KB.
+pub const MAX_PAYLOAD_SIZE: usize = 64 * 1024;
+// Payloads too large for a single MQTT message can be sent in chunks, e.g.
+// {"type":"chunk","id":"img1","index":0,"total":8,"data":"<base64 slice>"}, in any order. A transfer is discarded once
+// no chunk has arrived for CHUNK_TIMEOUT. At most CHUNK_MAX_TRANSFERS are reassembled at once, of at most
+// CHUNK_MAX_COUNT chunks and MAX_PAYLOAD_SIZE bytes.
+pub const CHUNK_TIMEOUT: Duration = Duration::from_secs(30);
+pub const CHUNK_MAX_TRANSFERS: usize = 2;
+pub const CHUNK_MAX_COUNT: u32 = 256;
+// How the payloads which aren't valid UTF-8, nor images or raw frames, are handled: dropped with a warning, drawn as
+// text with the invalid sequences replaced, or drawn as a hex dump
+pub const INVALID_UTF8_PAYLOAD: InvalidUtf8 = InvalidUtf8::Reject;
+// Received messages waiting for the main loop, e.g. while the panel refreshes, on top of those it already took. Once
+// MESSAGE_QUEUE_CAPACITY are queued, each new message either drops the oldest one queued to take its place, or is
+// dropped itself with QueueFullPolicy::DropNewest. Either way it's logged, and the memory used stays bounded.
+pub const MESSAGE_QUEUE_CAPACITY: usize = 16;
+pub const QUEUE_FULL_POLICY: QueueFullPolicy = QueueFullPolicy::DropOldest;
+// Payload blanking the whole panel
+pub const CLEAR_PAYLOAD: &str = "__clear__";
+
+// Level of the logs shown from boot, until it's changed with a "log=<level>" payload on
+// "<MQTT topic>/<MQTT_CONTROL_SUBTOPIC>". Levels past info need CONFIG_LOG_MAXIMUM_LEVEL in sdkconfig.
+pub const LOG_LEVEL: LevelFilter = LevelFilter::Info;
+
+// Namespace of the default NVS partition holding the runtime configuration, see Config for its keys, and the rotation
+// of the display chosen at runtime, see load_rotation
+pub const NVS_CONFIG_NAMESPACE: &str = "config";
+// Namespace of the default NVS partition keeping the hash of the frame on the panel, see load_last_frame
+pub const NVS_FRAME_NAMESPACE: &str = "frame";
+// Namespace of the default NVS partition keeping the screen state, see ScreenStore
+pub const NVS_SCREEN_NAMESPACE: &str = "screen";
+// NVS partition and namespace holding the certificates provisioned at runtime, see Certificates::load.
+// When the partition or one of its keys is missing, the embedded certificate is used instead, if any.
+pub const NVS_CERTS_PARTITION: &str = "certs";
+pub const NVS_CERTS_NAMESPACE: &str = "certs";
+// Longest configuration value stored in NVS, NUL terminator included
+pub const NVS_VALUE_MAX_LEN: usize = 256;
+
+// WiFi monitoring: how often the connection is checked, and the bounds of the exponential backoff between reconnection attempts
+pub const WIFI_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+pub const WIFI_RECONNECT_MIN_BACKOFF: Duration = Duration::from_secs(1);
+pub const WIFI_RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+// Bounds of the exponential backoff between attempts to re-establish the MQTT client after a disconnection
+pub const MQTT_RECONNECT_MIN_BACKOFF: Duration = Duration::from_secs(2);
+pub const MQTT_RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+// Fraction of each reconnection backoff taken off at random, so that the devices disconnected together, e.g. by a
+// broker restart, don't all retry at once. 0.0 keeps the delays exact.
+pub const RECONNECT_JITTER: f32 = 0.25;
+// Longest wait of the main loop for a message. It otherwise only wakes up when the telemetry, the status bar, the marquee,
+// the clock or a reconnection is due, so this bounds how long the WiFi and MQTT connection changes take to be handled.
+// Messages are displayed as soon as they arrive regardless.
+pub const CONNECTION_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+// How often the WiFi signal strength is published on "<MQTT topic>/telemetry/rssi"
+pub const TELEMETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// How often the health of the device is published on "<MQTT topic>/telemetry/health", from its own thread
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+// Battery monitoring, for boards with the battery wired to an ADC pin (GPIO36, set in main) through a voltage divider.
+// Disabled by default, since the readings are meaningless without the divider.
+pub const BATTERY_MONITOR: bool = false;
+// Ratio of the battery voltage to the voltage at the ADC pin, e.g. 2.0 for two equal resistors
+pub const BATTERY_DIVIDER_RATIO: f32 = 2.0;
+// Voltages of an empty and a full LiPo cell, the charge percentage is interpolated between them
+pub const BATTERY_EMPTY_VOLTAGE: f32 = 3.3;
+pub const BATTERY_FULL_VOLTAGE: f32 = 4.2;
+// ADC samples averaged by each reading, smoothing out the noise
+pub const BATTERY_SAMPLES: u32 = 16;
+
+// Task watchdog: the main loop, and the MQTT thread while it handles an event, have to feed it at least this often,
+// otherwise the device reboots. It has to be longer than the slowest panel refresh (around 15s for the tri-color one).
+pub const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(60);
+// Longest wait for the BUSY pin of the panel to clear. Past it, the refresh is cut short and the panel is reset rather
+// than hanging the device, e.g. when the panel is unplugged. Longer than the slowest refresh, and less than half of
+// WATCHDOG_TIMEOUT since the reset waits on the pin too.
+pub const PANEL_BUSY_TIMEOUT: Duration = Duration::from_secs(25);
+// Init sequence of the panel: attempts before the setup fails (and is retried STARTUP_ATTEMPTS times), how long the RST
+// pin is held low before each new attempt, and how long the panel is then left to wake up
+pub const PANEL_INIT_ATTEMPTS: u32 = 3;
+pub const PANEL_RESET_PULSE: Duration = Duration::from_millis(20);
+pub const PANEL_INIT_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+// Panics are shown on the panel, then the device reboots after PANIC_RESTART_DELAY. A panic outside of the main
+// thread waits up to PANIC_HANDOVER_TIMEOUT for the main loop to stop using the panel, before rebooting without showing it.
+pub const PANIC_RESTART_DELAY: Duration = Duration::from_secs(10);
+pub const PANIC_HANDOVER_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Deep sleep between updates, for battery powered devices. Once connected to the broker, the device stays awake until
+// no message has arrived for DEEP_SLEEP_AWAKE_WINDOW, then sleeps for the interval and boots again to check for new
+// messages. The panel keeps its image while asleep. None keeps the device always on.
+pub const DEEP_SLEEP_INTERVAL: Option<Duration> = None;
+pub const DEEP_SLEEP_AWAKE_WINDOW: Duration = Duration::from_secs(15);
+// Longest time awake while the broker can't be reached, before sleeping anyway
+pub const DEEP_SLEEP_MAX_AWAKE: Duration = Duration::from_secs(2 * 60);
+// Whether pressing the BOOT button (GPIO0) wakes the device from deep sleep, redrawing the latest message.
+// The button has to stay released for DEEP_SLEEP_BUTTON_DEBOUNCE before sleeping, so that bounces don't wake it up again
+pub const DEEP_SLEEP_WAKE_BUTTON: bool = true;
+pub const DEEP_SLEEP_BUTTON_DEBOUNCE: Duration = Duration::from_millis(50);
+
+// Serial provisioning of the configuration stored in NVS. It's entered when the BOOT button (GPIO0) is held at boot,
+// or when the magic line is received on the serial console within PROVISIONING_TIMEOUT from boot
+pub const PROVISIONING_MAGIC: &str = "provision";
+pub const PROVISIONING_TIMEOUT: Duration = Duration::from_secs(5);
+// How long each prompt waits for its value before provisioning is aborted
+pub const PROVISIONING_INPUT_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Captive portal for the first setup: the open access point started when no WiFi network is configured,
+// or when the BOOT button is held at boot. Its web page stores the network entered as the one with the highest priority.
+pub const PORTAL_SSID: &str = "epaper-setup";
+pub const PORTAL_CHANNEL: u8 = 1;
+// Largest form submission accepted
+pub const PORTAL_MAX_FORM_LEN: usize = 512;
+pub const PORTAL_PAGE: &str = "<!DOCTYPE html><html><head><meta name=\"viewport\" content=\"width=device-width\"><title>E-paper setup</title></head>\
+<body><h1>E-paper setup</h1><form method=\"post\" action=\"/save\">\
+<p><label>SSID <input name=\"ssid\" maxlength=\"32\" required></label></p>\
+<p><label>Password <input name=\"password\" type=\"password\" maxlength=\"64\"></label></p>\
+<p><label>Security <select name=\"auth\"><option value=\"wpa2personal\">WPA2</option><option value=\"wpawpa2personal\">WPA/WPA2</option>\
+<option value=\"wpa3personal\">WPA3</option><option value=\"none\">Open</option></select></label></p>\
+<p><button type=\"submit\">Save</button></p></form></body></html>";
+
+// WiFi configuration
+// WiFi networks as (SSID, password, auth method), in order of priority. They're used when the configuration stored
+// in NVS doesn't override them. The auth method is the weakest one accepted from the access point.
+pub const WIFI_NETWORKS: &[(&str, &str, AuthMethod)] = &[("", "", AuthMethod::None)];
+// WPA2-Enterprise (EAP-PEAP/TTLS): the EAP username of the networks of WIFI_NETWORKS whose auth method is
+// WPA2Enterprise, authenticating with the password of the network. The identity is the outer one, sent before the tunnel is set up, often
+// anonymous (e.g. "anonymous@example.org"). It's the username when empty.
+pub const WIFI_EAP_USERNAME: &str = "";
+pub const WIFI_EAP_IDENTITY: &str = "";
+// CA certificate checking the authentication server of the WPA2-Enterprise networks, NUL terminated as CA_CERT.
+// The server isn't checked when None. E.g. Some(concat!(include_str!("../certificates/eap-ca.pem"), "\0").as_bytes())
+pub const WIFI_EAP_CA_CERT: Option<&[u8]> = None;
+// Most WiFi networks stored in NVS
+pub const MAX_WIFI_NETWORKS: usize = 4;
+// Static IPv4 configuration as (address, gateway, netmask, DNS server), used instead of DHCP when set.
+// The DNS server can be left empty. E.g. Some(("192.168.1.50", "192.168.1.1", "255.255.255.0", "192.168.1.1"))
+pub const STATIC_IP: Option<(&str, &str, &str, &str)> = None;
+// Delay before trying all the WiFi networks again, when none of them could be joined at boot
+pub const WIFI_RETRY_DELAY: Duration = Duration::from_secs(30);
+// mDNS: once the WiFi is up, the device answers as "<MDNS_HOSTNAME>.local" and advertises the MDNS_SERVICE service, with
+// the MQTT topic in its TXT record. No mDNS when None. The hostname is a DNS label: letters, digits and hyphens.
+pub const MDNS_HOSTNAME: Option<&str> = Some("esp32-epaper");
+// Advertised service as (type, protocol, port). The device doesn't accept connections, but mDNS doesn't take port 0,
+// so the service is announced on the discard port.
+pub const MDNS_SERVICE: (&str, &str, u16) = ("_epaper", "_tcp", 9);
+// Local HTTP endpoint: the body POSTed to http://<device>/display is displayed as if it had been published on
+// MQTT_TOPIC_NAME, bypassing the broker. Anyone on the network can use it, so it's disabled by default.
+pub const HTTP_DISPLAY_SERVER: bool = false;
+// How long a request waits for its content to be on the panel
+pub const HTTP_DISPLAY_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Startup: the panel, WiFi and MQTT setup are attempted STARTUP_ATTEMPTS times, with their errors shown on the panel
+// once it's set up. The device restarts when one of them keeps failing, or after an error in the main loop.
+pub const STARTUP_ATTEMPTS: u32 = 3;
+pub const STARTUP_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+// MQTT configuration, used when the configuration stored in NVS doesn't override it. Not specific to AWS IoT, see
+// MQTT_SECURITY for the other brokers. If you want to use Emqx instead of AWS IoT, see https://www.emqx.com/en/blog/emqx-server-ssl-tls-secure-connection-configuration-guide
+pub const MQTT_ENDPOINT: &str = "mqtts://YOUR_AWS_IOT_MQTT_ENDPOINT_HERE:8883";
+pub const MQTT_CLIENT_ID: &str = "esp32-epaper-main";
+pub const MQTT_TOPIC_NAME: &str = "topic/sdk/test/rust";
+// How the connection to the broker is secured: MutualTls authenticates the device with its certificate, as AWS IoT
+// requires. ServerTls only checks the broker against CA_CERT, and Plain connects over plain TCP to an mqtt:// endpoint,
+// e.g. a local Mosquitto broker, without loading any certificate. The endpoint scheme has to match (mqtts:// or wss://
+// with TLS, mqtt:// or ws:// without).
+// Over plain TCP the username, the password and all the messages go in clear: anyone on the network can read them,
+// and impersonate the broker to display anything. Only use it on a trusted network.
+pub const MQTT_SECURITY: MqttSecurity = MqttSecurity::MutualTls;
+// Credentials sent to the broker when set (empty for none), whatever MQTT_SECURITY is: along with the device certificate
+// for the brokers requiring both, or as the only authentication of the device. The password is masked in the logs.
+pub const MQTT_USERNAME: &str = "";
+pub const MQTT_PASSWORD: &str = "";
+// QoS of the subscriptions (the highest QoS the broker delivers messages with) and of the published messages.
+// With AtLeastOnce or ExactlyOnce, the connection thread gets an Event::Published for each message acknowledged by the
+// broker, and with AtLeastOnce received messages may be delivered more than once (their details flag them as
+// duplicates). Messages published while the device is disconnected are only kept by the broker if it keeps the session.
+// AWS IoT doesn't support ExactlyOnce.
+pub const MQTT_SUBSCRIBE_QOS: QoS = QoS::AtLeastOnce;
+pub const MQTT_PUBLISH_QOS: QoS = QoS::AtMostOnce;
+// Connection status of the device, retained on "<MQTT topic>/<MQTT_STATUS_SUBTOPIC>": the online payload is published on
+// each connection, and the broker publishes the offline payload as the last will once the device stops answering, e.g.
+// after a power loss. That happens after 1.5 times the keep-alive interval (2 minutes by default).
+pub const MQTT_STATUS_SUBTOPIC: &str = "telemetry/status";
+pub const MQTT_STATUS_ONLINE: &str = "online";
+pub const MQTT_STATUS_OFFLINE: &str = "offline";
+// Published before entering deep sleep, the device then disconnects cleanly so the last will isn't published
+pub const MQTT_STATUS_ASLEEP: &str = "asleep";
+// Whether the retained messages, delivered by the broker right after subscribing, are displayed. This restores the content
+// of the panel after a reboot, so the hello message isn't published then. The client doesn't expose the retain flag of
+// the received messages: without honoring them, the messages received within MQTT_RETAINED_WINDOW from connecting are
+// taken as retained and dropped.
+pub const MQTT_HONOR_RETAINED: bool = true;
+pub const MQTT_RETAINED_WINDOW: Duration = Duration::from_secs(2);
+// Acknowledgments of the messages once they're on the panel are published on "<MQTT topic>/<MQTT_ACK_SUBTOPIC>"
+pub const MQTT_ACK_SUBTOPIC: &str = "telemetry/ack";
+// Warnings about displayed messages, e.g. text cut because it doesn't fit, are published on
+// "<MQTT topic>/<MQTT_WARNING_SUBTOPIC>"
+pub const MQTT_WARNING_SUBTOPIC: &str = "telemetry/warning";
+// The text drawn for text messages, as wrapped and truncated, is published on "<MQTT topic>/<MQTT_ECHO_SUBTOPIC>"
+// along with its font size and alignment, so that tests can check what the panel shows
+pub const MQTT_ECHO_SUBTOPIC: &str = "telemetry/echo";
+// Why the device last restarted is retained on "<MQTT topic>/<MQTT_RESET_SUBTOPIC>" once it first connects after booting
+pub const MQTT_RESET_SUBTOPIC: &str = "telemetry/reset";
+// Whether each chunked transfer has been reassembled or discarded is published on
+// "<MQTT topic>/<MQTT_TRANSFER_SUBTOPIC>", for the sender to retry the discarded ones
+pub const MQTT_TRANSFER_SUBTOPIC: &str = "telemetry/transfer";
+// The firmware version and build info are retained on "<MQTT topic>/<MQTT_FIRMWARE_SUBTOPIC>" on each connection
+pub const MQTT_FIRMWARE_SUBTOPIC: &str = "telemetry/firmware";
+// Hardware errors, e.g. the BUSY pin of the panel stuck past PANEL_BUSY_TIMEOUT, are published on
+// "<MQTT topic>/<MQTT_HARDWARE_SUBTOPIC>"
+pub const MQTT_HARDWARE_SUBTOPIC: &str = "telemetry/hardware";
+// The full and partial refreshes of the panel since the first boot are published on
+// "<MQTT topic>/<MQTT_REFRESHES_SUBTOPIC>" every TELEMETRY_INTERVAL
+pub const MQTT_REFRESHES_SUBTOPIC: &str = "telemetry/refreshes";
+// The refresh counters are kept in RAM and saved to NVS every REFRESH_STATS_SAVE_INTERVAL refreshes, on each
+// publication of the telemetry and before restarting or entering deep sleep. A crash or a power loss forgets the
+// refreshes counted since the last save.
+pub const REFRESH_STATS_SAVE_INTERVAL: u32 = 20;
+// Control commands of the device, e.g. "log=debug", are published on "<MQTT topic>/<MQTT_CONTROL_SUBTOPIC>"
+pub const MQTT_CONTROL_SUBTOPIC: &str = "control";
+// Payloads published on "<MQTT topic>/<MQTT_COMMAND_SUBTOPIC>/<action>" are just the data of the action, e.g. the text
+// to encode on ".../cmd/qr", see parse_action
+pub const MQTT_COMMAND_SUBTOPIC: &str = "cmd";
+// Firmware updates: the HTTPS URL of the image published on "<MQTT topic>/<MQTT_OTA_SUBTOPIC>" is downloaded into the
+// next OTA partition, and the outcome is published on "<MQTT topic>/<MQTT_OTA_STATUS_SUBTOPIC>"
+pub const MQTT_OTA_SUBTOPIC: &str = "ota";
+pub const MQTT_OTA_STATUS_SUBTOPIC: &str = "telemetry/ota";
+// The download is aborted when the server doesn't answer for this long
+pub const OTA_TIMEOUT: Duration = Duration::from_secs(30);
+pub const OTA_CHUNK_LEN: usize = 4096;
+// Percentage of the download between progress updates on the panel, each one refreshes it
+pub const OTA_PROGRESS_STEP: u8 = 10;
+// Additional topics subscribed to besides MQTT_TOPIC_NAME and its region subtopics, each with how its messages are displayed.
+// They must not be under MQTT_TOPIC_NAME, and the broker must allow subscribing to them.
+// E.g. &[("epaper/qr", QoS::AtLeastOnce, TopicHandler::Qr), ("home/weather", QoS::AtLeastOnce, TopicHandler::Weather)]
+pub const MQTT_TOPICS: &[(&str, QoS, TopicHandler)] = &[];
+// Rate limits of the topics, so that a chatty publisher can't monopolize the panel: (topic filter, messages, interval)
+// lets each topic matching the filter burst up to `messages` messages, refilled at `messages` per `interval`. The first
+// matching filter applies, and the topics matching none get RATE_LIMIT_DEFAULT (None leaving them unlimited).
+// E.g. &[("epaper/+", 6, Duration::from_secs(60))]. The messages over the limit are dropped with a warning logged.
+pub const RATE_LIMITS: &[(&str, u32, Duration)] = &[];
+pub const RATE_LIMIT_DEFAULT: Option<(u32, Duration)> = Some((30, Duration::from_secs(60)));
+// The messages dropped over the rate limits are counted on "<MQTT topic>/<MQTT_RATE_LIMIT_SUBTOPIC>", at most every
+// RATE_LIMIT_NOTICE_INTERVAL
+pub const MQTT_RATE_LIMIT_SUBTOPIC: &str = "telemetry/rate_limit";
+pub const RATE_LIMIT_NOTICE_INTERVAL: Duration = Duration::from_secs(60);
+
+// AWS IoT certificates embedded in the firmware with the embedded-certs feature, unless provisioned in the
+// NVS_CERTS_PARTITION partition. The TLS stack reads them up to a NUL terminator, appended at build time so that
+// they're borrowed for the whole program as they are, without copying them. PEM files are ASCII.
+// Without the feature, the certificates folder isn't needed to build, and TLS needs them provisioned in NVS.
+#[cfg(feature = "embedded-certs")]
+pub const CA_CERT: Option<&[u8]> =
+    Some(concat!(include_str!("../certificates/AmazonRootCA1.pem"), "\0").as_bytes());
+#[cfg(feature = "embedded-certs")]
+pub const THING_CERT: Option<&[u8]> = Some(
+    concat!(
+        include_str!("../certificates/esp32-epaper-main.client.crt"),
+        "\0"
+    )
+    .as_bytes(),
+);
+#[cfg(feature = "embedded-certs")]
+pub const THING_PRIVATE_KEY: Option<&[u8]> = Some(
+    concat!(
+        include_str!("../certificates/esp32-epaper-main.private.key"),
+        "\0"
+    )
+    .as_bytes(),
+);
+#[cfg(not(feature = "embedded-certs"))]
+pub const CA_CERT: Option<&[u8]> = None;
+#[cfg(not(feature = "embedded-certs"))]
+pub const THING_CERT: Option<&[u8]> = None;
+#[cfg(not(feature = "embedded-certs"))]
+pub const THING_PRIVATE_KEY: Option<&[u8]> = None;
+
+/// GPIO numbers of the panel connections, and the SPI clock rate
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BoardConfig {
+    pub sclk: i32,
+    /// SPI MOSI, the DIN pin of the panel
+    pub mosi: i32,
+    pub cs: i32,
+    pub busy: i32,
+    pub dc: i32,
+    pub rst: i32,
+    /// In Hz, up to the maximum rate of the panel
+    pub spi_baudrate: u32,
+}
+
+impl BoardConfig {
+    /// DFRobot Firebeetle ESP32, with the panel on the VSPI pins
+    pub const FIREBEETLE: BoardConfig = BoardConfig {
+        sclk: 18,
+        mosi: 23,
+        cs: 14,
+        busy: 4,
+        dc: 22,
+        rst: 21,
+        spi_baudrate: 112_500,
+    };
+
+    /// Checks that the pins are distinct GPIOs usable by the panel, and that the baudrate is within `max_baudrate`
+    pub fn validate(&self, max_baudrate: u32) -> anyhow::Result<()> {
+        let pins = [
+            ("SCLK", self.sclk, true),
+            ("MOSI", self.mosi, true),
+            ("CS", self.cs, true),
+            ("BUSY", self.busy, false),
+            ("DC", self.dc, true),
+            ("RST", self.rst, true),
+        ];
+        for (index, &(name, pin, output)) in pins.iter().enumerate() {
+            // 6 to 11 are wired to the flash, 0 is the BOOT button and 1 and 3 the serial console
+            if !(0..=39).contains(&pin) || matches!(pin, 0 | 1 | 3 | 6..=11 | 20 | 24 | 28..=31) {
+                anyhow::bail!("GPIO{} can't be used as the {} pin of the panel", pin, name);
+            }
+            // 34 to 39 are input only
+            if output && pin >= 34 {
+                anyhow::bail!("GPIO{} is input only, it can't be the {} pin", pin, name);
+            }
+            if let Some((other, ..)) = pins[..index].iter().find(|(_, p, _)| *p == pin) {
+                anyhow::bail!("GPIO{} is both the {} and the {} pin", pin, other, name);
+            }
+        }
+        if self.spi_baudrate == 0 || self.spi_baudrate > max_baudrate {
+            anyhow::bail!(
+                "the SPI baudrate of {}Hz is out of the range of the panel, up to {}Hz",
+                self.spi_baudrate,
+                max_baudrate
+            );
+        }
+        Ok(())
+    }
+}
+
+/// A WiFi network the device can join
+#[derive(Clone, PartialEq, Eq)]
+pub struct WifiCredentials {
+    pub ssid: String,
+    pub password: String,
+    pub auth_method: AuthMethod,
+    /// EAP username and outer identity, only used by WPA2-Enterprise networks
+    pub eap_username: String,
+    pub eap_identity: String,
+}
+
+impl WifiCredentials {
+    // The WiFi driver holds the SSID and password in fixed size buffers
+    const MAX_SSID_LEN: usize = 32;
+    const MAX_PASS_LEN: usize = 64;
+
+    pub fn new(ssid: &str, password: &str, auth_method: AuthMethod) -> Self {
+        WifiCredentials {
+            ssid: ssid.to_string(),
+            password: password.to_string(),
+            auth_method,
+            eap_username: String::new(),
+            eap_identity: String::new(),
+        }
+    }
+
+    /// WPA2-Enterprise network, authenticating as `username` with `password`
+    pub fn enterprise(ssid: &str, username: &str, identity: &str, password: &str) -> Self {
+        WifiCredentials {
+            eap_username: username.to_string(),
+            eap_identity: identity.to_string(),
+            ..Self::new(ssid, password, AuthMethod::WPA2Enterprise)
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.ssid.len() <= Self::MAX_SSID_LEN && self.password.len() <= Self::MAX_PASS_LEN
+    }
+
+    /// The outer EAP identity, falling back to the username
+    pub fn eap_outer_identity(&self) -> &str {
+        match self.eap_identity.as_str() {
+            "" => &self.eap_username,
+            identity => identity,
+        }
+    }
+
+    /// Checks that the password can be used with the auth method: WPA passphrases are 8 to 63 characters long,
+    /// or 64 hex digits. With no auth method the driver accepts any network, so any password goes.
+    /// WPA2-Enterprise needs a username and a password, of any length.
+    pub fn check_auth(&self) -> anyhow::Result<()> {
+        let len = self.password.len();
+        match self.auth_method {
+            AuthMethod::None => Ok(()),
+            AuthMethod::WPA2Enterprise if self.eap_username.is_empty() || len == 0 => {
+                anyhow::bail!("wpa2enterprise needs an EAP username and a password")
+            }
+            AuthMethod::WPA2Enterprise => Ok(()),
+            _ if (8..=63).contains(&len) => Ok(()),
+            _ if len == 64 && self.password.chars().all(|c| c.is_ascii_hexdigit()) => Ok(()),
+            _ => anyhow::bail!(
+                "{} needs a password of 8 to 63 characters or 64 hex digits, got {} characters",
+                auth_method_name(self.auth_method),
+                len
+            ),
+        }
+    }
+}
+
+// Names of the supported auth methods, as stored in NVS
+const AUTH_METHOD_NAMES: [(&str, AuthMethod); 5] = [
+    ("none", AuthMethod::None),
+    ("wpa2personal", AuthMethod::WPA2Personal),
+    ("wpa3personal", AuthMethod::WPA3Personal),
+    ("wpawpa2personal", AuthMethod::WPAWPA2Personal),
+    ("wpa2enterprise", AuthMethod::WPA2Enterprise),
+];
+
+/// Parses the name of a supported auth method, ignoring case
+pub fn parse_auth_method(name: &str) -> Option<AuthMethod> {
+    AUTH_METHOD_NAMES
+        .iter()
+        .find(|(method_name, _)| method_name.eq_ignore_ascii_case(name.trim()))
+        .map(|&(_, method)| method)
+}
+
+pub fn auth_method_name(method: AuthMethod) -> &'static str {
+    AUTH_METHOD_NAMES
+        .iter()
+        .find(|&&(_, supported)| supported == method)
+        .map_or("unsupported", |&(name, _)| name)
+}
+
+// The password is masked, so that the credentials can be logged
+impl fmt::Debug for WifiCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WifiCredentials")
+            .field("ssid", &self.ssid)
+            .field("password", &"********")
+            .field("auth_method", &self.auth_method)
+            .field("eap_username", &self.eap_username)
+            .field("eap_identity", &self.eap_identity)
+            .finish()
+    }
+}
+
+// The MQTT password is masked as the WiFi ones, so that the configuration can be logged
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let password = match self.mqtt_password.is_empty() {
+            true => "",
+            false => "********",
+        };
+        f.debug_struct("Config")
+            .field("wifi_networks", &self.wifi_networks)
+            .field("static_ip", &self.static_ip)
+            .field("mqtt_endpoint", &self.mqtt_endpoint)
+            .field("mqtt_client_id", &self.mqtt_client_id)
+            .field("mqtt_topic", &self.mqtt_topic)
+            .field("mqtt_username", &self.mqtt_username)
+            .field("mqtt_password", &password)
+            .finish()
+    }
+}
+
+/// Static IPv4 configuration of the WiFi interface, replacing DHCP
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StaticIp {
+    pub ip: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub dns: Option<Ipv4Addr>,
+}
+
+impl StaticIp {
+    /// Parses the dotted addresses of the configuration. An empty `dns` means no DNS server.
+    pub fn parse(ip: &str, gateway: &str, netmask: &str, dns: &str) -> anyhow::Result<Self> {
+        let address = |name: &str, value: &str| {
+            value
+                .parse::<Ipv4Addr>()
+                .map_err(|_| anyhow::anyhow!("invalid {} \"{}\"", name, value))
+        };
+        let static_ip = StaticIp {
+            ip: address("IP address", ip)?,
+            gateway: address("gateway", gateway)?,
+            netmask: address("netmask", netmask)?,
+            dns: match dns {
+                "" => None,
+                dns => Some(address("DNS server", dns)?),
+            },
+        };
+        // The netmask has to be a contiguous run of leading ones, as it's given to the interface as a prefix length
+        let mask = u32::from(static_ip.netmask);
+        if mask.leading_ones() + mask.trailing_zeros() != 32 {
+            anyhow::bail!("invalid netmask \"{}\"", netmask);
+        }
+        Ok(static_ip)
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        u32::from(self.netmask).leading_ones() as u8
+    }
+}
+
+/// Runtime configuration, read at boot from the NVS_CONFIG_NAMESPACE namespace of the default NVS partition.
+/// The MQTT settings are stored as strings under the key named after their field ("mqtt_endpoint", "mqtt_client_id",
+/// "mqtt_topic", "mqtt_username", "mqtt_password"). The WiFi networks are stored in order of priority under "wifi_ssid" and "wifi_pass" for the first
+/// one, then "wifi_ssid_<n>" and "wifi_pass_<n>" for the following ones, up to MAX_WIFI_NETWORKS. The EAP username
+/// and identity of WPA2-Enterprise networks are stored the same way under "wifi_eap_user" and "wifi_eap_id".
+/// A static IP configuration is stored under "ip_address", "ip_gateway", "ip_netmask" and "ip_dns".
+/// Missing keys fall back to the consts above.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Config {
+    pub wifi_networks: Vec<WifiCredentials>,
+    /// None to get the address through DHCP
+    pub static_ip: Option<StaticIp>,
+    pub mqtt_endpoint: String,
+    pub mqtt_client_id: String,
+    pub mqtt_topic: String,
+    /// Empty to connect without credentials
+    pub mqtt_username: String,
+    pub mqtt_password: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            wifi_networks: WIFI_NETWORKS
+                .iter()
+                .map(|&(ssid, password, auth_method)| match auth_method {
+                    AuthMethod::WPA2Enterprise => WifiCredentials::enterprise(
+                        ssid,
+                        WIFI_EAP_USERNAME,
+                        WIFI_EAP_IDENTITY,
+                        password,
+                    ),
+                    _ => WifiCredentials::new(ssid, password, auth_method),
+                })
+                .collect(),
+            static_ip: STATIC_IP.and_then(|(ip, gateway, netmask, dns)| {
+                StaticIp::parse(ip, gateway, netmask, dns)
+                    .map_err(|e| error!("Ignoring the static IP configuration: {}", e))
+                    .ok()
+            }),
+            mqtt_endpoint: MQTT_ENDPOINT.to_string(),
+            mqtt_client_id: MQTT_CLIENT_ID.to_string(),
+            mqtt_topic: MQTT_TOPIC_NAME.to_string(),
+            mqtt_username: MQTT_USERNAME.to_string(),
+            mqtt_password: MQTT_PASSWORD.to_string(),
+        }
+    }
+}
+
+impl Config {
+    /// NVS keys of the address, gateway, netmask and DNS server of the static IP configuration
+    const STATIC_IP_KEYS: [&'static str; 4] = ["ip_address", "ip_gateway", "ip_netmask", "ip_dns"];
+
+    #[cfg(target_os = "espidf")]
+    pub fn load_from_nvs(partition: EspDefaultNvsPartition) -> Result<Self, EspError> {
+        // Opened read-write so that the namespace gets created on the first boot
+        let nvs = EspDefaultNvs::new(partition, NVS_CONFIG_NAMESPACE, true)?;
+        let mut config = Config::default();
+        let mut buffer = [0u8; NVS_VALUE_MAX_LEN];
+        for (key, value) in config.mqtt_fields_mut() {
+            if let Some(stored) = nvs.get_str(key, &mut buffer)? {
+                *value = stored.trim_end_matches('\0').to_string();
+            }
+        }
+
+        let mut networks = Vec::new();
+        for index in 0..MAX_WIFI_NETWORKS {
+            let (ssid_key, pass_key, auth_key) = Self::wifi_keys(index);
+            let Some(ssid) = nvs.get_str(&ssid_key, &mut buffer)? else {
+                continue;
+            };
+            let ssid = ssid.trim_end_matches('\0').to_string();
+            let password = nvs
+                .get_str(&pass_key, &mut buffer)?
+                .unwrap_or_default()
+                .trim_end_matches('\0')
+                .to_string();
+            // Networks stored without an auth method are open, as they were before it was configurable
+            let auth_method = match nvs.get_str(&auth_key, &mut buffer)? {
+                Some(name) => {
+                    let name = name.trim_end_matches('\0');
+                    parse_auth_method(name).unwrap_or_else(|| {
+                        error!(
+                            "Unknown auth method \"{}\" for the stored WiFi network {}, using none",
+                            name, index
+                        );
+                        AuthMethod::None
+                    })
+                }
+                None => AuthMethod::None,
+            };
+            let (user_key, identity_key) = Self::eap_keys(index);
+            let mut eap_fields = [String::new(), String::new()];
+            for (key, value) in [user_key, identity_key].iter().zip(&mut eap_fields) {
+                if let Some(stored) = nvs.get_str(key, &mut buffer)? {
+                    *value = stored.trim_end_matches('\0').to_string();
+                }
+            }
+            let [eap_username, eap_identity] = eap_fields;
+            let network = WifiCredentials {
+                ssid,
+                password,
+                auth_method,
+                eap_username,
+                eap_identity,
+            };
+            if network.is_valid() {
+                networks.push(network);
+            } else {
+                warn!(
+                    "Ignoring the stored WiFi network {}, its credentials are too long",
+                    index
+                );
+            }
+        }
+        if !networks.is_empty() {
+            config.wifi_networks = networks;
+        }
+
+        let mut addresses = Vec::new();
+        for key in Self::STATIC_IP_KEYS {
+            let value = nvs.get_str(key, &mut buffer)?.unwrap_or_default();
+            addresses.push(value.trim_end_matches('\0').to_string());
+        }
+        if !addresses[0].is_empty() {
+            match StaticIp::parse(&addresses[0], &addresses[1], &addresses[2], &addresses[3]) {
+                Ok(static_ip) => config.static_ip = Some(static_ip),
+                Err(e) => error!("Ignoring the stored static IP configuration: {}", e),
+            }
+        }
+        Ok(config)
+    }
+
+    /// Keeps the MQTT topic changed at runtime, leaving the rest of the stored configuration as it is
+    #[cfg(target_os = "espidf")]
+    pub fn store_topic(partition: EspDefaultNvsPartition, topic: &str) -> Result<(), EspError> {
+        let mut nvs = EspDefaultNvs::new(partition, NVS_CONFIG_NAMESPACE, true)?;
+        nvs.set_str("mqtt_topic", topic)
+    }
+
+    #[cfg(target_os = "espidf")]
+    pub fn store_to_nvs(&self, partition: EspDefaultNvsPartition) -> Result<(), EspError> {
+        let mut nvs = EspDefaultNvs::new(partition, NVS_CONFIG_NAMESPACE, true)?;
+        for (key, value) in self.mqtt_fields() {
+            nvs.set_str(key, value)?;
+        }
+        for index in 0..MAX_WIFI_NETWORKS {
+            let (ssid_key, pass_key, auth_key) = Self::wifi_keys(index);
+            let (user_key, identity_key) = Self::eap_keys(index);
+            match self.wifi_networks.get(index) {
+                Some(network) => {
+                    nvs.set_str(&ssid_key, &network.ssid)?;
+                    nvs.set_str(&pass_key, &network.password)?;
+                    nvs.set_str(&auth_key, auth_method_name(network.auth_method))?;
+                    nvs.set_str(&user_key, &network.eap_username)?;
+                    nvs.set_str(&identity_key, &network.eap_identity)?;
+                }
+                // Networks removed from the list are removed from NVS too
+                None => {
+                    nvs.remove(&ssid_key)?;
+                    nvs.remove(&pass_key)?;
+                    nvs.remove(&auth_key)?;
+                    nvs.remove(&user_key)?;
+                    nvs.remove(&identity_key)?;
+                }
+            }
+        }
+        match self.static_ip {
+            Some(static_ip) => {
+                let dns = static_ip.dns.map(|dns| dns.to_string()).unwrap_or_default();
+                let values = [
+                    static_ip.ip.to_string(),
+                    static_ip.gateway.to_string(),
+                    static_ip.netmask.to_string(),
+                    dns,
+                ];
+                for (key, value) in Self::STATIC_IP_KEYS.into_iter().zip(values) {
+                    nvs.set_str(key, &value)?;
+                }
+            }
+            None => {
+                for key in Self::STATIC_IP_KEYS {
+                    nvs.remove(key)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether any WiFi network can be joined, as opposed to the empty default network
+    pub fn has_wifi_network(&self) -> bool {
+        self.wifi_networks
+            .iter()
+            .any(|network| !network.ssid.is_empty())
+    }
+
+    /// NVS keys of the SSID, the password and the auth method of the `index`-th WiFi network
+    fn wifi_keys(index: usize) -> (String, String, String) {
+        match index {
+            0 => (
+                "wifi_ssid".to_string(),
+                "wifi_pass".to_string(),
+                "wifi_auth".to_string(),
+            ),
+            _ => (
+                format!("wifi_ssid_{}", index),
+                format!("wifi_pass_{}", index),
+                format!("wifi_auth_{}", index),
+            ),
+        }
+    }
+
+    /// NVS keys of the EAP username and identity of the `index`-th WiFi network
+    fn eap_keys(index: usize) -> (String, String) {
+        match index {
+            0 => ("wifi_eap_user".to_string(), "wifi_eap_id".to_string()),
+            _ => (
+                format!("wifi_eap_user_{}", index),
+                format!("wifi_eap_id_{}", index),
+            ),
+        }
+    }
+
+    /// NVS keys of the MQTT configuration fields, along with their values
+    fn mqtt_fields(&self) -> [(&'static str, &String); 5] {
+        [
+            ("mqtt_endpoint", &self.mqtt_endpoint),
+            ("mqtt_client_id", &self.mqtt_client_id),
+            ("mqtt_topic", &self.mqtt_topic),
+            ("mqtt_username", &self.mqtt_username),
+            ("mqtt_password", &self.mqtt_password),
+        ]
+    }
+
+    fn mqtt_fields_mut(&mut self) -> [(&'static str, &mut String); 5] {
+        [
+            ("mqtt_endpoint", &mut self.mqtt_endpoint),
+            ("mqtt_client_id", &mut self.mqtt_client_id),
+            ("mqtt_topic", &mut self.mqtt_topic),
+            ("mqtt_username", &mut self.mqtt_username),
+            ("mqtt_password", &mut self.mqtt_password),
+        ]
+    }
+}
+
+/// Reads a line from the serial console, without its line ending. Returns None if no whole line arrives within `timeout`.
+#[cfg(target_os = "espidf")]
+fn read_line(uart: &UartDriver, timeout: Duration) -> Option<String> {
+    let deadline = Instant::now() + timeout;
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    while Instant::now() < deadline {
+        if let Ok(1) = uart.read(&mut byte, TickType::from(Duration::from_millis(100)).0) {
+            match byte[0] {
+                b'\n' => {
+                    let line = String::from_utf8_lossy(&line);
+                    return Some(line.trim_end_matches('\r').to_string());
+                }
+                byte => line.push(byte),
+            }
+        }
+    }
+    None
+}
+
+/// Waits up to `timeout` for the `expected` line on the serial console, skipping any other line
+#[cfg(target_os = "espidf")]
+pub fn wait_for_line(uart: &UartDriver, expected: &str, timeout: Duration) -> bool {
+    info!(
+        "Send \"{}\" within {}s to provision the configuration",
+        expected,
+        timeout.as_secs()
+    );
+    let deadline = Instant::now() + timeout;
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        if remaining.is_zero() {
+            break;
+        }
+        if read_line(uart, remaining).as_deref() == Some(expected) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Prompts on the serial console for a value of up to `max_len` bytes. An empty line keeps the current one.
+#[cfg(target_os = "espidf")]
+fn prompt_value(
+    uart: &UartDriver,
+    prompt: &str,
+    value: &mut String,
+    max_len: usize,
+) -> anyhow::Result<()> {
+    loop {
+        uart.write(format!("{}: ", prompt).as_bytes())?;
+        let Some(line) = read_line(uart, PROVISIONING_INPUT_TIMEOUT) else {
+            anyhow::bail!("no {} received", prompt);
+        };
+        if line.len() > max_len {
+            uart.write(format!("At most {} bytes are allowed\r\n", max_len).as_bytes())?;
+            continue;
+        }
+        if !line.is_empty() {
+            *value = line;
+        }
+        return Ok(());
+    }
+}
+
+/// Prompts on the serial console for the WiFi and MQTT settings, one per line, then stores them in NVS.
+/// The WiFi network entered becomes the one with the highest priority.
+/// An empty line keeps the current value. Nothing is stored if a prompt times out.
+#[cfg(target_os = "espidf")]
+pub fn provision(uart: &UartDriver, partition: EspDefaultNvsPartition) -> anyhow::Result<()> {
+    let mut config = Config::load_from_nvs(partition.clone())?;
+    info!("Provisioning the configuration, send an empty line to keep the current value");
+
+    if config.wifi_networks.is_empty() {
+        config
+            .wifi_networks
+            .push(WifiCredentials::new("", "", AuthMethod::None));
+    }
+    let network = &mut config.wifi_networks[0];
+    for (prompt, value, max_len) in [
+        (
+            "WiFi SSID",
+            &mut network.ssid,
+            WifiCredentials::MAX_SSID_LEN,
+        ),
+        (
+            "WiFi password",
+            &mut network.password,
+            WifiCredentials::MAX_PASS_LEN,
+        ),
+        (
+            "MQTT endpoint",
+            &mut config.mqtt_endpoint,
+            NVS_VALUE_MAX_LEN - 1,
+        ),
+        (
+            "MQTT client id",
+            &mut config.mqtt_client_id,
+            NVS_VALUE_MAX_LEN - 1,
+        ),
+    ] {
+        prompt_value(uart, prompt, value, max_len)?;
+    }
+    // Some brokers require credentials along with the device certificate
+    for (prompt, value) in [
+        ("MQTT username", &mut config.mqtt_username),
+        ("MQTT password", &mut config.mqtt_password),
+    ] {
+        prompt_value(uart, prompt, value, NVS_VALUE_MAX_LEN - 1)?;
+    }
+
+    let network = &mut config.wifi_networks[0];
+    loop {
+        uart.write(
+            b"WiFi auth method (none, wpa2personal, wpa3personal, wpawpa2personal, wpa2enterprise): ",
+        )?;
+        let Some(line) = read_line(uart, PROVISIONING_INPUT_TIMEOUT) else {
+            anyhow::bail!("no WiFi auth method received");
+        };
+        if !line.is_empty() {
+            let Some(auth_method) = parse_auth_method(&line) else {
+                uart.write(b"Unknown auth method\r\n")?;
+                continue;
+            };
+            network.auth_method = auth_method;
+        }
+        break;
+    }
+    if network.auth_method == AuthMethod::WPA2Enterprise {
+        for (prompt, value) in [
+            ("EAP username", &mut network.eap_username),
+            ("EAP identity", &mut network.eap_identity),
+        ] {
+            prompt_value(uart, prompt, value, NVS_VALUE_MAX_LEN - 1)?;
+        }
+    }
+    if let Err(e) = network.check_auth() {
+        uart.write(format!("Warning: {}\r\n", e).as_bytes())?;
+    }
+
+    config.wifi_networks.truncate(MAX_WIFI_NETWORKS);
+    config.store_to_nvs(partition)?;
+    info!("Stored the configuration: {:?}", config);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn static_ip_parses_the_addresses() {
+        let static_ip =
+            StaticIp::parse("192.168.1.50", "192.168.1.1", "255.255.255.0", "1.1.1.1").unwrap();
+        assert_eq!(
+            static_ip,
+            StaticIp {
+                ip: Ipv4Addr::new(192, 168, 1, 50),
+                gateway: Ipv4Addr::new(192, 168, 1, 1),
+                netmask: Ipv4Addr::new(255, 255, 255, 0),
+                dns: Some(Ipv4Addr::new(1, 1, 1, 1)),
+            }
+        );
+        assert_eq!(static_ip.prefix_len(), 24);
+        let without_dns = StaticIp::parse("10.0.0.2", "10.0.0.1", "255.0.0.0", "").unwrap();
+        assert_eq!(without_dns.dns, None);
+        assert_eq!(without_dns.prefix_len(), 8);
+    }
+
+    #[test]
+    fn static_ip_rejects_invalid_addresses() {
+        assert!(StaticIp::parse("192.168.1", "192.168.1.1", "255.255.255.0", "").is_err());
+        assert!(StaticIp::parse("192.168.1.50", "gateway", "255.255.255.0", "").is_err());
+        assert!(
+            StaticIp::parse("192.168.1.50", "192.168.1.1", "255.255.255.0", "1.1.1.256").is_err()
+        );
+    }
+
+    #[test]
+    fn static_ip_rejects_non_contiguous_netmasks() {
+        assert!(StaticIp::parse("192.168.1.50", "192.168.1.1", "255.0.255.0", "").is_err());
+        assert!(StaticIp::parse("192.168.1.50", "192.168.1.1", "0.0.0.255", "").is_err());
+        let any = StaticIp::parse("192.168.1.50", "192.168.1.1", "0.0.0.0", "").unwrap();
+        assert_eq!(any.prefix_len(), 0);
+        let host = StaticIp::parse("192.168.1.50", "192.168.1.1", "255.255.255.255", "").unwrap();
+        assert_eq!(host.prefix_len(), 32);
+    }
+
+    #[test]
+    fn auth_method_names() {
+        assert_eq!(
+            parse_auth_method("wpa2personal"),
+            Some(AuthMethod::WPA2Personal)
+        );
+        assert_eq!(
+            parse_auth_method(" WPA3Personal "),
+            Some(AuthMethod::WPA3Personal)
+        );
+        assert_eq!(parse_auth_method("None"), Some(AuthMethod::None));
+        assert_eq!(parse_auth_method("wep"), None);
+        assert_eq!(parse_auth_method(""), None);
+        for (name, method) in AUTH_METHOD_NAMES {
+            assert_eq!(
+                parse_auth_method(auth_method_name(method)),
+                Some(method),
+                "{}",
+                name
+            );
+        }
+    }
+}
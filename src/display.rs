@@ -0,0 +1,3922 @@
+//! Drawing on the E-Ink panel: text, widgets, layout regions and panel refreshes
+
+use crate::{
+    clock::{clock_label, now, LocalTime},
+    config::{
+        ANTI_GHOSTING_INTERVAL, ANTI_GHOSTING_UPDATES, BOARD, DEDUPLICATE_MESSAGES, FRAME_DIFF,
+        FRAME_DIFF_FULL_RATIO, IMAGE_DITHERING, MARQUEE_GAP, MARQUEE_INTERVAL, MARQUEE_STEP,
+        MISSING_GLYPH, NVS_CONFIG_NAMESPACE, NVS_FRAME_NAMESPACE, PANEL_BUSY_TIMEOUT,
+        PARTIAL_REFRESHES_BEFORE_FULL, PROGRESS_BAR_HEIGHT, PROGRESS_BAR_MARGIN, QR_QUIET_ZONE,
+        REFRESH_STATS_SAVE_INTERVAL, SPLASH_TEXT, STATUS_BAR_BOOTS, STATUS_BAR_HEIGHT,
+        STATUS_BAR_RSSI, STATUS_BAR_VERSION, TEXT_LAYOUT, TIMER_STEP, TIMER_STEP_FULL_REFRESH,
+    },
+    mqtt::{fnv1a_hash, DisplayMessage},
+    panel::{busy_timeouts, PanelColor, PanelDisplay, PanelFrame, PANEL_HEIGHT, PANEL_WIDTH},
+    power::BatteryReading,
+    system::{boot_count, uptime, FIRMWARE_VERSION},
+};
+#[cfg(target_os = "espidf")]
+use crate::{
+    panel::{DisplayDriver, EpdDriver, EpdSpi},
+    system::feed_watchdog,
+};
+use embedded_graphics::{
+    mono_font::{
+        iso_8859_1::{FONT_10X20, FONT_6X10, FONT_9X15},
+        MonoFont, MonoTextStyle, MonoTextStyleBuilder,
+    },
+    pixelcolor::{BinaryColor, Rgb888, RgbColor},
+    prelude::{Dimensions, DrawTarget, Pixel, Point, Size},
+    primitives::{Circle, Line, Primitive, PrimitiveStyle, Rectangle},
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+    Drawable,
+};
+use epd_waveshare::{
+    buffer_len,
+    prelude::{Display, DisplayRotation},
+};
+#[cfg(target_os = "espidf")]
+use esp_idf_hal::spi::SpiError;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+#[cfg(target_os = "espidf")]
+use esp_idf_sys::EspError;
+use log::*;
+use qrcodegen_no_heap::{DataTooLong, QrCode, QrCodeEcc, Version};
+use serde::{Deserialize, Deserializer};
+use std::{
+    borrow::Cow,
+    mem,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, PoisonError, TryLockError,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tinybmp::Bmp;
+
+// Size of a packed black/white framebuffer of the panel, as accepted by "raw:" messages
+pub const RAW_FRAME_LEN: usize = buffer_len(PANEL_WIDTH as usize, PANEL_HEIGHT as usize);
+
+/// What the drawing helpers draw on: the frame of the panel on the device, or any other target of panel colors, e.g. an
+/// embedded_graphics MockDisplay or a framebuffer checked off-device. Only pushing frames to the panel needs a
+/// PanelDisplay.
+pub trait Canvas: DrawTarget<Color = PanelColor> {}
+
+impl<T: DrawTarget<Color = PanelColor>> Canvas for T {}
+
+/// Area of the display available to messages, between the header (if any) and the status bar
+pub fn content_area<D: Canvas>(display: &D) -> Rectangle {
+    let bounds = display.bounding_box().size;
+    let header = header().map_or(0, |header| header.height());
+    Rectangle::new(
+        Point::new(0, header as i32),
+        Size::new(
+            bounds.width,
+            bounds.height.saturating_sub(STATUS_BAR_HEIGHT + header),
+        ),
+    )
+}
+
+/// Top of the status bar strip, at the bottom of the display
+fn status_bar_top<D: Canvas>(display: &D) -> i32 {
+    display
+        .bounding_box()
+        .size
+        .height
+        .saturating_sub(STATUS_BAR_HEIGHT) as i32
+}
+
+// Space left under the header, with a rule across the display in its middle
+const HEADER_GAP: u32 = 5;
+
+/// Title line kept at the top of the display, above the content, until it's cleared
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Header {
+    text: String,
+    options: TextOptions,
+}
+
+impl Header {
+    /// Height of the strip of the header, gap included. Fitted headers take up to a line of the large font.
+    fn height(&self) -> u32 {
+        let font = match self.options.size {
+            FontSize::Fit => FontSize::Large.font(),
+            size => size.font(),
+        };
+        font.character_size.height + HEADER_GAP
+    }
+}
+
+static HEADER: Mutex<Option<Header>> = Mutex::new(None);
+
+/// The header set, copied so that drawing it doesn't hold the lock
+fn header() -> Option<Header> {
+    HEADER
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+/// Sets the header from a text message with its options (e.g. "align=center;Kitchen"), or removes it with None.
+/// The content already drawn stays where it is, the next message is laid out below the new header.
+/// Returns the area to refresh.
+pub fn set_header<D: Canvas>(display: &mut D, message: Option<&str>) -> Rectangle {
+    let width = display.bounding_box().size.width;
+    let previous = header().map_or(0, |header| header.height());
+    *HEADER.lock().unwrap_or_else(PoisonError::into_inner) = message.map(|message| {
+        let (options, text) = parse_text_options(message);
+        Header {
+            text: text.to_string(),
+            options,
+        }
+    });
+    // The previous strip is blanked, in case the new header is shorter
+    let cleared = clear_region(display, 0, 0, width, previous);
+    rectangle_union(cleared, draw_header(display))
+}
+
+/// Redraws the header on its strip, returning the strip. Zero sized without a header.
+pub fn draw_header<D: Canvas>(display: &mut D) -> Rectangle {
+    let Some(header) = header() else {
+        return Rectangle::zero();
+    };
+    let width = display.bounding_box().size.width;
+    let area = clear_region(display, 0, 0, width, header.height());
+    let line = Rectangle::new(
+        Point::zero(),
+        Size::new(width, header.height() - HEADER_GAP),
+    );
+    if header.options.size != FontSize::Fit
+        || draw_fitted_text(display, &header.text, line, header.options).is_none()
+    {
+        draw_text(
+            display,
+            &header.text,
+            0,
+            0,
+            line.size.width,
+            line.size.height,
+            header.options,
+        );
+    }
+    let rule_y = (line.size.height + HEADER_GAP / 2) as i32;
+    let _ = Line::new(Point::new(0, rule_y), Point::new(width as i32 - 1, rule_y))
+        .into_styled(PrimitiveStyle::with_stroke(PanelColor::Black, 1))
+        .draw(display);
+    area
+}
+
+/// Clears the message area, leaving the status bar untouched
+pub fn clear_content<D: Canvas>(display: &mut D) {
+    let area = content_area(display);
+    clear_region(
+        display,
+        area.top_left.x,
+        area.top_left.y,
+        area.size.width,
+        area.size.height,
+    );
+}
+
+/// Fills a `width`x`height` region at (x, y) with white, leaving the rest of the frame untouched.
+/// The region is clamped to the display, returning the area actually cleared.
+pub fn clear_region<D: Canvas>(
+    display: &mut D,
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+) -> Rectangle {
+    let area = clamp_rect(
+        Rectangle::new(Point::new(x, y), Size::new(width, height)),
+        display.bounding_box(),
+    );
+    let _ = area
+        .into_styled(PrimitiveStyle::with_fill(PanelColor::White))
+        .draw(display);
+    area
+}
+
+// The coordinates given by the messages (text positions, shapes and icons) are brought into the display before drawing,
+// so that far off ones can neither overflow the arithmetic of embedded_graphics nor take forever to draw:
+// - points, e.g. the position of a text, are moved to the nearest pixel of the display, see clamp_point
+// - rectangles, e.g. a text column, are cut to their part inside the display, see clamp_rect
+// - lines are cut where they cross the edges of the display, see clip_line
+// Shapes partially off-screen have their visible part drawn as it would be on a larger display, see draw_primitive.
+
+/// Nearest pixel of `bounds` to `point`: coordinates before it are brought to its first row or column, and those past
+/// it to its last one. The top-left corner of `bounds` when it's empty.
+pub fn clamp_point(point: Point, bounds: Rectangle) -> Point {
+    match bounds.bottom_right() {
+        Some(bottom_right) => point
+            .component_max(bounds.top_left)
+            .component_min(bottom_right),
+        None => bounds.top_left,
+    }
+}
+
+/// Part of `area` inside `bounds`, zero sized if they don't overlap
+pub fn clamp_rect(area: Rectangle, bounds: Rectangle) -> Rectangle {
+    let top_left = area.top_left.component_max(bounds.top_left);
+    let bottom_right = Point::new(
+        area.top_left
+            .x
+            .saturating_add(area.size.width.min(i32::MAX as u32) as i32),
+        area.top_left
+            .y
+            .saturating_add(area.size.height.min(i32::MAX as u32) as i32),
+    )
+    .component_min(bounds.top_left + bounds.size);
+    let size = (bottom_right - top_left).component_max(Point::zero());
+    Rectangle::new(top_left, Size::new(size.x as u32, size.y as u32))
+}
+
+/// Part of the line from `start` to `end` inside `bounds`, None when it doesn't cross it (Liang-Barsky clipping)
+pub fn clip_line(start: Point, end: Point, bounds: Rectangle) -> Option<(Point, Point)> {
+    let bottom_right = bounds.bottom_right()?;
+    let (x0, y0) = (start.x as f64, start.y as f64);
+    let (dx, dy) = (end.x as f64 - x0, end.y as f64 - y0);
+    // Fractions of the line where it enters and leaves the bounds
+    let (mut enter, mut leave) = (0.0f64, 1.0f64);
+    for (direction, distance) in [
+        (-dx, x0 - bounds.top_left.x as f64),
+        (dx, bottom_right.x as f64 - x0),
+        (-dy, y0 - bounds.top_left.y as f64),
+        (dy, bottom_right.y as f64 - y0),
+    ] {
+        if direction == 0.0 {
+            // Parallel to this edge, and outside of it
+            if distance < 0.0 {
+                return None;
+            }
+        } else if direction < 0.0 {
+            enter = enter.max(distance / direction);
+        } else {
+            leave = leave.min(distance / direction);
+        }
+    }
+    if enter > leave {
+        return None;
+    }
+    let at = |fraction: f64| {
+        clamp_point(
+            Point::new(
+                (x0 + fraction * dx).round() as i32,
+                (y0 + fraction * dy).round() as i32,
+            ),
+            bounds,
+        )
+    };
+    Some((at(enter), at(leave)))
+}
+
+/// Content drawn for a message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rendered {
+    /// Area covered by the new content
+    pub area: Rectangle,
+    /// Whether the text didn't fit and was cut at the last visible line
+    pub truncated: bool,
+    /// The text drawn, for the text messages
+    pub text: Option<RenderedText>,
+}
+
+impl From<Rectangle> for Rendered {
+    fn from(area: Rectangle) -> Self {
+        Rendered {
+            area,
+            truncated: false,
+            text: None,
+        }
+    }
+}
+
+/// Text as it's shown on the panel, after wrapping, truncation and glyph substitution
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RenderedText {
+    pub lines: Vec<String>,
+    pub options: TextOptions,
+}
+
+impl Rendered {
+    /// `line` drawn on its own over `area`
+    fn single_line(area: Rectangle, line: String, options: TextOptions) -> Self {
+        Rendered {
+            area,
+            truncated: false,
+            text: Some(RenderedText {
+                lines: vec![line],
+                options,
+            }),
+        }
+    }
+}
+
+/// Clears the message area (or just its region) and draws a received message on it.
+/// Returns None, leaving the frame untouched, when the message can't be displayed.
+pub fn render_message<D: Canvas>(
+    display: &mut D,
+    layout: &Layout,
+    message: &DisplayMessage,
+) -> Option<Rendered> {
+    match message {
+        DisplayMessage::Text(text) => Some(render_text(display, text)),
+        DisplayMessage::RegionText { region, text } => {
+            let rendered = render_region(display, layout, region, text);
+            if rendered.is_none() {
+                warn!("Discarding text for the unknown region \"{}\"", region);
+            }
+            rendered
+        }
+        DisplayMessage::PositionedText { x, y, text } => render_positioned(display, *x, *y, text),
+        DisplayMessage::Bitmap(bytes) => {
+            // Validate the image before clearing, so that a broken one leaves the previous frame untouched
+            if let Err(e) = decode_bitmap(bytes, content_area(display).size) {
+                error!("Discarding the received image: {}", e);
+                return None;
+            }
+            clear_content(display);
+            let top_left = content_area(display).top_left;
+            draw_bitmap(display, bytes, top_left.x, top_left.y)
+                .ok()
+                .map(Rendered::from)
+        }
+        // Raw frames bypass drawing and are pushed to the panel by set_raw_frame
+        DisplayMessage::RawFrame(_) => None,
+        // Clearing bypasses drawing too, the main loop blanks the whole panel
+        DisplayMessage::Clear => None,
+        DisplayMessage::Clock => Some(draw_clock(display, now()).into()),
+        DisplayMessage::Timer(duration) => Some(draw_countdown(display, duration.as_secs()).into()),
+        DisplayMessage::Weather(weather) => Some(draw_weather(display, weather).into()),
+        // Handled by the main loop, which owns the panel
+        DisplayMessage::FirmwareUpdate(_) => None,
+        DisplayMessage::LogLevel(_) => None,
+        DisplayMessage::Rotate(_) => None,
+        DisplayMessage::Header(_) => None,
+        DisplayMessage::SetTopic(_) => None,
+        DisplayMessage::ReinitPanel => None,
+        DisplayMessage::DryRun(_) => None,
+        DisplayMessage::Drawing(commands) => {
+            let area = draw_commands(display, commands);
+            (!area.is_zero_sized()).then_some(area.into())
+        }
+        DisplayMessage::Batch { messages, .. } => {
+            // The text echoed is the last one drawn
+            messages
+                .iter()
+                .filter_map(|message| match message {
+                    DisplayMessage::Clear => {
+                        clear_content(display);
+                        Some(content_area(display).into())
+                    }
+                    DisplayMessage::Header(text) => {
+                        Some(set_header(display, text.as_deref()).into())
+                    }
+                    message => render_message(display, layout, message),
+                })
+                .filter(|drawn| !drawn.area.is_zero_sized())
+                .reduce(|drawn: Rendered, next| Rendered {
+                    area: rectangle_union(drawn.area, next.area),
+                    truncated: drawn.truncated || next.truncated,
+                    text: next.text.or(drawn.text),
+                })
+        }
+    }
+}
+
+/// Clears the message area and draws a text message on it.
+/// Messages starting with "qr:" are rendered as a QR code of the remaining text, optionally preceded by a "scale=<pixels>;" option.
+/// Messages starting with "progress:" are rendered as a progress bar of the percentage that follows.
+pub fn render_text<D: Canvas>(display: &mut D, message: &str) -> Rendered {
+    clear_content(display);
+    let area = content_area(display);
+    let Size { width, height } = area.size;
+    let top = area.top_left.y;
+    let column = text_column(area);
+
+    if let Some(data) = message.strip_prefix("qr:") {
+        let (scale, data) = match split_option(data) {
+            Some(("scale", value, rest)) => (value.parse().ok(), rest),
+            _ => (None, data),
+        };
+        match draw_qr(display, data, 0, top, scale) {
+            Ok(area) => return area.into(),
+            Err(e) => {
+                warn!("Couldn't encode the QR code: {}", e);
+                let lines = draw_text(
+                    display,
+                    &format!("QR code error: {}", e),
+                    column.top_left.x,
+                    column.top_left.y,
+                    column.size.width,
+                    column.size.height,
+                    TextOptions::default(),
+                );
+                return text_area(column.top_left.y, width, lines, TextOptions::default());
+            }
+        }
+    }
+
+    if let Some(value) = message.strip_prefix("progress:") {
+        let error = match value.trim().parse::<f32>() {
+            Ok(percent) if !percent.is_nan() => {
+                // The bar is centered in the message area
+                let bar_width = width.saturating_sub(2 * PROGRESS_BAR_MARGIN);
+                let bar_height = PROGRESS_BAR_HEIGHT.min(height);
+                return draw_progress(
+                    display,
+                    percent,
+                    ((width - bar_width) / 2) as i32,
+                    top + ((height - bar_height) / 2) as i32,
+                    bar_width,
+                    bar_height,
+                )
+                .into();
+            }
+            _ => format!("Invalid progress value: {}", value),
+        };
+        warn!("{}", error);
+        let lines = draw_text(
+            display,
+            &error,
+            column.top_left.x,
+            column.top_left.y,
+            column.size.width,
+            column.size.height,
+            TextOptions::default(),
+        );
+        return text_area(column.top_left.y, width, lines, TextOptions::default());
+    }
+
+    let (options, text) = parse_text_options(message);
+    if needs_marquee(text, options, width) {
+        let area = draw_marquee(display, text, 0, top, 0, options);
+        return Rendered::single_line(area, marquee_line(text), options);
+    }
+    if options.size == FontSize::Fit {
+        if let Some(area) = draw_fitted_text(display, text, area, options) {
+            return Rendered::single_line(area, font_glyphs(text).into_owned(), options);
+        }
+    }
+    let lines = draw_text(
+        display,
+        text,
+        column.top_left.x,
+        column.top_left.y,
+        column.size.width,
+        column.size.height,
+        options,
+    );
+    debug!("Rendered message over {} line(s)", lines.drawn());
+    text_area(column.top_left.y, width, lines, options)
+}
+
+/// What the screensaver shows, see SCREENSAVER_TIMEOUT
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Screensaver {
+    /// The whole panel blanked, status bar included
+    Blank,
+    /// A clock of the local time, kept up to date
+    Clock,
+}
+
+/// Rectangular area of the display, in rotated (logical) coordinates
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub x: i32,
+    pub y: i32,
+    pub w: u32,
+    pub h: u32,
+}
+
+impl Region {
+    pub fn area(&self) -> Rectangle {
+        Rectangle::new(Point::new(self.x, self.y), Size::new(self.w, self.h))
+    }
+}
+
+/// Named regions of the display, each showing its own content
+pub struct Layout {
+    regions: Vec<(String, Region)>,
+}
+
+impl Layout {
+    /// Warns about the regions exceeding the `size` of the display, which are clipped when drawn
+    pub fn new(regions: &[(&str, Region)], size: Size) -> Self {
+        for (name, region) in regions {
+            if region.x < 0
+                || region.y < 0
+                || region.x as i64 + region.w as i64 > size.width as i64
+                || region.y as i64 + region.h as i64 > size.height as i64
+            {
+                warn!(
+                    "Region \"{}\" ({}x{} at {},{}) exceeds the {}x{} display, it's clipped",
+                    name, region.w, region.h, region.x, region.y, size.width, size.height
+                );
+            }
+        }
+        Layout {
+            regions: regions
+                .iter()
+                .map(|(name, region)| (name.to_string(), *region))
+                .collect(),
+        }
+    }
+
+    pub fn region(&self, name: &str) -> Option<Region> {
+        self.regions
+            .iter()
+            .find(|(region_name, _)| region_name == name)
+            .map(|(_, region)| *region)
+    }
+}
+
+/// Clears the `name` region of the layout and draws a text message inside it, wrapped to the region width.
+/// The content covers the area of the region (clamped to the display). Returns None if the layout has no such region.
+pub fn render_region<D: Canvas>(
+    display: &mut D,
+    layout: &Layout,
+    name: &str,
+    text: &str,
+) -> Option<Rendered> {
+    let region = layout.region(name)?;
+    let area = clear_region(display, region.x, region.y, region.w, region.h);
+    let (options, text) = parse_text_options(text);
+    if options.size == FontSize::Fit && draw_fitted_text(display, text, area, options).is_some() {
+        return Some(Rendered::single_line(
+            area,
+            font_glyphs(text).into_owned(),
+            options,
+        ));
+    }
+    let column = text_column(area);
+    let lines = draw_text(
+        display,
+        text,
+        column.top_left.x,
+        column.top_left.y,
+        column.size.width,
+        column.size.height,
+        options,
+    );
+    debug!(
+        "Rendered region \"{}\" over {} line(s)",
+        name,
+        lines.drawn()
+    );
+    Some(Rendered {
+        area,
+        truncated: lines.truncated,
+        text: Some(RenderedText {
+            lines: lines.lines,
+            options,
+        }),
+    })
+}
+
+/// Draws a text message from (x, y) over the current content, wrapped to the right edge of the display, with the
+/// options of text messages. "size=fit;" draws it on a single line from (x, y), in the largest font fitting.
+/// Returns None, leaving the frame untouched, when (x, y) is outside the display or in the status bar.
+pub fn render_positioned<D: Canvas>(
+    display: &mut D,
+    x: u32,
+    y: u32,
+    text: &str,
+) -> Option<Rendered> {
+    let width = display.bounding_box().size.width;
+    let bottom = status_bar_top(display).max(0) as u32;
+    if x >= width || y >= bottom {
+        warn!(
+            "Discarding the text at ({}, {}), outside the {}x{} display above the status bar",
+            x, y, width, bottom
+        );
+        return None;
+    }
+    let area = Rectangle::new(
+        Point::new(x as i32, y as i32),
+        Size::new(width - x, bottom - y),
+    );
+    let (options, text) = parse_text_options(text);
+    if options.size == FontSize::Fit {
+        let text = font_glyphs(text);
+        if let Some(font) = fit_font(&text, area.size) {
+            let style = text_style(font, options.color, options.invert);
+            let text_style = TextStyleBuilder::new().baseline(Baseline::Top).build();
+            let _ = Text::with_text_style(&text, area.top_left, style, text_style).draw(display);
+            let size = Size::new(text_width(&text, font), font.character_size.height);
+            return Some(Rendered::single_line(
+                Rectangle::new(area.top_left, size),
+                text.into_owned(),
+                options,
+            ));
+        }
+    }
+    let lines = draw_text(
+        display,
+        text,
+        area.top_left.x,
+        area.top_left.y,
+        area.size.width,
+        area.size.height,
+        options,
+    );
+    let mut rendered = text_area(area.top_left.y, area.size.width, lines, options);
+    rendered.area.top_left.x = area.top_left.x;
+    Some(rendered)
+}
+
+/// Content of `lines` lines of text drawn from the top-left corner of a `width` pixels wide column starting at `top`
+fn text_area(top: i32, width: u32, lines: TextLines, options: TextOptions) -> Rendered {
+    Rendered {
+        area: Rectangle::new(Point::new(0, top), Size::new(width, lines.height)),
+        truncated: lines.truncated,
+        text: Some(RenderedText {
+            lines: lines.lines,
+            options,
+        }),
+    }
+}
+
+/// Font sizes selectable through the "size=<small|medium|large|fit>;" message prefix
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FontSize {
+    Small,
+    #[default]
+    Medium,
+    Large,
+    /// The largest font of FIT_FONTS fitting the message on a single line, centered on the display
+    Fit,
+}
+
+// Fonts tried by FontSize::Fit, from the largest to the smallest
+const FIT_FONTS: [&MonoFont; 6] = [
+    &profont::PROFONT_24_POINT,
+    &profont::PROFONT_18_POINT,
+    &FONT_10X20,
+    &profont::PROFONT_14_POINT,
+    &FONT_9X15,
+    &FONT_6X10,
+];
+
+impl FontSize {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "small" => Some(FontSize::Small),
+            "medium" => Some(FontSize::Medium),
+            "large" => Some(FontSize::Large),
+            "fit" => Some(FontSize::Fit),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FontSize::Small => "small",
+            FontSize::Medium => "medium",
+            FontSize::Large => "large",
+            FontSize::Fit => "fit",
+        }
+    }
+
+    /// Font used to wrap text. Messages not fitting on a single line with FontSize::Fit are wrapped with the smallest font.
+    pub fn font(self) -> &'static MonoFont<'static> {
+        match self {
+            FontSize::Small => &FONT_6X10,
+            FontSize::Medium => &FONT_10X20,
+            FontSize::Large => &profont::PROFONT_24_POINT,
+            FontSize::Fit => FIT_FONTS[FIT_FONTS.len() - 1],
+        }
+    }
+}
+
+/// Text colors selectable through the "color=<black|white|red>;" message prefix
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TextColor {
+    Black,
+    #[default]
+    White,
+    /// Red on the tri-color panel. Rendered as black on black/white panels.
+    Chromatic,
+}
+
+impl TextColor {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "black" => Some(TextColor::Black),
+            "white" => Some(TextColor::White),
+            "red" | "chromatic" => Some(TextColor::Chromatic),
+            _ => None,
+        }
+    }
+
+    pub fn panel_color(self) -> PanelColor {
+        match self {
+            TextColor::Black => PanelColor::Black,
+            TextColor::White => PanelColor::White,
+            #[cfg(feature = "tricolor")]
+            TextColor::Chromatic => PanelColor::Chromatic,
+            #[cfg(not(feature = "tricolor"))]
+            TextColor::Chromatic => PanelColor::Black,
+        }
+    }
+
+    /// Background drawn behind the glyphs so that the text stays readable
+    pub fn background(self) -> PanelColor {
+        match self {
+            TextColor::White => PanelColor::Black,
+            TextColor::Black | TextColor::Chromatic => PanelColor::White,
+        }
+    }
+}
+
+/// Builds the style used to render message text in the given font and color.
+/// With `invert` the text and background colors are swapped.
+pub fn text_style(
+    font: &'static MonoFont<'static>,
+    color: TextColor,
+    invert: bool,
+) -> MonoTextStyle<'static, PanelColor> {
+    let (text_color, background_color) = match invert {
+        false => (color.panel_color(), color.background()),
+        true => (color.background(), color.panel_color()),
+    };
+    MonoTextStyleBuilder::new()
+        .font(font)
+        .text_color(text_color)
+        .background_color(background_color)
+        .build()
+}
+
+/// Horizontal placement of each rendered line, selectable through the "align=<left|center|right>;" message prefix
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HorizontalAlignment {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+impl HorizontalAlignment {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "left" => Some(HorizontalAlignment::Left),
+            "center" => Some(HorizontalAlignment::Center),
+            "right" => Some(HorizontalAlignment::Right),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            HorizontalAlignment::Left => "left",
+            HorizontalAlignment::Center => "center",
+            HorizontalAlignment::Right => "right",
+        }
+    }
+}
+
+/// How text longer than the display width is rendered, selectable through the "mode=<wrap|marquee>;" message prefix
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TextMode {
+    /// Wrapped over multiple lines
+    #[default]
+    Wrap,
+    /// Kept on a single line, scrolling horizontally
+    Marquee,
+}
+
+impl TextMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "wrap" => Some(TextMode::Wrap),
+            "marquee" => Some(TextMode::Marquee),
+            _ => None,
+        }
+    }
+}
+
+/// Margins and line spacing of wrapped text, in pixels, see TEXT_LAYOUT
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TextLayout {
+    /// Blank space above the text of messages, in the message area and in the layout regions
+    pub margin_top: u32,
+    /// Blank space left of the text of messages, in the message area and in the layout regions
+    pub margin_left: u32,
+    /// Blank space added between consecutive lines of any wrapped text
+    pub line_spacing: u32,
+}
+
+/// The part of `area` the wrapped text of a message is drawn in, inside the margins of TEXT_LAYOUT
+fn text_column(area: Rectangle) -> Rectangle {
+    let TextLayout {
+        margin_top,
+        margin_left,
+        ..
+    } = TEXT_LAYOUT;
+    Rectangle::new(
+        area.top_left + Point::new(margin_left as i32, margin_top as i32),
+        Size::new(
+            area.size.width.saturating_sub(margin_left),
+            area.size.height.saturating_sub(margin_top),
+        ),
+    )
+}
+
+/// Rendering options for a message, parsed from its "key=value;" prefixes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TextOptions {
+    pub size: FontSize,
+    pub alignment: HorizontalAlignment,
+    pub color: TextColor,
+    pub mode: TextMode,
+    /// Swaps the text and background colors
+    pub invert: bool,
+}
+
+/// Strips the leading "key=value;" options (e.g. "size=large;align=center;color=red;") and "invert;" flags from the message,
+/// returning the parsed options and the remaining text. Parsing stops at the first unknown key.
+/// Unknown values for a known key are logged and the default is kept.
+pub fn parse_text_options(mut message: &str) -> (TextOptions, &str) {
+    let mut options = TextOptions::default();
+    loop {
+        if let Some(rest) = message.strip_prefix("invert;") {
+            options.invert = true;
+            message = rest;
+            continue;
+        }
+        let Some((key, value, rest)) = split_option(message) else {
+            break;
+        };
+        match key {
+            "size" => match FontSize::from_name(value) {
+                Some(size) => options.size = size,
+                None => warn!("Unknown font size \"{}\", using the default one", value),
+            },
+            "align" => match HorizontalAlignment::from_name(value) {
+                Some(alignment) => options.alignment = alignment,
+                None => warn!("Unknown alignment \"{}\", using the default one", value),
+            },
+            "color" => match TextColor::from_name(value) {
+                Some(color) => options.color = color,
+                None => warn!("Unknown text color \"{}\", using the default one", value),
+            },
+            "mode" => match TextMode::from_name(value) {
+                Some(mode) => options.mode = mode,
+                None => warn!("Unknown text mode \"{}\", using the default one", value),
+            },
+            _ => break,
+        }
+        message = rest;
+    }
+    (options, message)
+}
+
+/// Splits a leading "key=value;" option off the message, returning the key, the value and the remaining text
+fn split_option(message: &str) -> Option<(&str, &str, &str)> {
+    let (option, rest) = message.split_once(';')?;
+    let (key, value) = option.split_once('=')?;
+    Some((key, value, rest))
+}
+
+// Drawn at the end of the last line of a truncated text. The fonts have no ellipsis glyph, so it's made of three dots
+const ELLIPSIS: &str = "...";
+
+/// Lines of a text drawn by draw_text
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TextLines {
+    /// The lines drawn, the last one ending with ELLIPSIS when truncated
+    pub lines: Vec<String>,
+    /// Whether the text didn't fit and its last lines were dropped
+    pub truncated: bool,
+    /// Height of the lines drawn, in pixels
+    pub height: u32,
+}
+
+impl TextLines {
+    pub fn drawn(&self) -> u32 {
+        self.lines.len() as u32
+    }
+}
+
+/// Draws `text` inside the `width`x`height` pixels column starting at (x, y), wrapping on word boundaries.
+/// Each line is aligned independently within the column, cut to its part inside the display (see clamp_rect).
+/// Text that doesn't fit stops at the last visible line, which ends with an ellipsis.
+/// Inline markup switches the font within the text, see parse_markup.
+pub fn draw_text<D: Canvas>(
+    display: &mut D,
+    text: &str,
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+    options: TextOptions,
+) -> TextLines {
+    // Text beyond the display isn't drawn, and a column partially off-screen shrinks to its visible part
+    let column = clamp_rect(
+        Rectangle::new(Point::new(x, y), Size::new(width, height)),
+        display.bounding_box(),
+    );
+    if column.is_zero_sized() {
+        return TextLines {
+            lines: Vec::new(),
+            truncated: !text.is_empty(),
+            height: 0,
+        };
+    }
+    let text = font_glyphs(text);
+    if let Some(chars) = parse_markup(&text, options.size) {
+        return draw_markup_text(display, &chars, column, options);
+    }
+    let (x, y) = (column.top_left.x, column.top_left.y);
+    let (width, height) = (column.size.width, column.size.height);
+    let font = options.size.font();
+    let style = text_style(font, options.color, options.invert);
+
+    // The text position is the anchor point for the chosen alignment, so embedded_graphics measures each line for us
+    let (alignment, anchor_x) = match options.alignment {
+        HorizontalAlignment::Left => (Alignment::Left, x),
+        HorizontalAlignment::Center => (Alignment::Center, x + (width as i32 - 1) / 2),
+        HorizontalAlignment::Right => (Alignment::Right, x + width as i32 - 1),
+    };
+    let text_style = TextStyleBuilder::new()
+        .baseline(Baseline::Top)
+        .alignment(alignment)
+        .build();
+
+    let char_width = font.character_size.width + font.character_spacing;
+    let max_chars = (width / char_width) as usize;
+    let spacing = TEXT_LAYOUT.line_spacing as i32;
+    let line_height = font.character_size.height as i32;
+    let bottom = (y + height as i32).min(status_bar_top(display));
+    // Lines that would fall out of the column or into the status bar are dropped instead of being drawn partially.
+    // Wrapping stops past the budget, so a long payload isn't wrapped as a whole.
+    // The spacing only goes between lines, the last one can end at the bottom of the column.
+    let budget = (((bottom - y).max(0) + spacing) / (line_height + spacing)) as usize;
+    let mut lines = wrap_text(&text, max_chars, budget + 1);
+    let truncated = lines.len() > budget;
+    if truncated {
+        lines.truncate(budget);
+        if let Some(last) = lines.last_mut() {
+            *last = with_ellipsis(last, max_chars);
+        }
+        warn!("Truncated the text to the {} line(s) fitting", budget);
+    }
+
+    for (index, line) in lines.iter().enumerate() {
+        let line_y = y + index as i32 * (line_height + spacing);
+        let _ = Text::with_text_style(line, Point::new(anchor_x, line_y), style, text_style)
+            .draw(display);
+    }
+
+    let height = (lines.len() as i32 * (line_height + spacing) - spacing).max(0) as u32;
+    TextLines {
+        lines,
+        truncated,
+        height,
+    }
+}
+
+/// Font sizes of the inline markup tags, by name
+fn markup_font(tag: &str) -> Option<FontSize> {
+    match tag {
+        "small" => Some(FontSize::Small),
+        "medium" => Some(FontSize::Medium),
+        "large" | "big" => Some(FontSize::Large),
+        _ => None,
+    }
+}
+
+// Longest inline markup tag, braces excluded, so that a lone brace doesn't scan the rest of the text
+const MARKUP_TAG_MAX_LEN: usize = 7;
+
+/// The characters of `text` along with the font size they're drawn in, `base` outside of the markup: "{small}",
+/// "{medium}", "{large}" and "{big}" switch to their size until the matching "{/small}", "{/medium}"... closing tag,
+/// e.g. "{big}21°{/big} feels like 19". Tags nest, each closed by its own name, and the unknown or unmatched ones
+/// are drawn as they are.
+/// None when `text` has no markup.
+fn parse_markup(text: &str, base: FontSize) -> Option<Vec<(char, FontSize)>> {
+    if !text.contains('{') {
+        return None;
+    }
+    let mut chars = Vec::with_capacity(text.len());
+    // The tags open, by name, along with their size
+    let mut open: Vec<(&str, FontSize)> = Vec::new();
+    let mut marked = false;
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let size = open.last().map_or(base, |&(_, size)| size);
+        let tag = (c == '{')
+            .then(|| {
+                rest[1..]
+                    .char_indices()
+                    .take(MARKUP_TAG_MAX_LEN + 1)
+                    .find(|&(_, c)| c == '}')
+            })
+            .flatten()
+            .map(|(end, _)| &rest[1..end + 1]);
+        if let Some(tag) = tag {
+            let switched = match tag.strip_prefix('/') {
+                None => markup_font(tag)
+                    .map(|size| open.push((tag, size)))
+                    .is_some(),
+                // Only the tag open last can be closed, by its own name: "{big}" isn't closed by "{/large}"
+                Some(closed) => {
+                    let matching = open.last().map_or(false, |&(name, _)| name == closed);
+                    if matching {
+                        open.pop();
+                    }
+                    matching
+                }
+            };
+            if switched {
+                marked = true;
+                rest = &rest[tag.len() + 2..];
+                continue;
+            }
+        }
+        chars.push((c, size));
+        rest = &rest[c.len_utf8()..];
+    }
+    marked.then_some(chars)
+}
+
+/// Width in pixels of a character drawn in `size`
+fn char_width(size: FontSize) -> u32 {
+    let font = size.font();
+    font.character_size.width + font.character_spacing
+}
+
+/// Distance from the top of a line of characters to their shared baseline, and the height of the line,
+/// those of the `base` font for a blank line
+fn line_metrics(line: &[(char, FontSize)], base: FontSize) -> (u32, u32) {
+    let fonts = || {
+        line.iter()
+            .map(|(_, size)| size.font())
+            .chain(line.is_empty().then(|| base.font()))
+    };
+    let ascent = fonts().map(|font| font.baseline).max().unwrap_or_default();
+    let descent = fonts()
+        .map(|font| font.character_size.height - font.baseline)
+        .max()
+        .unwrap_or_default();
+    (ascent, ascent + descent)
+}
+
+/// Splits the characters of a text with markup into at most `max_lines` lines of at most `width` pixels, as wrap_text
+fn wrap_markup(
+    chars: &[(char, FontSize)],
+    width: u32,
+    max_lines: usize,
+) -> Vec<Vec<(char, FontSize)>> {
+    let mut lines = Vec::new();
+    for paragraph in chars.split(|&(c, _)| c == '\n') {
+        if lines.len() >= max_lines {
+            break;
+        }
+        let paragraph = match paragraph.split_last() {
+            Some((('\r', _), rest)) => rest,
+            _ => paragraph,
+        };
+        let mut line = Vec::new();
+        let mut line_width = 0;
+        for word in paragraph.split(|&(c, _)| c.is_whitespace()) {
+            let Some(&(_, first_size)) = word.first() else {
+                continue;
+            };
+            let word_width: u32 = word.iter().map(|&(_, size)| char_width(size)).sum();
+            let space_width = char_width(first_size);
+            if !line.is_empty() && line_width + space_width + word_width > width {
+                lines.push(mem::take(&mut line));
+                line_width = 0;
+            }
+            if !line.is_empty() {
+                line.push((' ', first_size));
+                line_width += space_width;
+            }
+            for &(c, size) in word {
+                // Words longer than a whole line are hard-broken
+                if !line.is_empty() && line_width + char_width(size) > width {
+                    lines.push(mem::take(&mut line));
+                    line_width = 0;
+                }
+                line.push((c, size));
+                line_width += char_width(size);
+            }
+        }
+        // Blank lines are kept, so that consecutive newlines still add vertical space
+        lines.push(line);
+    }
+    lines.truncate(max_lines);
+    lines
+}
+
+/// Draws the characters of a text with markup (see parse_markup) inside `column`, as draw_text does.
+/// Each line is as high as its largest font, with the fonts sharing its baseline.
+fn draw_markup_text<D: Canvas>(
+    display: &mut D,
+    chars: &[(char, FontSize)],
+    column: Rectangle,
+    options: TextOptions,
+) -> TextLines {
+    let (x, y) = (column.top_left.x, column.top_left.y);
+    let width = column.size.width;
+    let bottom = (y + column.size.height as i32).min(status_bar_top(display));
+    let available = (bottom - y).max(0) as u32;
+    // No line is lower than the smallest font, which bounds the lines worth wrapping
+    let max_lines = (available / FontSize::Small.font().character_size.height) as usize + 1;
+    let mut lines = wrap_markup(chars, width, max_lines);
+
+    let spacing = TEXT_LAYOUT.line_spacing;
+    let mut height = 0;
+    let fitting = lines
+        .iter()
+        .take_while(|line| {
+            height += line_metrics(line, options.size).1;
+            let fits = height <= available;
+            height += spacing;
+            fits
+        })
+        .count();
+    let truncated = fitting < lines.len();
+    if truncated {
+        lines.truncate(fitting);
+        if let Some(last) = lines.last_mut() {
+            let size = last.last().map_or(options.size, |&(_, size)| size);
+            let ellipsis_width = ELLIPSIS.len() as u32 * char_width(size);
+            while last.last().map_or(false, |&(c, _)| c.is_whitespace())
+                || (!last.is_empty()
+                    && last.iter().map(|&(_, size)| char_width(size)).sum::<u32>() + ellipsis_width
+                        > width)
+            {
+                last.pop();
+            }
+            last.extend(ELLIPSIS.chars().map(|c| (c, size)));
+        }
+        warn!("Truncated the text to the {} line(s) fitting", fitting);
+    }
+
+    let baseline = TextStyleBuilder::new()
+        .baseline(Baseline::Alphabetic)
+        .alignment(Alignment::Left)
+        .build();
+    let mut top = y;
+    for line in &lines {
+        let (ascent, line_height) = line_metrics(line, options.size);
+        let line_width: u32 = line.iter().map(|&(_, size)| char_width(size)).sum();
+        let mut cursor = match options.alignment {
+            HorizontalAlignment::Left => x,
+            HorizontalAlignment::Center => x + (width as i32 - line_width as i32) / 2,
+            HorizontalAlignment::Right => x + width as i32 - line_width as i32,
+        };
+        // Each run of characters of the same font is drawn at once
+        let mut start = 0;
+        while start < line.len() {
+            let size = line[start].1;
+            let end = line[start..]
+                .iter()
+                .position(|&(_, other)| other != size)
+                .map_or(line.len(), |length| start + length);
+            let run: String = line[start..end].iter().map(|&(c, _)| c).collect();
+            let style = text_style(size.font(), options.color, options.invert);
+            let _ = Text::with_text_style(
+                &run,
+                Point::new(cursor, top + ascent as i32),
+                style,
+                baseline,
+            )
+            .draw(display);
+            cursor += ((end - start) as u32 * char_width(size)) as i32;
+            start = end;
+        }
+        top += (line_height + spacing) as i32;
+    }
+
+    TextLines {
+        lines: lines
+            .iter()
+            .map(|line| line.iter().map(|&(c, _)| c).collect())
+            .collect(),
+        truncated,
+        height: ((top - y) as u32).saturating_sub(spacing),
+    }
+}
+
+/// `line` shortened to end with ELLIPSIS within `max_chars` characters
+fn with_ellipsis(line: &str, max_chars: usize) -> String {
+    let kept = max_chars.saturating_sub(ELLIPSIS.len());
+    let mut line: String = line.chars().take(kept).collect();
+    line.truncate(line.trim_end().len());
+    line.push_str(ELLIPSIS);
+    line
+}
+
+/// Splits `text` into at most `max_lines` lines of at most `max_chars` characters, honoring embedded newlines
+/// ("\n" or "\r\n"). The rest of the text is left out.
+fn wrap_text(text: &str, max_chars: usize, max_lines: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.split('\n') {
+        if lines.len() >= max_lines {
+            break;
+        }
+        let paragraph = paragraph.strip_suffix('\r').unwrap_or(paragraph);
+        let wrapped = wrap_paragraph(paragraph, max_chars, max_lines - lines.len());
+        if wrapped.is_empty() {
+            // Keep blank lines so consecutive newlines still add vertical space
+            lines.push(String::new());
+        } else {
+            lines.extend(wrapped);
+        }
+    }
+    lines
+}
+
+/// Splits a single line of `text` into at most `max_lines` lines of at most `max_chars` characters, breaking on whitespace.
+/// Words longer than a whole line are hard-broken.
+fn wrap_paragraph(text: &str, max_chars: usize, max_lines: usize) -> Vec<String> {
+    let max_chars = max_chars.max(1);
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    let mut line_len = 0;
+
+    for mut word in text.split_whitespace() {
+        if lines.len() >= max_lines {
+            break;
+        }
+        let mut word_len = word.chars().count();
+
+        while word_len > max_chars && lines.len() < max_lines {
+            if line_len > 0 {
+                lines.push(mem::take(&mut line));
+                line_len = 0;
+            }
+            let split = word
+                .char_indices()
+                .nth(max_chars)
+                .map_or(word.len(), |(index, _)| index);
+            lines.push(word[..split].to_string());
+            word = &word[split..];
+            word_len -= max_chars;
+        }
+
+        if line_len > 0 && line_len + 1 + word_len > max_chars {
+            lines.push(mem::take(&mut line));
+            line_len = 0;
+        }
+        if line_len > 0 {
+            line.push(' ');
+            line_len += 1;
+        }
+        line.push_str(word);
+        line_len += word_len;
+    }
+
+    if line_len > 0 {
+        lines.push(line);
+    }
+    // Hard-breaking a word can go past max_lines
+    lines.truncate(max_lines);
+    lines
+}
+
+// Base letters of Latin Extended-A (U+0100 to U+017F), that the fonts have no glyphs for
+const LATIN_EXTENDED_A_BASE: &[u8; 128] = b"AaAaAaCcCcCcCcDdDdEeEeEeEeEeGgGgGgGgHhHhIiIiIiIiIiIiJjKkkLlLlLlLlLlNnNnNnnNnOoOoOoOoRrRrRrSsSsSsSsTtTtTtUuUuUuUuUuUuWwYyYZzZzZzs";
+
+/// Whether the fonts have a glyph for `c`, line breaks included
+fn has_glyph(c: char) -> bool {
+    matches!(c, '\n' | '\r' | ' '..='~' | '\u{a0}'..='\u{ff}')
+}
+
+/// `text` with the characters the fonts have no glyph for replaced by the closest ones, or by MISSING_GLYPH
+pub fn font_glyphs(text: &str) -> Cow<'_, str> {
+    if text.chars().all(has_glyph) {
+        return Cow::Borrowed(text);
+    }
+    let mut glyphs = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            _ if has_glyph(c) => glyphs.push(c),
+            '\t' | '\u{2000}'..='\u{200a}' | '\u{202f}' => glyphs.push(' '),
+            // Zero-width spaces and joiners, and byte order marks
+            '\u{200b}'..='\u{200d}' | '\u{feff}' => {}
+            '\u{2010}'..='\u{2015}' => glyphs.push('-'),
+            '\u{2018}'..='\u{201b}' => glyphs.push('\''),
+            '\u{201c}'..='\u{201f}' => glyphs.push('"'),
+            '\u{2022}' => glyphs.push('\u{b7}'),
+            '\u{2026}' => glyphs.push_str("..."),
+            '\u{20ac}' => glyphs.push_str("EUR"),
+            '\u{132}' => glyphs.push_str("IJ"),
+            '\u{133}' => glyphs.push_str("ij"),
+            '\u{152}' => glyphs.push_str("OE"),
+            '\u{153}' => glyphs.push_str("oe"),
+            '\u{100}'..='\u{17f}' => glyphs.push(LATIN_EXTENDED_A_BASE[c as usize - 0x100] as char),
+            _ => glyphs.push(MISSING_GLYPH),
+        }
+    }
+    Cow::Owned(glyphs)
+}
+
+/// Width in pixels of `text` drawn on a single line with `font`
+fn text_width(text: &str, font: &MonoFont) -> u32 {
+    font_glyphs(text).chars().count() as u32 * (font.character_size.width + font.character_spacing)
+}
+
+/// Largest font of FIT_FONTS that fits `text` on a single line in an area of the given size
+pub fn fit_font(text: &str, available: Size) -> Option<&'static MonoFont<'static>> {
+    if text.contains('\n') {
+        return None;
+    }
+    FIT_FONTS.into_iter().find(|font| {
+        text_width(text, font) <= available.width && font.character_size.height <= available.height
+    })
+}
+
+/// Draws `text` on a single line centered in `area`, with the largest font fitting it, returning the area covered.
+/// Returns None without drawing when even the smallest font overflows.
+pub fn draw_fitted_text<D: Canvas>(
+    display: &mut D,
+    text: &str,
+    area: Rectangle,
+    options: TextOptions,
+) -> Option<Rectangle> {
+    let text = font_glyphs(text);
+    let font = fit_font(&text, area.size)?;
+    let size = Size::new(text_width(&text, font), font.character_size.height);
+    let top_left = area.top_left
+        + Point::new(
+            (area.size.width - size.width) as i32 / 2,
+            (area.size.height - size.height) as i32 / 2,
+        );
+    let style = text_style(font, options.color, options.invert);
+    let text_style = TextStyleBuilder::new().baseline(Baseline::Top).build();
+    let _ = Text::with_text_style(&text, top_left, style, text_style).draw(display);
+    Some(Rectangle::new(top_left, size))
+}
+
+/// Whether the text is in marquee mode and too long to fit in `width` pixels
+fn needs_marquee(text: &str, options: TextOptions, width: u32) -> bool {
+    options.mode == TextMode::Marquee && text_width(text, options.size.font()) > width
+}
+
+/// Draws `text` on a single line starting at (x, y), scrolled to the left by `offset` pixels.
+/// The text repeats after a gap, so that it keeps scrolling seamlessly. Returns the area of the line.
+pub fn draw_marquee<D: Canvas>(
+    display: &mut D,
+    text: &str,
+    x: i32,
+    y: i32,
+    offset: u32,
+    options: TextOptions,
+) -> Rectangle {
+    let font = options.size.font();
+    let line = marquee_line(text);
+    let period = (text_width(&line, font) + MARQUEE_GAP) as i32;
+    let area = clear_region(display, x, y, u32::MAX, font.character_size.height);
+
+    let style = text_style(options.size.font(), options.color, options.invert);
+    let text_style = TextStyleBuilder::new().baseline(Baseline::Top).build();
+    let start = x - (offset as i32 % period);
+    for repetition in [start, start + period] {
+        let _ = Text::with_text_style(&line, Point::new(repetition, y), style, text_style)
+            .draw(display);
+    }
+    area
+}
+
+/// `text` on the single line scrolled by draw_marquee
+fn marquee_line(text: &str) -> String {
+    font_glyphs(text).replace(['\r', '\n'], " ")
+}
+
+/// State of a message scrolling horizontally across the top of the display
+pub struct Marquee {
+    text: String,
+    options: TextOptions,
+    offset: u32,
+    last_step: Instant,
+}
+
+impl Marquee {
+    /// Starts scrolling a text message in marquee mode, if it doesn't fit in `width` pixels
+    pub fn from_message(message: &DisplayMessage, width: u32) -> Option<Self> {
+        let DisplayMessage::Text(message) = message else {
+            return None;
+        };
+        let (options, text) = parse_text_options(message);
+        needs_marquee(text, options, width).then(|| Marquee {
+            text: text.to_string(),
+            options,
+            offset: 0,
+            last_step: Instant::now(),
+        })
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.last_step.elapsed() >= MARQUEE_INTERVAL
+    }
+
+    /// When the text is scrolled next
+    pub fn next_step(&self) -> Instant {
+        self.last_step + MARQUEE_INTERVAL
+    }
+
+    /// Scrolls the text by MARQUEE_STEP pixels, returning the redrawn area
+    pub fn step<D: Canvas>(&mut self, display: &mut D) -> Rectangle {
+        self.offset += MARQUEE_STEP;
+        self.last_step = Instant::now();
+        let top = content_area(display).top_left.y;
+        draw_marquee(display, &self.text, 0, top, self.offset, self.options)
+    }
+}
+
+/// Draws the status bar in the strip at the bottom of the display, returning its area.
+/// It shows the current local time and how long ago the last message was displayed.
+/// `time` is the local time, None until it has been synchronized.
+/// With STATUS_BAR_RSSI, the WiFi signal strength is shown in the middle, `rssi` being None while disconnected.
+/// With STATUS_BAR_BOOTS, the boot counter and the uptime follow it, and with STATUS_BAR_VERSION the firmware version.
+/// The battery charge is shown on its right, unless `battery` is None.
+/// With `invert` it's drawn white on black, to match inverted messages.
+pub fn draw_status_bar<D: Canvas>(
+    display: &mut D,
+    now: SystemTime,
+    time: Option<LocalTime>,
+    last_update: Option<SystemTime>,
+    rssi: Option<i8>,
+    battery: Option<BatteryReading>,
+    invert: bool,
+) -> Rectangle {
+    let area = clear_region(
+        display,
+        0,
+        status_bar_top(display),
+        u32::MAX,
+        STATUS_BAR_HEIGHT,
+    );
+    let style = text_style(&FONT_6X10, TextColor::Black, invert);
+    let (foreground, background) = match invert {
+        false => (PanelColor::Black, PanelColor::White),
+        true => (PanelColor::White, PanelColor::Black),
+    };
+    let _ = area
+        .into_styled(PrimitiveStyle::with_fill(background))
+        .draw(display);
+    // Separator from the message area
+    let _ = Rectangle::new(area.top_left, Size::new(area.size.width, 1))
+        .into_styled(PrimitiveStyle::with_fill(foreground))
+        .draw(display);
+
+    let time = clock_label(time);
+    let updated = match last_update {
+        Some(last_update) => {
+            let ago = now
+                .duration_since(last_update)
+                .unwrap_or_default()
+                .as_secs();
+            match ago {
+                0..=59 => format!("updated {}s ago", ago),
+                60..=3599 => format!("updated {}m ago", ago / 60),
+                _ => format!("updated {}h ago", ago / 3600),
+            }
+        }
+        None => "no message yet".to_string(),
+    };
+    let signal = match (STATUS_BAR_RSSI, rssi) {
+        (false, _) => String::new(),
+        (true, Some(rssi)) => format!("WiFi {} dBm", rssi),
+        (true, None) => "WiFi disconnected".to_string(),
+    };
+    let signal = match (STATUS_BAR_BOOTS, boot_count()) {
+        (false, _) => signal,
+        (true, boots) => {
+            let boots = boots.map_or_else(|| "?".to_string(), |boots| boots.to_string());
+            let uptime = uptime_label(uptime());
+            match signal.is_empty() {
+                true => format!("boot #{} up {}", boots, uptime),
+                false => format!("{}, boot #{} up {}", signal, boots, uptime),
+            }
+        }
+    };
+    let signal = match (STATUS_BAR_VERSION, signal.is_empty()) {
+        (false, _) => signal,
+        (true, true) => format!("v{}", FIRMWARE_VERSION),
+        (true, false) => format!("{}, v{}", signal, FIRMWARE_VERSION),
+    };
+
+    let middle_y = area.top_left.y + area.size.height as i32 / 2;
+    for (text, alignment, x) in [
+        (time.as_str(), Alignment::Left, 2),
+        (
+            signal.as_str(),
+            Alignment::Center,
+            area.size.width as i32 / 2,
+        ),
+        (
+            updated.as_str(),
+            Alignment::Right,
+            area.size.width as i32 - 2,
+        ),
+    ] {
+        let text_style = TextStyleBuilder::new()
+            .baseline(Baseline::Middle)
+            .alignment(alignment)
+            .build();
+        let _ =
+            Text::with_text_style(text, Point::new(x, middle_y), style, text_style).draw(display);
+    }
+    if let Some(battery) = battery {
+        draw_battery(
+            display,
+            Point::new(area.size.width as i32 * 3 / 4, middle_y),
+            battery,
+            style,
+            foreground,
+        );
+    }
+
+    area
+}
+
+/// Uptime in the status bar, in its largest unit, e.g. "3h"
+fn uptime_label(uptime: Duration) -> String {
+    match uptime.as_secs() {
+        secs @ 0..=59 => format!("{}s", secs),
+        secs @ 60..=3599 => format!("{}m", secs / 60),
+        secs @ 3600..=86399 => format!("{}h", secs / 3600),
+        secs => format!("{}d", secs / 86400),
+    }
+}
+
+/// Draws a battery icon filled according to the charge, followed by its percentage, vertically centered on `left`
+fn draw_battery<D: Canvas>(
+    display: &mut D,
+    left: Point,
+    battery: BatteryReading,
+    style: MonoTextStyle<'_, PanelColor>,
+    color: PanelColor,
+) {
+    const BODY: Size = Size::new(20, 10);
+    let top_left = left - Point::new(0, BODY.height as i32 / 2);
+    let _ = Rectangle::new(top_left, BODY)
+        .into_styled(PrimitiveStyle::with_stroke(color, 1))
+        .draw(display);
+    // Terminal on the right side
+    let _ = Rectangle::new(
+        top_left + Point::new(BODY.width as i32, 3),
+        Size::new(2, BODY.height - 6),
+    )
+    .into_styled(PrimitiveStyle::with_fill(color))
+    .draw(display);
+    let level = (BODY.width - 4) * battery.percent as u32 / 100;
+    if level > 0 {
+        let _ = Rectangle::new(
+            top_left + Point::new(2, 2),
+            Size::new(level, BODY.height - 4),
+        )
+        .into_styled(PrimitiveStyle::with_fill(color))
+        .draw(display);
+    }
+    let text_style = TextStyleBuilder::new()
+        .baseline(Baseline::Middle)
+        .alignment(Alignment::Left)
+        .build();
+    let _ = Text::with_text_style(
+        &format!("{}%", battery.percent),
+        left + Point::new(BODY.width as i32 + 5, 0),
+        style,
+        text_style,
+    )
+    .draw(display);
+}
+
+/// Remaining time of a countdown drawn by draw_countdown: "M:SS", or "H:MM:SS" from an hour, and "DONE" at zero
+fn countdown_label(seconds: u64) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    match (hours, minutes, seconds) {
+        (0, 0, 0) => "DONE".to_string(),
+        (0, ..) => format!("{}:{:02}", minutes, seconds),
+        _ => format!("{}:{:02}:{:02}", hours, minutes, seconds),
+    }
+}
+
+/// Clears the content area and draws the `seconds` left of a countdown on it, as large as possible.
+/// Returns the content area.
+pub fn draw_countdown<D: Canvas>(display: &mut D, seconds: u64) -> Rectangle {
+    clear_content(display);
+    let area = content_area(display);
+    let _ = draw_fitted_text(
+        display,
+        &countdown_label(seconds),
+        area,
+        TextOptions::default(),
+    );
+    area
+}
+
+/// State of a countdown started by a "timer:<seconds>" message, redrawn every TIMER_STEP (TIMER_STEP_FULL_REFRESH on
+/// the panels without partial refreshes) until it shows DONE.
+/// It counts down on the monotonic clock, so it doesn't need the time to be synchronized.
+pub struct Countdown {
+    end: Instant,
+    /// Seconds left drawn last
+    shown: u64,
+}
+
+impl Countdown {
+    /// Starts counting down a timer message, unless it's already done
+    pub fn from_message(message: &DisplayMessage) -> Option<Self> {
+        let DisplayMessage::Timer(duration) = message else {
+            return None;
+        };
+        (duration.as_secs() > 0).then(|| Countdown {
+            end: Instant::now() + *duration,
+            shown: duration.as_secs(),
+        })
+    }
+
+    fn step_seconds() -> u64 {
+        let step = match PanelDisplay::PARTIAL_REFRESH {
+            true => TIMER_STEP,
+            false => TIMER_STEP_FULL_REFRESH,
+        };
+        step.as_secs().max(1)
+    }
+
+    /// When the countdown is redrawn next, as it reaches the next multiple of the step
+    pub fn next_step(&self) -> Instant {
+        let step = Self::step_seconds();
+        self.end - Duration::from_secs(self.shown.saturating_sub(1) / step * step)
+    }
+
+    pub fn is_due(&self) -> bool {
+        Instant::now() >= self.next_step()
+    }
+
+    /// Whether the countdown reached zero, showing DONE
+    pub fn finished(&self) -> bool {
+        self.shown == 0
+    }
+
+    /// Draws the time left, rounded up to the step, returning the redrawn area
+    pub fn step<D: Canvas>(&mut self, display: &mut D) -> Rectangle {
+        let step = Self::step_seconds();
+        let left = self.end.saturating_duration_since(Instant::now());
+        let left = left.as_secs() + u64::from(left.subsec_nanos() > 0);
+        self.shown = (left + step - 1) / step * step;
+        draw_countdown(display, self.shown)
+    }
+}
+
+/// Clears the content area and draws a clock of `time` on it: the time as large as possible, with the date below.
+/// Returns the content area.
+pub fn draw_clock<D: Canvas>(display: &mut D, time: Option<LocalTime>) -> Rectangle {
+    clear_content(display);
+    let area = content_area(display);
+    let date_height = FontSize::Medium.font().character_size.height;
+    let time_area = Rectangle::new(
+        area.top_left,
+        Size::new(area.size.width, area.size.height - date_height * 2),
+    );
+    let _ = draw_fitted_text(
+        display,
+        &clock_label(time),
+        time_area,
+        TextOptions::default(),
+    );
+    if let Some(time) = time {
+        let options = TextOptions {
+            alignment: HorizontalAlignment::Center,
+            ..Default::default()
+        };
+        draw_text(
+            display,
+            &time.date(),
+            area.top_left.x,
+            time_area.bottom_right().map_or(0, |corner| corner.y + 1),
+            area.size.width,
+            date_height * 2,
+            options,
+        );
+    }
+    area
+}
+
+/// Weather drawn by draw_weather, from a JSON payload such as {"temp":21,"cond":"rain","hi":24,"lo":15}.
+/// Missing fields, and those of the wrong type, are None and drawn as placeholders.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct Weather {
+    /// Current temperature, in the unit of the sender
+    #[serde(default, deserialize_with = "lenient_number")]
+    pub temp: Option<f32>,
+    /// Condition, e.g. "rain", drawn as an icon when recognized
+    #[serde(default, deserialize_with = "lenient_text")]
+    pub cond: Option<String>,
+    /// Highest and lowest temperatures of the day
+    #[serde(default, deserialize_with = "lenient_number")]
+    pub hi: Option<f32>,
+    #[serde(default, deserialize_with = "lenient_number")]
+    pub lo: Option<f32>,
+}
+
+/// A number, also accepted as a string such as "21.5", or None for anything else
+fn lenient_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f32>, D::Error> {
+    let number = match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Number(number) => number.as_f64().map(|number| number as f32),
+        serde_json::Value::String(text) => text.trim().parse().ok(),
+        _ => None,
+    };
+    Ok(number.filter(|number| number.is_finite()))
+}
+
+/// A non-empty string, or None for anything else
+fn lenient_text<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    Ok(match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(text) if !text.trim().is_empty() => Some(text),
+        _ => None,
+    })
+}
+
+/// `temperature` in whole degrees, or with a decimal when it has one, e.g. "21°" or "-3.5°". "--°" when it's missing.
+fn temperature_label(temperature: Option<f32>) -> String {
+    match temperature {
+        Some(temperature) if temperature.fract() == 0.0 => format!("{}\u{b0}", temperature),
+        Some(temperature) => format!("{:.1}\u{b0}", temperature),
+        None => "--\u{b0}".to_string(),
+    }
+}
+
+/// Clears the content area and draws a weather card on it: the icon of the condition next to the temperature,
+/// as large as possible, then the condition and the high and low temperatures below. Returns the content area.
+pub fn draw_weather<D: Canvas>(display: &mut D, weather: &Weather) -> Rectangle {
+    clear_content(display);
+    let area = content_area(display);
+    let line_height = FontSize::Large.font().character_size.height * 2;
+    let top = Rectangle::new(
+        area.top_left,
+        Size::new(
+            area.size.width,
+            area.size.height.saturating_sub(line_height * 2),
+        ),
+    );
+    let half = Size::new(top.size.width / 2, top.size.height);
+    let icon_side = half.width.min(half.height) * 3 / 4;
+    let icon = Rectangle::with_center(
+        Rectangle::new(top.top_left, half).center(),
+        Size::new(icon_side, icon_side),
+    );
+    draw_weather_icon(
+        display,
+        weather
+            .cond
+            .as_deref()
+            .and_then(WeatherIcon::from_condition),
+        icon,
+    );
+    let _ = draw_fitted_text(
+        display,
+        &temperature_label(weather.temp),
+        Rectangle::new(top.top_left + Point::new(half.width as i32, 0), half),
+        TextOptions::default(),
+    );
+
+    let below = top.top_left.y + top.size.height as i32;
+    let centered = TextOptions {
+        alignment: HorizontalAlignment::Center,
+        size: FontSize::Large,
+        ..Default::default()
+    };
+    draw_text(
+        display,
+        weather.cond.as_deref().unwrap_or("--"),
+        area.top_left.x,
+        below,
+        area.size.width,
+        line_height,
+        centered,
+    );
+    draw_text(
+        display,
+        &format!(
+            "H {}  L {}",
+            temperature_label(weather.hi),
+            temperature_label(weather.lo)
+        ),
+        area.top_left.x,
+        below + line_height as i32,
+        area.size.width,
+        line_height,
+        TextOptions {
+            size: FontSize::Medium,
+            ..centered
+        },
+    );
+    area
+}
+
+/// Icons drawn for the weather conditions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum WeatherIcon {
+    Sun,
+    Cloud,
+    Rain,
+    Snow,
+    Storm,
+    Fog,
+}
+
+impl WeatherIcon {
+    /// The icon of the first kind of weather `condition` mentions, the most remarkable ones first,
+    /// e.g. a storm for "rain and thunder"
+    fn from_condition(condition: &str) -> Option<Self> {
+        let condition = condition.to_lowercase();
+        let keywords: [(WeatherIcon, &[&str]); 6] = [
+            (WeatherIcon::Storm, &["thunder", "storm", "lightning"]),
+            (WeatherIcon::Snow, &["snow", "sleet", "hail", "ice"]),
+            (WeatherIcon::Rain, &["rain", "drizzle", "shower"]),
+            (WeatherIcon::Fog, &["fog", "mist", "haze"]),
+            (WeatherIcon::Cloud, &["cloud", "overcast"]),
+            (WeatherIcon::Sun, &["clear", "sun", "fair"]),
+        ];
+        keywords
+            .into_iter()
+            .find(|(_, words)| words.iter().any(|word| condition.contains(word)))
+            .map(|(icon, _)| icon)
+    }
+}
+
+/// Draws `icon` inside the square `area`, or a question mark in a circle for an unknown condition
+fn draw_weather_icon<D: Canvas>(display: &mut D, icon: Option<WeatherIcon>, area: Rectangle) {
+    let side = area.size.width as f32;
+    // Point at the fractions (x, y) of the area
+    let at = |x: f32, y: f32| area.top_left + Point::new((x * side) as i32, (y * side) as i32);
+    let stroke = PrimitiveStyle::with_stroke(PanelColor::Black, (side / 24.0).max(2.0) as u32);
+    let fill = PrimitiveStyle::with_fill(PanelColor::Black);
+    let line = |display: &mut D, from: (f32, f32), to: (f32, f32)| {
+        let _ = Line::new(at(from.0, from.1), at(to.0, to.1))
+            .into_styled(stroke)
+            .draw(display);
+    };
+    // The precipitations fall from a cloud in the upper part of the icon
+    let cloud = |display: &mut D, top: f32, scale: f32| {
+        let circle = |display: &mut D, x: f32, y: f32, diameter: f32| {
+            let _ = Circle::with_center(at(x, top + y * scale), (diameter * scale * side) as u32)
+                .into_styled(fill)
+                .draw(display);
+        };
+        circle(display, 0.3, 0.55, 0.36);
+        circle(display, 0.52, 0.42, 0.48);
+        circle(display, 0.72, 0.58, 0.3);
+        let _ = Rectangle::with_corners(at(0.3, top + 0.5 * scale), at(0.72, top + 0.73 * scale))
+            .into_styled(fill)
+            .draw(display);
+    };
+    match icon {
+        Some(WeatherIcon::Sun) => {
+            let _ = Circle::with_center(at(0.5, 0.5), (side * 0.44) as u32)
+                .into_styled(fill)
+                .draw(display);
+            for ray in 0..8 {
+                let (sin, cos) = (ray as f32 * std::f32::consts::FRAC_PI_4).sin_cos();
+                line(
+                    display,
+                    (0.5 + 0.3 * cos, 0.5 + 0.3 * sin),
+                    (0.5 + 0.47 * cos, 0.5 + 0.47 * sin),
+                );
+            }
+        }
+        Some(WeatherIcon::Cloud) => cloud(display, 0.05, 1.0),
+        Some(WeatherIcon::Rain) => {
+            cloud(display, 0.0, 0.75);
+            for x in [0.3, 0.5, 0.7] {
+                line(display, (x, 0.68), (x - 0.08, 0.95));
+            }
+        }
+        Some(WeatherIcon::Snow) => {
+            cloud(display, 0.0, 0.75);
+            for (x, y) in [(0.3, 0.75), (0.5, 0.88), (0.7, 0.75)] {
+                let _ = Circle::with_center(at(x, y), (side * 0.1) as u32)
+                    .into_styled(fill)
+                    .draw(display);
+            }
+        }
+        Some(WeatherIcon::Storm) => {
+            cloud(display, 0.0, 0.75);
+            line(display, (0.55, 0.6), (0.42, 0.8));
+            line(display, (0.42, 0.8), (0.58, 0.8));
+            line(display, (0.58, 0.8), (0.45, 0.98));
+        }
+        Some(WeatherIcon::Fog) => {
+            for (y, from, to) in [
+                (0.3, 0.1, 0.8),
+                (0.45, 0.2, 0.9),
+                (0.6, 0.1, 0.8),
+                (0.75, 0.2, 0.9),
+            ] {
+                line(display, (from, y), (to, y));
+            }
+        }
+        None => {
+            let _ = Circle::with_center(at(0.5, 0.5), (side * 0.9) as u32)
+                .into_styled(stroke)
+                .draw(display);
+            let _ = draw_fitted_text(display, "?", area, TextOptions::default());
+        }
+    }
+}
+
+/// Shape of a "draw:" command
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shape {
+    /// "rect <x> <y> <width> <height>"
+    Rect { x: i32, y: i32, w: u32, h: u32 },
+    /// "line <x1> <y1> <x2> <y2>"
+    Line { x1: i32, y1: i32, x2: i32, y2: i32 },
+    /// "circle <x> <y> <diameter>", with (x, y) being the top-left corner of its bounding box
+    Circle { x: i32, y: i32, diameter: u32 },
+}
+
+/// A shape to draw, parsed from a "draw:" command such as "rect 10 10 100 50 fill"
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DrawCommand {
+    pub shape: Shape,
+    /// Fills rectangles and circles instead of only outlining them ("fill" flag)
+    pub fill: bool,
+    /// On is black, Off is white ("white" flag)
+    pub color: BinaryColor,
+}
+
+/// Parses a single drawing command: the shape name, its coordinates, then the optional "fill" and "white" flags
+pub fn parse_draw_command(command: &str) -> anyhow::Result<DrawCommand> {
+    let mut words = command.split_whitespace();
+    let name = words.next().unwrap_or_default();
+    let argument_count = match name {
+        "rect" | "line" => 4,
+        "circle" => 3,
+        _ => anyhow::bail!("unknown shape \"{}\"", name),
+    };
+    let arguments = words
+        .by_ref()
+        .take(argument_count)
+        .map(|word| {
+            word.parse::<i32>()
+                .map_err(|_| anyhow::anyhow!("invalid coordinate \"{}\"", word))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if arguments.len() < argument_count {
+        anyhow::bail!("{} needs {} coordinates", name, argument_count);
+    }
+    let size = |value: i32| {
+        u32::try_from(value).map_err(|_| anyhow::anyhow!("negative size {} for {}", value, name))
+    };
+    let shape = match (name, arguments.as_slice()) {
+        ("rect", &[x, y, w, h]) => Shape::Rect {
+            x,
+            y,
+            w: size(w)?,
+            h: size(h)?,
+        },
+        ("line", &[x1, y1, x2, y2]) => Shape::Line { x1, y1, x2, y2 },
+        ("circle", &[x, y, diameter]) => Shape::Circle {
+            x,
+            y,
+            diameter: size(diameter)?,
+        },
+        _ => unreachable!(),
+    };
+
+    let mut command = DrawCommand {
+        shape,
+        fill: false,
+        color: BinaryColor::On,
+    };
+    for flag in words {
+        match flag {
+            "fill" => command.fill = true,
+            "white" => command.color = BinaryColor::Off,
+            _ => anyhow::bail!("unknown flag \"{}\"", flag),
+        }
+    }
+    Ok(command)
+}
+
+// Circles whose diameter exceeds this many times the longer side of the display are skipped, drawing them takes a
+// line per row
+const MAX_CIRCLE_DIAMETER_RATIO: u32 = 4;
+
+/// Draws a shape over the current content, returning the area it covers (clamped to the display), zero sized when
+/// nothing was drawn. Only the part of a shape inside the display is drawn, as a larger display would show it:
+/// rectangles are cut a pixel past its edges, so that their outline stays open where they cross them, and lines are cut
+/// at its edges. Shapes entirely off-screen are skipped.
+pub fn draw_primitive<D: Canvas>(display: &mut D, command: &DrawCommand) -> Rectangle {
+    let bounds = display.bounding_box();
+    let color = PanelColor::from(command.color);
+    // Lines can't be filled, so they're always stroked
+    let style = match (command.fill, command.shape) {
+        (true, Shape::Rect { .. } | Shape::Circle { .. }) => PrimitiveStyle::with_fill(color),
+        _ => PrimitiveStyle::with_stroke(color, 1),
+    };
+    let area = match command.shape {
+        Shape::Rect { x, y, w, h } => {
+            let rect = clamp_rect(
+                Rectangle::new(Point::new(x, y), Size::new(w, h)),
+                bounds.offset(1),
+            );
+            if rect.is_zero_sized() {
+                return Rectangle::zero();
+            }
+            let shape = rect.into_styled(style);
+            let _ = shape.draw(display);
+            shape.bounding_box()
+        }
+        Shape::Line { x1, y1, x2, y2 } => {
+            let Some((start, end)) = clip_line(Point::new(x1, y1), Point::new(x2, y2), bounds)
+            else {
+                return Rectangle::zero();
+            };
+            let shape = Line::new(start, end).into_styled(style);
+            let _ = shape.draw(display);
+            shape.bounding_box()
+        }
+        Shape::Circle { x, y, diameter } => {
+            let circle = Circle::new(Point::new(x, y), diameter);
+            if clamp_rect(circle.bounding_box(), bounds).is_zero_sized() {
+                return Rectangle::zero();
+            }
+            let longer_side = bounds.size.width.max(bounds.size.height);
+            if diameter > longer_side * MAX_CIRCLE_DIAMETER_RATIO {
+                warn!(
+                    "Skipping the circle of diameter {}, more than {} times the display",
+                    diameter, MAX_CIRCLE_DIAMETER_RATIO
+                );
+                return Rectangle::zero();
+            }
+            let shape = circle.into_styled(style);
+            let _ = shape.draw(display);
+            shape.bounding_box()
+        }
+    };
+    clamp_rect(area, bounds)
+}
+
+/// Draws the commands of a "draw:" message, one per line or separated by ';', returning the area they cover.
+/// Invalid commands are logged and skipped.
+pub fn draw_commands<D: Canvas>(display: &mut D, commands: &str) -> Rectangle {
+    let mut area: Option<Rectangle> = None;
+    for command in commands
+        .split(['\n', ';'])
+        .map(str::trim)
+        .filter(|command| !command.is_empty())
+    {
+        let drawn = match command.strip_prefix("icon ") {
+            Some(icon) => draw_icon_command(display, icon),
+            None => parse_draw_command(command).map(|command| draw_primitive(display, &command)),
+        };
+        match drawn {
+            Ok(shape_area) => {
+                if !shape_area.is_zero_sized() {
+                    area = Some(area.map_or(shape_area, |area| rectangle_union(area, shape_area)));
+                }
+            }
+            Err(e) => warn!("Skipping the drawing command \"{}\": {}", command, e),
+        }
+    }
+    area.unwrap_or_default()
+}
+
+// Side of the built-in icons, in pixels
+pub const ICON_SIZE: u32 = 16;
+// Gap between an icon and its label
+const ICON_LABEL_GAP: i32 = 4;
+
+/// 1-bit icon, one row per u16 with the leftmost pixel in the most significant bit. Set bits are drawn in black.
+type Icon = [u16; ICON_SIZE as usize];
+
+const ICON_WIFI: Icon = [
+    0b0000_0000_0000_0000,
+    0b0000_0000_0000_0000,
+    0b0000_1111_1111_0000,
+    0b0011_0000_0000_1100,
+    0b0100_0000_0000_0010,
+    0b1000_1111_1111_0001,
+    0b0001_0000_0000_1000,
+    0b0010_0111_1110_0100,
+    0b0000_1000_0001_0000,
+    0b0000_0100_0010_0000,
+    0b0000_0001_1000_0000,
+    0b0000_0011_1100_0000,
+    0b0000_0011_1100_0000,
+    0b0000_0001_1000_0000,
+    0b0000_0000_0000_0000,
+    0b0000_0000_0000_0000,
+];
+const ICON_BATTERY: Icon = [
+    0b0000_0000_0000_0000,
+    0b0000_0000_0000_0000,
+    0b0000_0000_0000_0000,
+    0b0011_1111_1111_1100,
+    0b0010_0000_0000_0100,
+    0b0010_1111_1111_0111,
+    0b0010_1111_1111_0101,
+    0b0010_1111_1111_0101,
+    0b0010_1111_1111_0101,
+    0b0010_1111_1111_0111,
+    0b0010_0000_0000_0100,
+    0b0011_1111_1111_1100,
+    0b0000_0000_0000_0000,
+    0b0000_0000_0000_0000,
+    0b0000_0000_0000_0000,
+    0b0000_0000_0000_0000,
+];
+const ICON_CLOCK: Icon = [
+    0b0000_0111_1110_0000,
+    0b0001_1000_0001_1000,
+    0b0010_0001_1000_0100,
+    0b0100_0001_1000_0010,
+    0b0100_0001_1000_0010,
+    0b1000_0001_1000_0001,
+    0b1000_0001_1000_0001,
+    0b1000_0001_1111_0001,
+    0b1000_0001_1111_0001,
+    0b1000_0000_0000_0001,
+    0b1000_0000_0000_0001,
+    0b0100_0000_0000_0010,
+    0b0100_0000_0000_0010,
+    0b0010_0000_0000_0100,
+    0b0001_1000_0001_1000,
+    0b0000_0111_1110_0000,
+];
+const ICON_ALERT: Icon = [
+    0b0000_0001_1000_0000,
+    0b0000_0011_1100_0000,
+    0b0000_0010_0100_0000,
+    0b0000_0100_0010_0000,
+    0b0000_0101_1010_0000,
+    0b0000_1001_1001_0000,
+    0b0000_1001_1001_0000,
+    0b0001_0001_1000_1000,
+    0b0001_0001_1000_1000,
+    0b0010_0001_1000_0100,
+    0b0010_0000_0000_0100,
+    0b0100_0001_1000_0010,
+    0b0100_0001_1000_0010,
+    0b1000_0000_0000_0001,
+    0b1111_1111_1111_1111,
+    0b0000_0000_0000_0000,
+];
+const ICON_CHECK: Icon = [
+    0b0000_0000_0000_0000,
+    0b0000_0000_0000_0011,
+    0b0000_0000_0000_0111,
+    0b0000_0000_0000_1110,
+    0b0000_0000_0001_1100,
+    0b0000_0000_0011_1000,
+    0b0000_0000_0111_0000,
+    0b1100_0000_1110_0000,
+    0b1110_0001_1100_0000,
+    0b0111_0011_1000_0000,
+    0b0011_1111_0000_0000,
+    0b0001_1110_0000_0000,
+    0b0000_1100_0000_0000,
+    0b0000_0000_0000_0000,
+    0b0000_0000_0000_0000,
+    0b0000_0000_0000_0000,
+];
+
+// Built-in icons, by the name used in "icon:" messages and "icon" drawing commands
+const ICONS: [(&str, &Icon); 5] = [
+    ("wifi", &ICON_WIFI),
+    ("battery", &ICON_BATTERY),
+    ("clock", &ICON_CLOCK),
+    ("alert", &ICON_ALERT),
+    ("check", &ICON_CHECK),
+];
+
+/// Draws the built-in icon `name` with its top-left corner at (x, y), over the current content: its unset pixels are
+/// left as they are. Returns the area it covers (clamped to the display, zero sized when it's entirely off-screen), or
+/// None when there's no such icon.
+pub fn draw_icon<D: Canvas>(display: &mut D, name: &str, x: i32, y: i32) -> Option<Rectangle> {
+    let (_, icon) = ICONS.iter().find(|(icon, _)| *icon == name)?;
+    let area = clamp_rect(
+        Rectangle::new(Point::new(x, y), Size::new(ICON_SIZE, ICON_SIZE)),
+        display.bounding_box(),
+    );
+    // Off-screen, the coordinates of its pixels could overflow
+    if area.is_zero_sized() {
+        return Some(area);
+    }
+    let pixels = icon.iter().enumerate().flat_map(|(row, bits)| {
+        (0..ICON_SIZE)
+            .filter(move |column| bits & (0x8000 >> column) != 0)
+            .map(move |column| {
+                Pixel(
+                    Point::new(x + column as i32, y + row as i32),
+                    PanelColor::Black,
+                )
+            })
+    });
+    let _ = display.draw_iter(pixels);
+    Some(area)
+}
+
+/// Draws an icon command: "<name> <x> <y>", optionally followed by a label drawn on the right of the icon in the
+/// large font, e.g. "wifi 10 10 Online". The label starts on the display, see clamp_point. Returns the area covered
+/// by the icon and its label.
+fn draw_icon_command<D: Canvas>(display: &mut D, command: &str) -> anyhow::Result<Rectangle> {
+    let command = command.trim();
+    let mut rest = command;
+    let mut word = || {
+        let (word, remaining) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        rest = remaining.trim_start();
+        word
+    };
+    let name = word();
+    let mut coordinate = || {
+        let value = word();
+        value
+            .parse::<i32>()
+            .map_err(|_| anyhow::anyhow!("invalid coordinate \"{}\"", value))
+    };
+    let (x, y) = (coordinate()?, coordinate()?);
+    let Some(mut area) = draw_icon(display, name, x, y) else {
+        anyhow::bail!("unknown icon \"{}\"", name);
+    };
+    if !rest.is_empty() {
+        let text = font_glyphs(rest);
+        let anchor = Point::new(
+            x.saturating_add(ICON_SIZE as i32 + ICON_LABEL_GAP),
+            y.saturating_add(ICON_SIZE as i32 / 2),
+        );
+        let label = Text::with_text_style(
+            &text,
+            clamp_point(anchor, display.bounding_box()),
+            text_style(FontSize::Large.font(), TextColor::Black, false),
+            TextStyleBuilder::new()
+                .baseline(Baseline::Middle)
+                .alignment(Alignment::Left)
+                .build(),
+        );
+        let _ = label.draw(display);
+        area = match area.is_zero_sized() {
+            true => label.bounding_box(),
+            false => rectangle_union(area, label.bounding_box()),
+        };
+    }
+    Ok(clamp_rect(area, display.bounding_box()))
+}
+
+/// Draws a `w`x`h` progress bar at (x, y): an outline filled for `percent` (clamped to 0-100) of its width,
+/// with the percentage written in the middle. Returns the area it covers.
+pub fn draw_progress<D: Canvas>(
+    display: &mut D,
+    percent: f32,
+    x: i32,
+    y: i32,
+    w: u32,
+    h: u32,
+) -> Rectangle {
+    let clamped = percent.clamp(0.0, 100.0);
+    if clamped != percent {
+        warn!(
+            "Progress {} is out of range, clamped to {}",
+            percent, clamped
+        );
+    }
+
+    let area = clear_region(display, x, y, w, h);
+    let outline = Rectangle::new(Point::new(x, y), Size::new(w, h));
+    let _ = outline
+        .into_styled(PrimitiveStyle::with_stroke(PanelColor::Black, 2))
+        .draw(display);
+    let filled = Size::new((w as f32 * clamped / 100.0) as u32, h);
+    let _ = Rectangle::new(outline.top_left, filled)
+        .into_styled(PrimitiveStyle::with_fill(PanelColor::Black))
+        .draw(display);
+
+    // The glyphs have a white background, so the percentage stays readable over both parts of the bar
+    let style = text_style(&FONT_10X20, TextColor::Black, false);
+    let text_style = TextStyleBuilder::new()
+        .baseline(Baseline::Middle)
+        .alignment(Alignment::Center)
+        .build();
+    let _ = Text::with_text_style(
+        &format!("{:.0}%", clamped),
+        outline.center(),
+        style,
+        text_style,
+    )
+    .draw(display);
+
+    area
+}
+
+/// Draws `data` as a QR code with its top-left corner (quiet zone included) at (x, y), returning the area it covers.
+/// Each module is `scale` pixels wide. When not specified, the largest scale fitting the display is used.
+pub fn draw_qr<D: Canvas>(
+    display: &mut D,
+    data: &str,
+    x: i32,
+    y: i32,
+    scale: Option<u32>,
+) -> Result<Rectangle, DataTooLong> {
+    // The buffers are quite big for the largest QR code version, so they're kept off the stack
+    let mut tempbuffer = vec![0u8; Version::MAX.buffer_len()];
+    let mut outbuffer = vec![0u8; Version::MAX.buffer_len()];
+    let qr = QrCode::encode_text(
+        data,
+        &mut tempbuffer,
+        &mut outbuffer,
+        QrCodeEcc::Medium,
+        Version::MIN,
+        Version::MAX,
+        None,
+        true,
+    )?;
+
+    let modules = (qr.size() + 2 * QR_QUIET_ZONE) as u32;
+    let content = content_area(display);
+    let available = (content.top_left.x + content.size.width as i32)
+        .saturating_sub(x)
+        .min((content.top_left.y + content.size.height as i32).saturating_sub(y))
+        .max(0) as u32;
+    let fitting = (available / modules).max(1);
+    let scale = match scale {
+        Some(requested) if requested > fitting => {
+            warn!(
+                "A scale of {} doesn't fit the QR code in {} pixels, using {}",
+                requested, available, fitting
+            );
+            fitting
+        }
+        Some(requested) => requested.max(1),
+        None => fitting,
+    };
+    info!(
+        "Drawing a {}x{} modules QR code with a scale of {}",
+        qr.size(),
+        qr.size(),
+        scale
+    );
+
+    let area = Rectangle::new(
+        Point::new(x, y),
+        Size::new_equal(modules.saturating_mul(scale)),
+    );
+    let _ = area
+        .into_styled(PrimitiveStyle::with_fill(PanelColor::White))
+        .draw(display);
+    let module_size = Size::new_equal(scale);
+    for module_y in 0..qr.size() {
+        for module_x in 0..qr.size() {
+            if qr.get_module(module_x, module_y) {
+                let top_left = Point::new(
+                    x.saturating_add((module_x + QR_QUIET_ZONE).saturating_mul(scale as i32)),
+                    y.saturating_add((module_y + QR_QUIET_ZONE).saturating_mul(scale as i32)),
+                );
+                let _ = Rectangle::new(top_left, module_size)
+                    .into_styled(PrimitiveStyle::with_fill(PanelColor::Black))
+                    .draw(display);
+            }
+        }
+    }
+
+    Ok(area)
+}
+
+/// Parses a BMP image, checking that it fits in an area of the given size
+fn decode_bitmap(bytes: &[u8], available: Size) -> anyhow::Result<Bmp<'_, Rgb888>> {
+    let bmp = Bmp::<Rgb888>::from_slice(bytes)
+        .map_err(|e| anyhow::anyhow!("invalid BMP image: {:?}", e))?;
+    let size = bmp.bounding_box().size;
+    if size.width > available.width || size.height > available.height {
+        anyhow::bail!(
+            "the {}x{} image doesn't fit in {}x{}",
+            size.width,
+            size.height,
+            available.width,
+            available.height
+        );
+    }
+    Ok(bmp)
+}
+
+/// How shades of gray are reduced to the black and white of the panel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dithering {
+    /// Black below mid-gray, flattening gradients
+    Threshold,
+    /// A regular pattern of a 4x4 Bayer matrix, which keeps flat areas steady
+    Ordered,
+    /// Floyd-Steinberg error diffusion, keeping finer detail with a noisier pattern
+    FloydSteinberg,
+}
+
+// Order of the pixels of the ordered dithering, each one turning white at its own gray level
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Dithers a gray image fed pixel by pixel, row after row. Only two rows of error are kept, so that a full screen
+/// image doesn't need a buffer of its own.
+pub struct Ditherer {
+    method: Dithering,
+    width: usize,
+    index: usize,
+    /// Error diffused to the current and the next row, with a padding pixel on each side
+    errors: [Vec<i16>; 2],
+}
+
+impl Ditherer {
+    pub fn new(method: Dithering, width: usize) -> Self {
+        let errors = match method {
+            Dithering::FloydSteinberg => [vec![0; width + 2], vec![0; width + 2]],
+            _ => [Vec::new(), Vec::new()],
+        };
+        Ditherer {
+            method,
+            width: width.max(1),
+            index: 0,
+            errors,
+        }
+    }
+
+    /// Reduces the next gray pixel (0 being black and 255 white) to black (On) or white (Off)
+    pub fn dither(&mut self, luma: u8) -> BinaryColor {
+        let (x, y) = (self.index % self.width, self.index / self.width);
+        self.index += 1;
+        let black = match self.method {
+            Dithering::Threshold => luma < 128,
+            // Thresholds spread evenly from 8 to 248, so that pure black and white stay as they are
+            Dithering::Ordered => u16::from(luma) < u16::from(BAYER_4X4[y % 4][x % 4]) * 16 + 8,
+            Dithering::FloydSteinberg => {
+                let [current, next] = &mut self.errors;
+                let level = i16::from(luma) + current[x + 1];
+                let black = level < 128;
+                let error = level - if black { 0 } else { 255 };
+                current[x + 2] += error * 7 / 16;
+                next[x] += error * 3 / 16;
+                next[x + 1] += error * 5 / 16;
+                next[x + 2] += error / 16;
+                if x + 1 == self.width {
+                    self.errors.swap(0, 1);
+                    self.errors[1].iter_mut().for_each(|error| *error = 0);
+                }
+                black
+            }
+        };
+        match black {
+            true => BinaryColor::On,
+            false => BinaryColor::Off,
+        }
+    }
+}
+
+/// Dithers the gray pixels of an image `width` pixels wide, given row by row, into black (On) and white (Off) pixels
+pub fn dither(
+    gray: &[u8],
+    width: usize,
+    method: Dithering,
+) -> impl Iterator<Item = BinaryColor> + '_ {
+    let mut ditherer = Ditherer::new(method, width);
+    gray.iter().map(move |&luma| ditherer.dither(luma))
+}
+
+/// Draws a BMP image with its top-left corner at (x, y), returning the area it covers.
+/// Colors are reduced to black and white according to their brightness, dithered with IMAGE_DITHERING.
+pub fn draw_bitmap<D: Canvas>(
+    display: &mut D,
+    bytes: &[u8],
+    x: i32,
+    y: i32,
+) -> anyhow::Result<Rectangle> {
+    let content = content_area(display);
+    let available = Size::new(
+        (content.top_left.x + content.size.width as i32 - x).max(0) as u32,
+        (content.top_left.y + content.size.height as i32 - y).max(0) as u32,
+    );
+    let bmp = decode_bitmap(bytes, available)?;
+    let offset = Point::new(x, y);
+    // The pixels come row after row, bottom-up for most BMP files, which diffuses the error upwards just as well
+    let mut ditherer = Ditherer::new(IMAGE_DITHERING, bmp.bounding_box().size.width as usize);
+    let _ = display.draw_iter(bmp.pixels().map(|Pixel(point, color)| {
+        let luma =
+            (color.r() as u32 * 299 + color.g() as u32 * 587 + color.b() as u32 * 114) / 1000;
+        let color = match ditherer.dither(luma as u8) {
+            BinaryColor::On => PanelColor::Black,
+            BinaryColor::Off => PanelColor::White,
+        };
+        Pixel(point + offset, color)
+    }));
+    Ok(Rectangle::new(offset, bmp.bounding_box().size))
+}
+
+/// How the pending changes of the frame should be pushed to the panel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Refresh {
+    None,
+    Partial(Rectangle),
+    Full,
+}
+
+/// Tracks the area of the frame changed since the last refresh, to choose between a partial and a full refresh
+pub struct RefreshTracker {
+    dirty: Option<Rectangle>,
+    full: bool,
+    partial_refreshes: u32,
+}
+
+impl RefreshTracker {
+    pub fn new() -> Self {
+        // What's on the panel at boot is unknown, so the first refresh is always a full one
+        RefreshTracker {
+            dirty: None,
+            full: true,
+            partial_refreshes: 0,
+        }
+    }
+
+    /// Marks an area of the frame as changed
+    pub fn mark(&mut self, area: Rectangle) {
+        if area.is_zero_sized() {
+            return;
+        }
+        self.dirty = Some(match self.dirty {
+            Some(dirty) => rectangle_union(dirty, area),
+            None => area,
+        });
+    }
+
+    /// Forces the next refresh to be a full one
+    pub fn mark_all(&mut self) {
+        self.full = true;
+    }
+
+    /// Decides how the pending changes should be pushed to the panel, and resets the tracked area
+    pub fn take(&mut self) -> Refresh {
+        match (mem::take(&mut self.full), self.dirty.take()) {
+            (false, None) => Refresh::None,
+            (false, Some(area))
+                if PanelDisplay::PARTIAL_REFRESH
+                    && self.partial_refreshes < PARTIAL_REFRESHES_BEFORE_FULL =>
+            {
+                self.partial_refreshes += 1;
+                Refresh::Partial(area)
+            }
+            _ => {
+                self.partial_refreshes = 0;
+                Refresh::Full
+            }
+        }
+    }
+}
+
+impl Default for RefreshTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counts the panel updates and the time since the panel was last cleared, to clear it periodically from ghosting.
+/// Also keeps when the panel was last refreshed, for MIN_REFRESH_INTERVAL.
+pub struct GhostingTracker {
+    updates: u32,
+    last_clear: Instant,
+    last_refresh: Option<Instant>,
+}
+
+impl GhostingTracker {
+    pub fn new() -> Self {
+        GhostingTracker {
+            updates: 0,
+            last_clear: Instant::now(),
+            last_refresh: None,
+        }
+    }
+
+    /// When the last refresh of the panel ended, None before the first one
+    pub fn last_refresh(&self) -> Option<Instant> {
+        self.last_refresh
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.updates >= ANTI_GHOSTING_UPDATES || self.last_clear.elapsed() >= ANTI_GHOSTING_INTERVAL
+    }
+
+    pub fn updated(&mut self) {
+        self.updates += 1;
+    }
+
+    /// Counts a clear of the panel, unless the dry run skipped it
+    pub fn cleared(&mut self) {
+        if dry_run() {
+            return;
+        }
+        self.updates = 0;
+        self.last_clear = Instant::now();
+        self.last_refresh = Some(self.last_clear);
+    }
+}
+
+impl Default for GhostingTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Smallest rectangle containing both `a` and `b`
+pub fn rectangle_union(a: Rectangle, b: Rectangle) -> Rectangle {
+    let top_left = a.top_left.component_min(b.top_left);
+    let bottom_right = (a.top_left + a.size).component_max(b.top_left + b.size);
+    Rectangle::new(
+        top_left,
+        Size::new(
+            (bottom_right.x - top_left.x) as u32,
+            (bottom_right.y - top_left.y) as u32,
+        ),
+    )
+}
+
+/// Copies a packed black/white framebuffer (1 bit per pixel, MSB first, rows of PANEL_WIDTH pixels) into the display
+/// and pushes it to the panel with a full refresh
+#[cfg(target_os = "espidf")]
+pub fn set_raw_frame(
+    epd: &mut EpdDriver,
+    device: &mut EpdSpi,
+    display: &mut PanelDisplay,
+    packed: &[u8],
+) -> anyhow::Result<()> {
+    if packed.len() != RAW_FRAME_LEN {
+        anyhow::bail!(
+            "the frame is {} bytes long, expected {} bytes",
+            packed.len(),
+            RAW_FRAME_LEN
+        );
+    }
+
+    // The display buffer isn't writable directly, so the pixels are drawn back in the unrotated orientation
+    let rotation = display.rotation();
+    display.set_rotation(DisplayRotation::Rotate0);
+    let row_bytes = (PANEL_WIDTH / 8) as usize;
+    let pixels = packed.iter().enumerate().flat_map(|(index, byte)| {
+        (0..8).map(move |bit| {
+            let x = (index % row_bytes * 8 + bit) as i32;
+            let y = (index / row_bytes) as i32;
+            let color = if byte & (0x80 >> bit) != 0 {
+                PanelColor::White
+            } else {
+                PanelColor::Black
+            };
+            Pixel(Point::new(x, y), color)
+        })
+    });
+    let _ = display.draw_iter(pixels);
+    display.set_rotation(rotation);
+
+    update_full(epd, device, display)?;
+    Ok(())
+}
+
+// Key of the rotation chosen at runtime in NVS_CONFIG_NAMESPACE, in degrees
+const NVS_ROTATION_KEY: &str = "rotation";
+
+/// The rotation of `degrees` clockwise, if it's one of 0, 90, 180 and 270
+pub fn rotation_from_degrees(degrees: u16) -> Option<DisplayRotation> {
+    match degrees {
+        0 => Some(DisplayRotation::Rotate0),
+        90 => Some(DisplayRotation::Rotate90),
+        180 => Some(DisplayRotation::Rotate180),
+        270 => Some(DisplayRotation::Rotate270),
+        _ => None,
+    }
+}
+
+pub fn rotation_degrees(rotation: DisplayRotation) -> u16 {
+    match rotation {
+        DisplayRotation::Rotate0 => 0,
+        DisplayRotation::Rotate90 => 90,
+        DisplayRotation::Rotate180 => 180,
+        DisplayRotation::Rotate270 => 270,
+    }
+}
+
+/// Loads the rotation chosen at runtime with "rotate=<degrees>", if any
+#[cfg(target_os = "espidf")]
+pub fn load_rotation(
+    partition: EspDefaultNvsPartition,
+) -> Result<Option<DisplayRotation>, EspError> {
+    let nvs = EspDefaultNvs::new(partition, NVS_CONFIG_NAMESPACE, true)?;
+    Ok(nvs
+        .get_u16(NVS_ROTATION_KEY)?
+        .and_then(rotation_from_degrees))
+}
+
+/// Keeps the rotation chosen at runtime across reboots
+#[cfg(target_os = "espidf")]
+pub fn save_rotation(
+    partition: EspDefaultNvsPartition,
+    rotation: DisplayRotation,
+) -> Result<(), EspError> {
+    let nvs = EspDefaultNvs::new(partition, NVS_CONFIG_NAMESPACE, true)?;
+    nvs.set_u16(NVS_ROTATION_KEY, rotation_degrees(rotation))
+}
+
+/// Maps an area in rotated (logical) drawing coordinates to the unrotated coordinates of a `width`x`height` panel
+fn panel_area(area: Rectangle, rotation: DisplayRotation, width: u32, height: u32) -> Rectangle {
+    let (x, y) = (area.top_left.x, area.top_left.y);
+    let (w, h) = (area.size.width as i32, area.size.height as i32);
+    let (width, height) = (width as i32, height as i32);
+    let swapped = Size::new(area.size.height, area.size.width);
+    let (top_left, size) = match rotation {
+        DisplayRotation::Rotate0 => (Point::new(x, y), area.size),
+        DisplayRotation::Rotate90 => (Point::new(width - y - h, x), swapped),
+        DisplayRotation::Rotate180 => (Point::new(width - x - w, height - y - h), area.size),
+        DisplayRotation::Rotate270 => (Point::new(y, height - x - w), swapped),
+    };
+    Rectangle::new(top_left.component_max(Point::zero()), size)
+}
+
+/// Pushes the pending changes of the frame to the panel. When due, the panel is cleared first,
+/// and the whole frame is then pushed again with a full refresh.
+/// A refresh cut short by the panel staying busy (see run_on_panel) is pushed again in full by the next one.
+/// During a dry run nothing is pushed, nor counted by `refresh` and `ghosting`: the changes drawn meanwhile stay pending
+/// for the first refresh once it's over.
+#[cfg(target_os = "espidf")]
+pub fn update_panel(
+    epd: &mut EpdDriver,
+    device: &mut EpdSpi,
+    display: &PanelDisplay,
+    refresh: &mut RefreshTracker,
+    ghosting: &mut GhostingTracker,
+) -> Result<(), SpiError> {
+    if dry_run() {
+        info!("Dry run, skipping the panel refresh");
+        return Ok(());
+    }
+    let timeouts = busy_timeouts();
+    if ghosting.is_due() {
+        info!("Clearing the panel from ghosting");
+        clear_panel(epd, device)?;
+        ghosting.cleared();
+        refresh.mark_all();
+    }
+    let pending = refresh.take();
+    if pending == Refresh::None {
+        return Ok(());
+    }
+    let changed = changed_area(display);
+    if changed == Some(None) {
+        info!("No pixel changed since the last refresh, skipping it");
+        return Ok(());
+    }
+    ghosting.updated();
+    match (pending, changed) {
+        // Whatever the area marked, only the changed pixels are refreshed
+        (Refresh::Partial(_), Some(Some(area))) => {
+            let covered = area.size.width as f32 * area.size.height as f32
+                / (PANEL_WIDTH * PANEL_HEIGHT) as f32;
+            match covered > FRAME_DIFF_FULL_RATIO {
+                true => update_full(epd, device, display)?,
+                false => update_region(
+                    epd,
+                    device,
+                    display,
+                    area.top_left.x as u32,
+                    area.top_left.y as u32,
+                    area.size.width,
+                    area.size.height,
+                )?,
+            }
+        }
+        (pending, _) => refresh_panel(epd, device, display, pending)?,
+    }
+    ghosting.last_refresh = Some(Instant::now());
+    if busy_timeouts() != timeouts {
+        refresh.mark_all();
+    }
+    Ok(())
+}
+
+/// Bounding box of the pixels which differ between two packed planes of `width` pixels per row (1 bit per pixel, MSB
+/// first, rows starting on a byte), None when they're identical. Planes of different lengths differ everywhere.
+pub fn frame_diff(previous: &[u8], current: &[u8], width: u32) -> Option<Rectangle> {
+    let row_bytes = ((width + 7) / 8) as usize;
+    if row_bytes == 0 || current.is_empty() {
+        return None;
+    }
+    if previous.len() != current.len() {
+        let rows = (current.len() + row_bytes - 1) / row_bytes;
+        return Some(Rectangle::new(Point::zero(), Size::new(width, rows as u32)));
+    }
+    // Inclusive pixel bounds of the changes
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+    for (index, (before, after)) in previous.iter().zip(current).enumerate() {
+        let changed = before ^ after;
+        if changed == 0 {
+            continue;
+        }
+        let y = (index / row_bytes) as u32;
+        let byte_x = (index % row_bytes) as u32 * 8;
+        let first = byte_x + changed.leading_zeros();
+        // The padding bits past the width aren't pixels
+        let last = (byte_x + 7 - changed.trailing_zeros()).min(width - 1);
+        if first > last {
+            continue;
+        }
+        bounds = Some(match bounds {
+            Some((left, top, right, _)) => (left.min(first), top, right.max(last), y),
+            None => (first, y, last, y),
+        });
+    }
+    bounds.map(|(left, top, right, bottom)| {
+        Rectangle::with_corners(
+            Point::new(left as i32, top as i32),
+            Point::new(right as i32, bottom as i32),
+        )
+    })
+}
+
+/// Bounding box of the pixels of `display` which differ from the frame on the panel, in unrotated panel coordinates:
+/// Some(None) when none does, and None when the frame on the panel is unknown (or FRAME_DIFF is off)
+fn changed_area(display: &PanelDisplay) -> Option<Option<Rectangle>> {
+    let sent = SENT_FRAME.lock().unwrap_or_else(PoisonError::into_inner);
+    let (bw, chromatic) = sent.as_ref()?;
+    let changes = [
+        frame_diff(bw, display.bw_plane(), PANEL_WIDTH),
+        frame_diff(
+            chromatic,
+            display.chromatic_plane().unwrap_or_default(),
+            PANEL_WIDTH,
+        ),
+    ];
+    Some(changes.into_iter().flatten().reduce(rectangle_union))
+}
+
+/// Draws the boot self-test on the panel, one full refresh each: a black fill, a white fill, and then the borders of the
+/// display with "EPD OK <resolution> baud=<SPI clock>" and the refreshes of the panel so far (see refresh_stats).
+/// The refresh times are logged. The frame is left blank.
+#[cfg(target_os = "espidf")]
+pub fn draw_self_test(
+    display: &mut PanelDisplay,
+    epd: &mut EpdDriver,
+    device: &mut EpdSpi,
+) -> Result<(), SpiError> {
+    info!("Running the panel self-test...");
+    for (step, color) in [("black", PanelColor::Black), ("white", PanelColor::White)] {
+        let started = Instant::now();
+        let _ = display.clear(color);
+        update_full(epd, device, display)?;
+        info!(
+            "Self-test {} fill refreshed in {}ms",
+            step,
+            started.elapsed().as_millis()
+        );
+    }
+
+    let started = Instant::now();
+    let bounds = display.bounding_box();
+    let _ = bounds
+        .into_styled(PrimitiveStyle::with_stroke(PanelColor::Black, 4))
+        .draw(display);
+    let style = text_style(&FONT_10X20, TextColor::Black, false);
+    let text_style = TextStyleBuilder::new()
+        .baseline(Baseline::Middle)
+        .alignment(Alignment::Center)
+        .build();
+    let _ = Text::with_text_style(
+        &format!(
+            "EPD OK {}x{} baud={}",
+            PANEL_WIDTH, PANEL_HEIGHT, BOARD.spi_baudrate
+        ),
+        bounds.center(),
+        style,
+        text_style,
+    )
+    .draw(display);
+    let _ = Text::with_text_style(
+        &format!("refreshes={}", refresh_stats().total()),
+        bounds.center() + Point::new(0, 30),
+        style,
+        text_style,
+    )
+    .draw(display);
+    update_full(epd, device, display)?;
+    info!(
+        "Self-test pattern refreshed in {}ms",
+        started.elapsed().as_millis()
+    );
+
+    // The pattern stays on the panel until the first message replaces it
+    let _ = display.clear(PanelColor::White);
+    Ok(())
+}
+
+/// Draws the splash shown until the first message with a full refresh: `name`, the firmware version and SPLASH_TEXT,
+/// centered in the content area. The frame is left showing it.
+#[cfg(target_os = "espidf")]
+pub fn draw_splash(
+    display: &mut PanelDisplay,
+    epd: &mut EpdDriver,
+    device: &mut EpdSpi,
+    name: &str,
+) -> Result<(), SpiError> {
+    clear_content(display);
+    let center = content_area(display).center();
+    let centered = TextStyleBuilder::new()
+        .baseline(Baseline::Middle)
+        .alignment(Alignment::Center)
+        .build();
+    let version = format!("Firmware {}", FIRMWARE_VERSION);
+    for (text, font, offset) in [
+        (name, &FONT_10X20, -30),
+        (version.as_str(), &FONT_9X15, 0),
+        (SPLASH_TEXT, &FONT_9X15, 30),
+    ] {
+        let _ = Text::with_text_style(
+            text,
+            center + Point::new(0, offset),
+            text_style(font, TextColor::Black, false),
+            centered,
+        )
+        .draw(display);
+    }
+    update_full(epd, device, display)
+}
+
+// Whether the messages are only drawn into the frame, the panel being left as it is, see set_dry_run
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/// Turns the dry run on or off: while it's on, the messages are still parsed and drawn into the frame, but the refreshes
+/// are skipped and logged instead, to check what publishers send without wearing the panel. It's off after a reboot.
+pub fn set_dry_run(enabled: bool) {
+    DRY_RUN.store(enabled, Ordering::Relaxed);
+    match enabled {
+        true => warn!("Dry run on, the panel won't be refreshed"),
+        false => info!("Dry run off"),
+    }
+}
+
+pub fn dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+/// Clears the whole panel to white with a full refresh, leaving the frame untouched
+#[cfg(target_os = "espidf")]
+pub fn clear_panel(epd: &mut EpdDriver, device: &mut EpdSpi) -> Result<(), SpiError> {
+    if dry_run() {
+        info!("Dry run, skipping the panel clear");
+        return Ok(());
+    }
+    save_last_frame(None);
+    save_sent_frame(None);
+    let result = run_on_panel(epd, device, |epd, device| {
+        epd.clear_frame(device)?;
+        epd.display_frame(device)
+    });
+    if result.is_ok() {
+        count_refresh(true);
+    }
+    result.map(drop)
+}
+
+/// Runs the reset and init sequence of the panel again, e.g. to recover it from a glitch, and clears it with a full
+/// refresh, leaving the frame untouched
+#[cfg(target_os = "espidf")]
+pub fn reinit_panel(epd: &mut EpdDriver, device: &mut EpdSpi) -> Result<(), SpiError> {
+    info!("Initializing the panel again");
+    epd.reset(device)?;
+    clear_panel(epd, device)
+}
+
+/// Runs `operation` on the panel, which is reset when its BUSY pin got stuck meanwhile: the drivers then carried on
+/// once PANEL_BUSY_TIMEOUT passed, so the panel content is unknown. Returns whether the operation completed normally.
+#[cfg(target_os = "espidf")]
+fn run_on_panel(
+    epd: &mut EpdDriver,
+    device: &mut EpdSpi,
+    operation: impl FnOnce(&mut EpdDriver, &mut EpdSpi) -> Result<(), SpiError>,
+) -> Result<bool, SpiError> {
+    let timeouts = busy_timeouts();
+    operation(epd, device)?;
+    if busy_timeouts() == timeouts {
+        return Ok(true);
+    }
+    error!(
+        "The panel stayed busy for more than {}s, aborting the refresh and resetting the panel",
+        PANEL_BUSY_TIMEOUT.as_secs()
+    );
+    // The stuck wait didn't feed the watchdog, the reset gets a whole period
+    feed_watchdog();
+    if let Err(e) = epd.reset(device) {
+        error!("Couldn't reset the panel: {:?}", e);
+    }
+    Ok(false)
+}
+
+/// Pushes the pending changes of the frame to the panel, as decided by the refresh tracker
+#[cfg(target_os = "espidf")]
+fn refresh_panel(
+    epd: &mut EpdDriver,
+    device: &mut EpdSpi,
+    display: &PanelDisplay,
+    refresh: Refresh,
+) -> Result<(), SpiError> {
+    match refresh {
+        Refresh::Full => update_full(epd, device, display),
+        Refresh::Partial(area) => {
+            let area = panel_area(area, display.rotation(), PANEL_WIDTH, PANEL_HEIGHT);
+            update_region(
+                epd,
+                device,
+                display,
+                area.top_left.x as u32,
+                area.top_left.y as u32,
+                area.size.width,
+                area.size.height,
+            )
+        }
+        Refresh::None => Ok(()),
+    }
+}
+
+/// Pushes the whole frame to the panel and refreshes it.
+/// With DEDUPLICATE_MESSAGES, the first refresh after a reboot or a wake is skipped when the panel already shows the
+/// same content, the status bar is updated by its next redraw.
+#[cfg(target_os = "espidf")]
+pub fn update_full(
+    epd: &mut EpdDriver,
+    device: &mut EpdSpi,
+    display: &PanelDisplay,
+) -> Result<(), SpiError> {
+    let hash = content_hash(display);
+    if dry_run() {
+        info!(
+            "Dry run, skipping the full refresh of the content {:08x}",
+            hash
+        );
+        return Ok(());
+    }
+    if DEDUPLICATE_MESSAGES && boot_frame() == Some(hash) {
+        info!("The panel already shows the content, skipping the refresh");
+        save_last_frame(Some(hash));
+        // Its status bar may differ from the frame
+        save_sent_frame(None);
+        return Ok(());
+    }
+    let result = run_on_panel(epd, device, |epd, device| {
+        epd.update_frame(device, display)?;
+        epd.display_frame(device)
+    });
+    // An aborted refresh still wore the panel
+    if result.is_ok() {
+        count_refresh(true);
+    }
+    // A failed or aborted refresh leaves the panel showing anything between the two frames
+    let shown = matches!(result, Ok(true));
+    save_last_frame(shown.then_some(hash));
+    save_sent_frame(shown.then_some(display));
+    result.map(drop)
+}
+
+/// Pushes the (x, y, w, h) area of the frame to the panel and refreshes only that area.
+/// The area is widened horizontally to byte boundaries, since each byte of the buffer holds 8 pixels.
+/// A partial refresh only draws black and white: when the area has red on the panel or in the frame, or the panel
+/// content is unknown, the whole frame is refreshed instead.
+#[cfg(target_os = "espidf")]
+pub fn update_region(
+    epd: &mut EpdDriver,
+    device: &mut EpdSpi,
+    display: &PanelDisplay,
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+) -> Result<(), SpiError> {
+    let x_start = x / 8 * 8;
+    let x_end = ((x + w + 7) / 8 * 8).min(PANEL_WIDTH);
+    let y_end = (y + h).min(PANEL_HEIGHT);
+    if x_start >= x_end || y >= y_end {
+        return Ok(());
+    }
+    if dry_run() {
+        info!(
+            "Dry run, skipping the partial refresh of {}x{} at ({}, {})",
+            x_end - x_start,
+            y_end - y,
+            x_start,
+            y
+        );
+        return Ok(());
+    }
+    if let Some(chromatic) = display.chromatic_plane() {
+        let (x, w, h) = (x_start, x_end - x_start, y_end - y);
+        let sent = SENT_FRAME.lock().unwrap_or_else(PoisonError::into_inner);
+        let red = sent.as_ref().map_or(true, |(_, sent)| {
+            plane_has_pixels(sent, x, y, w, h) || plane_has_pixels(chromatic, x, y, w, h)
+        });
+        drop(sent);
+        if red {
+            info!(
+                "Red in the {}x{} area at ({}, {}), refreshing the whole panel",
+                w, h, x, y
+            );
+            return update_full(epd, device, display);
+        }
+    }
+
+    let buffer = display.bw_plane();
+    let row_bytes = (PANEL_WIDTH / 8) as usize;
+    let region_bytes = ((x_end - x_start) / 8) as usize;
+    let mut region = Vec::with_capacity(region_bytes * (y_end - y) as usize);
+    for row in y..y_end {
+        let start = row as usize * row_bytes + (x_start / 8) as usize;
+        region.extend_from_slice(&buffer[start..start + region_bytes]);
+    }
+
+    let result = run_on_panel(epd, device, |epd, device| {
+        epd.update_partial_frame(device, &region, x_start, y, x_end - x_start, y_end - y)
+    });
+    if result.is_ok() {
+        count_refresh(false);
+    }
+    let shown = matches!(result, Ok(true));
+    // The rest of the panel is only known if it was before
+    let known = shown && last_frame().is_some();
+    save_last_frame(known.then(|| content_hash(display)));
+    let mut sent = SENT_FRAME.lock().unwrap_or_else(PoisonError::into_inner);
+    match (shown, sent.as_mut()) {
+        (true, Some((bw, chromatic))) => {
+            let planes = [
+                (bw, buffer),
+                (chromatic, display.chromatic_plane().unwrap_or_default()),
+            ];
+            for (sent, plane) in planes.into_iter().filter(|(sent, _)| !sent.is_empty()) {
+                for row in y..y_end {
+                    let start = row as usize * row_bytes + (x_start / 8) as usize;
+                    sent[start..start + region_bytes]
+                        .copy_from_slice(&plane[start..start + region_bytes]);
+                }
+            }
+        }
+        _ => *sent = None,
+    }
+    result.map(drop)
+}
+
+/// Whether any pixel of the (x, y, w, h) area of a packed plane of the panel is set, with x and w multiples of 8.
+/// Set pixels are red in the chromatic plane.
+fn plane_has_pixels(plane: &[u8], x: u32, y: u32, w: u32, h: u32) -> bool {
+    let row_bytes = ((PANEL_WIDTH + 7) / 8) as usize;
+    let (start, len) = ((x / 8) as usize, (w / 8) as usize);
+    (y..y + h).any(|row| {
+        let row = row as usize * row_bytes + start;
+        plane
+            .get(row..row + len)
+            .map_or(false, |bytes| bytes.iter().any(|&byte| byte != 0))
+    })
+}
+
+// Black/white and chromatic (empty without one) planes of the frame on the panel, kept by update_full, update_region
+// and clear_panel along with LAST_FRAME. None when the panel content is unknown, or without FRAME_DIFF.
+static SENT_FRAME: Mutex<Option<(Vec<u8>, Vec<u8>)>> = Mutex::new(None);
+
+/// Records the frame pushed to the panel, or None when the panel content is unknown
+fn save_sent_frame(display: Option<&PanelDisplay>) {
+    let mut sent = SENT_FRAME.lock().unwrap_or_else(PoisonError::into_inner);
+    match display.filter(|_| FRAME_DIFF) {
+        Some(display) => {
+            // The buffers are reused from one refresh to the next
+            let (bw, chromatic) = sent.get_or_insert_with(Default::default);
+            bw.clear();
+            bw.extend_from_slice(display.bw_plane());
+            chromatic.clear();
+            chromatic.extend_from_slice(display.chromatic_plane().unwrap_or_default());
+        }
+        None => *sent = None,
+    }
+}
+
+/// Hash of the message area on the panel, and the NVS namespace keeping it across reboots and deep sleep.
+/// The frame itself doesn't fit in RTC memory (8KB) nor in the NVS partition (24KB), at 38880 bytes
+/// for the 5.83" panel, so only its hash is kept.
+#[cfg(target_os = "espidf")]
+struct LastFrame {
+    /// None when the panel content is unknown
+    hash: Option<u32>,
+    /// Hash loaded from NVS, until the first panel update
+    at_boot: Option<u32>,
+    nvs: Option<EspDefaultNvs>,
+}
+
+// Kept by update_full, update_region and clear_panel, that all the panel updates go through
+#[cfg(target_os = "espidf")]
+static LAST_FRAME: Mutex<LastFrame> = Mutex::new(LastFrame {
+    hash: None,
+    at_boot: None,
+    nvs: None,
+});
+// Key of the hash in NVS_FRAME_NAMESPACE
+const NVS_FRAME_KEY: &str = "hash";
+
+/// FNV-1a hash of all the planes of the frame, leaving out the status bar since it changes all the time
+fn content_hash(display: &PanelDisplay) -> u32 {
+    let status_bar = Rectangle::new(
+        Point::new(0, status_bar_top(display)),
+        Size::new(display.bounding_box().size.width, STATUS_BAR_HEIGHT),
+    );
+    let status_bar = panel_area(status_bar, display.rotation(), PANEL_WIDTH, PANEL_HEIGHT);
+    let row_bytes = (PANEL_WIDTH / 8) as usize;
+    let rows = status_bar.rows();
+    let columns = status_bar.columns();
+    let in_status_bar = |index: usize| {
+        let (x, y) = ((index % row_bytes * 8) as i32, (index / row_bytes) as i32);
+        rows.contains(&y) && (x..x + 8).any(|x| columns.contains(&x))
+    };
+    let chromatic = display.chromatic_plane().unwrap_or_default();
+    let planes = [display.bw_plane(), chromatic];
+    fnv1a_hash(planes.into_iter().flat_map(|plane| {
+        plane
+            .iter()
+            .enumerate()
+            .filter(move |(index, _)| !in_status_bar(*index))
+            .map(|(_, byte)| byte)
+    }))
+}
+
+/// Hash of the content on the panel, None when it's unknown
+#[cfg(target_os = "espidf")]
+pub fn last_frame() -> Option<u32> {
+    // The panic hook updates the panel too
+    LAST_FRAME
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .hash
+}
+
+#[cfg(target_os = "espidf")]
+fn boot_frame() -> Option<u32> {
+    LAST_FRAME
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .at_boot
+}
+
+/// Loads the hash of the message area left on the panel before the reboot or the deep sleep, skipping the first
+/// refresh if it shows the same content. From then on, the hash is kept up to date in NVS.
+/// Unless `trusted`, e.g. after a crash that could have interrupted a refresh, the panel content is taken as unknown.
+#[cfg(target_os = "espidf")]
+pub fn load_last_frame(partition: EspDefaultNvsPartition, trusted: bool) -> Result<(), EspError> {
+    let nvs = EspDefaultNvs::new(partition, NVS_FRAME_NAMESPACE, true)?;
+    let hash = match trusted {
+        true => nvs.get_u32(NVS_FRAME_KEY)?,
+        false => None,
+    };
+    if let Some(hash) = hash {
+        info!("The panel shows the content {:08x}", hash);
+    }
+    *LAST_FRAME.lock().unwrap_or_else(PoisonError::into_inner) = LastFrame {
+        hash,
+        at_boot: hash,
+        nvs: Some(nvs),
+    };
+    if !trusted {
+        save_last_frame(None);
+    }
+    Ok(())
+}
+
+/// Records the hash of the message area on the panel, or None when it's unknown
+#[cfg(target_os = "espidf")]
+fn save_last_frame(hash: Option<u32>) {
+    let mut last = LAST_FRAME.lock().unwrap_or_else(PoisonError::into_inner);
+    last.hash = hash;
+    last.at_boot = None;
+    let Some(nvs) = last.nvs.as_mut() else {
+        return;
+    };
+    // Reading it back avoids wearing the flash with identical writes
+    if nvs.get_u32(NVS_FRAME_KEY).ok().flatten() == hash {
+        return;
+    }
+    let result = match hash {
+        Some(hash) => nvs.set_u32(NVS_FRAME_KEY, hash),
+        None => nvs.remove(NVS_FRAME_KEY).map(|_| ()),
+    };
+    if let Err(e) = result {
+        warn!("Couldn't save the hash of the panel content: {}", e);
+    }
+}
+
+// Keys of the NVS_FRAME_NAMESPACE namespace counting the full and partial refreshes of the panel since the first boot
+const NVS_FULL_REFRESHES_KEY: &str = "full_refreshes";
+const NVS_PARTIAL_REFRESHES_KEY: &str = "part_refreshes";
+
+/// Refreshes of the panel since the first boot, to track its wear: e-paper panels are rated for a limited number of them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RefreshStats {
+    pub full: u32,
+    pub partial: u32,
+}
+
+impl RefreshStats {
+    pub fn total(&self) -> u32 {
+        self.full.saturating_add(self.partial)
+    }
+
+    /// e.g. {"full":420,"partial":3150,"total":3570}
+    pub fn to_json(&self) -> String {
+        serde_json::json!({
+            "full": self.full,
+            "partial": self.partial,
+            "total": self.total(),
+        })
+        .to_string()
+    }
+}
+
+#[cfg(target_os = "espidf")]
+struct RefreshCounter {
+    stats: RefreshStats,
+    /// Refreshes counted since the counters were last saved
+    unsaved: u32,
+    nvs: Option<EspDefaultNvs>,
+}
+
+#[cfg(target_os = "espidf")]
+impl RefreshCounter {
+    /// Saves the counters to NVS, if any refresh is left to save and load_refresh_stats opened it
+    fn save(&mut self) {
+        let Some(nvs) = self.nvs.as_mut().filter(|_| self.unsaved > 0) else {
+            return;
+        };
+        let result = nvs
+            .set_u32(NVS_FULL_REFRESHES_KEY, self.stats.full)
+            .and_then(|_| nvs.set_u32(NVS_PARTIAL_REFRESHES_KEY, self.stats.partial));
+        match result {
+            Ok(()) => self.unsaved = 0,
+            Err(e) => warn!("Couldn't save the refresh counters to NVS: {}", e),
+        }
+    }
+}
+
+// Refreshes counted by update_full, update_region and clear_panel, and the NVS namespace keeping them once
+// load_refresh_stats opened it
+#[cfg(target_os = "espidf")]
+static REFRESH_COUNTER: Mutex<RefreshCounter> = Mutex::new(RefreshCounter {
+    stats: RefreshStats {
+        full: 0,
+        partial: 0,
+    },
+    unsaved: 0,
+    nvs: None,
+});
+
+/// Loads the refresh counters kept in NVS, which each refresh of the panel increments from then on.
+/// The refreshes done before, e.g. showing a startup error, are added to them.
+#[cfg(target_os = "espidf")]
+pub fn load_refresh_stats(partition: EspDefaultNvsPartition) -> Result<RefreshStats, EspError> {
+    let nvs = EspDefaultNvs::new(partition, NVS_FRAME_NAMESPACE, true)?;
+    let full = nvs.get_u32(NVS_FULL_REFRESHES_KEY)?.unwrap_or(0);
+    let partial = nvs.get_u32(NVS_PARTIAL_REFRESHES_KEY)?.unwrap_or(0);
+    let mut counter = REFRESH_COUNTER
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let before = counter.stats;
+    counter.stats = RefreshStats {
+        full: full.wrapping_add(before.full),
+        partial: partial.wrapping_add(before.partial),
+    };
+    if before != RefreshStats::default() {
+        nvs.set_u32(NVS_FULL_REFRESHES_KEY, counter.stats.full)?;
+        nvs.set_u32(NVS_PARTIAL_REFRESHES_KEY, counter.stats.partial)?;
+        counter.unsaved = 0;
+    }
+    counter.nvs = Some(nvs);
+    info!(
+        "The panel went through {} full and {} partial refreshes",
+        counter.stats.full, counter.stats.partial
+    );
+    Ok(counter.stats)
+}
+
+/// Counts a `full` or partial refresh of the panel in RAM, saving the counters to NVS every
+/// REFRESH_STATS_SAVE_INTERVAL refreshes once load_refresh_stats opened it
+#[cfg(target_os = "espidf")]
+fn count_refresh(full: bool) {
+    let mut counter = REFRESH_COUNTER
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    match full {
+        true => counter.stats.full = counter.stats.full.wrapping_add(1),
+        false => counter.stats.partial = counter.stats.partial.wrapping_add(1),
+    }
+    counter.unsaved += 1;
+    if counter.unsaved >= REFRESH_STATS_SAVE_INTERVAL {
+        counter.save();
+    }
+}
+
+/// Saves the refreshes counted since the last save to NVS, e.g. before a restart or deep sleep.
+/// Skipped while the counters are being updated, as they are by a thread panicking in the middle of it.
+#[cfg(target_os = "espidf")]
+pub fn save_refresh_stats() {
+    match REFRESH_COUNTER.try_lock() {
+        Ok(mut counter) => counter.save(),
+        Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner().save(),
+        Err(TryLockError::WouldBlock) => {
+            warn!("Couldn't save the refresh counters, they're in use")
+        }
+    }
+}
+
+/// Refreshes of the panel counted so far, since the first boot once load_refresh_stats loaded the counters
+#[cfg(target_os = "espidf")]
+pub fn refresh_stats() -> RefreshStats {
+    REFRESH_COUNTER
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_graphics::prelude::OriginDimensions;
+    use std::convert::Infallible;
+
+    /// Frame of pixels checked by the tests, white when created
+    struct Framebuffer {
+        size: Size,
+        pixels: Vec<PanelColor>,
+    }
+
+    impl Framebuffer {
+        fn new(width: u32, height: u32) -> Self {
+            Framebuffer {
+                size: Size::new(width, height),
+                pixels: vec![PanelColor::White; (width * height) as usize],
+            }
+        }
+
+        fn pixel(&self, x: i32, y: i32) -> PanelColor {
+            self.pixels[y as usize * self.size.width as usize + x as usize]
+        }
+
+        /// Smallest rectangle holding all the black pixels, zero sized when there are none
+        fn black_area(&self) -> Rectangle {
+            let mut corners: Option<(Point, Point)> = None;
+            for y in 0..self.size.height as i32 {
+                for x in 0..self.size.width as i32 {
+                    if self.pixel(x, y) == PanelColor::Black {
+                        let point = Point::new(x, y);
+                        corners = Some(corners.map_or((point, point), |(min, max)| {
+                            (min.component_min(point), max.component_max(point))
+                        }));
+                    }
+                }
+            }
+            corners.map_or(Rectangle::zero(), |(min, max)| {
+                Rectangle::with_corners(min, max)
+            })
+        }
+    }
+
+    impl DrawTarget for Framebuffer {
+        type Color = PanelColor;
+        type Error = Infallible;
+
+        fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Infallible>
+        where
+            I: IntoIterator<Item = Pixel<PanelColor>>,
+        {
+            let bounds = self.bounding_box();
+            for Pixel(point, color) in pixels {
+                if bounds.contains(point) {
+                    let index = point.y as usize * self.size.width as usize + point.x as usize;
+                    self.pixels[index] = color;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl OriginDimensions for Framebuffer {
+        fn size(&self) -> Size {
+            self.size
+        }
+    }
+
+    /// Width of a character of the default font, spacing included, and the distance between two of its lines
+    fn medium_metrics() -> (u32, u32) {
+        let font = FontSize::Medium.font();
+        (
+            font.character_size.width + font.character_spacing,
+            font.character_size.height + TEXT_LAYOUT.line_spacing,
+        )
+    }
+
+    #[test]
+    fn wraps_on_word_boundaries() {
+        assert_eq!(
+            wrap_text("the quick brown fox", 10, 5),
+            ["the quick", "brown fox"]
+        );
+    }
+
+    #[test]
+    fn wrap_keeps_line_breaks_and_blank_lines() {
+        assert_eq!(wrap_text("one\r\n\ntwo", 10, 5), ["one", "", "two"]);
+    }
+
+    #[test]
+    fn wrap_hard_breaks_long_words() {
+        assert_eq!(wrap_text("abcdefghijklm", 5, 5), ["abcde", "fghij", "klm"]);
+    }
+
+    #[test]
+    fn wrap_stops_at_max_lines() {
+        assert_eq!(wrap_text("a b c d e f", 1, 3), ["a", "b", "c"]);
+        assert_eq!(wrap_text("abcdefghijklm", 5, 2), ["abcde", "fghij"]);
+        assert_eq!(wrap_text("one\ntwo\nthree", 10, 2), ["one", "two"]);
+    }
+
+    #[test]
+    fn ellipsis_fits_in_the_line() {
+        assert_eq!(with_ellipsis("hello world", 8), "hello...");
+        // The space it would follow is dropped
+        assert_eq!(with_ellipsis("hello world", 9), "hello...");
+        assert_eq!(with_ellipsis("hello", 20), "hello...");
+    }
+
+    #[test]
+    fn draw_text_wraps_into_the_column() {
+        let (char_width, line_height) = medium_metrics();
+        let mut display = Framebuffer::new(char_width * 20, 200);
+        let lines = draw_text(
+            &mut display,
+            "the quick brown fox jumps",
+            0,
+            0,
+            char_width * 10,
+            200,
+            TextOptions::default(),
+        );
+        assert_eq!(lines.lines, ["the quick", "brown fox", "jumps"]);
+        assert_eq!(lines.drawn(), 3);
+        assert!(!lines.truncated);
+        assert_eq!(lines.height, 3 * line_height - TEXT_LAYOUT.line_spacing);
+        // White on black by default: the background of the glyphs covers the lines drawn, and nothing else
+        let black = display.black_area();
+        assert_eq!(black.top_left, Point::zero());
+        assert_eq!(black.size, Size::new(char_width * 9, lines.height));
+    }
+
+    #[test]
+    fn draw_text_truncates_with_an_ellipsis() {
+        let (char_width, line_height) = medium_metrics();
+        let mut display = Framebuffer::new(char_width * 10, 400);
+        let lines = draw_text(
+            &mut display,
+            "the quick brown fox jumps over the lazy dog",
+            0,
+            0,
+            char_width * 10,
+            2 * line_height,
+            TextOptions::default(),
+        );
+        assert_eq!(lines.lines, ["the quick", "brown f..."]);
+        assert!(lines.truncated);
+        assert!(display.black_area().size.height <= 2 * line_height);
+    }
+
+    #[test]
+    fn draw_text_stops_above_the_status_bar() {
+        let (char_width, line_height) = medium_metrics();
+        let height = STATUS_BAR_HEIGHT + 2 * line_height;
+        let mut display = Framebuffer::new(char_width * 10, height);
+        let lines = draw_text(
+            &mut display,
+            "a b c d e",
+            0,
+            0,
+            char_width * 4,
+            height,
+            TextOptions::default(),
+        );
+        assert_eq!(lines.lines, ["a b", "c..."]);
+        assert!(lines.truncated);
+        assert!(
+            display.black_area().bottom_right().unwrap().y < (height - STATUS_BAR_HEIGHT) as i32
+        );
+    }
+
+    #[test]
+    fn draw_text_aligns_each_line() {
+        let (char_width, _) = medium_metrics();
+        let width = char_width * 10;
+        for (alignment, left) in [
+            (HorizontalAlignment::Left, 0),
+            (HorizontalAlignment::Center, char_width * 4),
+            (HorizontalAlignment::Right, char_width * 8),
+        ] {
+            let mut display = Framebuffer::new(width, 100);
+            let options = TextOptions {
+                alignment,
+                ..TextOptions::default()
+            };
+            draw_text(&mut display, "ab", 0, 0, width, 100, options);
+            let black = display.black_area();
+            assert_eq!(black.top_left.x, left as i32, "{:?}", alignment);
+            assert_eq!(black.size.width, char_width * 2, "{:?}", alignment);
+        }
+    }
+
+    #[test]
+    fn draw_text_off_the_display_draws_nothing() {
+        let mut display = Framebuffer::new(100, 100);
+        let lines = draw_text(
+            &mut display,
+            "hidden",
+            200,
+            0,
+            100,
+            100,
+            TextOptions::default(),
+        );
+        assert_eq!(lines.drawn(), 0);
+        assert!(lines.truncated);
+        assert!(display.black_area().is_zero_sized());
+    }
+
+    #[test]
+    fn content_area_leaves_out_the_status_bar() {
+        let display = Framebuffer::new(200, 100);
+        assert_eq!(
+            content_area(&display),
+            Rectangle::new(Point::zero(), Size::new(200, 100 - STATUS_BAR_HEIGHT))
+        );
+        let display = Framebuffer::new(200, STATUS_BAR_HEIGHT / 2);
+        assert!(content_area(&display).is_zero_sized());
+    }
+
+    fn bounds() -> Rectangle {
+        Rectangle::new(Point::zero(), Size::new(100, 50))
+    }
+
+    #[test]
+    fn clamp_point_stays_in_the_bounds() {
+        assert_eq!(
+            clamp_point(Point::new(10, 20), bounds()),
+            Point::new(10, 20)
+        );
+        assert_eq!(clamp_point(Point::new(-5, 20), bounds()), Point::new(0, 20));
+        assert_eq!(clamp_point(Point::new(-5, -5), bounds()), Point::zero());
+        assert_eq!(
+            clamp_point(Point::new(200, 60), bounds()),
+            Point::new(99, 49)
+        );
+        assert_eq!(
+            clamp_point(Point::new(i32::MIN, i32::MAX), bounds()),
+            Point::new(0, 49)
+        );
+    }
+
+    #[test]
+    fn clamp_point_to_zero_sized_bounds() {
+        let empty = Rectangle::new(Point::new(10, 10), Size::zero());
+        assert_eq!(clamp_point(Point::new(50, -5), empty), Point::new(10, 10));
+    }
+
+    #[test]
+    fn clamp_rect_with_a_negative_origin() {
+        assert_eq!(
+            clamp_rect(
+                Rectangle::new(Point::new(-10, -10), Size::new(30, 20)),
+                bounds()
+            ),
+            Rectangle::new(Point::zero(), Size::new(20, 10))
+        );
+    }
+
+    #[test]
+    fn clamp_rect_larger_than_the_bounds() {
+        let larger = Rectangle::new(Point::new(-50, -50), Size::new(1000, 1000));
+        assert_eq!(clamp_rect(larger, bounds()), bounds());
+        let largest = Rectangle::new(Point::zero(), Size::new(u32::MAX, u32::MAX));
+        assert_eq!(clamp_rect(largest, bounds()), bounds());
+        let far = Rectangle::new(Point::new(i32::MAX, 0), Size::new(u32::MAX, 10));
+        assert!(clamp_rect(far, bounds()).is_zero_sized());
+    }
+
+    #[test]
+    fn clamp_rect_zero_sized() {
+        let empty = Rectangle::new(Point::new(10, 10), Size::zero());
+        assert_eq!(clamp_rect(empty, bounds()), empty);
+        let area = Rectangle::new(Point::new(10, 10), Size::new(20, 20));
+        let empty_bounds = Rectangle::new(Point::new(15, 15), Size::zero());
+        assert!(clamp_rect(area, empty_bounds).is_zero_sized());
+    }
+
+    #[test]
+    fn clamp_rect_outside_the_bounds() {
+        let outside = Rectangle::new(Point::new(200, 0), Size::new(10, 10));
+        assert!(clamp_rect(outside, bounds()).is_zero_sized());
+        let above = Rectangle::new(Point::new(0, -30), Size::new(10, 10));
+        assert!(clamp_rect(above, bounds()).is_zero_sized());
+    }
+
+    #[test]
+    fn clip_line_inside_the_bounds() {
+        let (start, end) = (Point::new(5, 5), Point::new(60, 40));
+        assert_eq!(clip_line(start, end, bounds()), Some((start, end)));
+        assert_eq!(clip_line(start, start, bounds()), Some((start, start)));
+    }
+
+    #[test]
+    fn clip_line_crossing_the_bounds() {
+        assert_eq!(
+            clip_line(Point::new(-10, 5), Point::new(200, 5), bounds()),
+            Some((Point::new(0, 5), Point::new(99, 5)))
+        );
+        let square = Rectangle::new(Point::zero(), Size::new(100, 100));
+        assert_eq!(
+            clip_line(Point::new(-10, -10), Point::new(110, 110), square),
+            Some((Point::zero(), Point::new(99, 99)))
+        );
+        assert_eq!(
+            clip_line(Point::new(i32::MIN, 0), Point::new(i32::MAX, 0), bounds()),
+            Some((Point::zero(), Point::new(99, 0)))
+        );
+    }
+
+    #[test]
+    fn clip_line_outside_the_bounds() {
+        assert_eq!(
+            clip_line(Point::new(-10, -10), Point::new(-1, 60), bounds()),
+            None
+        );
+        assert_eq!(
+            clip_line(Point::new(0, 60), Point::new(99, 60), bounds()),
+            None
+        );
+        let empty = Rectangle::new(Point::new(10, 10), Size::zero());
+        assert_eq!(
+            clip_line(Point::new(0, 10), Point::new(20, 10), empty),
+            None
+        );
+    }
+
+    #[test]
+    fn draw_qr_clamps_the_scale_to_the_display() {
+        let mut display = Framebuffer::new(200, 200);
+        let fitted = draw_qr(&mut display, "hello", 0, 0, None).unwrap();
+        let mut display = Framebuffer::new(200, 200);
+        let clamped = draw_qr(&mut display, "hello", 0, 0, Some(u32::MAX)).unwrap();
+        assert_eq!(clamped, fitted);
+        assert!(content_area(&display).contains(clamped.bottom_right().unwrap()));
+        assert!(!display.black_area().is_zero_sized());
+    }
+
+    #[test]
+    fn draw_qr_keeps_a_scale_that_fits() {
+        let mut display = Framebuffer::new(200, 200);
+        let area = draw_qr(&mut display, "hello", 0, 0, Some(2)).unwrap();
+        // The smallest QR code version has 21 modules, with a quiet zone around them
+        assert_eq!(
+            area.size,
+            Size::new_equal((21 + 2 * QR_QUIET_ZONE as u32) * 2)
+        );
+    }
+
+    #[test]
+    fn countdown_rounds_up_to_the_step() {
+        let step = Countdown::step_seconds();
+        assert_eq!(step, if PanelDisplay::PARTIAL_REFRESH { 1 } else { 60 });
+        let message = DisplayMessage::Timer(Duration::from_secs(90));
+        let mut countdown = Countdown::from_message(&message).unwrap();
+        let mut display = Framebuffer::new(200, 100);
+        countdown.step(&mut display);
+        assert_eq!(countdown.shown, (90 + step - 1) / step * step);
+        assert!(!countdown.finished());
+    }
+
+    /// Share of black pixels in each column of a horizontal gradient, from black on the left to white on the right
+    fn gradient_density(method: Dithering) -> Vec<f32> {
+        const WIDTH: usize = 256;
+        const HEIGHT: usize = 64;
+        let gray: Vec<u8> = (0..HEIGHT).flat_map(|_| 0..=255u8).collect();
+        let mut blacks = vec![0; WIDTH];
+        for (index, color) in dither(&gray, WIDTH, method).enumerate() {
+            if color == BinaryColor::On {
+                blacks[index % WIDTH] += 1;
+            }
+        }
+        blacks
+            .into_iter()
+            .map(|black| black as f32 / HEIGHT as f32)
+            .collect()
+    }
+
+    /// Checks that the density of black follows the gradient, over bands of 16 columns
+    fn assert_follows_gradient(density: &[f32]) {
+        assert_eq!(density[0], 1.0);
+        assert_eq!(density[255], 0.0);
+        let bands: Vec<f32> = density
+            .chunks(16)
+            .map(|band| band.iter().sum::<f32>() / band.len() as f32)
+            .collect();
+        for (index, band) in bands.iter().enumerate() {
+            let expected = 1.0 - (index as f32 * 16.0 + 7.5) / 255.0;
+            assert!(
+                (band - expected).abs() < 0.05,
+                "band {} is {} black, expected {}",
+                index,
+                band,
+                expected
+            );
+        }
+        assert!(
+            bands.windows(2).all(|pair| pair[0] > pair[1]),
+            "{:?}",
+            bands
+        );
+    }
+
+    #[test]
+    fn ordered_dithering_follows_a_gradient() {
+        assert_follows_gradient(&gradient_density(Dithering::Ordered));
+    }
+
+    #[test]
+    fn floyd_steinberg_follows_a_gradient() {
+        assert_follows_gradient(&gradient_density(Dithering::FloydSteinberg));
+    }
+
+    #[test]
+    fn threshold_splits_a_gradient() {
+        let density = gradient_density(Dithering::Threshold);
+        assert!(density[..128].iter().all(|&black| black == 1.0));
+        assert!(density[128..].iter().all(|&black| black == 0.0));
+    }
+
+    #[test]
+    fn countdown_labels() {
+        assert_eq!(countdown_label(0), "DONE");
+        assert_eq!(countdown_label(5), "0:05");
+        assert_eq!(countdown_label(59 * 60 + 59), "59:59");
+        assert_eq!(countdown_label(3600), "1:00:00");
+        assert_eq!(countdown_label(99 * 3600 + 59 * 60 + 59), "99:59:59");
+    }
+
+    /// The text of the characters drawn in `size` by parse_markup
+    fn marked(chars: &[(char, FontSize)], size: FontSize) -> String {
+        chars
+            .iter()
+            .filter(|&&(_, char_size)| char_size == size)
+            .map(|&(c, _)| c)
+            .collect()
+    }
+
+    #[test]
+    fn markup_switches_the_size() {
+        let chars = parse_markup("{big}21°{/big} feels like 19", FontSize::Medium).unwrap();
+        assert_eq!(marked(&chars, FontSize::Large), "21°");
+        assert_eq!(marked(&chars, FontSize::Medium), " feels like 19");
+        assert_eq!(parse_markup("no markup", FontSize::Medium), None);
+    }
+
+    #[test]
+    fn markup_tags_nest() {
+        let chars = parse_markup("{small}a{big}b{/big}c{/small}d", FontSize::Medium).unwrap();
+        assert_eq!(marked(&chars, FontSize::Small), "ac");
+        assert_eq!(marked(&chars, FontSize::Large), "b");
+        assert_eq!(marked(&chars, FontSize::Medium), "d");
+    }
+
+    #[test]
+    fn markup_closes_tags_by_name() {
+        // "{/large}" doesn't close "{big}", though both are large: it's drawn as it is
+        let chars = parse_markup("{big}a{/large}b{/big}c", FontSize::Medium).unwrap();
+        assert_eq!(marked(&chars, FontSize::Large), "a{/large}b");
+        assert_eq!(marked(&chars, FontSize::Medium), "c");
+        // Nor does a tag close one opened before the last
+        let chars = parse_markup("{small}a{big}b{/small}c", FontSize::Medium).unwrap();
+        assert_eq!(marked(&chars, FontSize::Large), "b{/small}c");
+        assert_eq!(marked(&chars, FontSize::Small), "a");
+        let chars = parse_markup("{/big}a{big}b", FontSize::Medium).unwrap();
+        assert_eq!(marked(&chars, FontSize::Medium), "{/big}a");
+    }
+
+    #[test]
+    fn plane_pixels_in_an_area() {
+        let row_bytes = ((PANEL_WIDTH + 7) / 8) as usize;
+        let mut plane = vec![0u8; row_bytes * 20];
+        assert!(!plane_has_pixels(&plane, 0, 0, PANEL_WIDTH / 8 * 8, 20));
+        // A single pixel at (19, 10), in the third byte of its row
+        plane[10 * row_bytes + 2] = 0x10;
+        assert!(plane_has_pixels(&plane, 16, 10, 8, 1));
+        assert!(plane_has_pixels(&plane, 0, 0, 64, 20));
+        assert!(!plane_has_pixels(&plane, 24, 0, 64, 20));
+        assert!(!plane_has_pixels(&plane, 0, 11, 64, 9));
+        assert!(!plane_has_pixels(&plane, 16, 0, 8, 10));
+        // Past the end of the plane
+        assert!(!plane_has_pixels(&plane, 0, 20, 64, 10));
+    }
+}
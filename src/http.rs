@@ -0,0 +1,95 @@
+//! Local HTTP endpoint displaying the content pushed to it, bypassing the broker
+
+use crate::{
+    config::{HTTP_DISPLAY_TIMEOUT, MAX_PAYLOAD_SIZE},
+    mqtt::{MessageRouter, MessageSender, MqttMessage, RateLimiter, RenderReply},
+};
+#[cfg(target_os = "espidf")]
+use embedded_svc::{
+    http::{Headers, Method},
+    io::{Read as _, Write as _},
+};
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::http::server::{Configuration as HttpServerConfig, EspHttpServer};
+use log::*;
+use std::sync::{mpsc, Arc, Mutex};
+
+/// Serves POST /display, whose body is routed by `router` and sent to the main loop through `sender` as if it had been
+/// received on `topic`, within its rate limit.
+/// Answers 200 with the number of text lines drawn once the panel shows it, e.g. {"lines":3},
+/// or 400 with the reason when the payload can't be displayed or is over the rate limit, e.g. {"error":"invalid_utf8"},
+/// and 503 when it's
+/// dropped from the full message queue, see QUEUE_FULL_POLICY.
+/// The server stops when the returned handle is dropped.
+#[cfg(target_os = "espidf")]
+pub fn start_display_server(
+    sender: MessageSender,
+    router: MessageRouter,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    topic: String,
+) -> anyhow::Result<EspHttpServer> {
+    // The payloads are parsed and routed on the server task, which needs more than the default stack
+    let mut server = EspHttpServer::new(&HttpServerConfig {
+        stack_size: 10240,
+        ..Default::default()
+    })?;
+    server.fn_handler("/display", Method::Post, move |mut request| {
+        let len = request.content_len().unwrap_or(0) as usize;
+        if len > MAX_PAYLOAD_SIZE {
+            request
+                .into_status_response(413)?
+                .write_all(b"Payload too large")?;
+            return Ok(());
+        }
+        let mut payload = vec![0; len];
+        request.read_exact(&mut payload)?;
+        info!("Received {} bytes on the HTTP endpoint", len);
+
+        let (reply, outcome) = mpsc::channel();
+        let Some(message) = MqttMessage::received(
+            &router,
+            &rate_limiter,
+            topic.clone(),
+            0,
+            payload,
+            Some(reply),
+        ) else {
+            request.into_status_response(400)?.write_all(
+                serde_json::json!({ "error": "rate_limited" })
+                    .to_string()
+                    .as_bytes(),
+            )?;
+            return Ok(());
+        };
+        sender.send(message)?;
+        // The main loop drops the reply when the message is superseded
+        let (status, body) = match outcome.recv_timeout(HTTP_DISPLAY_TIMEOUT) {
+            Ok(Ok(lines)) => (200, serde_json::json!({ "lines": lines })),
+            // Dropped before being drawn, or not shown by the panel, the payload itself may be fine
+            Ok(Err(reason @ ("queue_full" | "panel_error"))) => {
+                (503, serde_json::json!({ "error": reason }))
+            }
+            Ok(Err(reason)) => (400, serde_json::json!({ "error": reason })),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                (503, serde_json::json!({ "error": "not_displayed" }))
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                (503, serde_json::json!({ "error": "timeout" }))
+            }
+        };
+        request
+            .into_status_response(status)?
+            .write_all(body.to_string().as_bytes())?;
+        Ok(())
+    })?;
+    info!("Serving POST /display over HTTP");
+    Ok(server)
+}
+
+/// Sends the outcome of a message to the HTTP request which pushed it, if any
+pub fn send_reply(reply: &Option<mpsc::Sender<RenderReply>>, outcome: RenderReply) {
+    if let Some(reply) = reply {
+        // The request may have timed out already
+        let _ = reply.send(outcome);
+    }
+}
@@ -0,0 +1,14 @@
+// Off the device, for the unit tests, the code needing ESP-IDF is left out: some of the rest is then unused
+#![cfg_attr(not(target_os = "espidf"), allow(dead_code, unused_imports))]
+
+pub mod clock;
+pub mod config;
+pub mod display;
+pub mod http;
+pub mod mqtt;
+#[cfg(target_os = "espidf")]
+pub mod ota;
+pub mod panel;
+pub mod power;
+pub mod system;
+pub mod wifi;
@@ -1,242 +1,1326 @@
 use embedded_graphics::{
-    mono_font::MonoTextStyleBuilder,
-    pixelcolor::BinaryColor,
-    prelude::{DrawTarget, Point},
-    text::{Baseline, Text, TextStyleBuilder},
-    Drawable,
+    prelude::{Dimensions, DrawTarget},
+    primitives::Rectangle,
 };
-use embedded_svc::{
-    mqtt::client::{Connection, Event, Message, MessageImpl, QoS},
-    utils::mqtt::client::ConnState,
-    wifi::{AuthMethod, ClientConfiguration, Configuration},
-};
-use epd_waveshare::{
-    buffer_len,
-    epd5in83_v2::{self, Display5in83, Epd5in83},
-    graphics::VarDisplay,
-    prelude::{Color, Display, DisplayRotation, TriColor, WaveshareDisplay},
-};
-use esp_idf_hal::prelude::*;
+use epd_waveshare::prelude::Display;
 use esp_idf_hal::{
-    delay::{Delay, Ets},
-    gpio::{AnyIOPin, Gpio2, PinDriver},
+    gpio::{AnyIOPin, PinDriver, Pull},
+    peripheral::Peripheral,
     prelude::Peripherals,
-    spi::{config::Config, SpiDeviceDriver, SpiDriverConfig},
-};
-use esp_idf_svc::{
-    eventloop::EspSystemEventLoop,
-    mqtt::client::{EspMqttClient, MqttClientConfiguration},
-    nvs::EspDefaultNvsPartition,
-    tls::X509,
-    wifi::{BlockingWifi, EspWifi},
+    spi::SpiError,
+    uart::{config::Config as UartConfig, UartDriver},
 };
-use esp_idf_sys::{self as _, EspError}; // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
+use esp_idf_svc::{eventloop::EspSystemEventLoop, nvs::EspDefaultNvsPartition, sntp::EspSntp};
+use esp_idf_sys as _; // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
 use log::*;
+use rust_esp32_epaper_mqtt::{
+    clock::{clock_label, now, set_timezone, until_next_minute, wait_for_time_sync},
+    config::{
+        provision, wait_for_line, Config, BATTERY_MONITOR, BOARD, CONNECTION_CHECK_INTERVAL,
+        DEDUPLICATE_MESSAGES, DEEP_SLEEP_AWAKE_WINDOW, DEEP_SLEEP_INTERVAL, DEEP_SLEEP_MAX_AWAKE,
+        DEEP_SLEEP_WAKE_BUTTON, DISPLAY_ROTATION, HTTP_DISPLAY_SERVER, LAYOUT_REGIONS, LOG_LEVEL,
+        MDNS_HOSTNAME, MIN_REFRESH_INTERVAL, MQTT_HONOR_RETAINED, MQTT_SECURITY,
+        MQTT_STATUS_ASLEEP, MQTT_STATUS_OFFLINE, MQTT_STATUS_ONLINE, PROVISIONING_MAGIC,
+        PROVISIONING_TIMEOUT, RESET_REASON_ON_PANEL, RESTORE_SCREEN_STATE, ROTATION_INTERVAL,
+        SCREENSAVER, SCREENSAVER_TIMEOUT, SELF_TEST_ON_BOOT, SNTP_SYNC_TIMEOUT, SPLASH_SCREEN,
+        STARTUP_RETRY_DELAY, STATUS_BAR_INTERVAL, TELEMETRY_INTERVAL, TIMEZONE, WATCHDOG_TIMEOUT,
+        WIFI_RETRY_DELAY,
+    },
+    display::{
+        clear_content, clear_panel, content_area, draw_clock, draw_header, draw_self_test,
+        draw_splash, draw_status_bar, draw_text, dry_run, last_frame, load_last_frame,
+        load_refresh_stats, load_rotation, parse_text_options, rectangle_union, refresh_stats,
+        reinit_panel, render_message, render_text, rotation_degrees, save_refresh_stats,
+        save_rotation, set_dry_run, set_header, set_raw_frame, update_full, update_panel,
+        Countdown, GhostingTracker, Layout, Marquee, RefreshTracker, Screensaver, TextOptions,
+    },
+    http::{send_reply, start_display_server},
+    mqtt::{
+        coalesce, finish, message_hash, message_queue, mqtt_subscriptions, publish_battery,
+        publish_echo, publish_firmware, publish_hardware_error, publish_hello,
+        publish_rate_limited, publish_refresh_stats, publish_reset_reason, publish_status,
+        publish_telemetry, publish_transfer_status, publish_warning, resubscribe,
+        setup_mqtt_client, spawn_heartbeat, Certificates, ChunkOutcome, DisplayMessage,
+        MessageRouter, MqttBackoff, MqttClient, MqttMessage, PanelContent, RateLimiter, RenderAck,
+        Rotation, ScreenStore, TransferStatus, Transfers,
+    },
+    ota::{confirm_firmware, ota_update, publish_ota_status},
+    panel::{busy_timeouts, init_panel, PanelColor, PanelDisplay, PanelPeripherals},
+    power::{enter_deep_sleep, read_battery, wake_cause, Battery, SleepState, WakeCause},
+    system::{
+        count_boot, feed_watchdog, hand_over_panel, install_panic_hook, log_reset_reason,
+        record_panel_failure, register_panic_panel, reset_reason, restarted_abnormally,
+        retry_startup, set_log_level, start_watchdog, take_panel_failures, WatchdogGuard,
+        PANIC_HANDOVER_REQUESTED,
+    },
+    wifi::{
+        connect_wifi, init_wifi, read_rssi, run_portal, spawn_wifi_monitor, start_mdns, start_wifi,
+    },
+};
 use std::{
-    mem, slice,
-    sync::mpsc::{self, Sender},
+    collections::VecDeque,
+    iter,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError,
+    },
     thread,
-    time::Duration,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-// WiFi configuration
-const WIFI_SSID: &str = "";
-const WIFI_PASS: &str = "";
-
-// MQTT configuration. Not specific to AWS IoT but currently certificates aren't optional. If you want to use Emqx instead of AWS IoT, see https://www.emqx.com/en/blog/emqx-server-ssl-tls-secure-connection-configuration-guide
-const MQTT_ENDPOINT: &str = "YOUR_AWS_IOT_MQTT_ENDPOINT_HERE";
-const MQTT_CLIENT_ID: &str = "esp32-epaper-main";
-const MQTT_TOPIC_NAME: &str = "topic/sdk/test/rust";
-
-// AWS IoT certificate
-const CA_CERT_PATH: &str = "../certificates/AmazonRootCA1.pem";
-const THING_CERT_PATH: &str = "../certificates/esp32-epaper-main.client.crt";
-const THING_PRIVATE_KEY_PATH: &str = "../certificates/esp32-epaper-main.private.key";
-
-fn main() -> anyhow::Result<()> {
+fn main() {
     // It is necessary to call this function once. Otherwise some patches to the runtime
     // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
     esp_idf_sys::link_patches();
     // Bind the log crate to the ESP Logging facilities
     esp_idf_svc::log::EspLogger::initialize_default();
+    set_log_level(LOG_LEVEL);
+    install_panic_hook();
+    log_reset_reason();
+
+    // Failures which outlived their retries end up here
+    if let Err(e) = run() {
+        error!("{:#}", e);
+    }
+    error!("Restarting in {}s", STARTUP_RETRY_DELAY.as_secs());
+    thread::sleep(STARTUP_RETRY_DELAY);
+    save_refresh_stats();
+    esp_idf_hal::reset::restart();
+}
+
+fn run() -> anyhow::Result<()> {
+    start_watchdog(WATCHDOG_TIMEOUT)?;
 
-    let peripherals = Peripherals::take().unwrap();
+    let peripherals =
+        Peripherals::take().ok_or_else(|| anyhow::anyhow!("the peripherals are already taken"))?;
     let sys_loop = EspSystemEventLoop::take()?;
     let nvs = EspDefaultNvsPartition::take()?;
 
-    Delay::delay_ms(3000);
-    // Blocking so that we can block until the IP is obtained
-    let mut wifi = BlockingWifi::wrap(
-        EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs))?,
-        sys_loop,
+    // Provisioning happens over the serial console, on UART0
+    let uart = UartDriver::new(
+        peripherals.uart0,
+        peripherals.pins.gpio1,
+        peripherals.pins.gpio3,
+        Option::<AnyIOPin>::None,
+        Option::<AnyIOPin>::None,
+        &UartConfig::new().baudrate(115_200.into()),
     )?;
-
-    configure_wifi(&mut wifi)?;
-
-    Delay::delay_ms(3000);
+    let mut boot_button = PinDriver::input(peripherals.pins.gpio0)?;
+    boot_button.set_pull(Pull::Up)?;
+    // Waking up from deep sleep is a boot too, and the serial console isn't waited for then
+    let wake = wake_cause();
+    let woken = wake != WakeCause::PowerOn;
+    let mut sleep_state = if woken {
+        info!("Woken up from deep sleep: {:?}", wake);
+        SleepState::load()
+    } else {
+        SleepState::default()
+    };
+    match count_boot(nvs.clone(), woken) {
+        Ok(boots) => info!("Boot #{}", boots),
+        Err(e) => warn!("Couldn't read the boot counter from NVS: {}", e),
+    }
+    if wake == WakeCause::Button {
+        // Redraw the latest message even if it's the one displayed
+        sleep_state.rendered = None;
+    }
+    // Holding the BOOT button starts the captive portal instead, unless it was pressed to wake the device up
+    let portal_requested = boot_button.is_low() && wake != WakeCause::Button;
+    if !portal_requested && !woken && wait_for_line(&uart, PROVISIONING_MAGIC, PROVISIONING_TIMEOUT)
+    {
+        if let Err(e) = provision(&uart, nvs.clone()) {
+            error!("Provisioning failed: {}", e);
+        }
+    }
+    let mut config = Config::load_from_nvs(nvs.clone()).unwrap_or_else(|e| {
+        warn!(
+            "Couldn't load the configuration from NVS, using the defaults: {}",
+            e
+        );
+        Config::default()
+    });
 
     info!("Configuring the E-Ink display...");
-    let mut display = Display5in83::default();
+    let mut display = PanelDisplay::default();
+    let rotation = load_rotation(nvs.clone()).unwrap_or_else(|e| {
+        warn!("Couldn't load the rotation from NVS: {}", e);
+        None
+    });
+    display.set_rotation(rotation.unwrap_or(DISPLAY_ROTATION));
 
-    let spi = peripherals.spi2;
+    let mut panel_peripherals = PanelPeripherals {
+        spi: peripherals.spi2,
+        board: BOARD,
+    };
+    // There's no panel to show the errors on yet
+    let init_started = Instant::now();
+    let (mut device, mut epd) = retry_startup(
+        "set up the E-Ink display",
+        STARTUP_RETRY_DELAY,
+        None,
+        || init_panel(&mut panel_peripherals),
+    )
+    .map_err(|e| {
+        // Reported by the next boot bringing the panel up
+        if let Err(e) = record_panel_failure(nvs.clone()) {
+            warn!("Couldn't record the panel failure in NVS: {}", e);
+        }
+        e
+    })?;
+    info!(
+        "E-Ink display init completed in {}ms",
+        init_started.elapsed().as_millis()
+    );
+    // Dropped before the panel, which is declared before it
+    let _panic_panel = register_panic_panel(&mut epd, &mut device, &mut display);
+    // Skips redrawing the frame already on the panel, unless the wake button asks for it
+    let trusted = !restarted_abnormally() && wake != WakeCause::Button;
+    if let Err(e) = load_last_frame(nvs.clone(), trusted) {
+        warn!("Couldn't load the frame on the panel from NVS: {}", e);
+    }
+    if let Err(e) = load_refresh_stats(nvs.clone()) {
+        warn!("Couldn't load the panel refresh counters from NVS: {}", e);
+    }
+    let self_test = SELF_TEST_ON_BOOT && !woken;
+    if self_test {
+        draw_self_test(&mut display, &mut epd, &mut device)?;
+    }
+    let reset = reset_reason();
+    let reset_shown = RESET_REASON_ON_PANEL && reset.abnormal;
+    if reset_shown {
+        render_text(
+            &mut display,
+            &format!("Restarted after a {}", reset.description),
+        );
+        update_full(&mut epd, &mut device, &display)?;
+    }
+    let panel_failures = take_panel_failures(nvs.clone()).unwrap_or_else(|e| {
+        warn!("Couldn't read the panel failures from NVS: {}", e);
+        0
+    });
+    let failures_shown = panel_failures > 0;
+    if failures_shown {
+        let message = format!(
+            "The panel couldn't be initialized on the {} previous boot(s), check its wiring",
+            panel_failures
+        );
+        warn!("{}", message);
+        render_text(&mut display, &message);
+        update_full(&mut epd, &mut device, &display)?;
+    }
+    // Whether the splash is on the panel. The first message drawn clears it, even if it only draws over the content.
+    let mut splash =
+        SPLASH_SCREEN && !self_test && !reset_shown && !failures_shown && last_frame().is_none();
+    if splash {
+        draw_splash(&mut display, &mut epd, &mut device, &config.mqtt_client_id)?;
+    }
 
-    // Firebeetle pins
-    let sclk = peripherals.pins.gpio18;
-    let serial_out = peripherals.pins.gpio23;
-    let cs = PinDriver::output(peripherals.pins.gpio14)?;
-    let busy_in = PinDriver::input(peripherals.pins.gpio4)?;
-    let dc = PinDriver::output(peripherals.pins.gpio22)?;
-    let rst = PinDriver::output(peripherals.pins.gpio21)?;
+    // Firebeetle battery pin, on ADC1 which keeps working with the WiFi on
+    let mut battery = match BATTERY_MONITOR {
+        true => Battery::new(peripherals.adc1, peripherals.pins.gpio36)
+            .map_err(|e| error!("Couldn't set up the battery monitor: {}", e))
+            .ok(),
+        false => None,
+    };
 
-    let config = Config::new().baudrate(112500.into());
-    let mut device = SpiDeviceDriver::new_single(
-        spi,
-        sclk,
-        serial_out,
-        Option::<Gpio2>::None,
-        Option::<AnyIOPin>::None,
-        &SpiDriverConfig::default(),
-        &config,
+    let mut modem = peripherals.modem;
+    let mut wifi = retry_startup(
+        "set up the WiFi",
+        STARTUP_RETRY_DELAY,
+        Some((&mut epd, &mut device, &mut display)),
+        || {
+            // The driver of a failed attempt has been dropped, releasing the modem
+            let modem = unsafe { modem.clone_unchecked() };
+            init_wifi(modem, sys_loop.clone(), nvs.clone(), config.static_ip)
+        },
     )?;
 
-    let mut delay = Ets;
-
-    Delay::delay_ms(3000);
-    let mut epd = Epd5in83::new(&mut device, cs, busy_in, dc, rst, &mut delay, None)?;
-    info!("E-Ink display init completed!");
+    if portal_requested || !config.has_wifi_network() {
+        let network = run_portal(&mut wifi, nvs)?;
+        clear_content(&mut display);
+        let area = content_area(&display);
+        draw_text(
+            &mut display,
+            &format!(
+                "The WiFi network \"{}\" has been saved. Restarting...",
+                network.ssid
+            ),
+            0,
+            0,
+            area.size.width,
+            area.size.height,
+            TextOptions::default(),
+        );
+        update_full(&mut epd, &mut device, &display)?;
+        save_refresh_stats();
+        esp_idf_hal::reset::restart();
+        unreachable!("the device is restarting");
+    }
+    start_wifi(&mut wifi)?;
+    retry_startup(
+        "connect to the WiFi",
+        WIFI_RETRY_DELAY,
+        Some((&mut epd, &mut device, &mut display)),
+        || connect_wifi(&mut wifi, &config.wifi_networks),
+    )?;
+    // Only a convenience to find the device, the startup goes on without it. Answers until the end of run.
+    let mdns = MDNS_HOSTNAME.and_then(|hostname| {
+        start_mdns(hostname, &config.mqtt_topic)
+            .map(|mdns| (mdns, format!("{}.local", hostname)))
+            .map_err(|e| warn!("Couldn't set up mDNS, going on without it: {}", e))
+            .ok()
+    });
+    // Synchronizes the system clock in the background, for the status bar and the clock
+    set_timezone(TIMEZONE);
+    let sntp = retry_startup(
+        "start the time synchronization",
+        STARTUP_RETRY_DELAY,
+        Some((&mut epd, &mut device, &mut display)),
+        || Ok(EspSntp::new_default()?),
+    )?;
+    // The RTC keeps the time in deep sleep, it's synchronized again in the background
+    if !woken || now().is_none() {
+        wait_for_time_sync(&sntp, SNTP_SYNC_TIMEOUT);
+    }
+    // Set by the WiFi monitor once it reconnects, to re-establish the MQTT client
+    let wifi_reconnected = Arc::new(AtomicBool::new(false));
+    spawn_wifi_monitor(wifi, config.wifi_networks.clone(), wifi_reconnected.clone());
 
     //Set up a channel to send messages received from the MQTT queue (separate thread) to the main thread, to display them on the e-paper module
     info!("Setting up the MQTT client...");
-    let (sender, receiver) = mpsc::channel::<String>();
-    let _mqtt_client: EspMqttClient<ConnState<MessageImpl, EspError>> = setup_mqtt_client(sender)?;
+    let (sender, receiver) = message_queue();
+    // Changed along with the MQTT topic, by a "topic=<topic>" control command
+    let mut subscriptions = mqtt_subscriptions(&config.mqtt_topic);
+    // The payloads are routed by the subscriptions where they're received, by the MQTT client and the HTTP endpoint
+    let router = MessageRouter::new(subscriptions.clone(), config.mqtt_topic.clone());
+    // Messages allowed per topic, by RATE_LIMITS, before they're queued
+    let rate_limiter = Arc::new(Mutex::new(RateLimiter::new()));
+    // Plain TCP connections don't use any certificate
+    let certificates = MQTT_SECURITY.uses_tls().then(Certificates::load);
+    let (mut client, mut mqtt_link) = retry_startup(
+        "set up the MQTT client",
+        STARTUP_RETRY_DELAY,
+        Some((&mut epd, &mut device, &mut display)),
+        || {
+            setup_mqtt_client(
+                sender.clone(),
+                router.clone(),
+                rate_limiter.clone(),
+                &config,
+                &subscriptions,
+                certificates,
+            )
+        },
+    )?;
+    if MQTT_HONOR_RETAINED {
+        info!("Not publishing the hello message, the retained message restores the panel instead");
+    } else if let Err(e) = publish_hello(&mut client, &config.mqtt_topic) {
+        warn!("Couldn't publish the hello message: {}", e);
+    }
+    // Dropped when the connection is lost or the WiFi reconnects, and set up again
+    let mqtt_client: Arc<Mutex<Option<MqttClient>>> = Arc::new(Mutex::new(Some(client)));
+    let mut mqtt_backoff = MqttBackoff::new();
+    // A debugging aid as well, the startup goes on without it
+    let _display_server = HTTP_DISPLAY_SERVER
+        .then(|| {
+            start_display_server(
+                sender.clone(),
+                router.clone(),
+                rate_limiter.clone(),
+                config.mqtt_topic.clone(),
+            )
+            .map_err(|e| {
+                warn!(
+                    "Couldn't start the HTTP endpoint, going on without it: {}",
+                    e
+                )
+            })
+            .ok()
+        })
+        .flatten();
+    let heartbeat_topic = Arc::new(Mutex::new(config.mqtt_topic.clone()));
+    spawn_heartbeat(
+        mqtt_client.clone(),
+        heartbeat_topic.clone(),
+        mdns.as_ref().map(|(_, hostname)| hostname.clone()),
+    );
 
-    loop {
-        Delay::delay_ms(3000);
-        // Check for new messages every 3 seconds for 2 seconds
-        let message = receiver.recv_timeout(Duration::from_millis(2000));
-        if let Ok(message) = message {
-            info!("Message received in main thread: {:?}", message);
-            display.clear(Color::White)?;
-            draw_text(&mut display, &message, 0, 0);
-            epd.update_frame(&mut device, display.buffer(), &mut delay)?;
-            epd.display_frame(&mut device, &mut delay)?;
+    let mut layout = Layout::new(LAYOUT_REGIONS, display.bounding_box().size);
+    let mut refresh = RefreshTracker::new();
+    let mut ghosting = GhostingTracker::new();
+    let mut rotation = Rotation::new();
+    // Messages on the panel, displayed again when the display is rotated
+    let mut content = PanelContent::new();
+    // With SCREENSAVER_TIMEOUT, the screensaver replaces the content once no message has arrived for a while
+    let mut last_message = Instant::now();
+    let mut screensaver = false;
+    let mut last_area: Option<Rectangle> = None;
+    let mut marquee: Option<Marquee> = None;
+    let mut last_update: Option<SystemTime> = sleep_state.last_update();
+    let mut last_status = Instant::now();
+    let mut last_telemetry: Option<Instant> = None;
+    // Panel BUSY timeouts published so far
+    let mut reported_busy_timeouts = 0;
+    // Received messages waiting to be displayed, in order
+    let mut pending: VecDeque<MqttMessage> = VecDeque::new();
+    // Whether the messages pending are waiting for MIN_REFRESH_INTERVAL, once it's been logged
+    let mut throttled = false;
+    // Payloads received in chunks, until they're complete
+    let mut transfers = Transfers::new();
+    // Hash of the topic and payload of the last message displayed, with DEDUPLICATE_MESSAGES.
+    // It's kept in deep sleep, so that retained messages aren't redrawn on every wake
+    let mut last_rendered: Option<u32> = sleep_state.rendered;
+    // Whether the panel has been blanked by the clear command, leaving out the status bar
+    let mut blank = false;
+    // Time currently drawn by the clock, while it's displayed
+    let mut clock: Option<String> = None;
+    // Countdown of the last timer message, until it shows DONE
+    let mut countdown: Option<Countdown> = None;
+    // The status bar follows the inversion of the last text message
+    let mut invert = sleep_state.invert;
+    // With DEEP_SLEEP_INTERVAL, the device sleeps once no message has arrived for a while since connecting
+    let booted = Instant::now();
+    let mut last_activity: Option<Instant> = None;
+    // A firmware update is kept once it has connected to the broker, it's rolled back if it resets before
+    let mut firmware_confirmed = false;
+    // Published once connected, until it goes out
+    let mut unreported_reset = Some(reset);
+    let mut unreported_panel_failures = failures_shown.then_some(panel_failures);
+    // With RESTORE_SCREEN_STATE, the content of the panel is kept in NVS and drawn again first, before the messages
+    // received. Unless the device crashed, maybe because of that content.
+    let mut screen_store = RESTORE_SCREEN_STATE
+        .then(|| {
+            ScreenStore::new(nvs.clone())
+                .map_err(|e| warn!("Couldn't open the screen state in NVS: {}", e))
+                .ok()
+        })
+        .flatten();
+    if let Some(store) = screen_store.as_mut() {
+        match store.load().filter(|_| !restarted_abnormally()) {
+            Some(state) => {
+                info!(
+                    "Restoring the screen state: {} message(s)",
+                    state.messages.len()
+                );
+                if let Some(header) = &state.header {
+                    refresh.mark(set_header(&mut display, Some(header)));
+                    content.set_header(Some(header.clone()));
+                }
+                pending.extend(state.replay());
+                // The frame is blank, so the messages on the panel are drawn again. Its refresh is skipped if the
+                // frame turns out the same, see load_last_frame.
+                last_rendered = None;
+            }
+            // Not restored after the next reboot either
+            None => store.save(content.state()),
         }
     }
-}
-
-fn configure_wifi(wifi: &mut BlockingWifi<EspWifi>) -> Result<(), EspError> {
-    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
-        ssid: WIFI_SSID.into(),
-        password: WIFI_PASS.into(),
-        auth_method: AuthMethod::None,
-        ..Default::default()
-    }))?;
-    wifi.start()?;
-    info!("Wifi started!");
-
-    wifi.connect()?;
-    info!("Wifi connected!");
-
-    wifi.wait_netif_up()?;
-    info!("Wifi ready!");
-
-    Ok(())
-}
-
-fn setup_mqtt_client(
-    sender: Sender<String>,
-) -> Result<EspMqttClient<ConnState<MessageImpl, EspError>>, EspError> {
-    info!("About to start MQTT client");
-
-    let server_cert_bytes: Vec<u8> = include_bytes!(CA_CERT_PATH).to_vec();
-    let client_cert_bytes: Vec<u8> = include_bytes!(THING_CERT_PATH).to_vec();
-    let private_key_bytes: Vec<u8> = include_bytes!(THING_PRIVATE_KEY_PATH).to_vec();
-
-    let server_cert: X509 = convert_certificate(server_cert_bytes);
-    let client_cert: X509 = convert_certificate(client_cert_bytes);
-    let private_key: X509 = convert_certificate(private_key_bytes);
-
-    let conf = MqttClientConfiguration {
-        client_id: Some(MQTT_CLIENT_ID),
-        crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
-        server_certificate: Some(server_cert),
-        client_certificate: Some(client_cert),
-        private_key: Some(private_key),
-        ..Default::default()
+    // A panel failing to refresh doesn't stop the device, it's counted for the next boot to report and the messages
+    // after it try again
+    let panel_failed = |e: SpiError| {
+        error!("Couldn't update the panel: {}", e);
+        if let Err(e) = record_panel_failure(nvs.clone()) {
+            warn!("Couldn't record the panel failure in NVS: {}", e);
+        }
     };
-    let (mut client, mut connection) = EspMqttClient::new_with_conn(MQTT_ENDPOINT, &conf)?;
-
-    info!("MQTT client started!");
-
-    thread::spawn(move || {
-        info!("MQTT Listening for messages...");
-
-        // Send received messages back to the main thread to display them
-        while let Some(msg) = connection.next() {
-            match msg {
-                Err(e) => info!("MQTT Message ERROR: {}", e),
-                Ok(msg) => {
-                    info!("MQTT Message: {:?}", msg);
-                    if let Event::Received(msg) = msg {
-                        let parsed_string = String::from_utf8(msg.data().to_vec());
-                        if let Ok(parsed_string) = parsed_string {
-                            info!("Parsed MQTT message: {:?}", parsed_string);
-                            sender.send(parsed_string).unwrap();
+    // A hung panel update or network call reboots the device
+    let _watchdog = WatchdogGuard::new()?;
+    loop {
+        feed_watchdog();
+        // A panic on another thread shows itself on the panel
+        if PANIC_HANDOVER_REQUESTED.load(Ordering::SeqCst) {
+            hand_over_panel();
+        }
+        {
+            // The heartbeat thread publishes with the client too
+            let mut client_slot = mqtt_client.lock().unwrap();
+            // The previous client is dropped first, ending its connection thread, since the broker allows a single connection per client id
+            if wifi_reconnected.swap(false, Ordering::Relaxed) {
+                info!("WiFi reconnected, re-establishing the MQTT client");
+                *client_slot = None;
+                mqtt_backoff.retry_now();
+            }
+            if client_slot.is_some() && mqtt_link.lost.swap(false, Ordering::Relaxed) {
+                warn!("MQTT connection lost");
+                *client_slot = None;
+                mqtt_backoff.failed();
+            }
+            if client_slot.is_none() && mqtt_backoff.is_due() {
+                info!(
+                    "Re-establishing the MQTT client, attempt {}...",
+                    mqtt_backoff.attempts() + 1
+                );
+                match setup_mqtt_client(
+                    sender.clone(),
+                    router.clone(),
+                    rate_limiter.clone(),
+                    &config,
+                    &subscriptions,
+                    certificates,
+                ) {
+                    Ok((client, link)) => {
+                        *client_slot = Some(client);
+                        mqtt_link = link;
+                    }
+                    Err(e) => {
+                        error!("Couldn't re-establish the MQTT client: {:#}", e);
+                        mqtt_backoff.failed();
+                    }
+                }
+            }
+            if let Some(client) = client_slot.as_mut() {
+                if mqtt_link.connected.swap(false, Ordering::Relaxed) {
+                    mqtt_backoff.connected();
+                    if !firmware_confirmed {
+                        confirm_firmware();
+                        firmware_confirmed = true;
+                    }
+                    if let Some(reason) = unreported_reset.take() {
+                        if let Err(e) = publish_reset_reason(client, &config.mqtt_topic, reason) {
+                            warn!("Couldn't publish the reset reason: {}", e);
+                            unreported_reset = Some(reason);
                         }
                     }
+                    if let Some(count) = unreported_panel_failures.take() {
+                        if let Err(e) = publish_hardware_error(
+                            client,
+                            &config.mqtt_topic,
+                            "panel",
+                            "init_failed",
+                            count,
+                        ) {
+                            warn!("Couldn't publish the panel failures: {}", e);
+                            unreported_panel_failures = Some(count);
+                        }
+                    }
+                    last_activity = Some(Instant::now());
+                    if let Err(e) = publish_status(client, &config.mqtt_topic, MQTT_STATUS_ONLINE) {
+                        warn!("Couldn't publish the online status: {}", e);
+                    }
+                    if let Err(e) = publish_firmware(client, &config.mqtt_topic) {
+                        warn!("Couldn't publish the firmware version: {}", e);
+                    }
+                }
+                let busy_timeouts = busy_timeouts();
+                if busy_timeouts != reported_busy_timeouts {
+                    match publish_hardware_error(
+                        client,
+                        &config.mqtt_topic,
+                        "panel",
+                        "busy_timeout",
+                        busy_timeouts,
+                    ) {
+                        Ok(()) => reported_busy_timeouts = busy_timeouts,
+                        Err(e) => warn!("Couldn't publish the panel BUSY timeout: {}", e),
+                    }
+                }
+                let dropped = rate_limiter
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .notice();
+                if let Some(dropped) = dropped {
+                    if let Err(e) = publish_rate_limited(client, &config.mqtt_topic, &dropped) {
+                        warn!("Couldn't publish the rate limit notice: {}", e);
+                    }
+                }
+                if last_telemetry.map_or(true, |last| last.elapsed() >= TELEMETRY_INTERVAL) {
+                    if let Err(e) = publish_telemetry(client, &config.mqtt_topic) {
+                        warn!("Couldn't publish the telemetry: {}", e);
+                    }
+                    if let Some(reading) = read_battery(&mut battery) {
+                        if let Err(e) = publish_battery(client, &config.mqtt_topic, reading) {
+                            warn!("Couldn't publish the battery telemetry: {}", e);
+                        }
+                    }
+                    if let Err(e) =
+                        publish_refresh_stats(client, &config.mqtt_topic, refresh_stats())
+                    {
+                        warn!("Couldn't publish the refresh counters: {}", e);
+                    }
+                    save_refresh_stats();
+                    last_telemetry = Some(Instant::now());
                 }
             }
         }
+        // The transfers missing chunks for too long are discarded, for their sender to retry them
+        for (id, missing) in transfers.expire() {
+            publish_transfer_status(
+                mqtt_client.lock().unwrap().as_mut(),
+                &config.mqtt_topic,
+                &id,
+                TransferStatus::Discarded {
+                    reason: "timeout",
+                    missing: &missing,
+                },
+            );
+        }
+        // Block until a message arrives, displaying it right away, or until the next housekeeping is due.
+        // The connection changes flagged by the other threads are noticed within CONNECTION_CHECK_INTERVAL.
+        if pending.is_empty() {
+            // Once the messages received are displayed, the screen state is up to date
+            if let Some(store) = screen_store.as_mut() {
+                store.save(content.state());
+            }
+            let waiting_since = Instant::now();
+            let mut due = waiting_since + CONNECTION_CHECK_INTERVAL;
+            let client_up = mqtt_client.lock().unwrap().is_some();
+            let deadlines = [
+                // The telemetry waits for the client, which is set up again when the backoff allows it
+                match client_up {
+                    true => last_telemetry.map(|last| last + TELEMETRY_INTERVAL),
+                    false => mqtt_backoff.retry_at(),
+                },
+                marquee.as_ref().map(Marquee::next_step),
+                clock.as_ref().map(|_| waiting_since + until_next_minute()),
+                countdown.as_ref().map(Countdown::next_step),
+                transfers.next_expiry(),
+                (!blank).then(|| last_status + STATUS_BAR_INTERVAL),
+                rotation.next_due().filter(|_| !screensaver),
+                SCREENSAVER_TIMEOUT
+                    .filter(|_| !screensaver)
+                    .map(|timeout| last_message + timeout),
+                DEEP_SLEEP_INTERVAL.map(|_| match last_activity {
+                    Some(last) => last + DEEP_SLEEP_AWAKE_WINDOW,
+                    None => booted + DEEP_SLEEP_MAX_AWAKE,
+                }),
+            ];
+            for deadline in deadlines.into_iter().flatten() {
+                due = due.min(deadline);
+            }
+            match receiver.recv_timeout(due.saturating_duration_since(waiting_since)) {
+                Ok(received) => pending.push_back(received),
+                Err(_) => {
+                    // The next message of the rotation is displayed like a received one, without acknowledging it
+                    if let Some(next) = rotation.advance().filter(|_| !screensaver) {
+                        info!("Rotating to the message of \"{}\"", next.topic);
+                        pending.push_back(next);
+                        continue;
+                    }
+                    // The next message displayed replaces the screensaver
+                    if let Some(timeout) = SCREENSAVER_TIMEOUT {
+                        if !screensaver && last_message.elapsed() >= timeout {
+                            info!(
+                                "No message for {}s, showing the screensaver",
+                                timeout.as_secs()
+                            );
+                            screensaver = true;
+                            marquee = None;
+                            countdown = None;
+                            content.clear();
+                            last_rendered = None;
+                            match SCREENSAVER {
+                                Screensaver::Blank => {
+                                    let _ = display.clear(PanelColor::White);
+                                    if let Err(e) = clear_panel(&mut epd, &mut device) {
+                                        panel_failed(e);
+                                        continue;
+                                    }
+                                    ghosting.cleared();
+                                    let _ = refresh.take();
+                                    last_area = None;
+                                    clock = None;
+                                    blank = true;
+                                }
+                                Screensaver::Clock => {
+                                    refresh.mark(draw_clock(&mut display, now()));
+                                    refresh.mark_all();
+                                    last_area = Some(content_area(&display));
+                                    clock = Some(clock_label(now()));
+                                    blank = false;
+                                }
+                            }
+                        }
+                    }
+                    // Keep scrolling the current marquee until a new message arrives
+                    if let Some(marquee) = marquee.as_mut().filter(|marquee| marquee.is_due()) {
+                        refresh.mark(marquee.step(&mut display));
+                    }
+                    // Count the timer down, and stop once it shows DONE
+                    if let Some(timer) = countdown.as_mut().filter(|timer| timer.is_due()) {
+                        refresh.mark(timer.step(&mut display));
+                        if timer.finished() {
+                            info!("Timer done");
+                            countdown = None;
+                        }
+                    }
+                    // Keep the clock up to date, once a minute
+                    if let Some(shown) = clock.as_mut() {
+                        let time = now();
+                        if clock_label(time) != *shown {
+                            refresh.mark(draw_clock(&mut display, time));
+                            *shown = clock_label(time);
+                        }
+                    }
+                    if !blank && last_status.elapsed() >= STATUS_BAR_INTERVAL {
+                        refresh.mark(draw_status_bar(
+                            &mut display,
+                            SystemTime::now(),
+                            now(),
+                            last_update,
+                            read_rssi().map(|signal| signal.rssi),
+                            read_battery(&mut battery),
+                            invert,
+                        ));
+                        last_status = Instant::now();
+                    }
+                    // The panel isn't left out of date for the deep sleep
+                    if let Err(e) =
+                        update_panel(&mut epd, &mut device, &display, &mut refresh, &mut ghosting)
+                    {
+                        panel_failed(e);
+                        continue;
+                    }
+                    if let Some(interval) = DEEP_SLEEP_INTERVAL {
+                        // A countdown would stop in deep sleep
+                        let idle = countdown.is_none()
+                            && match last_activity {
+                                Some(last) => last.elapsed() >= DEEP_SLEEP_AWAKE_WINDOW,
+                                None => booted.elapsed() >= DEEP_SLEEP_MAX_AWAKE,
+                            };
+                        if idle {
+                            if let Some(mut client) = mqtt_client.lock().unwrap().take() {
+                                if let Err(e) = publish_status(
+                                    &mut client,
+                                    &config.mqtt_topic,
+                                    MQTT_STATUS_ASLEEP,
+                                ) {
+                                    warn!("Couldn't publish the asleep status: {}", e);
+                                }
+                                // Give the status time to go out before disconnecting
+                                thread::sleep(Duration::from_secs(1));
+                            }
+                            let state = SleepState {
+                                rendered: last_rendered,
+                                invert,
+                                last_update: last_update
+                                    .and_then(|update| update.duration_since(UNIX_EPOCH).ok())
+                                    .map(|since_epoch| since_epoch.as_secs()),
+                            };
+                            enter_deep_sleep(
+                                &mut epd,
+                                &mut device,
+                                interval,
+                                state,
+                                DEEP_SLEEP_WAKE_BUTTON.then_some(&boot_button),
+                            );
+                        }
+                    }
+                    continue;
+                }
+            }
+        }
+        // With MIN_REFRESH_INTERVAL, the messages wait for the interval since the last refresh to elapse, those arriving
+        // meanwhile superseding the ones of their topic. The connections are still checked every CONNECTION_CHECK_INTERVAL.
+        let resume = MIN_REFRESH_INTERVAL
+            .zip(ghosting.last_refresh())
+            .map(|(interval, last)| last + interval)
+            .filter(|resume| *resume > Instant::now());
+        if let Some(resume) = resume {
+            let delay = resume.saturating_duration_since(Instant::now());
+            if !throttled {
+                info!(
+                    "Delaying the refresh by {}ms, the panel was refreshed less than {}s ago",
+                    delay.as_millis(),
+                    MIN_REFRESH_INTERVAL.unwrap_or_default().as_secs()
+                );
+                throttled = true;
+            }
+            if let Ok(received) = receiver.recv_timeout(delay.min(CONNECTION_CHECK_INTERVAL)) {
+                if coalesce(&mut pending, received) {
+                    info!("Discarded a message superseded while the refresh is delayed");
+                }
+            }
+            continue;
+        }
+        throttled = false;
+        // Messages which arrived while the panel was refreshing have piled up, only the latest one of each topic is kept
+        let coalesced = iter::from_fn(|| receiver.try_recv().ok())
+            .fold(0, |coalesced, received| {
+                coalesced + coalesce(&mut pending, received) as usize
+            });
+        if coalesced > 0 {
+            info!("Discarded {} superseded message(s)", coalesced);
+        }
+        let Some(mut received) = pending.pop_front() else {
+            continue;
+        };
+        // Not kept by the rotation, the request is answered once
+        let reply = received.reply.take();
+        received.timing.mark_render_start();
 
-        info!("MQTT connection loop exit");
-    });
-
-    client.subscribe(MQTT_TOPIC_NAME, QoS::AtMostOnce)?;
-
-    info!("Subscribed to all topics ({})", MQTT_TOPIC_NAME);
-
-    Delay::delay_ms(1000);
-    // This will be the first message appearing on the screen
-    client.publish(
-        MQTT_TOPIC_NAME,
-        QoS::AtMostOnce,
-        false,
-        format!("Hello from {}!", MQTT_TOPIC_NAME).as_bytes(),
-    )?;
-
-    info!(
-        "Published a hello message to topic \"{}\".",
-        MQTT_TOPIC_NAME
-    );
-
-    Ok(client)
-}
-
-fn convert_certificate(mut certificate_bytes: Vec<u8>) -> X509<'static> {
-    // append NUL
-    certificate_bytes.push(0);
-
-    // convert the certificate
-    let certificate_slice: &[u8] = unsafe {
-        let ptr: *const u8 = certificate_bytes.as_ptr();
-        let len: usize = certificate_bytes.len();
-        mem::forget(certificate_bytes);
-
-        slice::from_raw_parts(ptr, len)
-    };
-
-    // return the certificate file in the correct format
-    X509::pem_until_nul(certificate_slice)
-}
-
-pub fn draw_text(display: &mut Display5in83, text: &str, x: i32, y: i32) {
-    let style = MonoTextStyleBuilder::new()
-        .font(&embedded_graphics::mono_font::ascii::FONT_10X20)
-        .text_color(Color::White)
-        .background_color(Color::Black)
-        .build();
+        let started = Instant::now();
+        // The rotation doesn't keep the device awake
+        if !received.replayed {
+            last_activity = Some(started);
+            last_message = started;
+        }
+        // A chunk is only displayed once it completes its transfer, as the reassembled payload
+        if let Some((id, outcome)) = transfers.receive(&mut received) {
+            match outcome {
+                ChunkOutcome::Pending | ChunkOutcome::Duplicate => {
+                    send_reply(&reply, Ok(0));
+                    continue;
+                }
+                ChunkOutcome::Complete => {
+                    publish_transfer_status(
+                        mqtt_client.lock().unwrap().as_mut(),
+                        &config.mqtt_topic,
+                        &id,
+                        TransferStatus::Reassembled {
+                            size: received.payload.len(),
+                        },
+                    );
+                    // A transfer counts once, when it's complete, the other messages are limited where they're received
+                    let allowed = rate_limiter
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .allow(&received.topic);
+                    if !allowed {
+                        send_reply(&reply, Err("rate_limited"));
+                        continue;
+                    }
+                }
+                ChunkOutcome::Failed(reason) => {
+                    send_reply(&reply, Err(reason));
+                    publish_transfer_status(
+                        mqtt_client.lock().unwrap().as_mut(),
+                        &config.mqtt_topic,
+                        &id,
+                        TransferStatus::Discarded {
+                            reason,
+                            missing: &[],
+                        },
+                    );
+                    continue;
+                }
+            }
+        }
+        let mut ack = RenderAck::new(&received);
+        let rendered =
+            DEDUPLICATE_MESSAGES.then(|| message_hash(&received.topic, &received.payload));
+        if rendered.is_some() && rendered == last_rendered {
+            info!(
+                "Skipping a message identical to the one displayed, received on \"{}\"",
+                received.topic
+            );
+            finish(
+                mqtt_client.lock().unwrap().as_mut(),
+                &config.mqtt_topic,
+                &reply,
+                &ack,
+                started.elapsed(),
+                Ok(0),
+            );
+            continue;
+        }
+        // Routed by the thread which received it, unless it's replayed or a reassembled transfer
+        let routed = received.routed.take();
+        let message = match routed.unwrap_or_else(|| router.dispatch(&received)) {
+            Ok(message) => message,
+            Err(reason) => {
+                finish(
+                    mqtt_client.lock().unwrap().as_mut(),
+                    &config.mqtt_topic,
+                    &reply,
+                    &ack,
+                    started.elapsed(),
+                    Err(reason),
+                );
+                continue;
+            }
+        };
+        let kept = received;
+        info!("Message received in main thread: {:?}", message);
+        if let DisplayMessage::Clear = message {
+            let _ = display.clear(PanelColor::White);
+            if let Err(e) = clear_panel(&mut epd, &mut device) {
+                panel_failed(e);
+                finish(
+                    mqtt_client.lock().unwrap().as_mut(),
+                    &config.mqtt_topic,
+                    &reply,
+                    &ack,
+                    started.elapsed(),
+                    Err("panel_error"),
+                );
+                continue;
+            }
+            rotation.clear();
+            content.clear();
+            screensaver = false;
+            ghosting.cleared();
+            // The pending changes are blanked too
+            let _ = refresh.take();
+            // The header stays, it has its own command to remove it
+            refresh.mark(draw_header(&mut display));
+            if let Err(e) =
+                update_panel(&mut epd, &mut device, &display, &mut refresh, &mut ghosting)
+            {
+                panel_failed(e);
+                finish(
+                    mqtt_client.lock().unwrap().as_mut(),
+                    &config.mqtt_topic,
+                    &reply,
+                    &ack,
+                    started.elapsed(),
+                    Err("panel_error"),
+                );
+                continue;
+            }
+            last_area = None;
+            marquee = None;
+            clock = None;
+            countdown = None;
+            splash = false;
+            // The same message can be displayed again
+            last_rendered = None;
+            last_update = Some(SystemTime::now());
+            blank = true;
+            finish(
+                mqtt_client.lock().unwrap().as_mut(),
+                &config.mqtt_topic,
+                &reply,
+                &ack,
+                started.elapsed(),
+                Ok(0),
+            );
+            continue;
+        }
+        if let DisplayMessage::Header(text) = &message {
+            refresh.mark(set_header(&mut display, text.as_deref()));
+            content.set_header(text.clone());
+            if let Err(e) =
+                update_panel(&mut epd, &mut device, &display, &mut refresh, &mut ghosting)
+            {
+                panel_failed(e);
+                finish(
+                    mqtt_client.lock().unwrap().as_mut(),
+                    &config.mqtt_topic,
+                    &reply,
+                    &ack,
+                    started.elapsed(),
+                    Err("panel_error"),
+                );
+                continue;
+            }
+            finish(
+                mqtt_client.lock().unwrap().as_mut(),
+                &config.mqtt_topic,
+                &reply,
+                &ack,
+                started.elapsed(),
+                Ok(0),
+            );
+            continue;
+        }
+        if let DisplayMessage::LogLevel(level) = message {
+            set_log_level(level);
+            finish(
+                mqtt_client.lock().unwrap().as_mut(),
+                &config.mqtt_topic,
+                &reply,
+                &ack,
+                started.elapsed(),
+                Ok(0),
+            );
+            continue;
+        }
+        if let DisplayMessage::DryRun(enabled) = message {
+            set_dry_run(enabled);
+            // The frame drawn meanwhile is pushed whole, the panel still shows what it did before
+            let result = match enabled {
+                true => Ok(()),
+                false => {
+                    refresh.mark_all();
+                    update_panel(&mut epd, &mut device, &display, &mut refresh, &mut ghosting)
+                }
+            };
+            if let Err(e) = result {
+                error!("Couldn't refresh the panel: {}", e);
+            }
+            finish(
+                mqtt_client.lock().unwrap().as_mut(),
+                &config.mqtt_topic,
+                &reply,
+                &ack,
+                started.elapsed(),
+                Ok(0),
+            );
+            continue;
+        }
+        if let DisplayMessage::ReinitPanel = message {
+            let result = reinit_panel(&mut epd, &mut device).and_then(|()| {
+                ghosting.cleared();
+                // The frame is kept, and pushed whole to the initialized panel
+                refresh.mark_all();
+                update_panel(&mut epd, &mut device, &display, &mut refresh, &mut ghosting)
+            });
+            match result {
+                Ok(()) => {
+                    finish(
+                        mqtt_client.lock().unwrap().as_mut(),
+                        &config.mqtt_topic,
+                        &reply,
+                        &ack,
+                        started.elapsed(),
+                        Ok(0),
+                    );
+                }
+                Err(e) => {
+                    error!("Couldn't initialize the panel again: {}", e);
+                    finish(
+                        mqtt_client.lock().unwrap().as_mut(),
+                        &config.mqtt_topic,
+                        &reply,
+                        &ack,
+                        started.elapsed(),
+                        Err("reinit_failed"),
+                    );
+                }
+            }
+            continue;
+        }
+        if let DisplayMessage::SetTopic(topic) = message {
+            let moved = mqtt_subscriptions(&topic);
+            let mut client = mqtt_client.lock().unwrap();
+            // Without a client, the next one subscribes to the new topic
+            let outcome = match client.as_mut() {
+                Some(client) => resubscribe(client, &subscriptions, &moved),
+                None => Ok(()),
+            };
+            if let Err(e) = outcome {
+                warn!("Couldn't move to the MQTT topic \"{}\": {}", topic, e);
+                finish(
+                    client.as_mut(),
+                    &config.mqtt_topic,
+                    &reply,
+                    &ack,
+                    started.elapsed(),
+                    Err("subscribe_failed"),
+                );
+                continue;
+            }
+            info!(
+                "Moved from the MQTT topic \"{}\" to \"{}\"",
+                config.mqtt_topic, topic
+            );
+            if let Err(e) = Config::store_topic(nvs.clone(), &topic) {
+                warn!(
+                    "Couldn't save the MQTT topic, it's lost at the next boot: {}",
+                    e
+                );
+            }
+            // Acknowledged on the topic the command came from, the status following the device
+            finish(
+                client.as_mut(),
+                &config.mqtt_topic,
+                &reply,
+                &ack,
+                started.elapsed(),
+                Ok(0),
+            );
+            if let Some(client) = client.as_mut() {
+                for (topic, status) in [
+                    (&config.mqtt_topic, MQTT_STATUS_OFFLINE),
+                    (&topic, MQTT_STATUS_ONLINE),
+                ] {
+                    if let Err(e) = publish_status(client, topic, status) {
+                        warn!("Couldn't publish the status on \"{}\": {}", topic, e);
+                    }
+                }
+            }
+            *heartbeat_topic
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = topic.clone();
+            router.set(moved.clone(), topic.clone());
+            config.mqtt_topic = topic;
+            subscriptions = moved;
+            continue;
+        }
+        if let DisplayMessage::Rotate(rotated) = message {
+            if let Err(e) = save_rotation(nvs.clone(), rotated) {
+                warn!("Couldn't save the rotation: {}", e);
+            }
+            if rotation_degrees(rotated) != rotation_degrees(display.rotation()) {
+                info!(
+                    "Rotating the display to {} degrees",
+                    rotation_degrees(rotated)
+                );
+                display.set_rotation(rotated);
+                layout = Layout::new(LAYOUT_REGIONS, display.bounding_box().size);
+                let _ = display.clear(PanelColor::White);
+                refresh.mark_all();
+                last_area = None;
+                marquee = None;
+                last_rendered = None;
+                // The content is displayed again in the new orientation, before the messages waiting
+                let replayed = content.replay();
+                if replayed.is_empty() {
+                    refresh.mark(draw_header(&mut display));
+                    if clock.is_some() {
+                        refresh.mark(draw_clock(&mut display, now()));
+                        last_area = Some(content_area(&display));
+                    }
+                    if !blank {
+                        refresh.mark(draw_status_bar(
+                            &mut display,
+                            SystemTime::now(),
+                            now(),
+                            last_update,
+                            read_rssi().map(|signal| signal.rssi),
+                            read_battery(&mut battery),
+                            invert,
+                        ));
+                        last_status = Instant::now();
+                    }
+                    if let Err(e) =
+                        update_panel(&mut epd, &mut device, &display, &mut refresh, &mut ghosting)
+                    {
+                        panel_failed(e);
+                        finish(
+                            mqtt_client.lock().unwrap().as_mut(),
+                            &config.mqtt_topic,
+                            &reply,
+                            &ack,
+                            started.elapsed(),
+                            Err("panel_error"),
+                        );
+                        continue;
+                    }
+                }
+                for message in replayed.into_iter().rev() {
+                    pending.push_front(message);
+                }
+            }
+            finish(
+                mqtt_client.lock().unwrap().as_mut(),
+                &config.mqtt_topic,
+                &reply,
+                &ack,
+                started.elapsed(),
+                Ok(0),
+            );
+            continue;
+        }
+        if let DisplayMessage::FirmwareUpdate(url) = &message {
+            let result = ota_update(url, |percent| {
+                refresh.mark(render_text(&mut display, &format!("progress:{}", percent)).area);
+                if let Err(e) =
+                    update_panel(&mut epd, &mut device, &display, &mut refresh, &mut ghosting)
+                {
+                    warn!("Couldn't show the update progress: {}", e);
+                }
+            });
+            publish_ota_status(
+                mqtt_client.lock().unwrap().as_mut(),
+                &config.mqtt_topic,
+                url,
+                &result,
+            );
+            send_reply(
+                &reply,
+                result.as_ref().map(|_| 0).map_err(|_| "update_failed"),
+            );
+            let text = match &result {
+                Ok(true) => "Firmware updated, restarting...".to_string(),
+                Ok(false) => "The firmware is already up to date".to_string(),
+                Err(e) => {
+                    error!("Firmware update failed: {:#}", e);
+                    format!("Firmware update failed: {:#}", e)
+                }
+            };
+            let area = render_text(&mut display, &text).area;
+            refresh.mark(last_area.map_or(area, |previous| rectangle_union(previous, area)));
+            // Already answered, the device restarts into the update regardless
+            if let Err(e) =
+                update_panel(&mut epd, &mut device, &display, &mut refresh, &mut ghosting)
+            {
+                panel_failed(e);
+            }
+            if let Ok(true) = result {
+                // Give the status time to go out
+                thread::sleep(Duration::from_secs(1));
+                save_refresh_stats();
+                esp_idf_hal::reset::restart();
+                unreachable!("the device is restarting");
+            }
+            last_area = Some(content_area(&display));
+            marquee = None;
+            clock = None;
+            countdown = None;
+            content.clear();
+            // The update can be retried with the same URL
+            last_rendered = None;
+            continue;
+        }
+        if let DisplayMessage::RawFrame(packed) = &message {
+            match set_raw_frame(&mut epd, &mut device, &mut display, packed) {
+                // The whole panel has been refreshed, and will have to be cleared by the next message
+                // The status bar is drawn over it again on its next update
+                Ok(()) => {
+                    last_area = Some(display.bounding_box());
+                    marquee = None;
+                    clock = None;
+                    countdown = None;
+                    splash = false;
+                    last_update = Some(SystemTime::now());
+                    last_rendered = rendered;
+                    blank = false;
+                    screensaver = false;
+                    if ROTATION_INTERVAL.is_some() {
+                        rotation.shown(kept.clone());
+                    }
+                    content.shown(kept, &message);
+                    finish(
+                        mqtt_client.lock().unwrap().as_mut(),
+                        &config.mqtt_topic,
+                        &reply,
+                        &ack,
+                        started.elapsed(),
+                        Ok(0),
+                    );
+                }
+                Err(e) => {
+                    error!("Couldn't display the raw frame: {}", e);
+                    finish(
+                        mqtt_client.lock().unwrap().as_mut(),
+                        &config.mqtt_topic,
+                        &reply,
+                        &ack,
+                        started.elapsed(),
+                        Err("invalid_frame"),
+                    );
+                }
+            }
+            continue;
+        }
+        if splash && !message.clears_content() {
+            clear_content(&mut display);
+            refresh.mark(content_area(&display));
+        }
+        let Some(drawn) = render_message(&mut display, &layout, &message) else {
+            finish(
+                mqtt_client.lock().unwrap().as_mut(),
+                &config.mqtt_topic,
+                &reply,
+                &ack,
+                started.elapsed(),
+                Err("not_displayable"),
+            );
+            continue;
+        };
+        splash = false;
+        let area = drawn.area;
+        if dry_run() {
+            info!(
+                "Dry run, drew {:?} into the frame over {}x{} at ({}, {}){}",
+                message,
+                area.size.width,
+                area.size.height,
+                area.top_left.x,
+                area.top_left.y,
+                drawn
+                    .text
+                    .as_ref()
+                    .map_or(String::new(), |text| format!(", lines {:?}", text.lines))
+            );
+        }
+        last_rendered = rendered;
+        blank = false;
+        screensaver = false;
+        refresh.mark(area);
+        if !message.clears_content() {
+            // Only part of the frame has been redrawn, the rest of the content stays on the panel
+            last_area = Some(last_area.map_or(area, |previous| rectangle_union(previous, area)));
+        } else {
+            // Rendering cleared the previous content, so its area has to be refreshed too
+            if let Some(previous_area) = last_area {
+                refresh.mark(previous_area);
+            }
+            last_area = Some(area);
+            marquee = Marquee::from_message(&message, display.bounding_box().size.width);
+            clock = matches!(message, DisplayMessage::Clock).then(|| clock_label(now()));
+            countdown = match (countdown.take(), Countdown::from_message(&message)) {
+                // Drawn again, e.g. after rotating the display, the timer keeps counting down
+                (Some(mut running), Some(_)) if ack.replayed => {
+                    refresh.mark(running.step(&mut display));
+                    Some(running)
+                }
+                (_, started) => started,
+            };
+            // The same timer can be started again
+            if countdown.is_some() {
+                last_rendered = None;
+            }
+            if ROTATION_INTERVAL.is_some() {
+                rotation.shown(kept.clone());
+            }
+        }
+        content.shown(kept, &message);
 
-    let text_style = TextStyleBuilder::new().baseline(Baseline::Top).build();
+        match &message {
+            DisplayMessage::Text(text) => invert = parse_text_options(text).0.invert,
+            DisplayMessage::Batch { messages, .. } => {
+                for message in messages {
+                    if let DisplayMessage::Header(text) = message {
+                        content.set_header(text.clone());
+                    }
+                }
+            }
+            _ => {}
+        }
+        last_update = Some(SystemTime::now());
+        // Regions and drawings may have covered the header
+        refresh.mark(draw_header(&mut display));
+        refresh.mark(draw_status_bar(
+            &mut display,
+            SystemTime::now(),
+            now(),
+            last_update,
+            read_rssi().map(|signal| signal.rssi),
+            read_battery(&mut battery),
+            invert,
+        ));
+        last_status = Instant::now();
+        ack.timing.mark_rendered();
 
-    let _ = Text::with_text_style(text, Point::new(x, y), style, text_style).draw(display);
+        if let Err(e) = update_panel(&mut epd, &mut device, &display, &mut refresh, &mut ghosting) {
+            panel_failed(e);
+            finish(
+                mqtt_client.lock().unwrap().as_mut(),
+                &config.mqtt_topic,
+                &reply,
+                &ack,
+                started.elapsed(),
+                Err("panel_error"),
+            );
+            continue;
+        }
+        ack.timing.mark_displayed();
+        if !ack.replayed {
+            ack.timing.log(&ack.topic);
+        }
+        feed_watchdog();
+        let mut client = mqtt_client.lock().unwrap();
+        if drawn.truncated {
+            publish_warning(client.as_mut(), &config.mqtt_topic, &ack, "truncated");
+        }
+        if let DisplayMessage::Batch { skipped: 1.., .. } = message {
+            publish_warning(
+                client.as_mut(),
+                &config.mqtt_topic,
+                &ack,
+                "skipped_commands",
+            );
+        }
+        publish_echo(client.as_mut(), &config.mqtt_topic, &ack, &drawn);
+        finish(
+            client.as_mut(),
+            &config.mqtt_topic,
+            &reply,
+            &ack,
+            started.elapsed(),
+            Ok(drawn.text.as_ref().map_or(0, |text| text.lines.len())),
+        );
+    }
 }
@@ -0,0 +1,3046 @@
+//! MQTT client setup, telemetry and the handling of the received messages
+
+use crate::{
+    config::{
+        Config, CA_CERT, CHUNK_MAX_COUNT, CHUNK_MAX_TRANSFERS, CHUNK_TIMEOUT, CLEAR_PAYLOAD,
+        CLOCK_PAYLOAD, DEEP_SLEEP_INTERVAL, HEARTBEAT_INTERVAL, INVALID_UTF8_PAYLOAD,
+        MAX_PAYLOAD_SIZE, MESSAGE_QUEUE_CAPACITY, MQTT_ACK_SUBTOPIC, MQTT_COMMAND_SUBTOPIC,
+        MQTT_CONTROL_SUBTOPIC, MQTT_ECHO_SUBTOPIC, MQTT_FIRMWARE_SUBTOPIC, MQTT_HARDWARE_SUBTOPIC,
+        MQTT_HONOR_RETAINED, MQTT_OTA_SUBTOPIC, MQTT_PUBLISH_QOS, MQTT_RATE_LIMIT_SUBTOPIC,
+        MQTT_RECONNECT_MAX_BACKOFF, MQTT_RECONNECT_MIN_BACKOFF, MQTT_REFRESHES_SUBTOPIC,
+        MQTT_RESET_SUBTOPIC, MQTT_RETAINED_WINDOW, MQTT_SECURITY, MQTT_STATUS_OFFLINE,
+        MQTT_STATUS_SUBTOPIC, MQTT_SUBSCRIBE_QOS, MQTT_TOPICS, MQTT_TRANSFER_SUBTOPIC,
+        MQTT_WARNING_SUBTOPIC, NVS_CERTS_NAMESPACE, NVS_CERTS_PARTITION, NVS_SCREEN_NAMESPACE,
+        NVS_VALUE_MAX_LEN, QUEUE_FULL_POLICY, RATE_LIMITS, RATE_LIMIT_DEFAULT,
+        RATE_LIMIT_NOTICE_INTERVAL, RECONNECT_JITTER, REDRAW_MAX_MESSAGES, ROTATION_INTERVAL,
+        ROTATION_SLOTS, SCREEN_STATE_MAX_LEN, THING_CERT, THING_PRIVATE_KEY,
+    },
+    display::{
+        rotation_degrees, rotation_from_degrees, FontSize, HorizontalAlignment, RefreshStats,
+        Rendered, RenderedText, Weather, RAW_FRAME_LEN,
+    },
+    http::send_reply,
+    power::BatteryReading,
+    system::{boot_count, firmware_json, uptime, Backoff, ResetReason},
+};
+#[cfg(target_os = "espidf")]
+use crate::{system::WatchdogGuard, wifi::read_rssi};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use embedded_svc::{
+    mqtt::client::{Connection, Details, Event, Message, MessageImpl, QoS},
+    utils::mqtt::client::ConnState,
+};
+use epd_waveshare::prelude::DisplayRotation;
+#[cfg(target_os = "espidf")]
+use esp_idf_hal::delay::Delay;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::{
+    mqtt::client::{EspMqttClient, LwtConfiguration, MqttClientConfiguration},
+    nvs::{EspCustomNvs, EspCustomNvsPartition, EspDefaultNvs, EspDefaultNvsPartition},
+    tls::X509,
+};
+#[cfg(target_os = "espidf")]
+use esp_idf_sys::EspError;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt, mem,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{RecvTimeoutError, Sender, TryRecvError},
+        Arc, Condvar, Mutex, PoisonError, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// MQTT client, with its connection handled by a separate thread
+#[cfg(target_os = "espidf")]
+pub type MqttClient = EspMqttClient<ConnState<MessageImpl, EspError>>;
+
+/// Connection state of an MQTT client, reported by its connection thread
+#[derive(Debug, Default)]
+pub struct MqttLink {
+    /// Set on each connection to the broker
+    pub connected: AtomicBool,
+    /// Set once disconnected from the broker, the client then has to be set up again
+    pub lost: AtomicBool,
+}
+
+/// Paces the attempts to re-establish the MQTT client with an exponential backoff, reset once connected
+#[derive(Debug)]
+#[cfg(target_os = "espidf")]
+pub struct MqttBackoff {
+    backoff: Backoff,
+    retry_at: Option<Instant>,
+}
+
+#[cfg(target_os = "espidf")]
+impl MqttBackoff {
+    pub fn new() -> Self {
+        MqttBackoff {
+            backoff: Backoff::new(
+                MQTT_RECONNECT_MIN_BACKOFF,
+                MQTT_RECONNECT_MAX_BACKOFF,
+                RECONNECT_JITTER,
+            ),
+            retry_at: None,
+        }
+    }
+
+    /// Failed attempts since the last connection
+    pub fn attempts(&self) -> u32 {
+        self.backoff.attempts()
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.retry_at
+            .map_or(true, |retry_at| Instant::now() >= retry_at)
+    }
+
+    /// When the next attempt is due, None when it already is
+    pub fn retry_at(&self) -> Option<Instant> {
+        self.retry_at
+    }
+
+    /// Schedules the next attempt after the current backoff, which then doubles up to MQTT_RECONNECT_MAX_BACKOFF
+    pub fn failed(&mut self) {
+        let delay = self.backoff.next_delay();
+        self.retry_at = Some(Instant::now() + delay);
+        info!(
+            "Next MQTT reconnection attempt in {:.1}s ({} failed so far)",
+            delay.as_secs_f32(),
+            self.backoff.attempts()
+        );
+    }
+
+    /// Lets the next attempt happen right away, e.g. once the WiFi is back
+    pub fn retry_now(&mut self) {
+        self.retry_at = None;
+    }
+
+    pub fn connected(&mut self) {
+        if self.backoff.attempts() > 0 {
+            info!(
+                "MQTT reconnected after {} attempt(s)",
+                self.backoff.attempts() + 1
+            );
+        }
+        self.backoff.reset();
+        self.retry_at = None;
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl Default for MqttBackoff {
+    fn default() -> Self {
+        MqttBackoff::new()
+    }
+}
+
+/// Connects to the broker and subscribes to each of the `subscriptions`, forwarding the received messages to `sender`.
+/// Returns the client along with the state of its connection, which is never re-established by the client itself:
+/// once lost, the client has to be set up again.
+#[cfg(target_os = "espidf")]
+pub fn setup_mqtt_client(
+    sender: MessageSender,
+    router: MessageRouter,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    config: &Config,
+    subscriptions: &[(String, QoS, TopicHandler)],
+    certificates: Option<Certificates>,
+) -> anyhow::Result<(MqttClient, Arc<MqttLink>)> {
+    info!("About to start MQTT client");
+
+    MQTT_SECURITY.validate(config)?;
+    let (server_cert, client_cert, private_key) = match (MQTT_SECURITY, certificates) {
+        (MqttSecurity::Plain, _) => {
+            warn!("Connecting to the broker over plain TCP, the credentials and messages aren't encrypted");
+            (None, None, None)
+        }
+        (_, None) => anyhow::bail!("the certificates aren't loaded, TLS needs them"),
+        (MqttSecurity::ServerTls, Some(certificates)) => (
+            Some(convert_certificate("CA certificate", certificates.ca)?),
+            None,
+            None,
+        ),
+        (MqttSecurity::MutualTls, Some(certificates)) => (
+            Some(convert_certificate(
+                "AWS root CA certificate",
+                certificates.ca,
+            )?),
+            Some(convert_certificate(
+                "device certificate",
+                certificates.thing,
+            )?),
+            Some(convert_certificate(
+                "device private key",
+                certificates.private_key,
+            )?),
+        ),
+    };
+
+    if !config.mqtt_username.is_empty() {
+        info!(
+            "Authenticating to the broker as \"{}\" ({:?})",
+            config.mqtt_username, MQTT_SECURITY
+        );
+    }
+
+    let status_topic = format!("{}/{}", config.mqtt_topic, MQTT_STATUS_SUBTOPIC);
+    let conf = MqttClientConfiguration {
+        client_id: Some(&config.mqtt_client_id),
+        lwt: Some(LwtConfiguration {
+            topic: &status_topic,
+            payload: MQTT_STATUS_OFFLINE.as_bytes(),
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        }),
+        // Reconnections are paced by MqttBackoff instead
+        reconnect_timeout: None,
+        // In deep sleep, the broker keeps the QoS 1 messages published meanwhile in the session, until the next wake
+        disable_clean_session: DEEP_SLEEP_INTERVAL.is_some(),
+        crt_bundle_attach: server_cert.map(|_| esp_idf_sys::esp_crt_bundle_attach as _),
+        server_certificate: server_cert,
+        client_certificate: client_cert,
+        private_key,
+        // Empty credentials aren't sent
+        username: Some(config.mqtt_username.as_str()).filter(|username| !username.is_empty()),
+        password: Some(config.mqtt_password.as_str()).filter(|password| !password.is_empty()),
+        ..Default::default()
+    };
+    let (mut client, mut connection) = EspMqttClient::new_with_conn(&config.mqtt_endpoint, &conf)?;
+
+    info!("MQTT client started!");
+
+    let link = Arc::new(MqttLink::default());
+    let connection_link = link.clone();
+    thread::spawn(move || {
+        info!("MQTT Listening for messages...");
+
+        // Payloads bigger than the MQTT buffer are delivered in chunks, which are reassembled here.
+        // Only the first chunk carries the topic.
+        let mut pending = Vec::new();
+        let mut topic = String::new();
+        let mut connected_at = Instant::now();
+
+        // Send received messages back to the main thread to display them
+        while let Some(msg) = connection.next() {
+            // Waiting for the next event can take any time, so only the handling of each one is watched
+            let _watchdog = WatchdogGuard::new().ok();
+            match msg {
+                Err(e) => info!("MQTT Message ERROR: {}", e),
+                Ok(msg) => {
+                    info!("MQTT Message: {:?}", msg);
+                    match msg {
+                        Event::Connected(_) => {
+                            connected_at = Instant::now();
+                            connection_link.connected.store(true, Ordering::Relaxed)
+                        }
+                        Event::Disconnected => connection_link.lost.store(true, Ordering::Relaxed),
+                        Event::Received(msg) => {
+                            if let Some(msg_topic) = msg.topic() {
+                                topic = msg_topic.to_string();
+                            }
+                            let Some(payload) =
+                                reassemble_payload(&mut pending, msg.details(), msg.data())
+                            else {
+                                continue;
+                            };
+                            // Retained messages are handled like any other, unless they're not honored
+                            if !MQTT_HONOR_RETAINED && connected_at.elapsed() < MQTT_RETAINED_WINDOW
+                            {
+                                info!(
+                                    "Dropping the message received on \"{}\" right after connecting, as retained",
+                                    topic
+                                );
+                                continue;
+                            }
+                            let Some(message) = MqttMessage::received(
+                                &router,
+                                &rate_limiter,
+                                topic.clone(),
+                                msg.id(),
+                                payload,
+                                None,
+                            ) else {
+                                continue;
+                            };
+                            if let Err(e) = sender.send(message) {
+                                error!(
+                                    "Couldn't queue the message received on \"{}\": {}",
+                                    topic, e
+                                );
+                                break;
+                            }
+                        }
+                        // Published and acknowledged events only follow QoS 1 and 2 messages, logging them is enough
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        info!("MQTT connection loop exit");
+        connection_link.lost.store(true, Ordering::Relaxed);
+    });
+
+    for (topic, qos, _) in subscriptions {
+        match client.subscribe(topic, *qos) {
+            Ok(_) => info!("Subscribed to \"{}\" with {:?}", topic, qos),
+            Err(e) => error!("Couldn't subscribe to \"{}\": {}", topic, e),
+        }
+    }
+
+    Ok((client, link))
+}
+
+/// Moves the subscriptions of `client` from `old` to `new`, both as given by mqtt_subscriptions, leaving alone the
+/// filters they share, e.g. MQTT_TOPICS. The new filters are subscribed to first, so that no message is missed, and
+/// if one of them fails the ones made are undone, keeping the old subscriptions.
+#[cfg(target_os = "espidf")]
+pub fn resubscribe(
+    client: &mut MqttClient,
+    old: &[(String, QoS, TopicHandler)],
+    new: &[(String, QoS, TopicHandler)],
+) -> Result<(), EspError> {
+    let has = |subscriptions: &[(String, QoS, TopicHandler)], topic: &str| {
+        subscriptions.iter().any(|(filter, _, _)| filter == topic)
+    };
+    let mut subscribed = Vec::new();
+    for (topic, qos, _) in new.iter().filter(|(topic, _, _)| !has(old, topic)) {
+        if let Err(e) = client.subscribe(topic, *qos) {
+            error!("Couldn't subscribe to \"{}\": {}", topic, e);
+            for topic in subscribed {
+                let _ = client.unsubscribe(topic);
+            }
+            return Err(e);
+        }
+        info!("Subscribed to \"{}\" with {:?}", topic, qos);
+        subscribed.push(topic.as_str());
+    }
+    for (topic, _, _) in old.iter().filter(|(topic, _, _)| !has(new, topic)) {
+        match client.unsubscribe(topic) {
+            Ok(_) => info!("Unsubscribed from \"{}\"", topic),
+            Err(e) => warn!("Couldn't unsubscribe from \"{}\": {}", topic, e),
+        }
+    }
+    Ok(())
+}
+
+/// Whether `topic` can be the MQTT topic of the device: a topic name without wildcards, which can't be one of the
+/// broker ($SYS...) nor start or end with a "/", short enough to be stored in NVS
+pub fn valid_topic(topic: &str) -> bool {
+    !topic.is_empty()
+        && topic.len() < NVS_VALUE_MAX_LEN
+        && !topic.contains(['+', '#', '\0'])
+        && !topic.starts_with(['$', '/'])
+        && !topic.ends_with('/')
+}
+
+#[cfg(target_os = "espidf")]
+pub fn publish_hello(client: &mut MqttClient, topic: &str) -> Result<(), EspError> {
+    Delay::delay_ms(1000);
+    // This will be the first message appearing on the screen
+    client.publish(
+        topic,
+        MQTT_PUBLISH_QOS,
+        false,
+        format!("Hello from {}!", topic).as_bytes(),
+    )?;
+
+    info!("Published a hello message to topic \"{}\".", topic);
+
+    Ok(())
+}
+
+/// Publishes the retained connection status of the device on "<topic>/<MQTT_STATUS_SUBTOPIC>"
+#[cfg(target_os = "espidf")]
+pub fn publish_status(client: &mut MqttClient, topic: &str, status: &str) -> Result<(), EspError> {
+    let status_topic = format!("{}/{}", topic, MQTT_STATUS_SUBTOPIC);
+    client.publish(&status_topic, QoS::AtLeastOnce, true, status.as_bytes())?;
+    info!(
+        "Published the status \"{}\" to \"{}\"",
+        status, status_topic
+    );
+    Ok(())
+}
+
+/// Publishes the uptime, boot counter, free heap and WiFi signal strength every HEARTBEAT_INTERVAL from a separate thread, so that the
+/// heartbeats go on while the main loop is busy refreshing the panel (or stuck doing so), as long as the client is set up.
+/// `topic` is the MQTT topic of the device, which can change at runtime, and `hostname` its mDNS name, if it's registered.
+#[cfg(target_os = "espidf")]
+pub fn spawn_heartbeat(
+    client: Arc<Mutex<Option<MqttClient>>>,
+    topic: Arc<Mutex<String>>,
+    hostname: Option<String>,
+) {
+    thread::spawn(move || loop {
+        thread::sleep(HEARTBEAT_INTERVAL);
+        let payload = health_json(
+            uptime(),
+            boot_count(),
+            unsafe { esp_idf_sys::esp_get_free_heap_size() },
+            read_rssi().map(|signal| signal.rssi),
+            hostname.as_deref(),
+        );
+        let health_topic = format!(
+            "{}/telemetry/health",
+            topic.lock().unwrap_or_else(PoisonError::into_inner)
+        );
+        let mut client = client.lock().unwrap();
+        let Some(client) = client.as_mut() else {
+            warn!("Skipping the heartbeat, no MQTT client");
+            continue;
+        };
+        match client.publish(&health_topic, MQTT_PUBLISH_QOS, false, payload.as_bytes()) {
+            Ok(_) => debug!("Published the heartbeat {}", payload),
+            Err(e) => warn!("Couldn't publish the heartbeat: {}", e),
+        }
+    });
+}
+
+/// Health of the device, e.g. {"uptime_s":3600,"boots":12,"free_heap":112340,"rssi":-61,"hostname":"esp32-epaper.local"}.
+/// The RSSI is null while disconnected, the boot counter when it couldn't be read, and the hostname without mDNS.
+/// The firmware version and build info follow, as "firmware".
+#[cfg(target_os = "espidf")]
+pub fn health_json(
+    uptime: Duration,
+    boots: Option<u32>,
+    free_heap: u32,
+    rssi: Option<i8>,
+    hostname: Option<&str>,
+) -> String {
+    serde_json::json!({
+        "uptime_s": uptime.as_secs(),
+        "boots": boots,
+        "free_heap": free_heap,
+        "rssi": rssi,
+        "hostname": hostname,
+        "firmware": firmware_json(),
+    })
+    .to_string()
+}
+
+/// Microseconds since boot, from the ESP timer
+fn timer_us() -> u64 {
+    uptime().as_micros() as u64
+}
+
+/// When a message went through each stage from its reception to the end of the panel refresh, in microseconds since
+/// boot (0 until reached)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timing {
+    /// The whole payload has been received
+    pub received_us: u64,
+    /// The main loop picked it up, after the messages before it
+    pub render_start_us: u64,
+    /// It's drawn into the frame
+    pub rendered_us: u64,
+    /// The panel refresh showing it completed
+    pub displayed_us: u64,
+}
+
+impl Timing {
+    pub fn received() -> Self {
+        Timing {
+            received_us: timer_us(),
+            ..Default::default()
+        }
+    }
+
+    pub fn mark_render_start(&mut self) {
+        self.render_start_us = timer_us();
+    }
+
+    pub fn mark_rendered(&mut self) {
+        self.rendered_us = timer_us();
+    }
+
+    pub fn mark_displayed(&mut self) {
+        self.displayed_us = timer_us();
+    }
+
+    /// Time spent waiting in the queue, drawing, and refreshing the panel, once displayed
+    fn breakdown(&self) -> Option<(u64, u64, u64)> {
+        (self.displayed_us != 0).then(|| {
+            (
+                self.render_start_us.saturating_sub(self.received_us),
+                self.rendered_us.saturating_sub(self.render_start_us),
+                self.displayed_us.saturating_sub(self.rendered_us),
+            )
+        })
+    }
+
+    /// Logs the breakdown of the latency of the message received on `topic`, once displayed
+    pub fn log(&self, topic: &str) {
+        if let Some((queued, render, refresh)) = self.breakdown() {
+            info!(
+                "Message of \"{}\" displayed {}us after its reception: {}us queued, {}us drawing, {}us refreshing",
+                topic,
+                self.displayed_us.saturating_sub(self.received_us),
+                queued,
+                render,
+                refresh
+            );
+        }
+    }
+}
+
+/// Identifies a received message in the acknowledgment published once it's on the panel
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RenderAck {
+    pub topic: String,
+    pub id: u32,
+    /// FNV-1a hash of the payload, telling apart QoS 0 messages
+    pub hash: u32,
+    /// The message is displayed again by the Rotation, it has already been acknowledged
+    pub replayed: bool,
+    /// Stages of the message, completed by the main loop as it's drawn and displayed
+    pub timing: Timing,
+}
+
+impl RenderAck {
+    pub fn new(received: &MqttMessage) -> Self {
+        RenderAck {
+            topic: received.topic.clone(),
+            id: received.id,
+            hash: fnv1a_hash(&received.payload),
+            replayed: received.replayed,
+            timing: received.timing,
+        }
+    }
+
+    /// JSON acknowledgment, e.g. {"topic":"topic/sdk/test/rust","id":0,"hash":"811c9dc5","render_ms":4821}.
+    /// Messages drawn into the frame also get the breakdown of their latency, e.g.
+    /// "latency_us":{"queued":1210,"render":35120,"refresh":4790330,"total":4826660}
+    pub fn to_json(&self, render_time: Duration) -> String {
+        let mut ack = serde_json::json!({
+            "topic": self.topic,
+            "id": self.id,
+            "hash": format!("{:08x}", self.hash),
+            "render_ms": render_time.as_millis() as u64,
+        });
+        if let Some((queued, render, refresh)) = self.timing.breakdown() {
+            ack["latency_us"] = serde_json::json!({
+                "queued": queued,
+                "render": render,
+                "refresh": refresh,
+                "total": self.timing.displayed_us.saturating_sub(self.timing.received_us),
+            });
+        }
+        ack.to_string()
+    }
+
+    /// JSON warning about the message, e.g. {"topic":"topic/sdk/test/rust","id":0,"hash":"811c9dc5","warning":"truncated"}
+    pub fn warning_json(&self, warning: &str) -> String {
+        serde_json::json!({
+            "topic": self.topic,
+            "id": self.id,
+            "hash": format!("{:08x}", self.hash),
+            "warning": warning,
+        })
+        .to_string()
+    }
+
+    /// JSON echo of the text drawn for the message, e.g. {"topic":"topic/sdk/test/rust","id":0,"hash":"811c9dc5",
+    /// "text":"Hello","lines":["Hello"],"size":"medium","align":"left","truncated":false}
+    pub fn echo_json(&self, text: &RenderedText, truncated: bool) -> String {
+        serde_json::json!({
+            "topic": self.topic,
+            "id": self.id,
+            "hash": format!("{:08x}", self.hash),
+            "text": text.lines.join("\n"),
+            "lines": text.lines,
+            "size": text.options.size.name(),
+            "align": text.options.alignment.name(),
+            "truncated": truncated,
+        })
+        .to_string()
+    }
+}
+
+/// 32-bit FNV-1a hash
+pub fn fnv1a_hash<'a>(data: impl IntoIterator<Item = &'a u8>) -> u32 {
+    data.into_iter().fold(0x811c_9dc5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// FNV-1a hash of a message, topic included, telling whether the same message is received again
+pub fn message_hash(topic: &str, payload: &[u8]) -> u32 {
+    let mut message = Vec::with_capacity(topic.len() + 1 + payload.len());
+    message.extend_from_slice(topic.as_bytes());
+    // The topic can't contain NUL characters, so different topic and payload splits don't collide
+    message.push(0);
+    message.extend_from_slice(payload);
+    fnv1a_hash(&message)
+}
+
+/// Publishes the acknowledgment of a message displayed in `render_time` on "<topic>/<MQTT_ACK_SUBTOPIC>".
+/// Acknowledgments are dropped while the MQTT client is being re-established.
+#[cfg(target_os = "espidf")]
+pub fn publish_ack(
+    client: Option<&mut MqttClient>,
+    topic: &str,
+    ack: &RenderAck,
+    render_time: Duration,
+) {
+    if ack.replayed {
+        return;
+    }
+    let Some(client) = client else {
+        warn!("Dropping the acknowledgment of {:?}, no MQTT client", ack);
+        return;
+    };
+    let payload = ack.to_json(render_time);
+    match client.publish(
+        &format!("{}/{}", topic, MQTT_ACK_SUBTOPIC),
+        MQTT_PUBLISH_QOS,
+        false,
+        payload.as_bytes(),
+    ) {
+        Ok(_) => debug!("Published the acknowledgment {}", payload),
+        Err(e) => warn!("Couldn't publish the acknowledgment {}: {}", payload, e),
+    }
+}
+
+/// Publishes the text a message has been drawn as, if it's a text message, on "<topic>/<MQTT_ECHO_SUBTOPIC>".
+/// Echoes are dropped like the warnings.
+#[cfg(target_os = "espidf")]
+pub fn publish_echo(
+    client: Option<&mut MqttClient>,
+    topic: &str,
+    ack: &RenderAck,
+    rendered: &Rendered,
+) {
+    let Some(text) = &rendered.text else {
+        return;
+    };
+    if ack.replayed {
+        return;
+    }
+    let Some(client) = client else {
+        warn!("Dropping the echo of {:?}, no MQTT client", ack);
+        return;
+    };
+    let payload = ack.echo_json(text, rendered.truncated);
+    match client.publish(
+        &format!("{}/{}", topic, MQTT_ECHO_SUBTOPIC),
+        MQTT_PUBLISH_QOS,
+        false,
+        payload.as_bytes(),
+    ) {
+        Ok(_) => debug!("Published the echo {}", payload),
+        Err(e) => warn!("Couldn't publish the echo {}: {}", payload, e),
+    }
+}
+
+/// Publishes a warning about a displayed message on "<topic>/<MQTT_WARNING_SUBTOPIC>".
+/// Warnings are dropped while the MQTT client is being re-established, and for the messages replayed by the Rotation.
+#[cfg(target_os = "espidf")]
+pub fn publish_warning(
+    client: Option<&mut MqttClient>,
+    topic: &str,
+    ack: &RenderAck,
+    warning: &str,
+) {
+    if ack.replayed {
+        return;
+    }
+    let Some(client) = client else {
+        warn!(
+            "Dropping the {} warning of {:?}, no MQTT client",
+            warning, ack
+        );
+        return;
+    };
+    let payload = ack.warning_json(warning);
+    match client.publish(
+        &format!("{}/{}", topic, MQTT_WARNING_SUBTOPIC),
+        MQTT_PUBLISH_QOS,
+        false,
+        payload.as_bytes(),
+    ) {
+        Ok(_) => info!("Published the warning {}", payload),
+        Err(e) => warn!("Couldn't publish the warning {}: {}", payload, e),
+    }
+}
+
+/// Answers the request a message came with, if any, then acknowledges the message handled in `render_time`, or warns
+/// about the reason it failed with
+#[cfg(target_os = "espidf")]
+pub fn finish(
+    client: Option<&mut MqttClient>,
+    topic: &str,
+    reply: &Option<Sender<RenderReply>>,
+    ack: &RenderAck,
+    render_time: Duration,
+    outcome: RenderReply,
+) {
+    send_reply(reply, outcome);
+    match outcome {
+        Ok(_) => publish_ack(client, topic, ack, render_time),
+        Err(reason) => publish_warning(client, topic, ack, reason),
+    }
+}
+
+/// Retains the firmware version and build info on "<topic>/<MQTT_FIRMWARE_SUBTOPIC>", see firmware_json
+#[cfg(target_os = "espidf")]
+pub fn publish_firmware(client: &mut MqttClient, topic: &str) -> Result<(), EspError> {
+    let payload = firmware_json().to_string();
+    client.publish(
+        &format!("{}/{}", topic, MQTT_FIRMWARE_SUBTOPIC),
+        MQTT_PUBLISH_QOS,
+        true,
+        payload.as_bytes(),
+    )?;
+    info!("Published the firmware {}", payload);
+    Ok(())
+}
+
+/// Retains why the device restarted on "<topic>/<MQTT_RESET_SUBTOPIC>",
+/// e.g. {"reason":"brownout","description":"brownout, check the power supply","abnormal":true}
+#[cfg(target_os = "espidf")]
+pub fn publish_reset_reason(
+    client: &mut MqttClient,
+    topic: &str,
+    reason: ResetReason,
+) -> Result<(), EspError> {
+    let payload = serde_json::json!({
+        "reason": reason.name,
+        "description": reason.description,
+        "abnormal": reason.abnormal,
+    })
+    .to_string();
+    client.publish(
+        &format!("{}/{}", topic, MQTT_RESET_SUBTOPIC),
+        MQTT_PUBLISH_QOS,
+        true,
+        payload.as_bytes(),
+    )?;
+    info!("Published the reset reason {}", payload);
+    Ok(())
+}
+
+/// Publishes a hardware error and how many times it happened since boot on "<topic>/<MQTT_HARDWARE_SUBTOPIC>",
+/// e.g. {"component":"panel","error":"busy_timeout","count":2}
+#[cfg(target_os = "espidf")]
+pub fn publish_hardware_error(
+    client: &mut MqttClient,
+    topic: &str,
+    component: &str,
+    error: &str,
+    count: u32,
+) -> Result<(), EspError> {
+    let payload = serde_json::json!({
+        "component": component,
+        "error": error,
+        "count": count,
+    })
+    .to_string();
+    client.publish(
+        &format!("{}/{}", topic, MQTT_HARDWARE_SUBTOPIC),
+        MQTT_PUBLISH_QOS,
+        false,
+        payload.as_bytes(),
+    )?;
+    info!("Published the hardware error {}", payload);
+    Ok(())
+}
+
+/// Publishes the refreshes of the panel since the first boot on "<topic>/<MQTT_REFRESHES_SUBTOPIC>",
+/// e.g. {"full":420,"partial":3150,"total":3570}
+#[cfg(target_os = "espidf")]
+pub fn publish_refresh_stats(
+    client: &mut MqttClient,
+    topic: &str,
+    stats: RefreshStats,
+) -> Result<(), EspError> {
+    let payload = stats.to_json();
+    client.publish(
+        &format!("{}/{}", topic, MQTT_REFRESHES_SUBTOPIC),
+        MQTT_PUBLISH_QOS,
+        false,
+        payload.as_bytes(),
+    )?;
+    debug!("Published the refresh counters: {}", payload);
+    Ok(())
+}
+
+/// Publishes the WiFi signal as JSON on "<topic>/telemetry/rssi", e.g. {"ssid":"home","rssi":-61}
+#[cfg(target_os = "espidf")]
+pub fn publish_telemetry(client: &mut MqttClient, topic: &str) -> anyhow::Result<()> {
+    let Some(signal) = read_rssi() else {
+        anyhow::bail!("the WiFi isn't connected, RSSI unavailable");
+    };
+    let payload = serde_json::json!({ "ssid": signal.ssid, "rssi": signal.rssi }).to_string();
+    client.publish(
+        &format!("{}/telemetry/rssi", topic),
+        MQTT_PUBLISH_QOS,
+        false,
+        payload.as_bytes(),
+    )?;
+    debug!("Published the telemetry: {}", payload);
+    Ok(())
+}
+
+/// Publishes the battery voltage and charge on "<topic>/telemetry/battery"
+#[cfg(target_os = "espidf")]
+pub fn publish_battery(
+    client: &mut MqttClient,
+    topic: &str,
+    reading: BatteryReading,
+) -> Result<(), EspError> {
+    let payload = reading.to_json();
+    client.publish(
+        &format!("{}/telemetry/battery", topic),
+        MQTT_PUBLISH_QOS,
+        false,
+        payload.as_bytes(),
+    )?;
+    debug!("Published the battery telemetry: {}", payload);
+    Ok(())
+}
+
+/// Collects the chunks of a received payload into `pending`, returning the whole payload once complete
+fn reassemble_payload(pending: &mut Vec<u8>, details: &Details, data: &[u8]) -> Option<Vec<u8>> {
+    match details {
+        Details::Complete => return Some(data.to_vec()),
+        Details::InitialChunk(chunk) => {
+            pending.clear();
+            if chunk.total_data_size > MAX_PAYLOAD_SIZE {
+                warn!(
+                    "Dropping a {} bytes payload, the maximum is {} bytes",
+                    chunk.total_data_size, MAX_PAYLOAD_SIZE
+                );
+                return None;
+            }
+            pending.reserve(chunk.total_data_size);
+            pending.extend_from_slice(data);
+        }
+        Details::SubsequentChunk(chunk) => {
+            // Chunks of a dropped or partially lost payload are discarded
+            if pending.is_empty() || chunk.current_data_offset != pending.len() {
+                pending.clear();
+                return None;
+            }
+            pending.extend_from_slice(data);
+            if pending.len() >= chunk.total_data_size {
+                return Some(mem::take(pending));
+            }
+        }
+    }
+    None
+}
+
+/// A whole payload received over MQTT, along with the topic it was published on
+#[derive(Clone)]
+pub struct MqttMessage {
+    pub topic: String,
+    /// Id of the MQTT message, 0 for QoS 0 messages
+    pub id: u32,
+    pub payload: Vec<u8>,
+    /// The payload parsed as a JSON command, if it is one
+    pub command: Option<Command>,
+    /// Displayed again by the Rotation, rather than received
+    pub replayed: bool,
+    /// Where the outcome goes once the message is handled, for the ones pushed over HTTP
+    pub reply: Option<Sender<RenderReply>>,
+    pub timing: Timing,
+    /// The message to display, or why the payload can't be displayed, once routed by the thread receiving it. None
+    /// for the messages replayed and the reassembled transfers, which the main loop routes.
+    pub routed: Option<Result<DisplayMessage, &'static str>>,
+}
+
+impl MqttMessage {
+    /// A payload received on `topic`, parsed and routed to its message right away, so that the main loop only has to
+    /// display it. None when it's over the rate limit of its topic, before queueing it. The chunks are limited and
+    /// routed once their transfer is reassembled.
+    pub fn received(
+        router: &MessageRouter,
+        rate_limiter: &Mutex<RateLimiter>,
+        topic: String,
+        id: u32,
+        payload: Vec<u8>,
+        reply: Option<Sender<RenderReply>>,
+    ) -> Option<Self> {
+        let timing = Timing::received();
+        let mut message = MqttMessage {
+            topic,
+            id,
+            command: Command::parse(&payload),
+            payload,
+            replayed: false,
+            reply,
+            timing,
+            routed: None,
+        };
+        if !matches!(message.command, Some(Command::Chunk { .. })) {
+            let allowed = rate_limiter
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .allow(&message.topic);
+            if !allowed {
+                return None;
+            }
+            message.routed = Some(router.dispatch(&message));
+        }
+        Some(message)
+    }
+}
+
+/// Outcome of a message pushed over HTTP: the number of text lines drawn (0 for other content), or why the payload
+/// can't be displayed
+pub type RenderReply = Result<usize, &'static str>;
+
+/// What happens to a message received while the queue to the main loop is full, see QUEUE_FULL_POLICY
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueueFullPolicy {
+    /// The oldest message queued is dropped to make room for it, favoring fresh content
+    DropOldest,
+    /// It's dropped, the messages queued are kept
+    DropNewest,
+}
+
+/// Bounded queue of the received messages, from the MQTT client and the HTTP endpoint to the main loop, holding up to
+/// MESSAGE_QUEUE_CAPACITY of them
+pub fn message_queue() -> (MessageSender, MessageReceiver) {
+    let queue = Arc::new(MessageQueue {
+        messages: Mutex::new(VecDeque::with_capacity(MESSAGE_QUEUE_CAPACITY)),
+        queued: Condvar::new(),
+    });
+    (
+        MessageSender {
+            queue: Arc::downgrade(&queue),
+        },
+        MessageReceiver(queue),
+    )
+}
+
+/// Messages shared by both halves of the message_queue
+struct MessageQueue {
+    messages: Mutex<VecDeque<MqttMessage>>,
+    /// Notified on each message queued, for the main loop waiting on an empty queue
+    queued: Condvar,
+}
+
+/// Sending half of the message_queue, applying QUEUE_FULL_POLICY when it's full
+#[derive(Clone)]
+pub struct MessageSender {
+    // Owned by the MessageReceiver, the queue is gone along with the main loop. The lock on its messages is only held
+    // to add or take one: waiting for a message releases it.
+    queue: Weak<MessageQueue>,
+}
+
+impl MessageSender {
+    /// Queues `message` for the main loop, without blocking: when the queue is full, either it or the oldest message
+    /// queued is dropped, as logged. A message pushed over HTTP is then answered with "queue_full".
+    /// Fails once the main loop is gone.
+    pub fn send(&self, message: MqttMessage) -> anyhow::Result<()> {
+        let Some(queue) = self.queue.upgrade() else {
+            anyhow::bail!("the main loop is gone");
+        };
+        let mut messages = queue
+            .messages
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let dropped = if messages.len() < MESSAGE_QUEUE_CAPACITY {
+            messages.push_back(message);
+            None
+        } else {
+            match QUEUE_FULL_POLICY {
+                QueueFullPolicy::DropNewest => Some((message, "new")),
+                QueueFullPolicy::DropOldest => {
+                    let oldest = messages.pop_front();
+                    messages.push_back(message);
+                    oldest.map(|oldest| (oldest, "oldest"))
+                }
+            }
+        };
+        drop(messages);
+        queue.queued.notify_one();
+        if let Some((message, which)) = dropped {
+            drop_queued(message, which);
+        }
+        Ok(())
+    }
+}
+
+/// Drops the `which` message of a full queue, answering its HTTP request
+fn drop_queued(message: MqttMessage, which: &str) {
+    warn!(
+        "The queue of {} messages is full, dropping the {} one, received on \"{}\"",
+        MESSAGE_QUEUE_CAPACITY, which, message.topic
+    );
+    send_reply(&message.reply, Err("queue_full"));
+}
+
+/// Receiving half of the message_queue, for the main loop. Since the main loop also holds a MessageSender, the queue
+/// is never disconnected: only timeouts and empty queues are reported.
+pub struct MessageReceiver(Arc<MessageQueue>);
+
+impl MessageReceiver {
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<MqttMessage, RecvTimeoutError> {
+        let messages = self
+            .0
+            .messages
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let (mut messages, _) = self
+            .0
+            .queued
+            .wait_timeout_while(messages, timeout, |messages| messages.is_empty())
+            .unwrap_or_else(PoisonError::into_inner);
+        messages.pop_front().ok_or(RecvTimeoutError::Timeout)
+    }
+
+    pub fn try_recv(&self) -> Result<MqttMessage, TryRecvError> {
+        self.0
+            .messages
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop_front()
+            .ok_or(TryRecvError::Empty)
+    }
+}
+
+/// Structured command published as JSON, e.g. {"type":"text","content":"Hello","size":"large","align":"center"}
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Command {
+    /// Replaces the content, or is drawn over it from (x, y) when both are given, as "@<x>,<y> <text>"
+    Text {
+        content: String,
+        #[serde(default)]
+        size: Option<FontSize>,
+        #[serde(default)]
+        align: Option<HorizontalAlignment>,
+        #[serde(default)]
+        x: Option<u32>,
+        #[serde(default)]
+        y: Option<u32>,
+    },
+    Clear,
+    Clock,
+    /// A countdown of `seconds`, as "timer:<seconds>"
+    Timer {
+        seconds: u64,
+    },
+    Qr {
+        data: String,
+    },
+    /// Sets the header kept at the top of the display
+    Header {
+        content: String,
+        #[serde(default)]
+        size: Option<FontSize>,
+        #[serde(default)]
+        align: Option<HorizontalAlignment>,
+    },
+    ClearHeader,
+    /// A base64-encoded BMP file
+    Image {
+        base64: String,
+    },
+    /// A weather card, e.g. {"type":"weather","temp":21,"cond":"rain","hi":24,"lo":15}
+    Weather(Weather),
+    /// Shapes drawn over the current content, as "draw:<commands>"
+    Draw {
+        commands: String,
+    },
+    /// Icons drawn over the current content, as "icon:<icons>"
+    Icon {
+        icons: String,
+    },
+    /// The commands of a JSON array, drawn in order into the frame and shown by a single refresh
+    #[serde(skip)]
+    Batch(Vec<serde_json::Value>),
+    /// A slice of a payload sent in `total` chunks, reassembled by Transfers
+    Chunk {
+        id: String,
+        index: u32,
+        total: u32,
+        data: String,
+    },
+}
+
+impl Command {
+    /// Parses a JSON command, or a JSON array of them as a batch. Returns None for anything else, such as the plain
+    /// text and prefixed payloads.
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        // Only objects and arrays can be commands, this avoids parsing images and frames
+        let parsed = match payload.iter().find(|byte| !byte.is_ascii_whitespace()) {
+            Some(b'{') => serde_json::from_slice(payload),
+            // The commands of a batch are parsed one by one, so that an invalid one doesn't drop the others
+            Some(b'[') => serde_json::from_slice(payload).map(Command::Batch),
+            _ => return None,
+        };
+        match parsed {
+            Ok(command) => Some(command),
+            Err(e) => {
+                info!("Not a JSON command, handling the payload as is: {}", e);
+                None
+            }
+        }
+    }
+
+    /// The message displaying the command, or why it can't be displayed
+    pub fn into_message(self) -> Result<DisplayMessage, &'static str> {
+        match self {
+            Command::Text {
+                content,
+                size,
+                align,
+                x,
+                y,
+            } => {
+                let text = with_options(&content, size, align);
+                match (x, y) {
+                    (None, None) => Ok(DisplayMessage::Text(text)),
+                    (Some(x), Some(y)) => Ok(DisplayMessage::PositionedText { x, y, text }),
+                    _ => {
+                        warn!("Dropping a text command with only one of its x and y coordinates");
+                        Err("invalid_position")
+                    }
+                }
+            }
+            Command::Clear => Ok(DisplayMessage::Clear),
+            Command::Clock => Ok(DisplayMessage::Clock),
+            Command::Timer { seconds } => timer_message(seconds),
+            Command::Qr { data } => Ok(DisplayMessage::Text(format!("qr:{}", data))),
+            Command::Header {
+                content,
+                size,
+                align,
+            } => Ok(DisplayMessage::Header(Some(with_options(
+                &content, size, align,
+            )))),
+            Command::ClearHeader => Ok(DisplayMessage::Header(None)),
+            Command::Image { base64 } => match BASE64.decode(base64) {
+                Ok(image) if is_bmp(&image) => Ok(DisplayMessage::Bitmap(image)),
+                Ok(_) => {
+                    warn!("Dropping an image command which isn't a BMP file");
+                    Err("invalid_image")
+                }
+                Err(e) => {
+                    warn!("Dropping an image command with invalid base64: {}", e);
+                    Err("invalid_base64")
+                }
+            },
+            Command::Weather(weather) => Ok(DisplayMessage::Weather(weather)),
+            Command::Draw { commands } => Ok(DisplayMessage::Drawing(commands)),
+            Command::Icon { icons } => Ok(DisplayMessage::Drawing(icon_commands(&icons))),
+            Command::Batch(commands) => {
+                let count = commands.len();
+                let messages: Vec<_> = commands
+                    .into_iter()
+                    .enumerate()
+                    .filter_map(|(index, command)| {
+                        let message = serde_json::from_value::<Command>(command)
+                            .map_err(|e| e.to_string())
+                            .and_then(|command| command.into_message().map_err(str::to_string));
+                        match message {
+                            // Kept up to date on their own, a clock or a timer can't be part of a frame drawn once
+                            Ok(DisplayMessage::Clock | DisplayMessage::Timer(_)) => {
+                                warn!("Skipping the clock or timer command {} of a batch", index);
+                                None
+                            }
+                            Ok(message) => Some(message),
+                            Err(e) => {
+                                warn!("Skipping the invalid command {} of a batch: {}", index, e);
+                                None
+                            }
+                        }
+                    })
+                    .collect();
+                if messages.is_empty() {
+                    warn!("Dropping a batch without any valid command");
+                    return Err("empty_batch");
+                }
+                Ok(DisplayMessage::Batch {
+                    skipped: count - messages.len(),
+                    messages,
+                })
+            }
+            // The chunks are handled before the messages are dispatched, this is a stray one
+            Command::Chunk { id, .. } => {
+                warn!(
+                    "Dropping a chunk of the transfer \"{}\" out of its transfer",
+                    id
+                );
+                Err("unexpected_chunk")
+            }
+        }
+    }
+}
+
+/// `content` prefixed with the options of a JSON command, as given to the renderer by plain text messages
+fn with_options(
+    content: &str,
+    size: Option<FontSize>,
+    align: Option<HorizontalAlignment>,
+) -> String {
+    let mut text = String::new();
+    if let Some(size) = size {
+        text.push_str(&format!("size={};", size.name()));
+    }
+    if let Some(align) = align {
+        text.push_str(&format!("align={};", align.name()));
+    }
+    text.push_str(content);
+    text
+}
+
+/// Queues a received message, discarding the message of the same topic it supersedes, if any. Drawings, icons,
+/// positioned texts and batches (which may only draw over it) are drawn over the current content, so they never
+/// supersede anything. Headers are kept apart from the content, and chunks only
+/// make up a message once reassembled, so they neither supersede nor get superseded. The same goes for the drawn,
+/// icon and header actions of the command topics. Returns whether a message was discarded.
+pub fn coalesce(pending: &mut VecDeque<MqttMessage>, received: MqttMessage) -> bool {
+    let kept_apart = |message: &MqttMessage| match command_action(&message.topic) {
+        _ if matches!(message.command, Some(Command::Chunk { .. })) => true,
+        Some(action) => action == "header",
+        None => {
+            message.payload.starts_with(b"header:")
+                || matches!(
+                    message.command,
+                    Some(Command::Header { .. } | Command::ClearHeader)
+                )
+        }
+    };
+    let drawn_over = |message: &MqttMessage| match command_action(&message.topic) {
+        Some(action) => matches!(action, "draw" | "icon"),
+        None => {
+            message.payload.starts_with(b"draw:")
+                || message.payload.starts_with(b"icon:")
+                || std::str::from_utf8(&message.payload)
+                    .ok()
+                    .and_then(parse_position)
+                    .is_some()
+                || matches!(
+                    message.command,
+                    Some(
+                        Command::Text {
+                            x: Some(_),
+                            y: Some(_),
+                            ..
+                        } | Command::Draw { .. }
+                            | Command::Icon { .. }
+                            | Command::Batch(_)
+                    )
+                )
+        }
+    };
+    let superseded = match drawn_over(&received) || kept_apart(&received) {
+        true => None,
+        false => pending
+            .iter()
+            .position(|queued| queued.topic == received.topic && !kept_apart(queued)),
+    };
+    if let Some(index) = superseded {
+        pending.remove(index);
+    }
+    pending.push_back(received);
+    superseded.is_some()
+}
+
+/// Token bucket of a topic, holding up to `capacity` messages and refilled at `capacity` per `interval`
+struct TokenBucket {
+    topic: String,
+    tokens: f32,
+    capacity: f32,
+    interval: Duration,
+    refilled: Instant,
+    /// Messages dropped since the last notice
+    dropped: u32,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f32();
+        self.tokens = (self.tokens + elapsed * self.capacity / self.interval.as_secs_f32())
+            .min(self.capacity);
+        self.refilled = now;
+    }
+}
+
+/// Rate limits of the topics, by RATE_LIMITS and RATE_LIMIT_DEFAULT
+pub struct RateLimiter {
+    /// Buckets of the topics which aren't full, or have dropped messages not noticed yet. A full bucket is the
+    /// same as a new one, so they're dropped.
+    buckets: Vec<TokenBucket>,
+    last_notice: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        RateLimiter {
+            buckets: Vec::new(),
+            last_notice: None,
+        }
+    }
+
+    /// Takes a token for a message received on `topic`, returning false when the topic is over its limit
+    pub fn allow(&mut self, topic: &str) -> bool {
+        let limit = RATE_LIMITS
+            .iter()
+            .find(|(filter, _, _)| topic_matches(filter, topic))
+            .map(|&(_, messages, interval)| (messages, interval))
+            .or(RATE_LIMIT_DEFAULT);
+        let Some((messages, interval)) = limit.filter(|(_, interval)| !interval.is_zero()) else {
+            return true;
+        };
+        let now = Instant::now();
+        self.buckets.retain_mut(|bucket| {
+            bucket.refill(now);
+            bucket.tokens < bucket.capacity || bucket.dropped > 0
+        });
+        let index = match self.buckets.iter().position(|bucket| bucket.topic == topic) {
+            Some(index) => index,
+            None => {
+                self.buckets.push(TokenBucket {
+                    topic: topic.to_string(),
+                    tokens: messages as f32,
+                    capacity: messages as f32,
+                    interval,
+                    refilled: now,
+                    dropped: 0,
+                });
+                self.buckets.len() - 1
+            }
+        };
+        let bucket = &mut self.buckets[index];
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return true;
+        }
+        bucket.dropped += 1;
+        warn!(
+            "Dropping a message received on \"{}\", over its limit of {} messages per {}s",
+            topic,
+            messages,
+            interval.as_secs()
+        );
+        false
+    }
+
+    /// The messages dropped by topic since the last notice, if any, once RATE_LIMIT_NOTICE_INTERVAL has passed since it
+    pub fn notice(&mut self) -> Option<Vec<(String, u32)>> {
+        if self
+            .last_notice
+            .map_or(false, |last| last.elapsed() < RATE_LIMIT_NOTICE_INTERVAL)
+        {
+            return None;
+        }
+        let dropped: Vec<_> = self
+            .buckets
+            .iter_mut()
+            .filter(|bucket| bucket.dropped > 0)
+            .map(|bucket| (bucket.topic.clone(), mem::take(&mut bucket.dropped)))
+            .collect();
+        if dropped.is_empty() {
+            return None;
+        }
+        self.last_notice = Some(Instant::now());
+        Some(dropped)
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Publishes how many messages each topic had dropped over its rate limit on "<topic>/<MQTT_RATE_LIMIT_SUBTOPIC>",
+/// e.g. {"dropped":{"epaper/news":12}}
+#[cfg(target_os = "espidf")]
+pub fn publish_rate_limited(
+    client: &mut MqttClient,
+    topic: &str,
+    dropped: &[(String, u32)],
+) -> Result<(), EspError> {
+    let dropped: serde_json::Map<_, _> = dropped
+        .iter()
+        .map(|(topic, count)| (topic.clone(), (*count).into()))
+        .collect();
+    let payload = serde_json::json!({ "dropped": dropped }).to_string();
+    client.publish(
+        &format!("{}/{}", topic, MQTT_RATE_LIMIT_SUBTOPIC),
+        MQTT_PUBLISH_QOS,
+        false,
+        payload.as_bytes(),
+    )?;
+    info!("Published the rate limit notice {}", payload);
+    Ok(())
+}
+
+/// Payload sent in chunks, being reassembled
+struct Transfer {
+    id: String,
+    /// Decoded chunks, by index
+    chunks: Vec<Option<Vec<u8>>>,
+    size: usize,
+    last_chunk: Instant,
+}
+
+impl Transfer {
+    /// Indexes of the chunks which haven't arrived
+    fn missing(&self) -> Vec<u32> {
+        (0..self.chunks.len() as u32)
+            .filter(|&index| self.chunks[index as usize].is_none())
+            .collect()
+    }
+}
+
+/// What a received chunk did to its transfer
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChunkOutcome {
+    /// Chunks are still missing
+    Pending,
+    /// The chunk had arrived already, it's ignored
+    Duplicate,
+    /// The last missing chunk arrived, the message now holds the reassembled payload
+    Complete,
+    /// The transfer is discarded, for this reason
+    Failed(&'static str),
+}
+
+/// Chunked transfers being reassembled, by their id
+pub struct Transfers {
+    transfers: Vec<Transfer>,
+}
+
+impl Transfers {
+    pub fn new() -> Self {
+        Transfers {
+            transfers: Vec::new(),
+        }
+    }
+
+    /// Adds `received` to its transfer if it's a chunk, returning the transfer id and what the chunk did. The first
+    /// chunk received, whatever its index, starts the transfer. Once complete, `received` holds the reassembled
+    /// payload and is handled like any other message of its topic.
+    pub fn receive(&mut self, received: &mut MqttMessage) -> Option<(String, ChunkOutcome)> {
+        let Some(Command::Chunk {
+            id,
+            index,
+            total,
+            data,
+        }) = &received.command
+        else {
+            return None;
+        };
+        let id = id.clone();
+        let outcome = match self.add(&id, *index, *total, data) {
+            Ok(Some(payload)) => {
+                info!(
+                    "Reassembled the {} bytes of the transfer \"{}\"",
+                    payload.len(),
+                    id
+                );
+                received.command = Command::parse(&payload);
+                received.payload = payload;
+                ChunkOutcome::Complete
+            }
+            Ok(None) => ChunkOutcome::Pending,
+            Err(ChunkOutcome::Duplicate) => {
+                debug!("Ignoring the duplicate chunk {} of \"{}\"", index, id);
+                ChunkOutcome::Duplicate
+            }
+            Err(outcome) => {
+                warn!("Discarding the transfer \"{}\": {:?}", id, outcome);
+                outcome
+            }
+        };
+        Some((id, outcome))
+    }
+
+    /// Stores a chunk, returning the payload once all the chunks have arrived.
+    /// The transfer is dropped on failure, and kept on duplicates.
+    fn add(
+        &mut self,
+        id: &str,
+        index: u32,
+        total: u32,
+        data: &str,
+    ) -> Result<Option<Vec<u8>>, ChunkOutcome> {
+        let position = self.transfers.iter().position(|transfer| transfer.id == id);
+        let position = match position {
+            Some(position) if self.transfers[position].chunks.len() != total as usize => {
+                self.transfers.remove(position);
+                return Err(ChunkOutcome::Failed("total_mismatch"));
+            }
+            Some(position) => position,
+            None if total == 0 || total > CHUNK_MAX_COUNT => {
+                return Err(ChunkOutcome::Failed("invalid_total"))
+            }
+            None if self.transfers.len() >= CHUNK_MAX_TRANSFERS => {
+                return Err(ChunkOutcome::Failed("too_many_transfers"))
+            }
+            None => {
+                self.transfers.push(Transfer {
+                    id: id.to_string(),
+                    chunks: vec![None; total as usize],
+                    size: 0,
+                    last_chunk: Instant::now(),
+                });
+                self.transfers.len() - 1
+            }
+        };
+        if index >= total {
+            self.transfers.remove(position);
+            return Err(ChunkOutcome::Failed("invalid_index"));
+        }
+        let transfer = &mut self.transfers[position];
+        if transfer.chunks[index as usize].is_some() {
+            return Err(ChunkOutcome::Duplicate);
+        }
+        let chunk = match BASE64.decode(data) {
+            Ok(chunk) if transfer.size + chunk.len() <= MAX_PAYLOAD_SIZE => chunk,
+            Ok(_) => {
+                self.transfers.remove(position);
+                return Err(ChunkOutcome::Failed("too_large"));
+            }
+            Err(_) => {
+                self.transfers.remove(position);
+                return Err(ChunkOutcome::Failed("invalid_base64"));
+            }
+        };
+        transfer.size += chunk.len();
+        transfer.chunks[index as usize] = Some(chunk);
+        transfer.last_chunk = Instant::now();
+        debug!(
+            "Received the chunk {} of \"{}\", {} missing",
+            index,
+            id,
+            transfer.missing().len()
+        );
+        if transfer.chunks.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+        let transfer = self.transfers.remove(position);
+        let mut payload = Vec::with_capacity(transfer.size);
+        for chunk in transfer.chunks.into_iter().flatten() {
+            payload.extend_from_slice(&chunk);
+        }
+        Ok(Some(payload))
+    }
+
+    /// Discards the transfers which haven't received a chunk for CHUNK_TIMEOUT, returning their id and missing chunks
+    pub fn expire(&mut self) -> Vec<(String, Vec<u32>)> {
+        let mut expired = Vec::new();
+        self.transfers.retain(|transfer| {
+            let alive = transfer.last_chunk.elapsed() < CHUNK_TIMEOUT;
+            if !alive {
+                warn!(
+                    "Discarding the transfer \"{}\", missing the chunks {:?}",
+                    transfer.id,
+                    transfer.missing()
+                );
+                expired.push((transfer.id.clone(), transfer.missing()));
+            }
+            alive
+        });
+        expired
+    }
+
+    /// When the next transfer times out, if any is in progress
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.transfers
+            .iter()
+            .map(|transfer| transfer.last_chunk + CHUNK_TIMEOUT)
+            .min()
+    }
+}
+
+impl Default for Transfers {
+    fn default() -> Self {
+        Transfers::new()
+    }
+}
+
+/// Outcome of a chunked transfer, as published
+pub enum TransferStatus<'a> {
+    Reassembled { size: usize },
+    Discarded { reason: &'a str, missing: &'a [u32] },
+}
+
+/// Publishes the outcome of the chunked transfer `id` on "<topic>/<MQTT_TRANSFER_SUBTOPIC>", e.g.
+/// {"id":"img1","status":"ack","size":38462} or {"id":"img1","status":"nack","reason":"timeout","missing":[3,7]}.
+/// Statuses are dropped while the MQTT client is being re-established, the sender retries on its own timeout then.
+#[cfg(target_os = "espidf")]
+pub fn publish_transfer_status(
+    client: Option<&mut MqttClient>,
+    topic: &str,
+    id: &str,
+    status: TransferStatus,
+) {
+    let payload = match status {
+        TransferStatus::Reassembled { size } => {
+            serde_json::json!({ "id": id, "status": "ack", "size": size })
+        }
+        TransferStatus::Discarded { reason, missing } => serde_json::json!({
+            "id": id,
+            "status": "nack",
+            "reason": reason,
+            "missing": missing,
+        }),
+    }
+    .to_string();
+    let Some(client) = client else {
+        warn!("Dropping the transfer status {}, no MQTT client", payload);
+        return;
+    };
+    match client.publish(
+        &format!("{}/{}", topic, MQTT_TRANSFER_SUBTOPIC),
+        MQTT_PUBLISH_QOS,
+        false,
+        payload.as_bytes(),
+    ) {
+        Ok(_) => info!("Published the transfer status {}", payload),
+        Err(e) => warn!("Couldn't publish the transfer status {}: {}", payload, e),
+    }
+}
+
+/// Last messages displayed on different topics, cycled through while no message arrives, with ROTATION_INTERVAL
+pub struct Rotation {
+    slots: VecDeque<MqttMessage>,
+    current: usize,
+    last_shown: Instant,
+}
+
+impl Rotation {
+    pub fn new() -> Self {
+        Rotation {
+            slots: VecDeque::new(),
+            current: 0,
+            last_shown: Instant::now(),
+        }
+    }
+
+    /// Keeps `received`, just displayed, in place of the previous message of its topic, or in a new slot, dropping the
+    /// oldest one beyond ROTATION_SLOTS. The next one is shown a whole interval later.
+    pub fn shown(&mut self, received: MqttMessage) {
+        match self
+            .slots
+            .iter()
+            .position(|slot| slot.topic == received.topic)
+        {
+            Some(index) => {
+                self.slots[index] = received;
+                self.current = index;
+            }
+            None => {
+                if self.slots.len() >= ROTATION_SLOTS {
+                    self.slots.pop_front();
+                }
+                self.slots.push_back(received);
+                self.current = self.slots.len() - 1;
+            }
+        }
+        self.last_shown = Instant::now();
+    }
+
+    /// Forgets the messages, once the panel has been cleared
+    pub fn clear(&mut self) {
+        self.slots.clear();
+        self.current = 0;
+    }
+
+    /// When the next message is due, None without ROTATION_INTERVAL or with less than two messages
+    pub fn next_due(&self) -> Option<Instant> {
+        let interval = ROTATION_INTERVAL?;
+        (self.slots.len() >= 2).then(|| self.last_shown + interval)
+    }
+
+    /// The next message once it's due, to be displayed again
+    pub fn advance(&mut self) -> Option<MqttMessage> {
+        if self.next_due()? > Instant::now() {
+            return None;
+        }
+        self.current = (self.current + 1) % self.slots.len();
+        self.last_shown = Instant::now();
+        let mut next = self.slots[self.current].clone();
+        next.replayed = true;
+        Some(next)
+    }
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Rotation::new()
+    }
+}
+
+/// Messages making up the content of the panel, displayed again once the display is rotated: the last one replacing
+/// the whole content, then the regions and drawings over it, up to REDRAW_MAX_MESSAGES. Also keeps the header, for
+/// the screen state.
+pub struct PanelContent {
+    messages: Vec<MqttMessage>,
+    header: Option<String>,
+}
+
+impl PanelContent {
+    pub fn new() -> Self {
+        PanelContent {
+            messages: Vec::new(),
+            header: None,
+        }
+    }
+
+    /// Keeps the header set, with its options, or None once it's removed
+    pub fn set_header(&mut self, header: Option<String>) {
+        self.header = header;
+    }
+
+    /// The screen state of the content, without its messages when one of them isn't text, e.g. an image
+    pub fn state(&self) -> ScreenState {
+        let messages: Option<Vec<(String, String)>> = self
+            .messages
+            .iter()
+            .map(|shown| {
+                Some((
+                    shown.topic.clone(),
+                    String::from_utf8(shown.payload.clone()).ok()?,
+                ))
+            })
+            .collect();
+        ScreenState {
+            header: self.header.clone(),
+            messages: messages.unwrap_or_default(),
+        }
+    }
+
+    /// Keeps `received`, just displayed as `message`. A region replaces the previous text of its topic.
+    pub fn shown(&mut self, received: MqttMessage, message: &DisplayMessage) {
+        match message {
+            DisplayMessage::RegionText { .. } => {
+                self.messages.retain(|shown| shown.topic != received.topic)
+            }
+            DisplayMessage::PositionedText { .. } | DisplayMessage::Drawing(_) => {}
+            DisplayMessage::Batch { .. } if !message.clears_content() => {}
+            _ => self.messages.clear(),
+        }
+        if self.messages.len() >= REDRAW_MAX_MESSAGES {
+            self.messages.remove(0);
+        }
+        self.messages.push(received);
+    }
+
+    /// Forgets the messages, once something else has replaced them on the panel
+    pub fn clear(&mut self) {
+        self.messages.clear();
+    }
+
+    /// Takes the messages to display again, in order. They're replayed, so they aren't acknowledged twice.
+    pub fn replay(&mut self) -> Vec<MqttMessage> {
+        let mut messages = mem::take(&mut self.messages);
+        for message in &mut messages {
+            message.replayed = true;
+        }
+        messages
+    }
+}
+
+impl Default for PanelContent {
+    fn default() -> Self {
+        PanelContent::new()
+    }
+}
+
+/// Composed content of the screen, kept in NVS so that it's drawn again after a reboot or a wake from deep sleep
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScreenState {
+    /// Header message, with its options
+    pub header: Option<String>,
+    /// Topic and payload of the messages on the panel, in the order they were displayed
+    pub messages: Vec<(String, String)>,
+}
+
+impl ScreenState {
+    /// The messages to display again, in order. They're replayed, so they aren't acknowledged.
+    pub fn replay(&self) -> Vec<MqttMessage> {
+        self.messages
+            .iter()
+            .map(|(topic, payload)| MqttMessage {
+                topic: topic.clone(),
+                id: 0,
+                payload: payload.as_bytes().to_vec(),
+                command: Command::parse(payload.as_bytes()),
+                replayed: true,
+                reply: None,
+                timing: Timing::received(),
+                routed: None,
+            })
+            .collect()
+    }
+}
+
+// Key of the screen state in NVS_SCREEN_NAMESPACE, as JSON
+const NVS_SCREEN_KEY: &str = "state";
+
+/// Keeps the screen state in NVS, writing it only when it changes
+#[cfg(target_os = "espidf")]
+pub struct ScreenStore {
+    nvs: EspDefaultNvs,
+    saved: Option<ScreenState>,
+}
+
+#[cfg(target_os = "espidf")]
+impl ScreenStore {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self, EspError> {
+        Ok(ScreenStore {
+            nvs: EspDefaultNvs::new(partition, NVS_SCREEN_NAMESPACE, true)?,
+            saved: None,
+        })
+    }
+
+    /// The screen state saved before the reboot or the deep sleep, if any
+    pub fn load(&mut self) -> Option<ScreenState> {
+        let mut buffer = vec![0; SCREEN_STATE_MAX_LEN];
+        let state = match self.nvs.get_blob(NVS_SCREEN_KEY, &mut buffer) {
+            Ok(Some(json)) => serde_json::from_slice::<ScreenState>(json)
+                .map_err(|e| warn!("Discarding the invalid screen state: {}", e))
+                .ok(),
+            Ok(None) => None,
+            Err(e) => {
+                warn!("Couldn't load the screen state from NVS: {}", e);
+                None
+            }
+        };
+        self.saved = state.clone();
+        state
+    }
+
+    /// Saves `state` unless it's the one saved already. States too large for SCREEN_STATE_MAX_LEN are kept without
+    /// their messages.
+    pub fn save(&mut self, state: ScreenState) {
+        if self.saved.as_ref() == Some(&state) {
+            return;
+        }
+        let mut json = serde_json::to_vec(&state).unwrap_or_default();
+        if json.len() > SCREEN_STATE_MAX_LEN {
+            warn!(
+                "The screen state takes {} bytes, more than the {} kept in NVS, saving the header only",
+                json.len(),
+                SCREEN_STATE_MAX_LEN
+            );
+            let header_only = ScreenState {
+                header: state.header.clone(),
+                messages: Vec::new(),
+            };
+            json = serde_json::to_vec(&header_only).unwrap_or_default();
+        }
+        match self.nvs.set_blob(NVS_SCREEN_KEY, &json) {
+            Ok(()) => debug!("Saved the screen state ({} bytes)", json.len()),
+            Err(e) => warn!("Couldn't save the screen state: {}", e),
+        }
+        self.saved = Some(state);
+    }
+}
+
+/// How the connection to the broker is secured, see MQTT_SECURITY
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MqttSecurity {
+    /// TLS, the device authenticating with its certificate and private key
+    MutualTls,
+    /// TLS checking the broker against the CA certificate, the device authenticating with its credentials if any
+    ServerTls,
+    /// Plain TCP, without any certificate
+    Plain,
+}
+
+impl MqttSecurity {
+    /// Whether the certificates are needed
+    pub fn uses_tls(self) -> bool {
+        self != MqttSecurity::Plain
+    }
+
+    /// Checks that the endpoint scheme matches, so that half of the connection isn't left without TLS or with it,
+    /// and that the credentials are complete
+    pub fn validate(self, config: &Config) -> anyhow::Result<()> {
+        let Some((scheme, _)) = config.mqtt_endpoint.split_once("://") else {
+            anyhow::bail!(
+                "the MQTT endpoint \"{}\" has no scheme, e.g. mqtts://",
+                config.mqtt_endpoint
+            );
+        };
+        match (scheme, self.uses_tls()) {
+            ("mqtts" | "wss", true) | ("mqtt" | "ws", false) => {}
+            ("mqtts" | "wss", false) => {
+                anyhow::bail!(
+                    "the {}:// MQTT endpoint needs TLS, but it's disabled",
+                    scheme
+                )
+            }
+            ("mqtt" | "ws", true) => anyhow::bail!(
+                "the {}:// MQTT endpoint is plain TCP, but {:?} is configured",
+                scheme,
+                self
+            ),
+            _ => anyhow::bail!("unknown MQTT endpoint scheme \"{}\"", scheme),
+        }
+        if config.mqtt_username.is_empty() && !config.mqtt_password.is_empty() {
+            anyhow::bail!("an MQTT password is set without a username");
+        }
+        if self == MqttSecurity::Plain && !config.mqtt_password.is_empty() {
+            warn!("The MQTT password is sent in clear over plain TCP");
+        }
+        Ok(())
+    }
+}
+
+/// How the messages received on a topic are displayed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TopicHandler {
+    /// Images, frames, drawings and text are told apart from the payload, text on a subtopic of the MQTT topic going to
+    /// the region of the layout of the same name
+    Auto,
+    /// The payload is drawn as text
+    Text,
+    /// The payload is drawn as a QR code
+    Qr,
+    /// The content of the display is cleared, whatever the payload
+    Clear,
+    /// The payload is the HTTPS URL of a firmware image to update to
+    FirmwareUpdate,
+    /// The payload is a control command of the device, see parse_control
+    Control,
+    /// The payload is the JSON weather drawn as a card, e.g. {"temp":21,"cond":"rain","hi":24,"lo":15}
+    Weather,
+    /// The last level of the topic is the action, the payload its data, see parse_action
+    Action,
+}
+
+/// Topic filters subscribed to, with their QoS and handler: the MQTT topic, its control and command subtopics, its
+/// region subtopics and MQTT_TOPICS
+pub fn mqtt_subscriptions(base_topic: &str) -> Vec<(String, QoS, TopicHandler)> {
+    let mut subscriptions = vec![
+        (
+            base_topic.to_string(),
+            MQTT_SUBSCRIBE_QOS,
+            TopicHandler::Auto,
+        ),
+        // Before the regions, since the first subscription matching a topic handles it
+        (
+            format!("{}/{}", base_topic, MQTT_OTA_SUBTOPIC),
+            MQTT_SUBSCRIBE_QOS,
+            TopicHandler::FirmwareUpdate,
+        ),
+        (
+            format!("{}/{}", base_topic, MQTT_CONTROL_SUBTOPIC),
+            MQTT_SUBSCRIBE_QOS,
+            TopicHandler::Control,
+        ),
+        (
+            format!("{}/{}/+", base_topic, MQTT_COMMAND_SUBTOPIC),
+            MQTT_SUBSCRIBE_QOS,
+            TopicHandler::Action,
+        ),
+        // Text for the regions of the layout
+        (
+            format!("{}/+", base_topic),
+            MQTT_SUBSCRIBE_QOS,
+            TopicHandler::Auto,
+        ),
+    ];
+    subscriptions.extend(
+        MQTT_TOPICS
+            .iter()
+            .map(|&(topic, qos, handler)| (topic.to_string(), qos, handler)),
+    );
+    subscriptions
+}
+
+/// Whether `topic` matches the MQTT topic filter, with its "+" and "#" wildcards
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut topic_levels = topic.split('/');
+    for filter_level in filter.split('/') {
+        match (filter_level, topic_levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (filter_level, Some(topic_level)) if filter_level == topic_level => {}
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+/// The subscriptions and MQTT topic the received payloads are routed by, shared by the threads receiving them and the
+/// main loop, which moves them to another topic
+#[derive(Clone)]
+pub struct MessageRouter {
+    routes: Arc<Mutex<Routes>>,
+}
+
+struct Routes {
+    subscriptions: Vec<(String, QoS, TopicHandler)>,
+    base_topic: String,
+}
+
+impl MessageRouter {
+    pub fn new(subscriptions: Vec<(String, QoS, TopicHandler)>, base_topic: String) -> Self {
+        MessageRouter {
+            routes: Arc::new(Mutex::new(Routes {
+                subscriptions,
+                base_topic,
+            })),
+        }
+    }
+
+    /// Turns a received payload into the message to display, see dispatch_message
+    pub fn dispatch(&self, received: &MqttMessage) -> Result<DisplayMessage, &'static str> {
+        let routes = self.routes.lock().unwrap_or_else(PoisonError::into_inner);
+        dispatch_message(&routes.subscriptions, &routes.base_topic, received)
+    }
+
+    /// Routes the payloads received from now on by the subscriptions of another topic
+    pub fn set(&self, subscriptions: Vec<(String, QoS, TopicHandler)>, base_topic: String) {
+        *self.routes.lock().unwrap_or_else(PoisonError::into_inner) = Routes {
+            subscriptions,
+            base_topic,
+        };
+    }
+}
+
+/// Turns a received payload into the message to display, according to the handler of the first subscription matching
+/// its topic. Payloads that can't be displayed are dropped, returning the reason published in their warning.
+fn dispatch_message(
+    subscriptions: &[(String, QoS, TopicHandler)],
+    base_topic: &str,
+    received: &MqttMessage,
+) -> Result<DisplayMessage, &'static str> {
+    let handler = subscriptions
+        .iter()
+        .find(|(filter, _, _)| topic_matches(filter, &received.topic))
+        .map_or(TopicHandler::Auto, |&(_, _, handler)| handler);
+    debug!(
+        "Dispatching the payload received on \"{}\" with {:?}",
+        received.topic, handler
+    );
+    let text = || String::from_utf8_lossy(&received.payload).into_owned();
+    match handler {
+        TopicHandler::Auto => match &received.command {
+            Some(command) => command.clone().into_message(),
+            None => parse_payload(received.payload.clone(), INVALID_UTF8_PAYLOAD),
+        }
+        .map(|message| route_message(base_topic, &received.topic, message)),
+        TopicHandler::Text => Ok(DisplayMessage::Text(text())),
+        TopicHandler::Qr => Ok(DisplayMessage::Text(format!("qr:{}", text()))),
+        TopicHandler::Clear => Ok(DisplayMessage::Clear),
+        TopicHandler::FirmwareUpdate => {
+            Ok(DisplayMessage::FirmwareUpdate(text().trim().to_string()))
+        }
+        TopicHandler::Control => parse_control(&text()),
+        TopicHandler::Weather => parse_weather(&received.payload),
+        TopicHandler::Action => {
+            let action = received.topic.rsplit('/').next().unwrap_or_default();
+            parse_action(action, received.payload.clone())
+        }
+    }
+}
+
+/// The action of a topic under the command subtopic, e.g. "qr" for "epaper/cmd/qr"
+fn command_action(topic: &str) -> Option<&str> {
+    let (parent, action) = topic.rsplit_once('/')?;
+    (parent.rsplit('/').next() == Some(MQTT_COMMAND_SUBTOPIC)).then_some(action)
+}
+
+/// Turns the data of a command topic into the message of its action: "text", "qr", "draw" (drawing commands),
+/// "icon" (icons as "<name> <x> <y> <label>", separated by semicolons or newlines), "header" (empty to remove it),
+/// "weather" (JSON), "image" (BMP file), "raw" (base64 frame), "timer" (seconds), "clear" and "clock" (whatever the data)
+fn parse_action(action: &str, payload: Vec<u8>) -> Result<DisplayMessage, &'static str> {
+    let text = || String::from_utf8_lossy(&payload).into_owned();
+    match action {
+        "text" => Ok(DisplayMessage::Text(text())),
+        "qr" => Ok(DisplayMessage::Text(format!("qr:{}", text()))),
+        "draw" => Ok(DisplayMessage::Drawing(text())),
+        "icon" => Ok(DisplayMessage::Drawing(icon_commands(&text()))),
+        "header" => {
+            let header = text();
+            Ok(DisplayMessage::Header(
+                (!header.is_empty()).then_some(header),
+            ))
+        }
+        "weather" => parse_weather(&payload),
+        "image" if is_bmp(&payload) => Ok(DisplayMessage::Bitmap(payload)),
+        "image" => {
+            warn!(
+                "Dropping an image of {} bytes which isn't a BMP file",
+                payload.len()
+            );
+            Err("invalid_image")
+        }
+        "raw" => parse_raw_frame(&payload),
+        "clear" => Ok(DisplayMessage::Clear),
+        "clock" => Ok(DisplayMessage::Clock),
+        "timer" => parse_timer(&text()),
+        _ => {
+            warn!("Dropping a payload for the unknown action \"{}\"", action);
+            Err("unknown_action")
+        }
+    }
+}
+
+// Longest countdown, drawn as 99:59:59
+const TIMER_MAX_SECONDS: u64 = 100 * 60 * 60 - 1;
+
+/// Parses the duration of a countdown, in seconds
+fn parse_timer(seconds: &str) -> Result<DisplayMessage, &'static str> {
+    match seconds.trim().parse() {
+        Ok(seconds) => timer_message(seconds),
+        Err(_) => {
+            warn!(
+                "Invalid timer \"{}\", expected a number of seconds",
+                seconds
+            );
+            Err("invalid_timer")
+        }
+    }
+}
+
+/// The countdown of `seconds`, up to TIMER_MAX_SECONDS
+fn timer_message(seconds: u64) -> Result<DisplayMessage, &'static str> {
+    if seconds > TIMER_MAX_SECONDS {
+        warn!(
+            "Dropping a timer of {}s, the longest is {}s",
+            seconds, TIMER_MAX_SECONDS
+        );
+        return Err("invalid_timer");
+    }
+    Ok(DisplayMessage::Timer(Duration::from_secs(seconds)))
+}
+
+/// Parses the JSON weather drawn as a card
+fn parse_weather(payload: &[u8]) -> Result<DisplayMessage, &'static str> {
+    match serde_json::from_slice(payload) {
+        Ok(weather) => Ok(DisplayMessage::Weather(weather)),
+        Err(e) => {
+            warn!(
+                "Dropping a weather payload which isn't a JSON object: {}",
+                e
+            );
+            Err("invalid_weather")
+        }
+    }
+}
+
+/// Parses a control command: "log=<off|error|warn|info|debug|trace>" sets the log level,
+/// "rotate=<0|90|180|270>" the rotation of the display, and "topic=<topic>" the MQTT topic of the device
+fn parse_control(command: &str) -> Result<DisplayMessage, &'static str> {
+    let command = command.trim();
+    match command.split_once('=') {
+        Some(("log", level)) => match level.parse() {
+            Ok(level) => Ok(DisplayMessage::LogLevel(level)),
+            Err(_) => {
+                warn!("Unknown log level \"{}\"", level);
+                Err("unknown_log_level")
+            }
+        },
+        Some(("rotate", degrees)) => {
+            match degrees.trim().parse().ok().and_then(rotation_from_degrees) {
+                Some(rotation) => Ok(DisplayMessage::Rotate(rotation)),
+                None => {
+                    warn!(
+                        "Invalid rotation \"{}\", expected 0, 90, 180 or 270",
+                        degrees
+                    );
+                    Err("invalid_rotation")
+                }
+            }
+        }
+        Some(("topic", topic)) if valid_topic(topic) => {
+            Ok(DisplayMessage::SetTopic(topic.to_string()))
+        }
+        Some(("topic", topic)) => {
+            warn!("Invalid MQTT topic \"{}\"", topic);
+            Err("invalid_topic")
+        }
+        None if command == "reinit" => Ok(DisplayMessage::ReinitPanel),
+        Some(("dryrun", value)) => match value.trim() {
+            "on" => Ok(DisplayMessage::DryRun(true)),
+            "off" => Ok(DisplayMessage::DryRun(false)),
+            _ => {
+                warn!("Invalid dry run \"{}\", expected on or off", value);
+                Err("invalid_dry_run")
+            }
+        },
+        _ => {
+            warn!("Unknown control command \"{}\"", command);
+            Err("unknown_command")
+        }
+    }
+}
+
+/// Content received over MQTT to be displayed
+#[derive(Clone)]
+pub enum DisplayMessage {
+    Text(String),
+    /// Text drawn into a region of the layout, leaving the rest of the frame untouched
+    RegionText {
+        region: String,
+        text: String,
+    },
+    /// A BMP image file
+    Bitmap(Vec<u8>),
+    /// A packed black/white framebuffer, pushed to the panel as-is
+    RawFrame(Vec<u8>),
+    /// Text drawn over the current content from (x, y), the top-left corner of its first line
+    PositionedText {
+        x: u32,
+        y: u32,
+        text: String,
+    },
+    /// Shapes drawn over the current content, one command per line (or separated by ';')
+    Drawing(String),
+    /// Blanks the whole panel, status bar included, until the next message. The header is drawn again.
+    Clear,
+    /// A clock of the local time, kept up to date until the next message
+    Clock,
+    /// A countdown of the duration, kept up to date until it shows DONE or the next message replaces it
+    Timer(Duration),
+    /// A weather card
+    Weather(Weather),
+    /// Updates the firmware from the HTTPS URL, showing the progress on the panel
+    FirmwareUpdate(String),
+    /// Sets the level of the logs shown, leaving the panel untouched
+    LogLevel(LevelFilter),
+    /// Rotates the display, drawing the content again in the new orientation
+    Rotate(DisplayRotation),
+    /// Moves the subscriptions to another MQTT topic, kept across reboots, leaving the panel untouched
+    SetTopic(String),
+    /// Runs the reset and init sequence of the panel again and clears it, before showing the frame again
+    ReinitPanel,
+    /// Turns the dry run on or off, see set_dry_run
+    DryRun(bool),
+    /// Sets the header kept at the top of the display, with the options of text messages, or removes it with None.
+    /// The content is laid out below it, and clearing it leaves the header.
+    Header(Option<String>),
+    /// Messages drawn in order into the frame, shown by a single refresh. Clear only blanks the content there.
+    /// `skipped` invalid commands were left out of the batch.
+    Batch {
+        messages: Vec<DisplayMessage>,
+        skipped: usize,
+    },
+}
+
+impl DisplayMessage {
+    /// Whether the message replaces the whole content of the display, rather than drawing over part of it
+    pub fn clears_content(&self) -> bool {
+        match self {
+            DisplayMessage::RegionText { .. }
+            | DisplayMessage::PositionedText { .. }
+            | DisplayMessage::Drawing(_)
+            | DisplayMessage::Header(_) => false,
+            DisplayMessage::Batch { messages, .. } => {
+                messages.iter().any(DisplayMessage::clears_content)
+            }
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Debug for DisplayMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisplayMessage::Text(text) => f.debug_tuple("Text").field(text).finish(),
+            DisplayMessage::RegionText { region, text } => f
+                .debug_struct("RegionText")
+                .field("region", region)
+                .field("text", text)
+                .finish(),
+            DisplayMessage::PositionedText { x, y, text } => f
+                .debug_struct("PositionedText")
+                .field("x", x)
+                .field("y", y)
+                .field("text", text)
+                .finish(),
+            DisplayMessage::Bitmap(bytes) => write!(f, "Bitmap({} bytes)", bytes.len()),
+            DisplayMessage::RawFrame(bytes) => write!(f, "RawFrame({} bytes)", bytes.len()),
+            DisplayMessage::Drawing(commands) => f.debug_tuple("Drawing").field(commands).finish(),
+            DisplayMessage::Clear => write!(f, "Clear"),
+            DisplayMessage::Clock => write!(f, "Clock"),
+            DisplayMessage::Timer(duration) => write!(f, "Timer({}s)", duration.as_secs()),
+            DisplayMessage::Weather(weather) => f.debug_tuple("Weather").field(weather).finish(),
+            DisplayMessage::FirmwareUpdate(url) => {
+                f.debug_tuple("FirmwareUpdate").field(url).finish()
+            }
+            DisplayMessage::LogLevel(level) => f.debug_tuple("LogLevel").field(level).finish(),
+            DisplayMessage::Rotate(rotation) => {
+                write!(f, "Rotate({})", rotation_degrees(*rotation))
+            }
+            DisplayMessage::SetTopic(topic) => f.debug_tuple("SetTopic").field(topic).finish(),
+            DisplayMessage::ReinitPanel => write!(f, "ReinitPanel"),
+            DisplayMessage::DryRun(enabled) => f.debug_tuple("DryRun").field(enabled).finish(),
+            DisplayMessage::Header(text) => f.debug_tuple("Header").field(text).finish(),
+            DisplayMessage::Batch { messages, skipped } => f
+                .debug_struct("Batch")
+                .field("messages", messages)
+                .field("skipped", skipped)
+                .finish(),
+        }
+    }
+}
+
+/// Tells apart BMP images (recognized by their header), "raw:<base64 framebuffer>" frames, "draw:<commands>",
+/// "header:<text>" (removing the header when empty), the clear command and text payloads.
+/// Payloads which aren't valid UTF-8 either are handled as set by `invalid_utf8`, i.e. INVALID_UTF8_PAYLOAD.
+fn parse_payload(
+    payload: Vec<u8>,
+    invalid_utf8: InvalidUtf8,
+) -> Result<DisplayMessage, &'static str> {
+    if is_bmp(&payload) {
+        return Ok(DisplayMessage::Bitmap(payload));
+    }
+    if let Some(encoded) = payload.strip_prefix(b"raw:") {
+        return parse_raw_frame(encoded);
+    }
+    let text = match String::from_utf8(payload) {
+        Ok(text) => text,
+        Err(e) => {
+            warn!(
+                "The payload of {} bytes isn't valid UTF-8: {}",
+                e.as_bytes().len(),
+                e.utf8_error()
+            );
+            match invalid_utf8 {
+                InvalidUtf8::Reject => return Err("invalid_utf8"),
+                InvalidUtf8::Lossy => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+                InvalidUtf8::HexDump => return Ok(DisplayMessage::Text(hex_dump(e.as_bytes()))),
+            }
+        }
+    };
+    if text == CLEAR_PAYLOAD {
+        return Ok(DisplayMessage::Clear);
+    }
+    if text == CLOCK_PAYLOAD {
+        return Ok(DisplayMessage::Clock);
+    }
+    if let Some(seconds) = text.strip_prefix("timer:") {
+        return parse_timer(seconds);
+    }
+    if let Some(header) = text.strip_prefix("header:") {
+        return Ok(DisplayMessage::Header(
+            (!header.is_empty()).then(|| header.to_string()),
+        ));
+    }
+    if let Some((x, y, positioned)) = parse_position(&text) {
+        return Ok(DisplayMessage::PositionedText {
+            x,
+            y,
+            text: positioned.to_string(),
+        });
+    }
+    if let Some(icons) = text.strip_prefix("icon:") {
+        return Ok(DisplayMessage::Drawing(icon_commands(icons)));
+    }
+    Ok(match text.strip_prefix("draw:") {
+        Some(commands) => DisplayMessage::Drawing(commands.to_string()),
+        None => DisplayMessage::Text(text),
+    })
+}
+
+/// Decodes a base64 frame, which must be RAW_FRAME_LEN bytes once decoded
+fn parse_raw_frame(encoded: &[u8]) -> Result<DisplayMessage, &'static str> {
+    match BASE64.decode(encoded) {
+        Ok(packed) if packed.len() == RAW_FRAME_LEN => Ok(DisplayMessage::RawFrame(packed)),
+        Ok(packed) => {
+            warn!(
+                "Dropping a raw frame of {} bytes, expected {} bytes",
+                packed.len(),
+                RAW_FRAME_LEN
+            );
+            Err("invalid_raw_frame")
+        }
+        Err(e) => {
+            warn!("Dropping a raw frame with invalid base64: {}", e);
+            Err("invalid_base64")
+        }
+    }
+}
+
+/// The icon commands of a drawing for icons separated by semicolons or newlines, drawn over the current content
+fn icon_commands(icons: &str) -> String {
+    let commands: Vec<String> = icons
+        .split(['\n', ';'])
+        .map(str::trim)
+        .filter(|icon| !icon.is_empty())
+        .map(|icon| format!("icon {}", icon))
+        .collect();
+    commands.join("\n")
+}
+
+/// Splits a "@<x>,<y> <text>" message into the position of the text and the text, with its options.
+/// None when it doesn't start with a position, e.g. "@home".
+fn parse_position(text: &str) -> Option<(u32, u32, &str)> {
+    let (position, text) = text.strip_prefix('@')?.split_once(char::is_whitespace)?;
+    let (x, y) = position.split_once(',')?;
+    Some((x.parse().ok()?, y.parse().ok()?, text))
+}
+
+/// How the payloads which aren't valid UTF-8, nor images or raw frames, are handled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvalidUtf8 {
+    /// Dropped, publishing an "invalid_utf8" warning
+    Reject,
+    /// Drawn as text, the invalid sequences replaced by MISSING_GLYPH
+    Lossy,
+    /// Drawn as a hex dump, in the small font
+    HexDump,
+}
+
+// Longest part of a payload drawn as a hex dump, more than the panels fit
+const HEX_DUMP_MAX_BYTES: usize = 1024;
+
+/// Text message of the offsets, bytes and printable characters of `payload`, 16 bytes per line.
+/// The text is wrapped on whitespace, which collapses runs of it, so the spaces are dumped as dots too.
+fn hex_dump(payload: &[u8]) -> String {
+    let rows: Vec<String> = payload
+        .chunks(16)
+        .take(HEX_DUMP_MAX_BYTES / 16)
+        .enumerate()
+        .map(|(index, row)| {
+            let hex: Vec<String> = row.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let printable: String = row
+                .iter()
+                .map(|&byte| match byte {
+                    b'!'..=b'~' => byte as char,
+                    _ => '.',
+                })
+                .collect();
+            format!("{:04x} {} |{}|", index * 16, hex.join(" "), printable)
+        })
+        .collect();
+    format!("size=small;{}", rows.join("\n"))
+}
+
+/// Turns text published on "<base topic>/<region name>" into text for that region of the layout
+fn route_message(base_topic: &str, topic: &str, message: DisplayMessage) -> DisplayMessage {
+    let region = topic
+        .strip_prefix(base_topic)
+        .and_then(|suffix| suffix.strip_prefix('/'));
+    match (region, message) {
+        (Some(region), DisplayMessage::Text(text)) => DisplayMessage::RegionText {
+            region: region.to_string(),
+            text,
+        },
+        (_, message) => message,
+    }
+}
+
+/// Whether the payload starts with a BMP file header matching its length
+fn is_bmp(payload: &[u8]) -> bool {
+    payload.len() >= 14
+        && payload.starts_with(b"BM")
+        && u32::from_le_bytes([payload[2], payload[3], payload[4], payload[5]]) as usize
+            == payload.len()
+}
+
+// Start of a PEM certificate or key, e.g. "-----BEGIN CERTIFICATE-----"
+const PEM_BEGIN: &[u8] = b"-----BEGIN ";
+
+/// PEM certificates and key of the device, NUL terminated, None when they're neither provisioned nor embedded
+#[derive(Clone, Copy)]
+pub struct Certificates {
+    /// AWS root CA certificate
+    pub ca: Option<&'static [u8]>,
+    /// Device certificate
+    pub thing: Option<&'static [u8]>,
+    pub private_key: Option<&'static [u8]>,
+}
+
+impl Certificates {
+    /// Certificates embedded in the firmware, with the embedded-certs feature
+    pub const EMBEDDED: Certificates = Certificates {
+        ca: CA_CERT,
+        thing: THING_CERT,
+        private_key: THING_PRIVATE_KEY,
+    };
+
+    /// Reads the certificates from the NVS_CERTS_PARTITION partition, under the `ca_cert`, `thing_cert` and
+    /// `thing_key` keys of the NVS_CERTS_NAMESPACE namespace. Each one missing falls back to the embedded one, if any.
+    /// Meant to be called once at boot: the certificates read are kept for the whole program, since the client
+    /// borrows them however many times it's set up again.
+    #[cfg(target_os = "espidf")]
+    pub fn load() -> Certificates {
+        let nvs = match EspCustomNvsPartition::take(NVS_CERTS_PARTITION)
+            .and_then(|partition| EspCustomNvs::new(partition, NVS_CERTS_NAMESPACE, false))
+        {
+            Ok(nvs) => nvs,
+            Err(e) => {
+                info!(
+                    "No certificates provisioned in the {} partition ({}), using the embedded ones",
+                    NVS_CERTS_PARTITION, e
+                );
+                return Self::EMBEDDED;
+            }
+        };
+        let load = |key, embedded| match load_certificate(&nvs, key) {
+            Ok(Some(certificate)) => {
+                info!("Using the {} provisioned in NVS", key);
+                Some(&*Box::leak(certificate.into_boxed_slice()))
+            }
+            Ok(None) => embedded,
+            Err(e) => {
+                error!(
+                    "Failed to read {} from NVS, using the embedded one: {:#}",
+                    key, e
+                );
+                embedded
+            }
+        };
+        Certificates {
+            ca: load("ca_cert", CA_CERT),
+            thing: load("thing_cert", THING_CERT),
+            private_key: load("thing_key", THING_PRIVATE_KEY),
+        }
+    }
+}
+
+/// Reads the `key` blob, NUL terminated as the TLS stack expects, or None when it's missing or empty
+#[cfg(target_os = "espidf")]
+fn load_certificate(nvs: &EspCustomNvs, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+    let Some(len) = nvs.blob_len(key)?.filter(|&len| len > 0) else {
+        return Ok(None);
+    };
+    let mut certificate = vec![0; len];
+    let read = nvs
+        .get_blob(key, &mut certificate)?
+        .map_or(0, |blob| blob.len());
+    certificate.truncate(read);
+    // Files generated with nvs_partition_gen.py aren't NUL terminated
+    if certificate.last() != Some(&0) {
+        certificate.push(0);
+    }
+    Ok(Some(certificate))
+}
+
+/// Wraps the `name` PEM certificate or key, NUL terminated, for the TLS stack.
+/// The certificates live for the whole program, so the client borrows them without any copy, however many times it's
+/// set up again. Fails when it's missing or isn't PEM, instead of leaving the TLS handshake to fail on it.
+#[cfg(target_os = "espidf")]
+fn convert_certificate(
+    name: &str,
+    certificate: Option<&'static [u8]>,
+) -> anyhow::Result<X509<'static>> {
+    let Some(certificate) = certificate else {
+        anyhow::bail!(
+            "the {} isn't provisioned in NVS, nor embedded with the embedded-certs feature",
+            name
+        );
+    };
+    let Some((0, pem)) = certificate.split_last() else {
+        anyhow::bail!("the {} isn't NUL terminated", name);
+    };
+    if !pem
+        .windows(PEM_BEGIN.len())
+        .any(|window| window == PEM_BEGIN)
+    {
+        anyhow::bail!("the {} isn't in PEM format, it has no BEGIN marker", name);
+    }
+    // The TLS stack would stop reading it there
+    if pem.contains(&0) {
+        anyhow::bail!("the {} contains a NUL byte", name);
+    }
+    Ok(X509::pem_until_nul(certificate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::iter;
+
+    fn message(topic: &str) -> MqttMessage {
+        MqttMessage {
+            topic: topic.to_string(),
+            id: 0,
+            payload: Vec::new(),
+            command: None,
+            replayed: false,
+            reply: None,
+            timing: Timing::received(),
+            routed: None,
+        }
+    }
+
+    #[test]
+    fn queue_drops_a_message_when_full() {
+        let (sender, receiver) = message_queue();
+        for index in 0..=MESSAGE_QUEUE_CAPACITY {
+            sender.send(message(&index.to_string())).unwrap();
+        }
+        let topics: Vec<_> = iter::from_fn(|| receiver.try_recv().ok())
+            .map(|message| message.topic)
+            .collect();
+        assert_eq!(topics.len(), MESSAGE_QUEUE_CAPACITY);
+        let kept = match QUEUE_FULL_POLICY {
+            QueueFullPolicy::DropOldest => 1..=MESSAGE_QUEUE_CAPACITY,
+            QueueFullPolicy::DropNewest => 0..=MESSAGE_QUEUE_CAPACITY - 1,
+        };
+        assert_eq!(
+            topics,
+            kept.map(|index| index.to_string()).collect::<Vec<_>>()
+        );
+    }
+
+    /// Base64 of a chunk holding `data`
+    fn chunk(data: &str) -> String {
+        BASE64.encode(data)
+    }
+
+    #[test]
+    fn transfer_reassembles_out_of_order_chunks() {
+        let mut transfers = Transfers::new();
+        assert_eq!(transfers.add("t", 2, 3, &chunk("c")), Ok(None));
+        assert_eq!(transfers.add("t", 0, 3, &chunk("a")), Ok(None));
+        assert_eq!(
+            transfers.add("t", 1, 3, &chunk("b")),
+            Ok(Some(b"abc".to_vec()))
+        );
+        assert!(transfers.transfers.is_empty());
+        assert_eq!(transfers.next_expiry(), None);
+    }
+
+    #[test]
+    fn transfer_completes_the_message() {
+        let mut transfers = Transfers::new();
+        let mut first = message("epaper");
+        first.command = Command::parse(
+            br#"{"type":"chunk","id":"t","index":0,"total":2,"data":"eyJ0eXBlIjoi"}"#,
+        );
+        assert_eq!(
+            transfers.receive(&mut first),
+            Some(("t".to_string(), ChunkOutcome::Pending))
+        );
+        let mut last = message("epaper");
+        last.command = Command::parse(
+            br#"{"type":"chunk","id":"t","index":1,"total":2,"data":"Y2xlYXIifQ=="}"#,
+        );
+        assert_eq!(
+            transfers.receive(&mut last),
+            Some(("t".to_string(), ChunkOutcome::Complete))
+        );
+        assert_eq!(last.payload, br#"{"type":"clear"}"#);
+        assert!(matches!(last.command, Some(Command::Clear)));
+        // Messages which aren't chunks are left alone
+        assert_eq!(transfers.receive(&mut message("epaper")), None);
+    }
+
+    #[test]
+    fn transfer_ignores_duplicate_chunks() {
+        let mut transfers = Transfers::new();
+        assert_eq!(transfers.add("t", 0, 2, &chunk("a")), Ok(None));
+        assert_eq!(
+            transfers.add("t", 0, 2, &chunk("x")),
+            Err(ChunkOutcome::Duplicate)
+        );
+        assert_eq!(
+            transfers.add("t", 1, 2, &chunk("b")),
+            Ok(Some(b"ab".to_vec()))
+        );
+    }
+
+    #[test]
+    fn transfer_discarded_on_a_total_mismatch() {
+        let mut transfers = Transfers::new();
+        assert_eq!(transfers.add("t", 0, 2, &chunk("a")), Ok(None));
+        assert_eq!(
+            transfers.add("t", 1, 3, &chunk("b")),
+            Err(ChunkOutcome::Failed("total_mismatch"))
+        );
+        assert!(transfers.transfers.is_empty());
+        // The next chunk starts over
+        assert_eq!(transfers.add("t", 1, 2, &chunk("b")), Ok(None));
+    }
+
+    #[test]
+    fn transfer_discarded_on_an_invalid_chunk() {
+        let mut transfers = Transfers::new();
+        assert_eq!(transfers.add("t", 0, 2, &chunk("a")), Ok(None));
+        assert_eq!(
+            transfers.add("t", 2, 2, &chunk("b")),
+            Err(ChunkOutcome::Failed("invalid_index"))
+        );
+        assert!(transfers.transfers.is_empty());
+        assert_eq!(
+            transfers.add("t", 5, 2, &chunk("b")),
+            Err(ChunkOutcome::Failed("invalid_index"))
+        );
+        assert!(transfers.transfers.is_empty());
+        assert_eq!(
+            transfers.add("t", 0, 0, &chunk("a")),
+            Err(ChunkOutcome::Failed("invalid_total"))
+        );
+        assert_eq!(
+            transfers.add("t", 0, CHUNK_MAX_COUNT + 1, &chunk("a")),
+            Err(ChunkOutcome::Failed("invalid_total"))
+        );
+        assert_eq!(transfers.add("t", 0, 2, &chunk("a")), Ok(None));
+        assert_eq!(
+            transfers.add("t", 1, 2, "not base64!"),
+            Err(ChunkOutcome::Failed("invalid_base64"))
+        );
+        assert!(transfers.transfers.is_empty());
+    }
+
+    #[test]
+    fn transfer_bounded_in_size() {
+        let mut transfers = Transfers::new();
+        let half = "a".repeat(MAX_PAYLOAD_SIZE / 2);
+        assert_eq!(transfers.add("t", 0, 3, &chunk(&half)), Ok(None));
+        assert_eq!(transfers.add("t", 1, 3, &chunk(&half)), Ok(None));
+        assert_eq!(
+            transfers.add("t", 2, 3, &chunk("a")),
+            Err(ChunkOutcome::Failed("too_large"))
+        );
+        assert!(transfers.transfers.is_empty());
+    }
+
+    #[test]
+    fn transfers_limited_in_number() {
+        let mut transfers = Transfers::new();
+        for id in 0..CHUNK_MAX_TRANSFERS {
+            assert_eq!(transfers.add(&id.to_string(), 0, 2, &chunk("a")), Ok(None));
+        }
+        assert_eq!(
+            transfers.add("extra", 0, 2, &chunk("a")),
+            Err(ChunkOutcome::Failed("too_many_transfers"))
+        );
+        // The transfers in progress go on
+        assert_eq!(
+            transfers.add("0", 1, 2, &chunk("b")),
+            Ok(Some(b"ab".to_vec()))
+        );
+        assert_eq!(transfers.add("extra", 0, 2, &chunk("a")), Ok(None));
+    }
+
+    #[test]
+    fn transfers_expire() {
+        let mut transfers = Transfers::new();
+        assert_eq!(transfers.add("old", 1, 3, &chunk("b")), Ok(None));
+        assert_eq!(transfers.add("new", 0, 2, &chunk("a")), Ok(None));
+        let Some(long_ago) = Instant::now().checked_sub(CHUNK_TIMEOUT) else {
+            // Too close to the start of the monotonic clock
+            return;
+        };
+        transfers.transfers[0].last_chunk = long_ago;
+        assert_eq!(transfers.next_expiry(), Some(long_ago + CHUNK_TIMEOUT));
+        assert_eq!(transfers.expire(), [("old".to_string(), vec![0, 2])]);
+        assert_eq!(transfers.transfers.len(), 1);
+        assert!(transfers.expire().is_empty());
+    }
+
+    #[test]
+    fn queue_is_sent_to_while_waiting() {
+        let (sender, receiver) = message_queue();
+        let sending = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            sender.send(message("sent")).unwrap();
+            sender
+        });
+        let received = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(received.topic, "sent");
+        let sender = sending.join().unwrap();
+        assert!(receiver.recv_timeout(Duration::from_millis(10)).is_err());
+        drop(receiver);
+        assert!(sender.send(message("late")).is_err());
+    }
+
+    // Not valid UTF-8: a lone continuation byte
+    const INVALID: &[u8] = b"caf\xa9 ok";
+
+    #[test]
+    fn invalid_utf8_is_rejected() {
+        assert!(matches!(
+            parse_payload(INVALID.to_vec(), InvalidUtf8::Reject),
+            Err("invalid_utf8")
+        ));
+    }
+
+    #[test]
+    fn invalid_utf8_is_replaced() {
+        match parse_payload(INVALID.to_vec(), InvalidUtf8::Lossy) {
+            Ok(DisplayMessage::Text(text)) => assert_eq!(text, "caf\u{fffd} ok"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_utf8_is_dumped() {
+        match parse_payload(INVALID.to_vec(), InvalidUtf8::HexDump) {
+            Ok(DisplayMessage::Text(text)) => assert_eq!(text, hex_dump(INVALID)),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn valid_utf8_ignores_the_mode() {
+        for mode in [
+            InvalidUtf8::Reject,
+            InvalidUtf8::Lossy,
+            InvalidUtf8::HexDump,
+        ] {
+            match parse_payload("café".as_bytes().to_vec(), mode) {
+                Ok(DisplayMessage::Text(text)) => assert_eq!(text, "café"),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn hex_dump_rows() {
+        assert_eq!(
+            hex_dump(INVALID),
+            "size=small;0000 63 61 66 a9 20 6f 6b |caf..ok|"
+        );
+        let payload: Vec<u8> = (b'a'..=b'q').collect();
+        assert_eq!(
+            hex_dump(&payload),
+            "size=small;0000 61 62 63 64 65 66 67 68 69 6a 6b 6c 6d 6e 6f 70 |abcdefghijklmnop|\n\
+             0010 71 |q|"
+        );
+        assert_eq!(hex_dump(&[]), "size=small;");
+    }
+
+    #[test]
+    fn hex_dump_is_bounded() {
+        let dump = hex_dump(&[0; 2 * HEX_DUMP_MAX_BYTES]);
+        assert_eq!(dump.lines().count(), HEX_DUMP_MAX_BYTES / 16);
+    }
+
+    #[test]
+    fn topic_matches_levels() {
+        assert!(topic_matches("epaper", "epaper"));
+        assert!(topic_matches("epaper/header", "epaper/header"));
+        assert!(!topic_matches("epaper", "epaper/header"));
+        assert!(!topic_matches("epaper/header", "epaper"));
+        assert!(!topic_matches("epaper/header", "epaper/footer"));
+    }
+
+    #[test]
+    fn topic_matches_wildcards() {
+        assert!(topic_matches("epaper/+", "epaper/header"));
+        assert!(topic_matches("epaper/+", "epaper/"));
+        assert!(!topic_matches("epaper/+", "epaper"));
+        assert!(!topic_matches("epaper/+", "epaper/header/left"));
+        assert!(topic_matches("+/header", "epaper/header"));
+        assert!(topic_matches("epaper/#", "epaper/header/left"));
+        assert!(topic_matches("epaper/#", "epaper"));
+        assert!(topic_matches("#", "epaper/header"));
+        assert!(!topic_matches("epaper/#", "other/header"));
+    }
+
+    /// A message received on `topic` with `payload`, parsed as it is on reception
+    fn received(topic: &str, payload: &str) -> MqttMessage {
+        MqttMessage {
+            payload: payload.as_bytes().to_vec(),
+            command: Command::parse(payload.as_bytes()),
+            ..message(topic)
+        }
+    }
+
+    #[test]
+    fn batch_skips_the_invalid_commands() {
+        let command = Command::parse(
+            br#"[{"type":"text","content":"Hello"},{"type":"bogus"},{"type":"clock"},{"type":"draw","commands":"line 0 0 10 10"}]"#,
+        )
+        .unwrap();
+        match command.into_message() {
+            Ok(DisplayMessage::Batch { messages, skipped }) => {
+                assert_eq!(skipped, 2);
+                assert!(matches!(
+                    messages.as_slice(),
+                    [DisplayMessage::Text(text), DisplayMessage::Drawing(_)] if text == "Hello"
+                ));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn batch_without_a_valid_command_is_rejected() {
+        let command =
+            Command::parse(br#"[{"type":"bogus"},{"type":"timer","seconds":60}]"#).unwrap();
+        assert!(matches!(command.into_message(), Err("empty_batch")));
+        let command = Command::parse(b"[]").unwrap();
+        assert!(matches!(command.into_message(), Err("empty_batch")));
+    }
+
+    #[test]
+    fn coalesce_keeps_the_messages_under_a_batch() {
+        let mut pending = VecDeque::new();
+        assert!(!coalesce(&mut pending, received("epaper", "first")));
+        let batch = r#"[{"type":"draw","commands":"line 0 0 10 10"}]"#;
+        assert!(!coalesce(&mut pending, received("epaper", batch)));
+        assert_eq!(pending.len(), 2);
+        // A text replaces the content, along with whatever was queued to be drawn over it
+        assert!(coalesce(&mut pending, received("epaper", "second")));
+        assert_eq!(pending.len(), 2);
+        assert!(coalesce(&mut pending, received("epaper", "third")));
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending.back().unwrap().payload, b"third");
+        assert!(!coalesce(&mut pending, received("other", "text")));
+        assert_eq!(pending.len(), 3);
+    }
+
+    #[test]
+    fn content_keeps_the_messages_under_a_batch() {
+        let mut content = PanelContent::new();
+        content.shown(
+            received("epaper", "Hello"),
+            &DisplayMessage::Text("Hello".to_string()),
+        );
+        let drawing = DisplayMessage::Batch {
+            messages: vec![DisplayMessage::Drawing("line 0 0 10 10".to_string())],
+            skipped: 0,
+        };
+        content.shown(received("epaper", "[]"), &drawing);
+        assert_eq!(content.messages.len(), 2);
+        let replacing = DisplayMessage::Batch {
+            messages: vec![
+                DisplayMessage::Text("Bye".to_string()),
+                DisplayMessage::Drawing("line 0 0 10 10".to_string()),
+            ],
+            skipped: 0,
+        };
+        content.shown(received("epaper", "[]"), &replacing);
+        assert_eq!(content.messages.len(), 1);
+    }
+
+    #[test]
+    fn received_messages_are_routed() {
+        let router = MessageRouter::new(mqtt_subscriptions("epaper"), "epaper".to_string());
+        let rate_limiter = Mutex::new(RateLimiter::new());
+        let routed = |topic: &str, payload: &[u8]| {
+            MqttMessage::received(
+                &router,
+                &rate_limiter,
+                topic.to_string(),
+                0,
+                payload.to_vec(),
+                None,
+            )
+            .unwrap()
+            .routed
+        };
+        assert!(matches!(
+            routed("epaper", b"raw:AAAA"),
+            Some(Err("invalid_raw_frame"))
+        ));
+        assert!(matches!(
+            routed("epaper/control", b"rotate=90"),
+            Some(Ok(DisplayMessage::Rotate(_)))
+        ));
+        // Not routed until the transfer is reassembled
+        let chunk = format!(
+            r#"{{"type":"chunk","id":"a","index":0,"total":2,"data":"{}"}}"#,
+            chunk("x")
+        );
+        assert!(routed("epaper", chunk.as_bytes()).is_none());
+        router.set(mqtt_subscriptions("kitchen"), "kitchen".to_string());
+        assert!(matches!(
+            routed("kitchen/control", b"rotate=90"),
+            Some(Ok(DisplayMessage::Rotate(_)))
+        ));
+    }
+
+    /// The messages a topic matching none of RATE_LIMITS can burst, and the interval they're refilled over
+    fn default_limit() -> (u32, Duration) {
+        RATE_LIMIT_DEFAULT.expect("the tests need a default rate limit")
+    }
+
+    /// Moves the last refill of the bucket of `topic` back by `elapsed`
+    fn age_bucket(limiter: &mut RateLimiter, topic: &str, elapsed: Duration) {
+        let bucket = limiter
+            .buckets
+            .iter_mut()
+            .find(|bucket| bucket.topic == topic)
+            .unwrap();
+        bucket.refilled = bucket.refilled.checked_sub(elapsed).unwrap();
+    }
+
+    #[test]
+    fn rate_limiter_refills_the_bucket() {
+        let (messages, interval) = default_limit();
+        let mut limiter = RateLimiter::new();
+        assert!((0..messages).all(|_| limiter.allow("epaper")));
+        assert!(!limiter.allow("epaper"));
+        // Another topic has its own bucket
+        assert!(limiter.allow("epaper/news"));
+        // One and a half tokens are back
+        age_bucket(&mut limiter, "epaper", interval * 3 / 2 / messages);
+        assert!(limiter.allow("epaper"));
+        assert!(!limiter.allow("epaper"));
+    }
+
+    #[test]
+    fn rate_limiter_drops_the_full_buckets() {
+        let (_, interval) = default_limit();
+        let mut limiter = RateLimiter::new();
+        assert!(limiter.allow("epaper"));
+        assert!(limiter.allow("epaper/news"));
+        assert_eq!(limiter.buckets.len(), 2);
+        age_bucket(&mut limiter, "epaper", interval);
+        assert!(limiter.allow("epaper/news"));
+        assert_eq!(limiter.buckets.len(), 1);
+        assert_eq!(limiter.buckets[0].topic, "epaper/news");
+    }
+
+    #[test]
+    fn rate_limiter_keeps_the_drops_not_noticed() {
+        let (messages, interval) = default_limit();
+        let mut limiter = RateLimiter::new();
+        assert!((0..messages).all(|_| limiter.allow("epaper")));
+        assert!(!limiter.allow("epaper"));
+        // Full again, the bucket still counts its drop
+        age_bucket(&mut limiter, "epaper", interval);
+        assert!(limiter.allow("epaper/news"));
+        assert_eq!(limiter.notice(), Some(vec![("epaper".to_string(), 1)]));
+    }
+
+    #[test]
+    fn rate_limiter_throttles_the_notices() {
+        let (messages, _) = default_limit();
+        let mut limiter = RateLimiter::new();
+        assert_eq!(limiter.notice(), None);
+        assert!((0..messages).all(|_| limiter.allow("epaper")));
+        assert!(!limiter.allow("epaper"));
+        assert!(!limiter.allow("epaper"));
+        assert_eq!(limiter.notice(), Some(vec![("epaper".to_string(), 2)]));
+        // Counted until the next notice is due
+        assert!(!limiter.allow("epaper"));
+        assert_eq!(limiter.notice(), None);
+        limiter.last_notice = Instant::now().checked_sub(RATE_LIMIT_NOTICE_INTERVAL);
+        assert_eq!(limiter.notice(), Some(vec![("epaper".to_string(), 1)]));
+        limiter.last_notice = Instant::now().checked_sub(RATE_LIMIT_NOTICE_INTERVAL);
+        assert_eq!(limiter.notice(), None);
+    }
+
+    #[test]
+    fn received_messages_are_rate_limited() {
+        let (messages, _) = default_limit();
+        let router = MessageRouter::new(mqtt_subscriptions("epaper"), "epaper".to_string());
+        let rate_limiter = Mutex::new(RateLimiter::new());
+        let received = |payload: &str| {
+            MqttMessage::received(
+                &router,
+                &rate_limiter,
+                "epaper".to_string(),
+                0,
+                payload.as_bytes().to_vec(),
+                None,
+            )
+        };
+        assert!((0..messages).all(|_| received("Hello").is_some()));
+        assert!(received("Hello").is_none());
+        // The chunks count once their transfer is reassembled
+        let chunk = format!(
+            r#"{{"type":"chunk","id":"a","index":0,"total":2,"data":"{}"}}"#,
+            chunk("x")
+        );
+        assert!(received(&chunk).is_some());
+    }
+}
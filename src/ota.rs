@@ -0,0 +1,156 @@
+//! Firmware updates over the air
+
+use crate::{
+    config::{MQTT_OTA_STATUS_SUBTOPIC, OTA_CHUNK_LEN, OTA_PROGRESS_STEP, OTA_TIMEOUT},
+    mqtt::MqttClient,
+    system::feed_watchdog,
+};
+use embedded_svc::{
+    http::client::Client as HttpClient,
+    mqtt::client::QoS,
+    ota::{FirmwareInfoLoader, LoadResult},
+};
+use esp_idf_svc::{
+    http::client::{Configuration as HttpClientConfig, EspHttpConnection},
+    ota::{EspFirmwareInfoLoader, EspOta},
+};
+use log::*;
+
+/// Downloads the firmware image at the HTTPS `url` into the next OTA partition, validates it and sets it as the boot
+/// partition, reporting the percentage downloaded to `progress` every OTA_PROGRESS_STEP when the size is known.
+/// Returns false without updating when the image is the running firmware. On errors, including interrupted downloads,
+/// the update is aborted and the running firmware stays the boot one.
+pub fn ota_update(url: &str, mut progress: impl FnMut(u8)) -> anyhow::Result<bool> {
+    if !url.starts_with("https://") {
+        anyhow::bail!("the firmware URL must be an HTTPS one: {}", url);
+    }
+    info!("Downloading the firmware from {}", url);
+    let mut client = HttpClient::wrap(EspHttpConnection::new(&HttpClientConfig {
+        timeout: Some(OTA_TIMEOUT),
+        crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+        ..Default::default()
+    })?);
+    let mut response = client.get(url)?.submit()?;
+    if response.status() != 200 {
+        anyhow::bail!("the server answered with status {}", response.status());
+    }
+    let total = response
+        .header("Content-Length")
+        .and_then(|length| length.parse::<usize>().ok());
+
+    let mut ota = EspOta::new()?;
+    let running = ota.get_running_slot()?.firmware;
+    let update = ota.initiate_update()?;
+    let mut loader = EspFirmwareInfoLoader::new();
+    let mut buffer = [0; OTA_CHUNK_LEN];
+    let mut downloaded = 0;
+    let mut reported = 0;
+    let result = loop {
+        feed_watchdog();
+        let len = match response.read(&mut buffer) {
+            Ok(0) => break Ok(true),
+            Ok(len) => len,
+            Err(e) => break Err(anyhow::anyhow!("the download was interrupted: {}", e)),
+        };
+        if !loader.is_loaded() {
+            // The image header tells whether it's the running firmware, e.g. a retained URL received again after updating
+            if let Ok(LoadResult::Loaded) = loader.load(&buffer[..len]) {
+                if let Ok(info) = loader.get_info() {
+                    info!(
+                        "Downloading the firmware {} built on {}",
+                        info.version, info.released
+                    );
+                    let same = running.as_ref().map_or(false, |running| {
+                        running.signature.is_some() && running.signature == info.signature
+                    });
+                    if same {
+                        break Ok(false);
+                    }
+                }
+            }
+        }
+        if let Err(e) = update.write(&buffer[..len]) {
+            break Err(e.into());
+        }
+        downloaded += len;
+        if let Some(total) = total.filter(|&total| total > 0) {
+            let percent = (downloaded * 100 / total).min(100) as u8;
+            if percent >= reported + OTA_PROGRESS_STEP {
+                reported = percent - percent % OTA_PROGRESS_STEP;
+                progress(percent);
+            }
+        }
+    };
+    let result = result.and_then(|updated| match total {
+        Some(total) if updated && downloaded != total => Err(anyhow::anyhow!(
+            "the download was interrupted at {} of {} bytes",
+            downloaded,
+            total
+        )),
+        _ => Ok(updated),
+    });
+    match result {
+        Ok(true) => {
+            // Checks the image before making it the boot partition
+            update.complete()?;
+            info!("Firmware updated, {} bytes", downloaded);
+            Ok(true)
+        }
+        Ok(false) => {
+            info!("The firmware at {} is the running one", url);
+            update.abort()?;
+            Ok(false)
+        }
+        Err(e) => {
+            if let Err(e) = update.abort() {
+                warn!("Couldn't abort the firmware update: {}", e);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Publishes the outcome of a firmware update on "<topic>/<MQTT_OTA_STATUS_SUBTOPIC>", e.g.
+/// {"url":"<url>","status":"updated"}, "up_to_date", or "failed" along with the "error"
+pub fn publish_ota_status(
+    client: Option<&mut MqttClient>,
+    topic: &str,
+    url: &str,
+    result: &anyhow::Result<bool>,
+) {
+    let payload = match result {
+        Ok(true) => serde_json::json!({ "url": url, "status": "updated" }),
+        Ok(false) => serde_json::json!({ "url": url, "status": "up_to_date" }),
+        Err(e) => {
+            serde_json::json!({ "url": url, "status": "failed", "error": format!("{:#}", e) })
+        }
+    }
+    .to_string();
+    let Some(client) = client else {
+        warn!(
+            "Dropping the firmware update status {}, no MQTT client",
+            payload
+        );
+        return;
+    };
+    match client.publish(
+        &format!("{}/{}", topic, MQTT_OTA_STATUS_SUBTOPIC),
+        QoS::AtLeastOnce,
+        false,
+        payload.as_bytes(),
+    ) {
+        Ok(_) => info!("Published the firmware update status {}", payload),
+        Err(e) => warn!(
+            "Couldn't publish the firmware update status {}: {}",
+            payload, e
+        ),
+    }
+}
+
+/// Marks the running firmware as valid, cancelling the rollback to the previous one after an update
+pub fn confirm_firmware() {
+    match EspOta::new().and_then(|mut ota| ota.mark_running_slot_valid()) {
+        Ok(()) => info!("Firmware confirmed"),
+        Err(e) => warn!("Couldn't confirm the firmware: {}", e),
+    }
+}
@@ -0,0 +1,444 @@
+//! Waveshare panel models, selected at build time by their Cargo feature
+
+#[cfg(not(any(feature = "epd5in83_v2", feature = "epd7in5_v2")))]
+compile_error!("no panel model selected: enable the \"epd5in83_v2\" or the \"epd7in5_v2\" feature");
+#[cfg(all(feature = "epd5in83_v2", feature = "epd7in5_v2"))]
+compile_error!("only one panel model can be selected: the \"epd5in83_v2\" and \"epd7in5_v2\" features are both enabled");
+
+use crate::config::{
+    BoardConfig, PANEL_BUSY_TIMEOUT, PANEL_INIT_ATTEMPTS, PANEL_INIT_RETRY_DELAY, PANEL_RESET_PULSE,
+};
+use embedded_hal::digital::v2::InputPin;
+use epd_waveshare::{
+    epd5in83_v2, epd5in83b_v2, epd7in5_v2,
+    prelude::{Display, WaveshareDisplay, WaveshareThreeColorDisplay},
+};
+#[cfg(target_os = "espidf")]
+use esp_idf_hal::{
+    delay::Ets,
+    gpio::{AnyIOPin, AnyInputPin, AnyOutputPin, Input, Output, PinDriver},
+    peripheral::Peripheral,
+    spi::{
+        config::Config as SpiConfig, SpiDeviceDriver, SpiDriver, SpiDriverConfig, SpiError, SPI2,
+    },
+};
+use log::*;
+use std::{
+    cell::Cell,
+    convert::Infallible,
+    sync::atomic::{AtomicU32, Ordering},
+    thread,
+    time::Instant,
+};
+
+// Pixel color of the panel. The black/white/red (B) variant of the 5.83" V2 panel needs the "tricolor" feature
+#[cfg(not(feature = "tricolor"))]
+pub type PanelColor = epd_waveshare::color::Color;
+#[cfg(feature = "tricolor")]
+pub type PanelColor = epd_waveshare::color::TriColor;
+
+// Frame of the panel model selected by the Cargo features
+#[cfg(all(feature = "epd5in83_v2", not(feature = "tricolor")))]
+pub type PanelDisplay = epd5in83_v2::Display5in83;
+#[cfg(feature = "tricolor")]
+pub type PanelDisplay = epd5in83b_v2::Display5in83;
+#[cfg(feature = "epd7in5_v2")]
+pub type PanelDisplay = epd7in5_v2::Display7in5;
+
+// Size of the unrotated panel, in pixels
+pub const PANEL_WIDTH: u32 = PanelDisplay::WIDTH;
+pub const PANEL_HEIGHT: u32 = PanelDisplay::HEIGHT;
+
+/// Frame of a panel model, as used by the drawing code. Unlike the drivers, it doesn't need the panel.
+pub trait PanelFrame: Default {
+    /// Size of the unrotated panel, in pixels
+    const WIDTH: u32;
+    const HEIGHT: u32;
+    /// Whether the panel can refresh an area on its own
+    const PARTIAL_REFRESH: bool;
+
+    /// Packed black/white pixels of the frame (1 bit per pixel, MSB first, rows of WIDTH pixels)
+    fn bw_plane(&self) -> &[u8];
+    /// Packed chromatic pixels of the frame, on the panels having a second plane
+    fn chromatic_plane(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+// The black/white drivers don't implement partial updates
+impl PanelFrame for epd5in83_v2::Display5in83 {
+    const WIDTH: u32 = epd5in83_v2::WIDTH;
+    const HEIGHT: u32 = epd5in83_v2::HEIGHT;
+    const PARTIAL_REFRESH: bool = false;
+
+    fn bw_plane(&self) -> &[u8] {
+        self.buffer()
+    }
+}
+
+impl PanelFrame for epd7in5_v2::Display7in5 {
+    const WIDTH: u32 = epd7in5_v2::WIDTH;
+    const HEIGHT: u32 = epd7in5_v2::HEIGHT;
+    const PARTIAL_REFRESH: bool = false;
+
+    fn bw_plane(&self) -> &[u8] {
+        self.buffer()
+    }
+}
+
+// The black/white/red (B) variant of the 5.83" V2 panel has a second, chromatic plane
+impl PanelFrame for epd5in83b_v2::Display5in83 {
+    const WIDTH: u32 = epd5in83b_v2::WIDTH;
+    const HEIGHT: u32 = epd5in83b_v2::HEIGHT;
+    const PARTIAL_REFRESH: bool = true;
+
+    fn bw_plane(&self) -> &[u8] {
+        self.bw_buffer()
+    }
+
+    fn chromatic_plane(&self) -> Option<&[u8]> {
+        Some(self.chromatic_buffer())
+    }
+}
+
+// SPI device of the E-Ink panel and its control pins, wired to the pins of the board configuration
+#[cfg(target_os = "espidf")]
+pub type EpdSpi = SpiDeviceDriver<'static, SpiDriver<'static>>;
+#[cfg(target_os = "espidf")]
+pub type EpdCs = PinDriver<'static, AnyOutputPin, Output>;
+#[cfg(target_os = "espidf")]
+pub type EpdDc = PinDriver<'static, AnyOutputPin, Output>;
+#[cfg(target_os = "espidf")]
+pub type EpdRst = PinDriver<'static, AnyOutputPin, Output>;
+
+/// BUSY pin of the panel, polled by the drivers until the panel is done. A pin busy for longer than PANEL_BUSY_TIMEOUT
+/// is then seen idle, so that a stuck panel can't hang the device: the timeouts are counted by busy_timeouts.
+#[cfg(target_os = "espidf")]
+pub struct EpdBusy {
+    pin: PinDriver<'static, AnyInputPin, Input>,
+    busy_since: Cell<Option<Instant>>,
+}
+
+// Number of waits on the BUSY pin cut short by PANEL_BUSY_TIMEOUT since boot
+static BUSY_TIMEOUTS: AtomicU32 = AtomicU32::new(0);
+
+#[cfg(target_os = "espidf")]
+impl EpdBusy {
+    pub fn new(pin: PinDriver<'static, AnyInputPin, Input>) -> Self {
+        Self {
+            pin,
+            busy_since: Cell::new(None),
+        }
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl InputPin for EpdBusy {
+    type Error = Infallible;
+
+    // The supported panels pull BUSY low while they're busy
+    fn is_low(&self) -> Result<bool, Infallible> {
+        if !self.pin.is_low() {
+            self.busy_since.set(None);
+            return Ok(false);
+        }
+        let since = self.busy_since.get().unwrap_or_else(Instant::now);
+        if since.elapsed() < PANEL_BUSY_TIMEOUT {
+            self.busy_since.set(Some(since));
+            return Ok(true);
+        }
+        error!(
+            "The panel BUSY pin didn't clear within {}s",
+            PANEL_BUSY_TIMEOUT.as_secs()
+        );
+        BUSY_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+        self.busy_since.set(None);
+        Ok(false)
+    }
+
+    fn is_high(&self) -> Result<bool, Infallible> {
+        self.is_low().map(|low| !low)
+    }
+}
+
+/// Number of waits on the panel BUSY pin cut short by PANEL_BUSY_TIMEOUT since boot
+pub fn busy_timeouts() -> u32 {
+    BUSY_TIMEOUTS.load(Ordering::Relaxed)
+}
+
+// Clock of the APB bus, that the SPI clock is divided from
+const APB_CLK_FREQ: i32 = 80_000_000;
+
+// Driver of the panel model selected by the Cargo features
+#[cfg(all(
+    target_os = "espidf",
+    feature = "epd5in83_v2",
+    not(feature = "tricolor")
+))]
+pub type EpdDriver = epd5in83_v2::Epd5in83<EpdSpi, EpdCs, EpdBusy, EpdDc, EpdRst, Ets>;
+#[cfg(all(target_os = "espidf", feature = "tricolor"))]
+pub type EpdDriver = epd5in83b_v2::Epd5in83<EpdSpi, EpdCs, EpdBusy, EpdDc, EpdRst, Ets>;
+#[cfg(all(target_os = "espidf", feature = "epd7in5_v2"))]
+pub type EpdDriver = epd7in5_v2::Epd7in5<EpdSpi, EpdCs, EpdBusy, EpdDc, EpdRst, Ets>;
+
+/// Driver of a panel model, as used by the refresh code
+#[cfg(target_os = "espidf")]
+pub trait DisplayDriver: Sized {
+    /// Frame the panel is drawn into
+    type Display: PanelFrame;
+    /// Fastest SPI clock of the panel, in Hz
+    const MAX_SPI_BAUDRATE: u32;
+
+    /// Resets and initializes the panel
+    fn init(
+        device: &mut EpdSpi,
+        cs: EpdCs,
+        busy: EpdBusy,
+        dc: EpdDc,
+        rst: EpdRst,
+    ) -> Result<Self, SpiError>;
+    /// Sends the whole frame to the panel, shown by the next display_frame
+    fn update_frame(
+        &mut self,
+        device: &mut EpdSpi,
+        display: &Self::Display,
+    ) -> Result<(), SpiError>;
+    /// Sends the packed black/white pixels of the (x, y, w, h) area, with x and w multiples of 8, and refreshes only that
+    /// area of the panel. Only used when PARTIAL_REFRESH is set.
+    fn update_partial_frame(
+        &mut self,
+        device: &mut EpdSpi,
+        buffer: &[u8],
+        x: u32,
+        y: u32,
+        w: u32,
+        h: u32,
+    ) -> Result<(), SpiError>;
+    /// Refreshes the panel with the frame sent
+    fn display_frame(&mut self, device: &mut EpdSpi) -> Result<(), SpiError>;
+    /// Sends a white frame to the panel, shown by the next display_frame
+    fn clear_frame(&mut self, device: &mut EpdSpi) -> Result<(), SpiError>;
+    /// Puts the panel controller into deep sleep, the panel keeps its image
+    fn sleep(&mut self, device: &mut EpdSpi) -> Result<(), SpiError>;
+    /// Resets the panel with its RST pin and initializes it again
+    fn reset(&mut self, device: &mut EpdSpi) -> Result<(), SpiError>;
+}
+
+// Fastest SPI clock of the supported panels, whose datasheets require a clock cycle of at least 100ns when writing
+const MAX_SPI_BAUDRATE: u32 = 10_000_000;
+
+// Panels whose whole frame is a single black/white plane
+macro_rules! bw_display_driver {
+    ($model:ident, $epd:ident, $display:ident) => {
+        #[cfg(target_os = "espidf")]
+        impl DisplayDriver for $model::$epd<EpdSpi, EpdCs, EpdBusy, EpdDc, EpdRst, Ets> {
+            type Display = $model::$display;
+            const MAX_SPI_BAUDRATE: u32 = MAX_SPI_BAUDRATE;
+
+            fn init(
+                device: &mut EpdSpi,
+                cs: EpdCs,
+                busy: EpdBusy,
+                dc: EpdDc,
+                rst: EpdRst,
+            ) -> Result<Self, SpiError> {
+                Self::new(device, cs, busy, dc, rst, &mut Ets, None)
+            }
+
+            fn update_frame(
+                &mut self,
+                device: &mut EpdSpi,
+                display: &Self::Display,
+            ) -> Result<(), SpiError> {
+                WaveshareDisplay::update_frame(self, device, display.buffer(), &mut Ets)
+            }
+
+            fn update_partial_frame(
+                &mut self,
+                device: &mut EpdSpi,
+                buffer: &[u8],
+                x: u32,
+                y: u32,
+                w: u32,
+                h: u32,
+            ) -> Result<(), SpiError> {
+                WaveshareDisplay::update_partial_frame(self, device, &mut Ets, buffer, x, y, w, h)?;
+                WaveshareDisplay::display_frame(self, device, &mut Ets)
+            }
+
+            fn display_frame(&mut self, device: &mut EpdSpi) -> Result<(), SpiError> {
+                WaveshareDisplay::display_frame(self, device, &mut Ets)
+            }
+
+            fn clear_frame(&mut self, device: &mut EpdSpi) -> Result<(), SpiError> {
+                WaveshareDisplay::clear_frame(self, device, &mut Ets)
+            }
+
+            fn sleep(&mut self, device: &mut EpdSpi) -> Result<(), SpiError> {
+                WaveshareDisplay::sleep(self, device, &mut Ets)
+            }
+
+            fn reset(&mut self, device: &mut EpdSpi) -> Result<(), SpiError> {
+                WaveshareDisplay::wake_up(self, device, &mut Ets)
+            }
+        }
+    };
+}
+
+bw_display_driver!(epd5in83_v2, Epd5in83, Display5in83);
+bw_display_driver!(epd7in5_v2, Epd7in5, Display7in5);
+
+#[cfg(target_os = "espidf")]
+impl DisplayDriver for epd5in83b_v2::Epd5in83<EpdSpi, EpdCs, EpdBusy, EpdDc, EpdRst, Ets> {
+    type Display = epd5in83b_v2::Display5in83;
+    const MAX_SPI_BAUDRATE: u32 = MAX_SPI_BAUDRATE;
+
+    fn init(
+        device: &mut EpdSpi,
+        cs: EpdCs,
+        busy: EpdBusy,
+        dc: EpdDc,
+        rst: EpdRst,
+    ) -> Result<Self, SpiError> {
+        Self::new(device, cs, busy, dc, rst, &mut Ets, None)
+    }
+
+    fn update_frame(
+        &mut self,
+        device: &mut EpdSpi,
+        display: &Self::Display,
+    ) -> Result<(), SpiError> {
+        // The chromatic plane doubles the data sent and the refresh takes noticeably longer (~15s instead of ~5s)
+        self.update_color_frame(
+            device,
+            &mut Ets,
+            display.bw_buffer(),
+            display.chromatic_buffer(),
+        )
+    }
+
+    fn update_partial_frame(
+        &mut self,
+        device: &mut EpdSpi,
+        buffer: &[u8],
+        x: u32,
+        y: u32,
+        w: u32,
+        h: u32,
+    ) -> Result<(), SpiError> {
+        // The driver refreshes the window itself, in black and white: red is left out of it, see update_region
+        WaveshareDisplay::update_partial_frame(self, device, &mut Ets, buffer, x, y, w, h)
+    }
+
+    fn display_frame(&mut self, device: &mut EpdSpi) -> Result<(), SpiError> {
+        WaveshareDisplay::display_frame(self, device, &mut Ets)
+    }
+
+    fn clear_frame(&mut self, device: &mut EpdSpi) -> Result<(), SpiError> {
+        WaveshareDisplay::clear_frame(self, device, &mut Ets)
+    }
+
+    fn sleep(&mut self, device: &mut EpdSpi) -> Result<(), SpiError> {
+        WaveshareDisplay::sleep(self, device, &mut Ets)
+    }
+
+    fn reset(&mut self, device: &mut EpdSpi) -> Result<(), SpiError> {
+        WaveshareDisplay::wake_up(self, device, &mut Ets)
+    }
+}
+
+/// Peripherals wired to the E-Ink panel
+#[cfg(target_os = "espidf")]
+pub struct PanelPeripherals {
+    pub spi: SPI2,
+    /// The pins, taken by number
+    pub board: BoardConfig,
+}
+
+/// Sets up the SPI link to the panel and initializes it, see init_epd
+#[cfg(target_os = "espidf")]
+pub fn init_panel(peripherals: &mut PanelPeripherals) -> anyhow::Result<(EpdSpi, EpdDriver)> {
+    let board = peripherals.board;
+    board.validate(EpdDriver::MAX_SPI_BAUDRATE)?;
+    // The drivers of a failed attempt have been dropped, releasing the peripherals.
+    // The pins are validated, and not used by anything else.
+    let (spi, sclk, mosi) = unsafe {
+        (
+            peripherals.spi.clone_unchecked(),
+            AnyOutputPin::new(board.sclk),
+            AnyOutputPin::new(board.mosi),
+        )
+    };
+
+    let spi_config = SpiConfig::new().baudrate(board.spi_baudrate.into());
+    let mut device: EpdSpi = SpiDeviceDriver::new_single(
+        spi,
+        sclk,
+        mosi,
+        Option::<AnyIOPin>::None,
+        Option::<AnyOutputPin>::None,
+        &SpiDriverConfig::default(),
+        &spi_config,
+    )?;
+    // The clock is divided from the APB one, so it can end up slower than the one configured
+    let effective =
+        unsafe { esp_idf_sys::spi_get_actual_clock(APB_CLK_FREQ, board.spi_baudrate as i32, 128) };
+    info!(
+        "Panel SPI clock at {}Hz ({}Hz configured)",
+        effective, board.spi_baudrate
+    );
+
+    let epd = init_epd(&mut device, board)?;
+    Ok((device, epd))
+}
+
+/// Runs the init sequence of the panel, up to PANEL_INIT_ATTEMPTS times: a cold panel sometimes misses it, so before
+/// each new attempt its RST pin is held low for PANEL_RESET_PULSE, and the panel is left PANEL_INIT_RETRY_DELAY to settle
+#[cfg(target_os = "espidf")]
+fn init_epd(device: &mut EpdSpi, board: BoardConfig) -> anyhow::Result<EpdDriver> {
+    let mut attempt = 1;
+    loop {
+        // The pins of a failed attempt have been dropped along with its driver
+        let (cs, busy, dc, rst) = unsafe {
+            (
+                AnyOutputPin::new(board.cs),
+                AnyInputPin::new(board.busy),
+                AnyOutputPin::new(board.dc),
+                AnyOutputPin::new(board.rst),
+            )
+        };
+        let mut rst = PinDriver::output(rst)?;
+        if attempt > 1 {
+            rst.set_low()?;
+            thread::sleep(PANEL_RESET_PULSE);
+            rst.set_high()?;
+            thread::sleep(PANEL_INIT_RETRY_DELAY);
+        }
+        let cs = PinDriver::output(cs)?;
+        let busy = EpdBusy::new(PinDriver::input(busy)?);
+        let dc = PinDriver::output(dc)?;
+        match EpdDriver::init(device, cs, busy, dc, rst) {
+            Ok(epd) => {
+                if attempt > 1 {
+                    info!(
+                        "Panel initialized at attempt {} of {}",
+                        attempt, PANEL_INIT_ATTEMPTS
+                    );
+                }
+                return Ok(epd);
+            }
+            Err(e) if attempt < PANEL_INIT_ATTEMPTS => warn!(
+                "Couldn't initialize the panel (attempt {} of {}): {}. Resetting it...",
+                attempt, PANEL_INIT_ATTEMPTS, e
+            ),
+            Err(e) => {
+                error!(
+                    "Couldn't initialize the panel, giving up after {} attempts: {}",
+                    attempt, e
+                );
+                return Err(e.into());
+            }
+        }
+        attempt += 1;
+    }
+}
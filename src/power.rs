@@ -0,0 +1,200 @@
+//! Battery monitoring and deep sleep
+
+use crate::config::{
+    BATTERY_DIVIDER_RATIO, BATTERY_EMPTY_VOLTAGE, BATTERY_FULL_VOLTAGE, BATTERY_SAMPLES,
+    DEEP_SLEEP_BUTTON_DEBOUNCE,
+};
+#[cfg(target_os = "espidf")]
+use crate::{
+    display::save_refresh_stats,
+    panel::{DisplayDriver, EpdDriver, EpdSpi},
+    system::feed_watchdog,
+};
+#[cfg(target_os = "espidf")]
+use esp_idf_hal::{
+    adc::{config::Config as AdcConfig, AdcChannelDriver, AdcDriver, Atten11dB, ADC1},
+    gpio::{Gpio0, Gpio36, Input, PinDriver},
+};
+#[cfg(target_os = "espidf")]
+use esp_idf_sys::EspError;
+use log::*;
+use std::{
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Battery voltage, and the charge estimated from it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BatteryReading {
+    pub volts: f32,
+    pub percent: u8,
+}
+
+impl BatteryReading {
+    /// Estimates the charge linearly between BATTERY_EMPTY_VOLTAGE and BATTERY_FULL_VOLTAGE
+    pub fn from_volts(volts: f32) -> Self {
+        let charge =
+            (volts - BATTERY_EMPTY_VOLTAGE) / (BATTERY_FULL_VOLTAGE - BATTERY_EMPTY_VOLTAGE);
+        BatteryReading {
+            volts,
+            percent: (charge.clamp(0.0, 1.0) * 100.0).round() as u8,
+        }
+    }
+
+    /// JSON payload of the battery telemetry, e.g. {"volts":3.92,"percent":69}
+    pub fn to_json(&self) -> String {
+        serde_json::json!({
+            // Rounded in f64, the f32 would show up as 3.9200000762939453
+            "volts": (self.volts as f64 * 100.0).round() / 100.0,
+            "percent": self.percent,
+        })
+        .to_string()
+    }
+}
+
+/// ADC channel the battery is wired to through the divider
+#[cfg(target_os = "espidf")]
+pub struct Battery {
+    adc: AdcDriver<'static, ADC1>,
+    pin: AdcChannelDriver<'static, Gpio36, Atten11dB<ADC1>>,
+}
+
+#[cfg(target_os = "espidf")]
+impl Battery {
+    pub fn new(adc: ADC1, pin: Gpio36) -> Result<Self, EspError> {
+        Ok(Battery {
+            // Calibrated readings are in millivolts
+            adc: AdcDriver::new(adc, &AdcConfig::new().calibration(true))?,
+            pin: AdcChannelDriver::new(pin)?,
+        })
+    }
+}
+
+/// Reads the battery voltage, averaging BATTERY_SAMPLES samples and compensating for the divider
+#[cfg(target_os = "espidf")]
+pub fn read_battery_voltage(battery: &mut Battery) -> Result<BatteryReading, EspError> {
+    let mut millivolts = 0;
+    for _ in 0..BATTERY_SAMPLES {
+        millivolts += battery.adc.read(&mut battery.pin)? as u32;
+    }
+    let volts = millivolts as f32 / BATTERY_SAMPLES as f32 / 1000.0 * BATTERY_DIVIDER_RATIO;
+    Ok(BatteryReading::from_volts(volts))
+}
+
+/// Reads the battery, when monitored, logging failures
+#[cfg(target_os = "espidf")]
+pub fn read_battery(battery: &mut Option<Battery>) -> Option<BatteryReading> {
+    match read_battery_voltage(battery.as_mut()?) {
+        Ok(reading) => Some(reading),
+        Err(e) => {
+            warn!("Couldn't read the battery voltage: {}", e);
+            None
+        }
+    }
+}
+
+/// State kept in RTC memory during deep sleep, while the rest of the RAM is lost.
+/// The framebuffer doesn't fit in it, so the panel content is drawn from scratch after a wake. The hash of the frame
+/// on the panel is kept in NVS instead, see load_last_frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SleepState {
+    /// message_hash of the last message displayed
+    pub rendered: Option<u32>,
+    /// Inversion of the status bar
+    pub invert: bool,
+    /// When the last message was displayed, in seconds since the Unix epoch
+    pub last_update: Option<u64>,
+}
+
+// Initialized at power on, and kept in deep sleep
+#[link_section = ".rtc.data"]
+static mut SLEEP_STATE: SleepState = SleepState {
+    rendered: None,
+    invert: false,
+    last_update: None,
+};
+
+impl SleepState {
+    /// The state saved before the last deep sleep
+    pub fn load() -> Self {
+        unsafe { SLEEP_STATE }
+    }
+
+    fn save(self) {
+        unsafe { SLEEP_STATE = self };
+    }
+
+    pub fn last_update(&self) -> Option<SystemTime> {
+        self.last_update
+            .map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds))
+    }
+}
+
+/// Why the device booted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WakeCause {
+    /// Power on or reset, not a wake from deep sleep
+    PowerOn,
+    /// The deep sleep interval elapsed
+    Timer,
+    /// The wake button was pressed
+    Button,
+}
+
+#[cfg(target_os = "espidf")]
+pub fn wake_cause() -> WakeCause {
+    #[allow(non_upper_case_globals)]
+    match unsafe { esp_idf_sys::esp_sleep_get_wakeup_cause() } {
+        esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_UNDEFINED => WakeCause::PowerOn,
+        esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT0 => WakeCause::Button,
+        _ => WakeCause::Timer,
+    }
+}
+
+/// Waits until `button` has been released for `debounce`
+#[cfg(target_os = "espidf")]
+fn wait_for_release(button: &PinDriver<'_, Gpio0, Input>, debounce: Duration) {
+    if button.is_low() {
+        info!("Waiting for the wake button to be released...");
+    }
+    let mut released = Instant::now();
+    while released.elapsed() < debounce {
+        feed_watchdog();
+        if button.is_low() {
+            released = Instant::now();
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Saves `state` to RTC memory, puts the panel to sleep and then the device into deep sleep for `interval`.
+/// The device boots again on wake, or as soon as `wake_button` is pressed.
+#[cfg(target_os = "espidf")]
+pub fn enter_deep_sleep(
+    epd: &mut EpdDriver,
+    device: &mut EpdSpi,
+    interval: Duration,
+    state: SleepState,
+    wake_button: Option<&PinDriver<'_, Gpio0, Input>>,
+) -> ! {
+    state.save();
+    save_refresh_stats();
+    if let Some(button) = wake_button {
+        // The wake up is level triggered, it would happen right away while the button is held
+        wait_for_release(button, DEEP_SLEEP_BUTTON_DEBOUNCE);
+        // ext0 keeps the RTC peripherals powered, along with the pull-up of the pin
+        unsafe {
+            esp_idf_sys::rtc_gpio_pullup_en(button.pin());
+            esp_idf_sys::esp_sleep_enable_ext0_wakeup(button.pin(), 0);
+        }
+    }
+    // The panel keeps its image without power, its controller is woken up by the reset of the next boot
+    if let Err(e) = epd.sleep(device) {
+        warn!("Couldn't put the panel to sleep: {}", e);
+    }
+    info!("Entering deep sleep for {}s", interval.as_secs());
+    unsafe {
+        esp_idf_sys::esp_sleep_enable_timer_wakeup(interval.as_micros() as u64);
+        esp_idf_sys::esp_deep_sleep_start()
+    }
+}
@@ -0,0 +1,528 @@
+//! Reset reasons, boot counter, panics, task watchdog, startup retries and the backoff of the network retries
+
+use crate::{
+    config::{NVS_CONFIG_NAMESPACE, PANIC_HANDOVER_TIMEOUT, PANIC_RESTART_DELAY, STARTUP_ATTEMPTS},
+    display::{clear_content, content_area, draw_text, TextOptions},
+};
+#[cfg(target_os = "espidf")]
+use crate::{
+    display::{save_refresh_stats, update_full},
+    panel::{EpdDriver, EpdSpi, PanelDisplay},
+};
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::{
+    log::EspLogger,
+    nvs::{EspDefaultNvs, EspDefaultNvsPartition},
+};
+#[cfg(target_os = "espidf")]
+use esp_idf_sys::EspError;
+use log::*;
+use std::{
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Exponential backoff with jitter between the attempts of a network retry: the delays double from `base` up to `max`,
+/// each one shortened by up to `jitter` (a fraction between 0 and 1) of itself at random. As an iterator, it yields
+/// the successive delays.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    jitter: f32,
+    /// Source of the random fraction of the jitter applied to each delay, between 0 and 1
+    random: fn() -> f32,
+    next: Duration,
+    attempts: u32,
+}
+
+impl Backoff {
+    /// Backoff drawing its jitter from the hardware random number generator
+    #[cfg(target_os = "espidf")]
+    pub fn new(base: Duration, max: Duration, jitter: f32) -> Self {
+        Backoff::with_random(base, max, jitter, hardware_random)
+    }
+
+    /// Backoff drawing its jitter from `random`, which returns fractions between 0 and 1
+    pub fn with_random(base: Duration, max: Duration, jitter: f32, random: fn() -> f32) -> Self {
+        Backoff {
+            base,
+            max,
+            jitter: jitter.clamp(0.0, 1.0),
+            random,
+            next: base.min(max),
+            attempts: 0,
+        }
+    }
+
+    /// Delays given out since the last reset, that is the failed attempts
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// The delay before the next attempt, within [(1 - jitter) * d, d] of the current backoff d, which then doubles
+    pub fn next_delay(&mut self) -> Duration {
+        let backoff = self.next;
+        self.next = backoff.saturating_mul(2).min(self.max);
+        self.attempts += 1;
+        let random = (self.random)().clamp(0.0, 1.0);
+        backoff.mul_f32(1.0 - self.jitter * random)
+    }
+
+    /// Starts over from `base`, once the attempt succeeded
+    pub fn reset(&mut self) {
+        self.next = self.base.min(self.max);
+        self.attempts = 0;
+    }
+}
+
+impl Iterator for Backoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        Some(self.next_delay())
+    }
+}
+
+/// Uniform random fraction between 0 and 1, from the hardware random number generator
+#[cfg(target_os = "espidf")]
+fn hardware_random() -> f32 {
+    let random = unsafe { esp_idf_sys::esp_random() };
+    random as f32 / u32::MAX as f32
+}
+
+/// Runs the startup `step` until it succeeds, up to STARTUP_ATTEMPTS times `delay` apart, returning the last error
+/// once they're all spent. The errors are shown on `panel`, when it's set up.
+#[cfg(target_os = "espidf")]
+pub fn retry_startup<T>(
+    step: &str,
+    delay: Duration,
+    mut panel: Option<(&mut EpdDriver, &mut EpdSpi, &mut PanelDisplay)>,
+    mut attempt: impl FnMut() -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let e = match attempt() {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        let message = match attempts < STARTUP_ATTEMPTS {
+            true => format!(
+                "Couldn't {} (attempt {} of {}): {:#}. Trying again in {}s...",
+                step,
+                attempts,
+                STARTUP_ATTEMPTS,
+                e,
+                delay.as_secs()
+            ),
+            false => format!("Couldn't {}: {:#}. Restarting...", step, e),
+        };
+        error!("{}", message);
+        if let Some((epd, device, display)) = panel.as_mut() {
+            let display = &mut **display;
+            clear_content(display);
+            let area = content_area(display);
+            draw_text(
+                display,
+                &message,
+                area.top_left.x,
+                area.top_left.y,
+                area.size.width,
+                area.size.height,
+                TextOptions::default(),
+            );
+            if let Err(e) = update_full(epd, device, display) {
+                warn!("Couldn't show the error on the panel: {}", e);
+            }
+        }
+        if attempts >= STARTUP_ATTEMPTS {
+            return Err(e.context(format!("couldn't {}", step)));
+        }
+        thread::sleep(delay);
+    }
+}
+
+/// Sets the level of the logs shown, of the firmware and of the ESP-IDF components alike.
+/// Levels past CONFIG_LOG_MAXIMUM_LEVEL, the most verbose one compiled in, are lowered to it.
+#[cfg(target_os = "espidf")]
+pub fn set_log_level(level: LevelFilter) {
+    let level = level.min(EspLogger.get_max_level());
+    log::set_max_level(level);
+    if let Err(e) = EspLogger.set_target_level("*", level) {
+        warn!("Couldn't set the ESP-IDF log level: {}", e);
+    }
+    if let Some(level) = level.to_level() {
+        log!(level, "Log level set to {}", level);
+    }
+}
+
+/// Why the device restarted, as reported by ESP-IDF
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResetReason {
+    /// Short name, as published, e.g. "brownout"
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Caused by a crash, a watchdog or a brownout
+    pub abnormal: bool,
+}
+
+/// Why the device restarted
+#[cfg(target_os = "espidf")]
+pub fn reset_reason() -> ResetReason {
+    let reason = |name, description, abnormal| ResetReason {
+        name,
+        description,
+        abnormal,
+    };
+    #[allow(non_upper_case_globals)]
+    match unsafe { esp_idf_sys::esp_reset_reason() } {
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_POWERON => reason("poweron", "power on", false),
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_EXT => reason("external", "external pin", false),
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_SW => reason("software", "software restart", false),
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP => {
+            reason("deepsleep", "wake from deep sleep", false)
+        }
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_SDIO => reason("sdio", "reset over SDIO", false),
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_PANIC => reason("panic", "panic", true),
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_TASK_WDT => {
+            reason("task_wdt", "task watchdog, a task hung", true)
+        }
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_INT_WDT => {
+            reason("int_wdt", "interrupt watchdog", true)
+        }
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_WDT => reason("wdt", "watchdog", true),
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_BROWNOUT => {
+            reason("brownout", "brownout, check the power supply", true)
+        }
+        _ => reason("unknown", "unknown", false),
+    }
+}
+
+/// Logs why the device restarted, warning about resets caused by a crash, a watchdog or a brownout
+#[cfg(target_os = "espidf")]
+pub fn log_reset_reason() {
+    let reason = reset_reason();
+    match reason.abnormal {
+        true => warn!("Restarted after a {}", reason.description),
+        false => info!("Reset reason: {}", reason.description),
+    }
+}
+
+/// Whether the device restarted because of a crash, a watchdog or a brownout
+#[cfg(target_os = "espidf")]
+pub fn restarted_abnormally() -> bool {
+    reset_reason().abnormal
+}
+
+// Key of the NVS_CONFIG_NAMESPACE namespace counting the boots
+const NVS_BOOT_COUNT_KEY: &str = "boots";
+
+// Boots counted so far, this one included, 0 until count_boot has read the counter
+static BOOT_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// Increments the boot counter kept in NVS, unless `woken` from deep sleep (a wake isn't a restart), and returns it.
+/// When the counter can't be written, the one read is kept for this boot, counting it, and the next boot tries again.
+#[cfg(target_os = "espidf")]
+pub fn count_boot(partition: EspDefaultNvsPartition, woken: bool) -> Result<u32, EspError> {
+    let nvs = EspDefaultNvs::new(partition, NVS_CONFIG_NAMESPACE, true)?;
+    let stored = nvs.get_u32(NVS_BOOT_COUNT_KEY)?.unwrap_or(0);
+    let count = match woken {
+        true => stored.max(1),
+        false => stored.wrapping_add(1).max(1),
+    };
+    BOOT_COUNT.store(count, Ordering::Relaxed);
+    if count != stored {
+        if let Err(e) = nvs.set_u32(NVS_BOOT_COUNT_KEY, count) {
+            warn!("Couldn't save the boot counter to NVS: {}", e);
+        }
+    }
+    Ok(count)
+}
+
+// Key of the NVS_CONFIG_NAMESPACE namespace counting the boots in a row which couldn't initialize the panel
+const NVS_PANEL_FAILURES_KEY: &str = "panel_failures";
+
+/// Counts in NVS a boot which couldn't initialize the panel, for the next boot to report it, and returns the count
+#[cfg(target_os = "espidf")]
+pub fn record_panel_failure(partition: EspDefaultNvsPartition) -> Result<u32, EspError> {
+    let nvs = EspDefaultNvs::new(partition, NVS_CONFIG_NAMESPACE, true)?;
+    let count = nvs
+        .get_u32(NVS_PANEL_FAILURES_KEY)?
+        .unwrap_or(0)
+        .saturating_add(1);
+    nvs.set_u32(NVS_PANEL_FAILURES_KEY, count)?;
+    Ok(count)
+}
+
+/// The boots in a row which couldn't initialize the panel before this one, 0 if none, clearing the count
+#[cfg(target_os = "espidf")]
+pub fn take_panel_failures(partition: EspDefaultNvsPartition) -> Result<u32, EspError> {
+    let mut nvs = EspDefaultNvs::new(partition, NVS_CONFIG_NAMESPACE, true)?;
+    let count = nvs.get_u32(NVS_PANEL_FAILURES_KEY)?.unwrap_or(0);
+    if count > 0 {
+        nvs.remove(NVS_PANEL_FAILURES_KEY)?;
+    }
+    Ok(count)
+}
+
+/// Boots of the device counted in NVS, None when the counter couldn't be read
+pub fn boot_count() -> Option<u32> {
+    match BOOT_COUNT.load(Ordering::Relaxed) {
+        0 => None,
+        count => Some(count),
+    }
+}
+
+// Version of the firmware, and its build info set by build.rs: the build time in seconds since the epoch and the short
+// git hash of the commit, unset when built outside of a git checkout
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const BUILD_TIMESTAMP: Option<&str> = option_env!("BUILD_TIMESTAMP");
+pub const GIT_HASH: Option<&str> = option_env!("GIT_HASH");
+
+/// The firmware version and build info, e.g. {"version":"0.1.0","built":1760400000,"git":"d4046a0"}.
+/// "built" and "git" are null when unknown.
+pub fn firmware_json() -> serde_json::Value {
+    serde_json::json!({
+        "version": FIRMWARE_VERSION,
+        "built": BUILD_TIMESTAMP.and_then(|timestamp| timestamp.parse::<u64>().ok()),
+        "git": GIT_HASH,
+    })
+}
+
+/// Time since this boot, from the ESP timer
+#[cfg(target_os = "espidf")]
+pub fn uptime() -> Duration {
+    Duration::from_micros(unsafe { esp_idf_sys::esp_timer_get_time() } as u64)
+}
+
+/// Off-device there's no boot to count from
+#[cfg(not(target_os = "espidf"))]
+pub fn uptime() -> Duration {
+    Duration::ZERO
+}
+
+/// The panel as reached by the panic hook, while it's owned by the main thread
+#[cfg(target_os = "espidf")]
+struct PanicPanel {
+    epd: *mut EpdDriver,
+    device: *mut EpdSpi,
+    display: *mut PanelDisplay,
+}
+
+// Set once the panel is set up. Taken by the first panic, so that a panic while showing it doesn't try again
+#[cfg(target_os = "espidf")]
+static PANIC_PANEL: AtomicPtr<PanicPanel> = AtomicPtr::new(ptr::null_mut());
+// Set by a panic outside of the main thread, asking the main loop to stop using the panel, and by the main loop once it has
+pub static PANIC_HANDOVER_REQUESTED: AtomicBool = AtomicBool::new(false);
+static PANIC_HANDOVER_DONE: AtomicBool = AtomicBool::new(false);
+
+/// Makes the panel reachable by the panic hook, until the returned guard is dropped. The panel has to stay in place
+/// meanwhile: the guard must be dropped before it's moved or dropped, as it is when declared after it.
+#[cfg(target_os = "espidf")]
+#[must_use]
+pub fn register_panic_panel(
+    epd: &mut EpdDriver,
+    device: &mut EpdSpi,
+    display: &mut PanelDisplay,
+) -> PanicPanelGuard {
+    let panel = Box::new(PanicPanel {
+        epd,
+        device,
+        display,
+    });
+    let previous = PANIC_PANEL.swap(Box::into_raw(panel), Ordering::SeqCst);
+    if !previous.is_null() {
+        drop(unsafe { Box::from_raw(previous) });
+    }
+    PanicPanelGuard(())
+}
+
+/// Keeps the panel registered by register_panic_panel reachable by the panic hook, until dropped
+#[cfg(target_os = "espidf")]
+pub struct PanicPanelGuard(());
+
+#[cfg(target_os = "espidf")]
+impl Drop for PanicPanelGuard {
+    fn drop(&mut self) {
+        // Null once taken by a panic, which never returns
+        let panel = PANIC_PANEL.swap(ptr::null_mut(), Ordering::SeqCst);
+        if !panel.is_null() {
+            drop(unsafe { Box::from_raw(panel) });
+        }
+    }
+}
+
+/// Stops the main loop for good, leaving the panel to the panic hook of another thread
+pub fn hand_over_panel() -> ! {
+    warn!("Handing the panel over to the panic hook");
+    PANIC_HANDOVER_DONE.store(true, Ordering::SeqCst);
+    loop {
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// Installs a panic hook showing "PANIC: <message>" on the panel, best-effort, then rebooting.
+/// Panics before the panel is set up, or while the main loop doesn't hand it over, are only logged.
+#[cfg(target_os = "espidf")]
+pub fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        let message = panic_message(info);
+        if let Some(panel) = take_panic_panel() {
+            // SAFETY: the main thread owning the panel is either the one panicking, or stopped by hand_over_panel
+            let panel = unsafe { &mut *panel };
+            let (epd, device, display) =
+                unsafe { (&mut *panel.epd, &mut *panel.device, &mut *panel.display) };
+            clear_content(display);
+            let area = content_area(display);
+            draw_text(
+                display,
+                &message,
+                area.top_left.x,
+                area.top_left.y,
+                area.size.width,
+                area.size.height,
+                TextOptions::default(),
+            );
+            if let Err(e) = update_full(epd, device, display) {
+                error!("Couldn't show the panic on the panel: {}", e);
+            }
+        }
+        error!("Restarting in {}s", PANIC_RESTART_DELAY.as_secs());
+        thread::sleep(PANIC_RESTART_DELAY);
+        save_refresh_stats();
+        esp_idf_hal::reset::restart();
+    }));
+}
+
+/// "PANIC: <message> (<file>:<line>)" of a panic
+pub fn panic_message(info: &std::panic::PanicInfo<'_>) -> String {
+    let payload = info.payload();
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown error");
+    match info.location() {
+        Some(location) => format!(
+            "PANIC: {} ({}:{})",
+            message,
+            location.file(),
+            location.line()
+        ),
+        None => format!("PANIC: {}", message),
+    }
+}
+
+/// The panel, once the current thread can draw on it: right away on the main thread, after the main loop hands it over
+/// on the others. None when it isn't set up, or it has already been taken.
+#[cfg(target_os = "espidf")]
+fn take_panic_panel() -> Option<*mut PanicPanel> {
+    let panel = PANIC_PANEL.swap(ptr::null_mut(), Ordering::SeqCst);
+    if panel.is_null() {
+        return None;
+    }
+    if thread::current().name() != Some("main") {
+        PANIC_HANDOVER_REQUESTED.store(true, Ordering::SeqCst);
+        let started = Instant::now();
+        while !PANIC_HANDOVER_DONE.load(Ordering::SeqCst) {
+            if started.elapsed() >= PANIC_HANDOVER_TIMEOUT {
+                error!("The main loop didn't hand the panel over");
+                return None;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+    Some(panel)
+}
+
+/// Starts the task watchdog, which reboots the device when a task subscribed to it isn't fed within `timeout`
+#[cfg(target_os = "espidf")]
+pub fn start_watchdog(timeout: Duration) -> Result<(), EspError> {
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_task_wdt_init(timeout.as_secs() as u32, true) })
+}
+
+/// Subscription of the current task to the task watchdog, until dropped
+#[cfg(target_os = "espidf")]
+pub struct WatchdogGuard(());
+
+#[cfg(target_os = "espidf")]
+impl WatchdogGuard {
+    pub fn new() -> Result<Self, EspError> {
+        esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_task_wdt_add(ptr::null_mut()) })?;
+        Ok(WatchdogGuard(()))
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl Drop for WatchdogGuard {
+    fn drop(&mut self) {
+        unsafe { esp_idf_sys::esp_task_wdt_delete(ptr::null_mut()) };
+    }
+}
+
+/// Tells the task watchdog the current task isn't hung. Harmless for tasks not subscribed to it.
+#[cfg(target_os = "espidf")]
+pub fn feed_watchdog() {
+    unsafe { esp_idf_sys::esp_task_wdt_reset() };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff(random: fn() -> f32) -> Backoff {
+        Backoff::with_random(Duration::from_secs(1), Duration::from_secs(10), 0.5, random)
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        let delays: Vec<u64> = backoff(|| 0.0)
+            .take(6)
+            .map(|delay| delay.as_secs())
+            .collect();
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+    }
+
+    #[test]
+    fn backoff_counts_and_resets_the_attempts() {
+        let mut backoff = backoff(|| 0.0);
+        assert_eq!(backoff.attempts(), 0);
+        backoff.by_ref().take(3).for_each(drop);
+        assert_eq!(backoff.attempts(), 3);
+        backoff.reset();
+        assert_eq!(backoff.attempts(), 0);
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn backoff_jitter_stays_within_its_bounds() {
+        // The shortest delays, then the ones halfway
+        let delays: Vec<Duration> = backoff(|| 1.0).take(5).collect();
+        assert_eq!(
+            delays,
+            [500, 1000, 2000, 4000, 5000].map(Duration::from_millis)
+        );
+        let delays: Vec<Duration> = backoff(|| 0.5).take(2).collect();
+        assert_eq!(delays, [750, 1500].map(Duration::from_millis));
+        // Fractions out of range are clamped, the delays never go past [(1 - jitter) * d, d]
+        assert_eq!(backoff(|| 2.0).next_delay(), Duration::from_millis(500));
+        assert_eq!(backoff(|| -1.0).next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn backoff_base_above_the_max() {
+        let mut backoff = Backoff::with_random(
+            Duration::from_secs(20),
+            Duration::from_secs(10),
+            0.0,
+            || 0.5,
+        );
+        assert_eq!(backoff.next_delay(), Duration::from_secs(10));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(10));
+    }
+}
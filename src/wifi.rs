@@ -0,0 +1,531 @@
+//! WiFi connection and monitoring, and the captive portal for the first setup
+
+use crate::{
+    config::{
+        parse_auth_method, Config, StaticIp, WifiCredentials, MAX_WIFI_NETWORKS, MDNS_SERVICE,
+        PORTAL_CHANNEL, PORTAL_MAX_FORM_LEN, PORTAL_PAGE, PORTAL_SSID, RECONNECT_JITTER,
+        WIFI_CHECK_INTERVAL, WIFI_EAP_CA_CERT, WIFI_RECONNECT_MAX_BACKOFF,
+        WIFI_RECONNECT_MIN_BACKOFF,
+    },
+    system::Backoff,
+};
+use embedded_svc::{
+    http::{Headers, Method},
+    io::{Read as _, Write as _},
+    ipv4::{self, ClientSettings, Mask, Subnet},
+    wifi::{AccessPointConfiguration, AuthMethod, ClientConfiguration, Configuration},
+};
+#[cfg(target_os = "espidf")]
+use esp_idf_hal::modem::Modem;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::{
+    eventloop::EspSystemEventLoop,
+    http::server::{Configuration as HttpServerConfig, EspHttpServer},
+    mdns::EspMdns,
+    netif::{EspNetif, NetifConfiguration, NetifStack},
+    nvs::EspDefaultNvsPartition,
+    wifi::{BlockingWifi, EspWifi, WifiDriver},
+};
+#[cfg(target_os = "espidf")]
+use esp_idf_sys::{esp, EspError};
+use log::*;
+use std::{
+    net::{Ipv4Addr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+};
+
+/// Sets up the WiFi driver, with the station interface configured by `static_ip` and the access point one for the portal
+#[cfg(target_os = "espidf")]
+pub fn init_wifi(
+    modem: Modem,
+    sys_loop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+    static_ip: Option<StaticIp>,
+) -> anyhow::Result<BlockingWifi<EspWifi<'static>>> {
+    // Blocking so that we can block until the IP is obtained
+    let driver = WifiDriver::new(modem, sys_loop.clone(), Some(nvs))?;
+    Ok(BlockingWifi::wrap(
+        EspWifi::wrap_all(
+            driver,
+            wifi_client_netif(static_ip)?,
+            EspNetif::new(NetifStack::Ap)?,
+        )?,
+        sys_loop,
+    )?)
+}
+
+/// Starts the open PORTAL_SSID access point serving the setup page, answering every DNS query with the address of the
+/// device so that clients open the page on their own. Waits for a valid WiFi network to be submitted, then stores it in NVS.
+#[cfg(target_os = "espidf")]
+pub fn run_portal(
+    wifi: &mut BlockingWifi<EspWifi>,
+    partition: EspDefaultNvsPartition,
+) -> anyhow::Result<WifiCredentials> {
+    let mut config = Config::load_from_nvs(partition.clone())?;
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: PORTAL_SSID.into(),
+        auth_method: AuthMethod::None,
+        channel: PORTAL_CHANNEL,
+        ..Default::default()
+    }))?;
+    wifi.start()?;
+    wifi.wait_netif_up()?;
+    let ip = wifi.wifi().ap_netif().get_ip_info()?.ip;
+    info!(
+        "Captive portal started, join \"{}\" and open http://{}",
+        PORTAL_SSID, ip
+    );
+    spawn_dns_responder(ip)?;
+
+    let (sender, receiver) = mpsc::channel::<WifiCredentials>();
+    let mut server = EspHttpServer::new(&HttpServerConfig {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+    server.fn_handler("/save", Method::Post, move |mut request| {
+        let len = request.content_len().unwrap_or(0) as usize;
+        if len > PORTAL_MAX_FORM_LEN {
+            request
+                .into_status_response(413)?
+                .write_all(b"Form too large")?;
+            return Ok(());
+        }
+        let mut body = vec![0; len];
+        request.read_exact(&mut body)?;
+        match parse_portal_form(&String::from_utf8_lossy(&body)) {
+            Ok(network) => {
+                request
+                    .into_ok_response()?
+                    .write_all(b"Saved, the device is restarting")?;
+                let _ = sender.send(network);
+            }
+            Err(e) => {
+                request
+                    .into_status_response(400)?
+                    .write_all(format!("Invalid settings: {}", e).as_bytes())?;
+            }
+        }
+        Ok(())
+    })?;
+    // Any other page, such as the connectivity checks of the clients, gets the setup page
+    server.fn_handler("/*", Method::Get, |request| {
+        request
+            .into_ok_response()?
+            .write_all(PORTAL_PAGE.as_bytes())?;
+        Ok(())
+    })?;
+
+    let network = receiver.recv()?;
+    info!(
+        "Received the WiFi network from the captive portal: {:?}",
+        network
+    );
+    config.wifi_networks.insert(0, network.clone());
+    config.wifi_networks.truncate(MAX_WIFI_NETWORKS);
+    config.store_to_nvs(partition)?;
+    Ok(network)
+}
+
+/// Reads the WiFi network from the urlencoded form of the setup page
+pub fn parse_portal_form(form: &str) -> anyhow::Result<WifiCredentials> {
+    let mut network = WifiCredentials::new("", "", AuthMethod::WPA2Personal);
+    for field in form.split('&') {
+        let (key, value) = field.split_once('=').unwrap_or((field, ""));
+        let value = url_decode(value);
+        match key {
+            "ssid" => network.ssid = value,
+            "password" => network.password = value,
+            "auth" => {
+                network.auth_method = parse_auth_method(&value)
+                    .ok_or_else(|| anyhow::anyhow!("unknown auth method \"{}\"", value))?
+            }
+            _ => {}
+        }
+    }
+    if network.ssid.is_empty() {
+        anyhow::bail!("the SSID is empty");
+    }
+    if !network.is_valid() {
+        anyhow::bail!("the SSID or the password is too long");
+    }
+    network.check_auth()?;
+    Ok(network)
+}
+
+/// Decodes an application/x-www-form-urlencoded value. Invalid escapes are kept as they are.
+pub fn url_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'+' => decoded.push(b' '),
+            b'%' => match value
+                .get(index + 1..index + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                Some(byte) => {
+                    decoded.push(byte);
+                    index += 2;
+                }
+                None => decoded.push(b'%'),
+            },
+            byte => decoded.push(byte),
+        }
+        index += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Answers every DNS query with `ip` from a separate thread, so that clients joining the portal are sent to its page
+#[cfg(target_os = "espidf")]
+fn spawn_dns_responder(ip: Ipv4Addr) -> std::io::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 53))?;
+    thread::spawn(move || {
+        let mut buffer = [0; 512];
+        loop {
+            let (len, source) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("Captive portal DNS receive failed: {}", e);
+                    continue;
+                }
+            };
+            if let Some(response) = dns_response(&buffer[..len], ip) {
+                let _ = socket.send_to(&response, source);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Builds the response to a DNS query with a single question, answering it with an A record of `ip`.
+/// Returns None for anything else than a standard query.
+pub fn dns_response(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    const HEADER_LEN: usize = 12;
+    // Not a query, or not a standard one, or not a single question
+    if query.len() < HEADER_LEN || query[2] & 0xF8 != 0 || query[4..6] != [0, 1] {
+        return None;
+    }
+    // The question is the name, as length-prefixed labels ending with an empty one, then its type and class
+    let mut end = HEADER_LEN;
+    loop {
+        let label_len = *query.get(end)? as usize;
+        end += 1 + label_len;
+        if label_len == 0 {
+            break;
+        }
+    }
+    end += 4;
+    if end > query.len() {
+        return None;
+    }
+
+    let mut response = Vec::with_capacity(end + 16);
+    response.extend_from_slice(&query[..2]);
+    // Response, recursion desired and available, no error
+    response.extend_from_slice(&[0x81, 0x80]);
+    // One question, one answer, no authority nor additional records
+    response.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 0]);
+    response.extend_from_slice(&query[HEADER_LEN..end]);
+    // The name is a pointer to the question, then type A, class IN, a TTL of 60 seconds and the address
+    response.extend_from_slice(&[0xC0, HEADER_LEN as u8, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+    response.extend_from_slice(&ip.octets());
+    Some(response)
+}
+
+/// Network interface of the WiFi client, with either a static IP configuration or DHCP
+#[cfg(target_os = "espidf")]
+fn wifi_client_netif(static_ip: Option<StaticIp>) -> Result<EspNetif, EspError> {
+    let Some(static_ip) = static_ip else {
+        return EspNetif::new(NetifStack::Sta);
+    };
+    info!("Using the static IP configuration {:?}", static_ip);
+    EspNetif::new_with_conf(&NetifConfiguration {
+        ip_configuration: ipv4::Configuration::Client(ipv4::ClientConfiguration::Fixed(
+            ClientSettings {
+                ip: static_ip.ip,
+                subnet: Subnet {
+                    gateway: static_ip.gateway,
+                    mask: Mask(static_ip.prefix_len()),
+                },
+                dns: static_ip.dns,
+                secondary_dns: None,
+            },
+        )),
+        ..NetifConfiguration::wifi_default_client()
+    })
+}
+
+/// Starts the WiFi in station mode, ready to join a network
+#[cfg(target_os = "espidf")]
+pub fn start_wifi(wifi: &mut BlockingWifi<EspWifi>) -> anyhow::Result<()> {
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+    wifi.start()?;
+    info!("Wifi started!");
+    Ok(())
+}
+
+/// Tries to join each network in order of priority, trying the ones found by a scan first
+#[cfg(target_os = "espidf")]
+pub fn connect_wifi(
+    wifi: &mut BlockingWifi<EspWifi>,
+    networks: &[WifiCredentials],
+) -> anyhow::Result<()> {
+    let visible: Vec<String> = match wifi.scan() {
+        Ok(access_points) => access_points
+            .iter()
+            .map(|access_point| access_point.ssid.to_string())
+            .collect(),
+        Err(e) => {
+            warn!("WiFi scan failed, trying all the networks: {}", e);
+            Vec::new()
+        }
+    };
+    let (in_range, out_of_range): (Vec<_>, Vec<_>) = networks
+        .iter()
+        .partition(|network| visible.contains(&network.ssid));
+
+    for network in in_range.into_iter().chain(out_of_range) {
+        if let Err(e) = network.check_auth() {
+            error!("Skipping the WiFi network \"{}\": {}", network.ssid, e);
+            continue;
+        }
+        let enterprise = network.auth_method == AuthMethod::WPA2Enterprise;
+        info!("Connecting to the WiFi network \"{}\"...", network.ssid);
+        wifi.set_configuration(&Configuration::Client(ClientConfiguration {
+            ssid: network.ssid.as_str().into(),
+            // The password of WPA2-Enterprise networks goes to the EAP supplicant instead
+            password: if enterprise {
+                ""
+            } else {
+                network.password.as_str()
+            }
+            .into(),
+            auth_method: network.auth_method,
+            ..Default::default()
+        }))?;
+        if let Err(e) = configure_eap(network) {
+            error!(
+                "Skipping the WiFi network \"{}\", WPA2-Enterprise couldn't be set up: {}",
+                network.ssid, e
+            );
+            continue;
+        }
+        if let Err(e) = wifi.connect() {
+            if enterprise {
+                warn!(
+                    "EAP authentication as \"{}\" on \"{}\" failed, check the username, password and CA certificate: {}",
+                    network.eap_username, network.ssid, e
+                );
+            } else {
+                warn!("Couldn't connect to \"{}\": {}", network.ssid, e);
+            }
+            continue;
+        }
+        if enterprise {
+            info!(
+                "EAP authentication as \"{}\" on \"{}\" succeeded",
+                network.eap_username, network.ssid
+            );
+        }
+        info!("Wifi connected!");
+
+        wifi.wait_netif_up()?;
+        let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
+        info!("Wifi ready! IP address: {}", ip_info.ip);
+        return Ok(());
+    }
+    anyhow::bail!(
+        "Couldn't connect to any of the {} WiFi networks",
+        networks.len()
+    )
+}
+
+/// Hands the EAP credentials of a WPA2-Enterprise network to the supplicant, or turns EAP off for the other networks
+#[cfg(target_os = "espidf")]
+fn configure_eap(network: &WifiCredentials) -> Result<(), EspError> {
+    if network.auth_method != AuthMethod::WPA2Enterprise {
+        return esp!(unsafe { esp_idf_sys::esp_wifi_sta_wpa2_ent_disable() });
+    }
+    let identity = network.eap_outer_identity();
+    // The supplicant copies the values, they only have to live during the calls
+    unsafe {
+        esp!(esp_idf_sys::esp_wifi_sta_wpa2_ent_set_identity(
+            identity.as_ptr(),
+            identity.len() as _
+        ))?;
+        esp!(esp_idf_sys::esp_wifi_sta_wpa2_ent_set_username(
+            network.eap_username.as_ptr(),
+            network.eap_username.len() as _
+        ))?;
+        esp!(esp_idf_sys::esp_wifi_sta_wpa2_ent_set_password(
+            network.password.as_ptr(),
+            network.password.len() as _
+        ))?;
+        match WIFI_EAP_CA_CERT {
+            Some(ca_cert) => esp!(esp_idf_sys::esp_wifi_sta_wpa2_ent_set_ca_cert(
+                ca_cert.as_ptr(),
+                ca_cert.len() as _
+            ))?,
+            None => {
+                warn!(
+                    "No CA certificate for WPA2-Enterprise, the authentication server of \"{}\" isn't checked",
+                    network.ssid
+                );
+                esp_idf_sys::esp_wifi_sta_wpa2_ent_clear_ca_cert();
+            }
+        }
+        esp!(esp_idf_sys::esp_wifi_sta_wpa2_ent_enable())
+    }
+}
+
+/// Answers mDNS queries for "<hostname>.local" and advertises MDNS_SERVICE for the device subscribed to `topic`.
+/// mDNS stops when the returned handle is dropped.
+#[cfg(target_os = "espidf")]
+pub fn start_mdns(hostname: &str, topic: &str) -> anyhow::Result<EspMdns> {
+    if hostname.is_empty()
+        || hostname.len() > 63
+        || !hostname
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        anyhow::bail!("\"{}\" isn't a valid mDNS hostname", hostname);
+    }
+    let mut mdns = EspMdns::take()?;
+    mdns.set_hostname(hostname)?;
+    mdns.set_instance_name(hostname)?;
+    let (service_type, proto, port) = MDNS_SERVICE;
+    mdns.add_service(None, service_type, proto, port, &[("topic", topic)])?;
+    info!(
+        "Reachable as {}.local, advertising {}.{}",
+        hostname, service_type, proto
+    );
+    Ok(mdns)
+}
+
+/// Watches the WiFi connection from a separate thread, reconnecting with an exponential backoff when it drops.
+/// `reconnected` is set after each successful reconnection.
+#[cfg(target_os = "espidf")]
+pub fn spawn_wifi_monitor(
+    mut wifi: BlockingWifi<EspWifi<'static>>,
+    networks: Vec<WifiCredentials>,
+    reconnected: Arc<AtomicBool>,
+) {
+    thread::spawn(move || loop {
+        thread::sleep(WIFI_CHECK_INTERVAL);
+        if wifi.is_connected().unwrap_or(false) {
+            continue;
+        }
+
+        warn!("WiFi connection lost");
+        let mut backoff = Backoff::new(
+            WIFI_RECONNECT_MIN_BACKOFF,
+            WIFI_RECONNECT_MAX_BACKOFF,
+            RECONNECT_JITTER,
+        );
+        loop {
+            let attempt = backoff.attempts() + 1;
+            info!("WiFi reconnection attempt {}...", attempt);
+            match connect_wifi(&mut wifi, &networks) {
+                Ok(()) => {
+                    info!("WiFi reconnected after {} attempt(s)", attempt);
+                    reconnected.store(true, Ordering::Relaxed);
+                    break;
+                }
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    warn!(
+                        "WiFi reconnection attempt {} failed, retrying in {:.1}s: {}",
+                        attempt,
+                        delay.as_secs_f32(),
+                        e
+                    );
+                    thread::sleep(delay);
+                }
+            }
+        }
+    });
+}
+
+/// Signal of the access point the station is connected to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WifiSignal {
+    pub ssid: String,
+    /// In dBm
+    pub rssi: i8,
+}
+
+/// Queries the signal of the current access point. Returns None while the WiFi isn't connected.
+/// The driver is queried directly, since the WiFi itself is owned by the monitor thread.
+#[cfg(target_os = "espidf")]
+pub fn read_rssi() -> Option<WifiSignal> {
+    let mut ap_info = esp_idf_sys::wifi_ap_record_t::default();
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_wifi_sta_get_ap_info(&mut ap_info) }).ok()?;
+    let ssid_len = ap_info
+        .ssid
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(ap_info.ssid.len());
+    Some(WifiSignal {
+        ssid: String::from_utf8_lossy(&ap_info.ssid[..ssid_len]).into_owned(),
+        rssi: ap_info.rssi,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_decode_escapes() {
+        assert_eq!(url_decode("my+home%20net"), "my home net");
+        assert_eq!(url_decode("p%40ss%2Bw%3d"), "p@ss+w=");
+        assert_eq!(url_decode("caf%C3%A9"), "café");
+        assert_eq!(url_decode(""), "");
+    }
+
+    #[test]
+    fn url_decode_keeps_invalid_escapes() {
+        assert_eq!(url_decode("100%"), "100%");
+        assert_eq!(url_decode("%4"), "%4");
+        assert_eq!(url_decode("%zz%41"), "%zzA");
+        assert_eq!(url_decode("%ff"), "\u{fffd}");
+    }
+
+    // Standard query with recursion desired for the A record of "a.lan"
+    const QUERY: &[u8] = &[
+        0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 1, b'a', 3, b'l', b'a', b'n', 0, 0, 1, 0, 1,
+    ];
+
+    #[test]
+    fn dns_response_answers_with_the_address() {
+        let response = dns_response(QUERY, Ipv4Addr::new(192, 168, 4, 1)).unwrap();
+        let mut expected = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0];
+        expected.extend_from_slice(&QUERY[12..]);
+        expected.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 168, 4, 1]);
+        assert_eq!(response, expected);
+    }
+
+    #[test]
+    fn dns_response_ignores_other_packets() {
+        let ip = Ipv4Addr::new(192, 168, 4, 1);
+        // Too short for a header
+        assert_eq!(dns_response(&QUERY[..8], ip), None);
+        // A response
+        let mut response = QUERY.to_vec();
+        response[2] |= 0x80;
+        assert_eq!(dns_response(&response, ip), None);
+        // Two questions
+        let mut questions = QUERY.to_vec();
+        questions[5] = 2;
+        assert_eq!(dns_response(&questions, ip), None);
+        // Cut in the middle of the name, then of the type and class
+        assert_eq!(dns_response(&QUERY[..16], ip), None);
+        assert_eq!(dns_response(&QUERY[..QUERY.len() - 1], ip), None);
+    }
+}